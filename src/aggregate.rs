@@ -0,0 +1,430 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::column::{Batch, BatchValue, Bitmap, ColumnVector};
+use crate::expr::{Expr, TableRow};
+use crate::metadata::{ColumnMetaData, MaterializedView, TableMetaData};
+use crate::{DType, DValue};
+
+// Aggregates are stored as partial states, which can be combined with other states of the same
+// aggregate without going back to the rows they were computed from. Every state here is a single
+// value: the number of rows for Count, the running total for Sum and the extreme value for Min/Max.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggregateFunction {
+    Count,
+    Sum,
+    Min,
+    Max,
+}
+
+impl std::fmt::Display for AggregateFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            AggregateFunction::Count => "count",
+            AggregateFunction::Sum => "sum",
+            AggregateFunction::Min => "min",
+            AggregateFunction::Max => "max",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl AggregateFunction {
+    // The state for a single row, given the value of the aggregate's argument
+    pub fn initial_state(self, value: Option<DValue>) -> Result<DValue> {
+        match (self, value) {
+            (AggregateFunction::Count, None) => Ok(DValue::Uint64(1)),
+            (AggregateFunction::Sum, Some(value @ DValue::Uint64(_))) => Ok(value),
+            (AggregateFunction::Min | AggregateFunction::Max, Some(value)) => Ok(value),
+            (function, value) => Err(anyhow!("Invalid argument for {:?}: {:?}", function, value)),
+        }
+    }
+
+    pub fn merge_states(self, left: &DValue, right: &DValue) -> Result<DValue> {
+        match (self, left, right) {
+            (AggregateFunction::Count | AggregateFunction::Sum, DValue::Uint64(l), DValue::Uint64(r)) => l
+                .checked_add(*r)
+                .map(DValue::Uint64)
+                .ok_or(anyhow!("Overflow merging {:?} states {} and {}", self, l, r)),
+            (AggregateFunction::Min, left, right) => Ok(left.min(right).clone()),
+            (AggregateFunction::Max, left, right) => Ok(left.max(right).clone()),
+            _ => Err(anyhow!("Can't merge {:?} states {:?} and {:?}", self, left, right)),
+        }
+    }
+
+    // The type of the state, if it doesn't depend on the argument
+    pub fn state_dtype(self) -> Option<DType> {
+        match self {
+            AggregateFunction::Count | AggregateFunction::Sum => Some(DType::Uint64),
+            AggregateFunction::Min | AggregateFunction::Max => None,
+        }
+    }
+}
+
+// Combine the rows of a part that have the same key, merging the state columns and keeping the last
+// value of any other column. The result is sorted by key.
+pub fn aggregate_rows(
+    rows: Vec<Vec<DValue>>,
+    key_indexes: &[usize],
+    states: &[(usize, AggregateFunction)],
+) -> Result<Vec<Vec<DValue>>> {
+    let mut groups: HashMap<Vec<DValue>, Vec<DValue>> = HashMap::new();
+    for row in rows {
+        let key: Vec<DValue> = key_indexes.iter().map(|&i| row[i].clone()).collect();
+        match groups.get_mut(&key) {
+            Some(existing) => {
+                let mut merged = row;
+                for &(i, function) in states {
+                    merged[i] = function.merge_states(&existing[i], &merged[i])?;
+                }
+                *existing = merged;
+            }
+            None => {
+                groups.insert(key, row);
+            }
+        }
+    }
+
+    let mut rows: Vec<(Vec<DValue>, Vec<DValue>)> = groups.into_iter().collect();
+    rows.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(rows.into_iter().map(|(_, row)| row).collect())
+}
+
+// The rows to insert into a view's target table for rows written to its source, as maps of target
+// column name to value. Source rows are grouped by the view's key, so there is one row per key.
+pub fn view_rows(
+    view: &MaterializedView,
+    source: &TableMetaData,
+    rows: &[Vec<DValue>],
+) -> Result<Vec<HashMap<String, DValue>>> {
+    let mut groups: HashMap<Vec<DValue>, Vec<DValue>> = HashMap::new();
+    let mut order = Vec::new();
+    for values in rows {
+        let row = TableRow { columns: &source.columns, values };
+        let key = view
+            .key
+            .iter()
+            .map(|(_, expr)| expr.eval(&row))
+            .collect::<Result<Vec<DValue>>>()?;
+        let states = view
+            .aggregates
+            .iter()
+            .map(|aggregate| {
+                let value = aggregate.arg.as_ref().map(|arg| arg.eval(&row)).transpose()?;
+                aggregate.function.initial_state(value)
+            })
+            .collect::<Result<Vec<DValue>>>()?;
+
+        match groups.get_mut(&key) {
+            Some(existing) => {
+                for ((state, new), aggregate) in existing.iter_mut().zip(states).zip(&view.aggregates) {
+                    *state = aggregate.function.merge_states(state, &new)?;
+                }
+            }
+            None => {
+                order.push(key.clone());
+                groups.insert(key, states);
+            }
+        }
+    }
+
+    Ok(order
+        .into_iter()
+        .map(|key| {
+            let states = groups.remove(&key).unwrap();
+            let key_names = view.key.iter().map(|(name, _)| name.clone());
+            let state_names = view.aggregates.iter().map(|aggregate| aggregate.column.clone());
+            key_names.zip(key).chain(state_names.zip(states)).collect()
+        })
+        .collect())
+}
+
+// One aggregate of a materialized view, stored in the target table's `column`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ViewAggregate {
+    pub column: String,
+    pub function: AggregateFunction,
+    // evaluated against each source row, Count doesn't take one
+    pub arg: Option<Expr>,
+}
+
+impl ViewAggregate {
+    pub fn count(column: &str) -> ViewAggregate {
+        ViewAggregate { column: column.to_string(), function: AggregateFunction::Count, arg: None }
+    }
+
+    pub fn new(column: &str, function: AggregateFunction, arg: Expr) -> ViewAggregate {
+        ViewAggregate { column: column.to_string(), function, arg: Some(arg) }
+    }
+}
+
+// Partial aggregates of a query, the aggregate states of each group by the group's values
+pub(crate) type Groups = HashMap<Vec<DValue>, Vec<DValue>>;
+
+// An aggregate query. Rows are grouped by the values of the group_by expressions, and the result has
+// one row per group: the group's values followed by the value of each aggregate. Rows can be folded
+// into separate partial Groups (e.g. one per thread) which are merged at the end.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Aggregation {
+    pub group_by: Vec<Expr>,
+    // Count doesn't take an argument, the other functions do
+    pub aggregates: Vec<(AggregateFunction, Option<Expr>)>,
+}
+
+impl Aggregation {
+    pub fn new(group_by: Vec<Expr>) -> Aggregation {
+        Aggregation { group_by, aggregates: vec![] }
+    }
+
+    pub fn with_count(mut self) -> Aggregation {
+        self.aggregates.push((AggregateFunction::Count, None));
+        self
+    }
+
+    pub fn with_aggregate(mut self, function: AggregateFunction, arg: Expr) -> Aggregation {
+        self.aggregates.push((function, Some(arg)));
+        self
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        for (function, arg) in &self.aggregates {
+            if (*function == AggregateFunction::Count) != arg.is_none() {
+                return Err(anyhow!("Invalid argument for {:?}: {:?}", function, arg));
+            }
+        }
+        Ok(())
+    }
+
+    // Fold the selected rows of a batch into partial groups. The rows are only looked at one at a
+    // time to find their group, each aggregate is then computed in one loop over the batch.
+    pub(crate) fn add_batch(&self, groups: &mut Groups, columns: &[ColumnMetaData], batch: &Batch) -> Result<()> {
+        if batch.selection.count_ones() == 0 {
+            return Ok(());
+        }
+        let keys = self
+            .group_by
+            .iter()
+            .map(|expr| expr.eval_batch(columns, batch))
+            .collect::<Result<Vec<BatchValue>>>()?;
+        let (group_keys, row_groups) = group_rows(&keys, &batch.selection);
+        let mut group_states = vec![Vec::with_capacity(self.aggregates.len()); group_keys.len()];
+        for (function, arg) in &self.aggregates {
+            let arg = arg.as_ref().map(|arg| arg.eval_batch(columns, batch)).transpose()?;
+            let states = aggregate_batch(*function, arg.as_ref(), &row_groups, group_keys.len())?;
+            for (group, state) in group_states.iter_mut().zip(states) {
+                group.push(state);
+            }
+        }
+        for (key, states) in group_keys.into_iter().zip(group_states) {
+            self.add_states(groups, key, states)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn merge_groups(&self, groups: &mut Groups, other: Groups) -> Result<()> {
+        for (key, states) in other {
+            self.add_states(groups, key, states)?;
+        }
+        Ok(())
+    }
+
+    fn add_states(&self, groups: &mut Groups, key: Vec<DValue>, states: Vec<DValue>) -> Result<()> {
+        match groups.get_mut(&key) {
+            Some(existing) => {
+                for ((state, new), (function, _)) in existing.iter_mut().zip(states).zip(&self.aggregates) {
+                    *state = function.merge_states(state, &new)?;
+                }
+            }
+            None => {
+                groups.insert(key, states);
+            }
+        }
+        Ok(())
+    }
+
+    // The result rows, sorted by the group values. Without a GROUP BY there is always one row, unless
+    // no rows matched and there is a Min or Max, which has no value for no rows.
+    pub(crate) fn finish(&self, groups: Groups) -> Vec<Vec<DValue>> {
+        if groups.is_empty() && self.group_by.is_empty() {
+            let empty_states: Option<Vec<DValue>> = self
+                .aggregates
+                .iter()
+                .map(|(function, _)| function.state_dtype().map(|dtype| dtype.zero_value()))
+                .collect();
+            return empty_states.into_iter().collect();
+        }
+        let mut rows: Vec<(Vec<DValue>, Vec<DValue>)> = groups.into_iter().collect();
+        rows.sort_by(|(a, _), (b, _)| a.cmp(b));
+        rows.into_iter().map(|(mut key, states)| {
+            key.extend(states);
+            key
+        }).collect()
+    }
+}
+
+// The distinct group keys of the selected rows, and each selected row with the index of its group
+fn group_rows(keys: &[BatchValue], selection: &Bitmap) -> (Vec<Vec<DValue>>, Vec<(usize, usize)>) {
+    let mut group_keys = Vec::new();
+    let mut row_groups = Vec::with_capacity(selection.count_ones());
+    let mut new_group = |key: Vec<DValue>| {
+        group_keys.push(key);
+        group_keys.len() - 1
+    };
+    match keys {
+        [] => {
+            new_group(vec![]);
+            row_groups.extend(selection.ones().map(|i| (i, 0)));
+        }
+        // grouping by a single column doesn't need a key to be built for each row
+        [BatchValue::Column(column)] => match column.as_ref() {
+            ColumnVector::Uint64 { values, .. } => {
+                let mut ids: HashMap<u64, usize> = HashMap::new();
+                for i in selection.ones() {
+                    let id = *ids.entry(values[i]).or_insert_with(|| new_group(vec![DValue::Uint64(values[i])]));
+                    row_groups.push((i, id));
+                }
+            }
+            ColumnVector::String { .. } => {
+                let mut ids: HashMap<&str, usize> = HashMap::new();
+                for i in selection.ones() {
+                    let value = column.str_at(i).unwrap();
+                    let id = *ids.entry(value).or_insert_with(|| new_group(vec![DValue::String(value.to_string())]));
+                    row_groups.push((i, id));
+                }
+            }
+        },
+        keys => {
+            let mut ids: HashMap<Vec<DValue>, usize> = HashMap::new();
+            for i in selection.ones() {
+                let key: Vec<DValue> = keys.iter().map(|key| key.get(i)).collect();
+                let id = match ids.get(&key) {
+                    Some(id) => *id,
+                    None => {
+                        let id = new_group(key.clone());
+                        ids.insert(key, id);
+                        id
+                    }
+                };
+                row_groups.push((i, id));
+            }
+        }
+    }
+    (group_keys, row_groups)
+}
+
+// The state of an aggregate for each group, given the rows of each group
+fn aggregate_batch(
+    function: AggregateFunction,
+    arg: Option<&BatchValue>,
+    row_groups: &[(usize, usize)],
+    n_groups: usize,
+) -> Result<Vec<DValue>> {
+    let dtype = arg.map(|arg| arg.dtype());
+    match (function, dtype) {
+        (AggregateFunction::Count, None) => {
+            let mut counts = vec![0; n_groups];
+            for &(_, group) in row_groups {
+                counts[group] += 1;
+            }
+            Ok(counts.into_iter().map(DValue::Uint64).collect())
+        }
+        (AggregateFunction::Sum, Some(DType::Uint64)) => {
+            let values = arg.unwrap().uint64s().unwrap();
+            let mut sums = vec![0u64; n_groups];
+            for &(i, group) in row_groups {
+                let (sum, value) = (sums[group], values.at(i));
+                sums[group] = sum.checked_add(value).ok_or(anyhow!("Overflow merging {:?} states {} and {}", function, sum, value))?;
+            }
+            Ok(sums.into_iter().map(DValue::Uint64).collect())
+        }
+        (AggregateFunction::Min | AggregateFunction::Max, Some(DType::Uint64)) => {
+            let values = arg.unwrap().uint64s().unwrap();
+            let mut states: Vec<Option<u64>> = vec![None; n_groups];
+            for &(i, group) in row_groups {
+                let value = values.at(i);
+                states[group] = Some(match (states[group], function) {
+                    (None, _) => value,
+                    (Some(state), AggregateFunction::Min) => state.min(value),
+                    (Some(state), _) => state.max(value),
+                });
+            }
+            // every group has at least one row
+            Ok(states.into_iter().map(|state| DValue::Uint64(state.unwrap())).collect())
+        }
+        (AggregateFunction::Min | AggregateFunction::Max, Some(DType::String)) => {
+            let values = arg.unwrap().strs().unwrap();
+            let mut states: Vec<Option<&str>> = vec![None; n_groups];
+            for &(i, group) in row_groups {
+                let value = values.at(i);
+                states[group] = Some(match (states[group], function) {
+                    (None, _) => value,
+                    (Some(state), AggregateFunction::Min) => state.min(value),
+                    (Some(state), _) => state.max(value),
+                });
+            }
+            Ok(states.into_iter().map(|state| DValue::String(state.unwrap().to_string())).collect())
+        }
+        (function, dtype) => Err(anyhow!("Invalid argument for {:?}: {:?}", function, dtype)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::ColumnMetaData;
+
+    #[test]
+    fn test_merge_states() {
+        let a = DValue::Uint64(3);
+        let b = DValue::Uint64(5);
+        assert_eq!(AggregateFunction::Count.merge_states(&a, &b).unwrap(), DValue::Uint64(8));
+        assert_eq!(AggregateFunction::Min.merge_states(&a, &b).unwrap(), a);
+        assert_eq!(AggregateFunction::Max.merge_states(&a, &b).unwrap(), b);
+        assert!(AggregateFunction::Sum.merge_states(&a, &DValue::Uint64(u64::MAX)).is_err());
+        assert!(AggregateFunction::Sum.initial_state(Some(DValue::String("x".to_string()))).is_err());
+        assert!(AggregateFunction::Count.initial_state(Some(a)).is_err());
+    }
+
+    #[test]
+    fn test_aggregate_rows() {
+        let row = |key: &str, count: u64, max: u64| vec![DValue::String(key.to_string()), DValue::Uint64(count), DValue::Uint64(max)];
+        let rows = vec![row("b", 1, 7), row("a", 2, 3), row("b", 3, 4)];
+        let states = [(1, AggregateFunction::Count), (2, AggregateFunction::Max)];
+        assert_eq!(aggregate_rows(rows, &[0], &states).unwrap(), vec![row("a", 2, 3), row("b", 4, 7)]);
+    }
+
+    #[test]
+    fn test_aggregation() {
+        let columns = vec![ColumnMetaData::new("event", DType::String), ColumnMetaData::new("id", DType::Uint64)];
+        let aggregation = Aggregation::new(vec![Expr::column("event")])
+            .with_count()
+            .with_aggregate(AggregateFunction::Sum, Expr::column("id"));
+        let batch = |events: &[&str], ids: &[u64], selected: &[usize]| {
+            let events: Vec<_> = events.iter().map(|event| Some(DValue::String(event.to_string()))).collect();
+            let ids: Vec<_> = ids.iter().map(|id| Some(DValue::Uint64(*id))).collect();
+            let mut selection = Bitmap::new(ids.len(), false);
+            for i in selected {
+                selection.set(*i, true);
+            }
+            let columns = vec![ColumnVector::from_values(&events, &DType::String).unwrap(), ColumnVector::from_values(&ids, &DType::Uint64).unwrap()];
+            Batch { columns: columns.into_iter().map(std::sync::Arc::new).collect(), selection }
+        };
+
+        // batches folded into two sets of partial groups give the same result as folding them into one
+        let mut groups = [Groups::new(), Groups::new()];
+        aggregation.add_batch(&mut groups[0], &columns, &batch(&["b", "x", "a"], &[1, 100, 2], &[0, 2])).unwrap();
+        aggregation.add_batch(&mut groups[1], &columns, &batch(&["b"], &[3], &[0])).unwrap();
+        let [mut merged, other] = groups;
+        aggregation.merge_groups(&mut merged, other).unwrap();
+        let result = |key: &str, count: u64, sum: u64| vec![DValue::String(key.to_string()), DValue::Uint64(count), DValue::Uint64(sum)];
+        assert_eq!(aggregation.finish(merged), vec![result("a", 1, 2), result("b", 2, 4)]);
+
+        let total = Aggregation::new(vec![]).with_count();
+        assert_eq!(total.finish(Groups::new()), vec![vec![DValue::Uint64(0)]]);
+        let max = Aggregation::new(vec![]).with_aggregate(AggregateFunction::Max, Expr::column("id"));
+        assert!(max.finish(Groups::new()).is_empty());
+        assert!(Aggregation::new(vec![]).with_aggregate(AggregateFunction::Count, Expr::column("id")).validate().is_err());
+    }
+}
@@ -0,0 +1,328 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use arrow_array::builder::StringBuilder;
+use arrow_array::cast::AsArray;
+use arrow_array::types::{
+    ArrowPrimitiveType, Date32Type, Date64Type, Int16Type, Int32Type, Int64Type, Int8Type, TimestampMicrosecondType,
+    TimestampMillisecondType, TimestampNanosecondType, TimestampSecondType, UInt16Type, UInt32Type, UInt64Type, UInt8Type,
+};
+use arrow_array::{Array, ArrayRef, RecordBatch, UInt64Array};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+
+use crate::column::{Batch, BatchValue, Bitmap, ColumnVector};
+use crate::expr::Expr;
+use crate::metadata::{ColumnMetaData, TableMetaData};
+use crate::parallel::ParallelScan;
+use crate::query::QueryResult;
+use crate::{DType, DValue, DB};
+
+// Conversion to and from Apache Arrow, behind the arrow feature. Scans build each Arrow column
+// straight from the decoded column vectors of a block, so the rows never go through DValue.
+// Arrow columns are never null: like a scan, a value missing from a block reads as the type's zero
+// value.
+
+pub fn to_arrow_type(dtype: &DType) -> DataType {
+    match dtype {
+        DType::String => DataType::Utf8,
+        DType::Uint64 => DataType::UInt64,
+    }
+}
+
+// Any Arrow string type is a String, and any integer type a UInt64, whose negative values can't be
+// written. Timestamps and dates are UInt64 seconds since the epoch, and booleans 0 or 1.
+pub fn from_arrow_type(data_type: &DataType) -> Result<DType> {
+    match data_type {
+        DataType::Utf8 | DataType::LargeUtf8 | DataType::Utf8View => Ok(DType::String),
+        DataType::UInt8 | DataType::UInt16 | DataType::UInt32 | DataType::UInt64 => Ok(DType::Uint64),
+        DataType::Int8 | DataType::Int16 | DataType::Int32 | DataType::Int64 => Ok(DType::Uint64),
+        DataType::Timestamp(_, _) | DataType::Date32 | DataType::Date64 | DataType::Boolean => Ok(DType::Uint64),
+        other => Err(anyhow!("Arrow type {} has no DType", other)),
+    }
+}
+
+pub fn to_arrow_schema(table: &TableMetaData) -> Schema {
+    Schema::new(table.columns.iter().map(|col| Field::new(&col.name, to_arrow_type(&col.dtype), false)).collect::<Vec<Field>>())
+}
+
+// A table with a column for each field of the schema
+pub fn table_from_arrow_schema(name: &str, schema: &Schema) -> Result<TableMetaData> {
+    let columns = schema
+        .fields()
+        .iter()
+        .map(|field| Ok(ColumnMetaData::new(field.name(), from_arrow_type(field.data_type())?)))
+        .collect::<Result<Vec<ColumnMetaData>>>()?;
+    let table = TableMetaData::new(name, columns);
+    table.validate()?;
+    Ok(table)
+}
+
+// The rows of a table that match the filter, as one RecordBatch per block group read
+pub fn scan_arrow<'a>(db: &'a DB, table_name: &str, filter: Option<&'a Expr>) -> Result<impl Iterator<Item = Result<RecordBatch>> + 'a> {
+    let table = db.tables.iter().find(|table| table.name == table_name).ok_or(anyhow!("Table {} doesn't exist", table_name))?;
+    let schema = Arc::new(to_arrow_schema(table));
+    let scan = ParallelScan::new(db, table, filter)?;
+    Ok(scan.into_batches().map(move |batch| to_record_batch(&schema, &batch?)))
+}
+
+fn to_record_batch(schema: &SchemaRef, batch: &Batch) -> Result<RecordBatch> {
+    let arrays = batch.columns.iter().map(|column| to_array(column, &batch.selection)).collect();
+    Ok(RecordBatch::try_new(schema.clone(), arrays)?)
+}
+
+// The selected rows of a column vector
+fn to_array(column: &ColumnVector, selection: &Bitmap) -> ArrayRef {
+    match column {
+        ColumnVector::Uint64 { values, .. } => match selection.count_ones() == values.len() {
+            true => Arc::new(UInt64Array::from(values.clone())),
+            false => Arc::new(UInt64Array::from_iter_values(selection.ones().map(|i| values[i]))),
+        },
+        ColumnVector::String { data, .. } => {
+            let mut builder = StringBuilder::with_capacity(selection.count_ones(), data.len());
+            for i in selection.ones() {
+                builder.append_value(column.str_at(i).unwrap());
+            }
+            Arc::new(builder.finish())
+        }
+    }
+}
+
+// The schema of a query result with these columns
+pub fn result_schema(columns: &[(String, DType)]) -> Schema {
+    Schema::new(columns.iter().map(|(name, dtype)| Field::new(name, to_arrow_type(dtype), false)).collect::<Vec<Field>>())
+}
+
+// A query result as a single RecordBatch
+pub fn result_to_record_batch(result: &QueryResult) -> Result<RecordBatch> {
+    let arrays = result
+        .columns
+        .iter()
+        .enumerate()
+        .map(|(i, (_, dtype))| -> ArrayRef {
+            match dtype {
+                DType::Uint64 => Arc::new(result.rows.iter().map(|row| match &row[i] {
+                    DValue::Uint64(u) => Some(*u),
+                    DValue::String(_) => None,
+                }).collect::<UInt64Array>()),
+                DType::String => {
+                    let mut builder = StringBuilder::new();
+                    for row in &result.rows {
+                        match &row[i] {
+                            DValue::String(s) => builder.append_value(s),
+                            DValue::Uint64(_) => builder.append_null(),
+                        }
+                    }
+                    Arc::new(builder.finish())
+                }
+            }
+        })
+        .collect();
+    Ok(RecordBatch::try_new(Arc::new(result_schema(&result.columns)), arrays)?)
+}
+
+// A batch with a column vector for each of the table's columns, built straight from the arrays of a
+// RecordBatch whose columns are matched to the table's by name. Columns that aren't in the
+// RecordBatch, and null values, get the column's default.
+pub(crate) fn record_batch_columns(table: &TableMetaData, batch: &RecordBatch) -> Result<Batch> {
+    let mut columns: Vec<Option<Arc<ColumnVector>>> = vec![None; table.columns.len()];
+    for (field, array) in batch.schema().fields().iter().zip(batch.columns()) {
+        let index = table
+            .columns
+            .iter()
+            .position(|col| col.name == *field.name())
+            .ok_or(anyhow!("No column {} in table {}", field.name(), table.name))?;
+        let col = &table.columns[index];
+        let column = array_column(array.as_ref(), &col.dtype).map_err(|e| anyhow!("Column {}: {}", col.name, e))?;
+        columns[index] = Some(Arc::new(column));
+    }
+
+    // as in TableMetaData::row_from_named, defaults are filled in column order, and can use the
+    // columns given without nulls and the ones filled in before them
+    let mut filled: Vec<bool> = columns.iter().map(|column| column.as_ref().is_some_and(|column| column.validity().is_none())).collect();
+    for i in 0..columns.len() {
+        if !filled[i] {
+            columns[i] = Some(Arc::new(with_defaults(table, &columns, &filled, i, batch.num_rows())?));
+            filled[i] = true;
+        }
+    }
+    Ok(Batch { columns: columns.into_iter().map(Option::unwrap).collect(), selection: Bitmap::new(batch.num_rows(), true) })
+}
+
+// Column i with its default in every row it has no value for. The default is evaluated once for
+// all those rows, over the filled columns.
+fn with_defaults(table: &TableMetaData, columns: &[Option<Arc<ColumnVector>>], filled: &[bool], i: usize, n_rows: usize) -> Result<ColumnVector> {
+    let col = &table.columns[i];
+    let given = columns[i].clone().map(BatchValue::Column);
+    let mut missing = Bitmap::new(n_rows, true);
+    if let Some(validity) = columns[i].as_ref().and_then(|column| column.validity()) {
+        for row in validity.ones() {
+            missing.set(row, false);
+        }
+    }
+    let default = match &col.default {
+        // eval_batch needs a selected row
+        Some(expr) if missing.count_ones() > 0 => {
+            let (filled_cols, filled_columns): (Vec<ColumnMetaData>, Vec<Arc<ColumnVector>>) = table
+                .columns
+                .iter()
+                .zip(columns)
+                .zip(filled)
+                .filter(|(_, &filled)| filled)
+                .map(|((col, column), _)| (col.clone(), column.clone().unwrap()))
+                .unzip();
+            expr.eval_batch(&filled_cols, &Batch { columns: filled_columns, selection: missing.clone() })
+                .with_context(|| format!("Failed to evaluate default for column {}", col.name))?
+        }
+        _ => BatchValue::Scalar(col.dtype.zero_value()),
+    };
+    if default.dtype() != col.dtype {
+        return Err(anyhow!("Default for column {} is not a {:?}", col.name, col.dtype));
+    }
+
+    match col.dtype {
+        DType::Uint64 => {
+            let (default, given) = (default.uint64s().unwrap(), given.as_ref().map(|given| given.uint64s().unwrap()));
+            let values = (0..n_rows)
+                .map(|row| match given {
+                    Some(given) if !missing.get(row) => given.at(row),
+                    _ => default.at(row),
+                })
+                .collect();
+            Ok(ColumnVector::Uint64 { values, validity: None })
+        }
+        DType::String => {
+            let (default, given) = (default.strs().unwrap(), given.as_ref().map(|given| given.strs().unwrap()));
+            let mut offsets = Vec::with_capacity(n_rows + 1);
+            offsets.push(0);
+            let mut data = String::new();
+            for row in 0..n_rows {
+                match given {
+                    Some(given) if !missing.get(row) => data.push_str(given.at(row)),
+                    _ => data.push_str(default.at(row)),
+                }
+                offsets.push(data.len());
+            }
+            Ok(ColumnVector::String { offsets, data, validity: None })
+        }
+    }
+}
+
+// The values of an array as a column vector, with its null values unset in the validity bitmap
+fn array_column(array: &dyn Array, dtype: &DType) -> Result<ColumnVector> {
+    let column = match (dtype, array.data_type()) {
+        (DType::String, DataType::Utf8) => string_column(array.as_string::<i32>().iter()),
+        (DType::String, DataType::LargeUtf8) => string_column(array.as_string::<i64>().iter()),
+        (DType::String, DataType::Utf8View) => string_column(array.as_string_view().iter()),
+        (DType::Uint64, DataType::UInt8) => uint64_column::<UInt8Type>(array, |u| u)?,
+        (DType::Uint64, DataType::UInt16) => uint64_column::<UInt16Type>(array, |u| u)?,
+        (DType::Uint64, DataType::UInt32) => uint64_column::<UInt32Type>(array, |u| u)?,
+        (DType::Uint64, DataType::UInt64) => uint64_column::<UInt64Type>(array, |u| u)?,
+        (DType::Uint64, DataType::Int8) => uint64_column::<Int8Type>(array, |u| u)?,
+        (DType::Uint64, DataType::Int16) => uint64_column::<Int16Type>(array, |u| u)?,
+        (DType::Uint64, DataType::Int32) => uint64_column::<Int32Type>(array, |u| u)?,
+        (DType::Uint64, DataType::Int64) => uint64_column::<Int64Type>(array, |u| u)?,
+        (DType::Uint64, DataType::Timestamp(TimeUnit::Second, _)) => uint64_column::<TimestampSecondType>(array, |u| u)?,
+        (DType::Uint64, DataType::Timestamp(TimeUnit::Millisecond, _)) => uint64_column::<TimestampMillisecondType>(array, |u| u / 1_000)?,
+        (DType::Uint64, DataType::Timestamp(TimeUnit::Microsecond, _)) => uint64_column::<TimestampMicrosecondType>(array, |u| u / 1_000_000)?,
+        (DType::Uint64, DataType::Timestamp(TimeUnit::Nanosecond, _)) => uint64_column::<TimestampNanosecondType>(array, |u| u / 1_000_000_000)?,
+        (DType::Uint64, DataType::Date32) => uint64_column::<Date32Type>(array, |days| days * 86400)?,
+        (DType::Uint64, DataType::Date64) => uint64_column::<Date64Type>(array, |u| u / 1_000)?,
+        (DType::Uint64, DataType::Boolean) => {
+            let values = array.as_boolean().iter().map(|b| b.map_or(0, u64::from)).collect();
+            ColumnVector::Uint64 { values, validity: None }
+        }
+        (dtype, data_type) => return Err(anyhow!("Expected a {:?}, got Arrow {}", dtype, data_type)),
+    };
+    Ok(match validity(array) {
+        Some(bitmap) => match column {
+            ColumnVector::Uint64 { values, .. } => ColumnVector::Uint64 { values, validity: Some(bitmap) },
+            ColumnVector::String { offsets, data, .. } => ColumnVector::String { offsets, data, validity: Some(bitmap) },
+        },
+        None => column,
+    })
+}
+
+// Which rows of an array have a value, or None if they all do
+fn validity(array: &dyn Array) -> Option<Bitmap> {
+    if array.null_count() == 0 {
+        return None;
+    }
+    let mut validity = Bitmap::new(array.len(), true);
+    for i in (0..array.len()).filter(|&i| array.is_null(i)) {
+        validity.set(i, false);
+    }
+    Some(validity)
+}
+
+fn string_column<'a>(values: impl ExactSizeIterator<Item = Option<&'a str>>) -> ColumnVector {
+    let mut offsets = Vec::with_capacity(values.len() + 1);
+    offsets.push(0);
+    let mut data = String::new();
+    for value in values {
+        data.push_str(value.unwrap_or_default());
+        offsets.push(data.len());
+    }
+    ColumnVector::String { offsets, data, validity: None }
+}
+
+// Non-negative integers, converted e.g. from milliseconds to seconds. Null values are 0.
+fn uint64_column<T: ArrowPrimitiveType>(array: &dyn Array, convert: fn(u64) -> u64) -> Result<ColumnVector>
+where
+    T::Native: TryInto<u64>,
+{
+    let values = array
+        .as_primitive::<T>()
+        .iter()
+        .map(|value| match value {
+            Some(value) => match value.try_into() {
+                Ok(u) => Ok(convert(u)),
+                Err(_) => Err(anyhow!("Expected a UInt64, got {:?}", value)),
+            },
+            None => Ok(0),
+        })
+        .collect::<Result<Vec<u64>>>()?;
+    Ok(ColumnVector::Uint64 { values, validity: None })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::{Int64Array, StringArray};
+    use crate::expr::Function;
+
+    #[test]
+    fn test_record_batch_columns() {
+        let table = TableMetaData::new(
+            "events",
+            vec![
+                ColumnMetaData::new("event", DType::String),
+                ColumnMetaData::new("id", DType::Uint64).with_default(Expr::Literal(DValue::Uint64(7))),
+                ColumnMetaData::new("label", DType::String)
+                    .with_default(Expr::function(Function::Concat, vec![Expr::column("event"), Expr::literal(DValue::String("!".to_string()))])),
+            ],
+        );
+        let from_schema = table_from_arrow_schema("events", &to_arrow_schema(&table)).unwrap();
+        assert_eq!(from_schema.columns.len(), 3);
+        assert_eq!(from_schema.columns[1], ColumnMetaData::new("id", DType::Uint64));
+
+        let batch = RecordBatch::try_from_iter(vec![
+            ("id", Arc::new(Int64Array::from(vec![Some(1), None, Some(3)])) as ArrayRef),
+            ("label", Arc::new(StringArray::from(vec![None, Some("x"), None])) as ArrayRef),
+            ("event", Arc::new(StringArray::from(vec!["a", "b", "c"])) as ArrayRef),
+        ])
+        .unwrap();
+        let string = |s: &str| DValue::String(s.to_string());
+        assert_eq!(record_batch_columns(&table, &batch).unwrap().rows(), vec![
+            vec![string("a"), DValue::Uint64(1), string("a!")],
+            vec![string("b"), DValue::Uint64(7), string("x")],
+            vec![string("c"), DValue::Uint64(3), string("c!")],
+        ]);
+        let negative = RecordBatch::try_from_iter(vec![("id", Arc::new(Int64Array::from(vec![-1])) as ArrayRef)]).unwrap();
+        assert!(record_batch_columns(&table, &negative).is_err());
+        let wrong_type = RecordBatch::try_from_iter(vec![("event", Arc::new(Int64Array::from(vec![1])) as ArrayRef)]).unwrap();
+        assert!(record_batch_columns(&table, &wrong_type).is_err());
+        // the default of label needs event
+        let without_event = RecordBatch::try_from_iter(vec![("id", Arc::new(Int64Array::from(vec![1])) as ArrayRef)]).unwrap();
+        assert_eq!(record_batch_columns(&table, &without_event).unwrap().rows(), vec![vec![string(""), DValue::Uint64(1), string("!")]]);
+    }
+}
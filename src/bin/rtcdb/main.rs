@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::process::ExitCode;
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+
+use rtcdb::export::{ExportFormat, RowWriter};
+use rtcdb::import::{CsvOptions, ImportSummary, JsonlOptions};
+use rtcdb::sql::{self, Statement};
+use rtcdb::{Aggregation, DValue, Expr, TableMetaData, DB};
+
+mod shell;
+
+// A command line tool for setting up, loading, querying and inspecting a database without writing
+// any Rust. Results are printed as tab separated text with a header row, or in the format given by
+// --format or a FORMAT clause.
+
+const USAGE: &str = "Usage:
+  rtcdb init <dir> --schema <schema.json>
+  rtcdb insert <dir> <table> --format csv|jsonl [--delimiter <char>] [--map <path>=<column>,...]
+               [--catch-all <column>] [--max-errors <n>] < <file>
+  rtcdb insert <dir> <table> --format parquet --file <file.parquet>
+  rtcdb query <dir> <sql or query json> [--format tsv|csv|jsonl|json]
+  rtcdb query <dir> <sql> --format parquet > <file.parquet>
+  rtcdb shell <dir>
+  rtcdb tables <dir>
+  rtcdb inspect <dir> <table> <column>";
+
+// A scan or aggregation written as JSON, using the same JSON as the Rust types in metadata.json,
+// e.g. {"table": "events", "filter": {"BinaryOp": [{"Column": "id"}, "Lt", {"Literal": {"Uint64": 10}}]}}
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonQuery {
+    table: String,
+    #[serde(default)]
+    filter: Option<Expr>,
+    #[serde(default)]
+    aggregation: Option<Aggregation>,
+    // for an aggregation, 0 for one thread per core. Scans are written out as they're read, on one thread.
+    #[serde(default)]
+    max_threads: usize,
+}
+
+// The schema file is either one table or a list of them
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Schema {
+    Tables(Vec<TableMetaData>),
+    Table(TableMetaData),
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:#}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: &[String]) -> Result<()> {
+    let (positional, options) = parse_args(args)?;
+    let mut out = BufWriter::new(io::stdout().lock());
+    match positional.as_slice() {
+        ["init", dir] => {
+            let schema = options.get("schema").ok_or(anyhow!("init needs --schema <schema.json>"))?;
+            let json = fs::read_to_string(schema).with_context(|| format!("Couldn't read {}", schema))?;
+            let tables = match serde_json::from_str(&json).with_context(|| format!("Invalid schema in {}", schema))? {
+                Schema::Tables(tables) => tables,
+                Schema::Table(table) => vec![table],
+            };
+            fs::create_dir_all(dir).with_context(|| format!("Couldn't create {}", dir))?;
+            DB::init(dir, tables)?;
+        }
+        ["insert", dir, table] => {
+            let db = DB::open(dir)?;
+            let stdin = io::stdin().lock();
+            let max_errors = match options.get("max-errors") {
+                Some(n) => n.parse().map_err(|_| anyhow!("--max-errors needs a number, got {}", n))?,
+                None => 0,
+            };
+            let summary = match options.get("format").map(|format| format.as_str()) {
+                Some("csv") => {
+                    let mut csv_options = CsvOptions::new().with_max_errors(max_errors);
+                    if let Some(delimiter) = options.get("delimiter") {
+                        csv_options = csv_options.with_delimiter(parse_char(delimiter)?);
+                    }
+                    db.import_csv(table, stdin, &csv_options)?
+                }
+                Some("jsonl") => {
+                    let mut jsonl_options = JsonlOptions::new().with_max_errors(max_errors);
+                    for mapping in options.get("map").iter().flat_map(|map| map.split(',')) {
+                        let (path, column) = mapping.split_once('=').ok_or(anyhow!("--map needs <path>=<column>, got {}", mapping))?;
+                        jsonl_options = jsonl_options.with_path_mapping(path, column);
+                    }
+                    if let Some(column) = options.get("catch-all") {
+                        jsonl_options = jsonl_options.with_catch_all_column(column);
+                    }
+                    db.import_jsonl(table, stdin, &jsonl_options)?
+                }
+                Some("parquet") => import_parquet(&db, table, options.get("file"))?,
+                _ => return Err(anyhow!("insert needs --format csv, jsonl or parquet")),
+            };
+            for error in &summary.errors {
+                eprintln!("Skipped {}", error);
+            }
+            eprintln!("Inserted {} rows", summary.rows_written);
+        }
+        ["query", dir, query] => {
+            let mut db = DB::open(dir)?;
+            if options.get("format").is_some_and(|format| format == "parquet") {
+                return export_parquet(&db, query);
+            }
+            let format = match options.get("format") {
+                Some(name) => ExportFormat::from_name(name)?,
+                None => ExportFormat::Tsv,
+            };
+            if query.trim_start().starts_with('{') {
+                json_query(&db, query, format, &mut out)?;
+            } else {
+                for (i, statement) in sql::parse_script(query)?.into_iter().enumerate() {
+                    let result = match statement {
+                        // statements like CREATE TABLE don't return anything
+                        Statement::CreateTable(_) | Statement::Insert(_) | Statement::DropTable { .. } => {
+                            db.execute_statement(statement, None).map(|_| ())
+                        }
+                        statement => db.export_statement(statement, format, &mut out).map(|_| ()),
+                    };
+                    result.with_context(|| format!("Statement {} failed", i + 1))?;
+                }
+            }
+        }
+        ["shell", dir] => shell::run(dir)?,
+        ["tables", dir] => {
+            let db = DB::open(dir)?;
+            for table in &db.tables {
+                let columns: Vec<String> = table.columns.iter().map(|col| format!("{} {:?}", col.name, col.dtype)).collect();
+                writeln!(out, "{}\t{}", table.name, columns.join(", "))?;
+            }
+        }
+        ["inspect", dir, table, column] => {
+            let db = DB::open(dir)?;
+            writeln!(out, "part\tblock\toffset\tcompressed_size\tdecompressed_size\trows\tmin\tmax")?;
+            for (part_path, blocks) in db.inspect_column(table, column)? {
+                let part = part_path.strip_prefix(Path::new(dir)).unwrap_or(&part_path).to_string_lossy().to_string();
+                let part = if part.is_empty() { ".".to_string() } else { part };
+                for block in blocks {
+                    let (min, max) = match &block.range {
+                        Some((min, max)) => (format_value(min), format_value(max)),
+                        None => ("-".to_string(), "-".to_string()),
+                    };
+                    writeln!(
+                        out,
+                        "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                        part, block.block, block.start_position, block.compressed_size, block.decompressed_size, block.n_rows, min, max
+                    )?;
+                }
+            }
+        }
+        _ => return Err(anyhow!("{}", USAGE)),
+    }
+    out.flush()?;
+    Ok(())
+}
+
+// Split the arguments into positional ones and --name value options
+fn parse_args(args: &[String]) -> Result<(Vec<&str>, HashMap<String, String>)> {
+    let mut positional = vec![];
+    let mut options = HashMap::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.strip_prefix("--") {
+            Some(name) => {
+                let value = args.next().ok_or(anyhow!("--{} needs a value", name))?;
+                options.insert(name.to_string(), value.clone());
+            }
+            None => positional.push(arg.as_str()),
+        }
+    }
+    Ok((positional, options))
+}
+
+// A single character, or \t for a tab
+fn parse_char(text: &str) -> Result<char> {
+    let mut chars = text.chars();
+    match (text, chars.next(), chars.next()) {
+        ("\\t", _, _) => Ok('\t'),
+        (_, Some(c), None) => Ok(c),
+        _ => Err(anyhow!("Expected a single character, got {}", text)),
+    }
+}
+
+// Parquet needs to seek, so it's read from a file rather than stdin
+#[cfg(feature = "parquet")]
+fn import_parquet(db: &DB, table: &str, path: Option<&String>) -> Result<ImportSummary> {
+    let path = path.ok_or(anyhow!("--format parquet needs --file <file.parquet>"))?;
+    let file = fs::File::open(path).with_context(|| format!("Couldn't open {}", path))?;
+    Ok(ImportSummary { rows_written: db.import_parquet(table, file)?, errors: vec![] })
+}
+
+#[cfg(not(feature = "parquet"))]
+fn import_parquet(_db: &DB, _table: &str, _path: Option<&String>) -> Result<ImportSummary> {
+    Err(anyhow!("rtcdb was built without the parquet feature"))
+}
+
+#[cfg(feature = "parquet")]
+fn export_parquet(db: &DB, sql: &str) -> Result<()> {
+    db.export_parquet(sql, BufWriter::new(io::stdout()))?;
+    Ok(())
+}
+
+#[cfg(not(feature = "parquet"))]
+fn export_parquet(_db: &DB, _sql: &str) -> Result<()> {
+    Err(anyhow!("rtcdb was built without the parquet feature"))
+}
+
+fn json_query(db: &DB, query: &str, format: ExportFormat, out: &mut impl Write) -> Result<()> {
+    let query: JsonQuery = serde_json::from_str(query).context("Invalid query JSON")?;
+    match &query.aggregation {
+        None => {
+            db.export_table(&query.table, query.filter.as_ref(), format, out)?;
+        }
+        Some(aggregation) => {
+            let names: Vec<String> = aggregation
+                .group_by
+                .iter()
+                .map(|expr| expr.to_string())
+                .chain(aggregation.aggregates.iter().map(|(function, arg)| {
+                    let arg = arg.as_ref().map_or(String::new(), |arg| arg.to_string());
+                    format!("{}({})", function, arg)
+                }))
+                .collect();
+            let mut writer = RowWriter::new(out, format, &names)?;
+            for row in db.aggregate(&query.table, aggregation, query.filter.as_ref(), query.max_threads)? {
+                writer.write_row(&row)?;
+            }
+            writer.finish()?;
+        }
+    }
+    Ok(())
+}
+
+fn format_value(value: &DValue) -> String {
+    match value {
+        DValue::Uint64(u) => u.to_string(),
+        DValue::String(s) => s.clone(),
+    }
+}
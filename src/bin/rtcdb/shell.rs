@@ -0,0 +1,315 @@
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+
+use anyhow::Result;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Editor, Helper};
+
+use rtcdb::explain::QueryStats;
+use rtcdb::{sql, DType, DValue, QueryResult, DB};
+
+// An interactive prompt for running SQL against a database. Statements end with a semicolon and can
+// span several lines. Lines starting with a backslash are meta-commands, see HELP.
+
+const HELP: &str = "Statements end with ; and can span several lines.
+  \\d          list the tables
+  \\d <table>  describe a table
+  \\?          show this help
+  \\q          quit";
+
+// Completed along with the table and column names
+const COMPLETIONS: [&str; 39] = [
+    "SELECT", "FROM", "WHERE", "GROUP", "BY", "HAVING", "ORDER", "LIMIT", "OFFSET", "SETTINGS", "AS", "ASC", "DESC",
+    "AND", "OR", "NOT", "CREATE", "TABLE", "INSERT", "INTO", "VALUES", "DROP", "SHOW", "TABLES", "DESCRIBE", "EXPLAIN",
+    "ANALYZE", "FORMAT", "count", "sum", "min", "max", "lower", "upper", "concat", "contains", "toStartOfHour", "toStartOfDay",
+    "toYYYYMM",
+];
+
+// Completes the word before the cursor from keywords and the database's table and column names
+struct ShellHelper {
+    words: Vec<String>,
+}
+
+impl ShellHelper {
+    fn new(db: &DB) -> ShellHelper {
+        let mut helper = ShellHelper { words: vec![] };
+        helper.refresh(db);
+        helper
+    }
+
+    // Pick up tables created or dropped since the last statement
+    fn refresh(&mut self, db: &DB) {
+        let names = db.tables.iter().flat_map(|table| {
+            std::iter::once(table.name.clone()).chain(table.columns.iter().map(|col| col.name.clone()))
+        });
+        self.words = COMPLETIONS.iter().map(|word| word.to_string()).chain(names).collect();
+        self.words.sort();
+        self.words.dedup();
+    }
+
+    fn completions(&self, line: &str, pos: usize) -> (usize, Vec<String>) {
+        let start = line[..pos]
+            .char_indices()
+            .rev()
+            .find(|&(_, c)| !(c.is_alphanumeric() || c == '_'))
+            .map_or(0, |(i, c)| i + c.len_utf8());
+        let word = line[start..pos].to_lowercase();
+        if word.is_empty() {
+            return (start, vec![]);
+        }
+        let matches = self.words.iter().filter(|candidate| candidate.to_lowercase().starts_with(&word)).cloned().collect();
+        (start, matches)
+    }
+}
+
+impl Completer for ShellHelper {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &rustyline::Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(self.completions(line, pos))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+pub fn run(dir: &str) -> Result<()> {
+    let mut db = DB::open(dir)?;
+    let mut editor: Editor<ShellHelper, DefaultHistory> = Editor::new()?;
+    editor.set_helper(Some(ShellHelper::new(&db)));
+    let history_path = std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".rtcdb_history"));
+    if let Some(path) = &history_path {
+        // there's no history the first time
+        let _ = editor.load_history(path);
+    }
+
+    let mut statement = String::new();
+    loop {
+        let prompt = if statement.is_empty() { "rtcdb> " } else { "    -> " };
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            // Ctrl-C abandons the statement being typed
+            Err(ReadlineError::Interrupted) => {
+                statement.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        if statement.is_empty() && line.trim().is_empty() {
+            continue;
+        }
+        editor.add_history_entry(line.as_str())?;
+
+        if statement.is_empty() && line.trim_start().starts_with('\\') {
+            match meta_command(&db, line.trim()) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => println!("Error: {:#}", e),
+            }
+            continue;
+        }
+        statement.push_str(&line);
+        statement.push('\n');
+        if statement.trim_end().ends_with(';') {
+            run_statements(&mut db, &std::mem::take(&mut statement));
+            if let Some(helper) = editor.helper_mut() {
+                helper.refresh(&db);
+            }
+        }
+    }
+    // run a last statement that wasn't ended with a semicolon
+    if !statement.trim().is_empty() {
+        run_statements(&mut db, &statement);
+    }
+
+    if let Some(path) = &history_path {
+        editor.save_history(path)?;
+    }
+    Ok(())
+}
+
+// Returns false to quit
+fn meta_command(db: &DB, command: &str) -> Result<bool> {
+    let mut words = command.split_whitespace();
+    match (words.next(), words.next()) {
+        (Some("\\q"), None) => return Ok(false),
+        (Some("\\?"), None) => println!("{}", HELP),
+        (Some("\\d"), None) => {
+            let columns = vec![("table".to_string(), DType::String), ("columns".to_string(), DType::Uint64)];
+            let rows = db
+                .tables
+                .iter()
+                .map(|table| vec![DValue::String(table.name.clone()), DValue::Uint64(table.columns.len() as u64)])
+                .collect();
+            print!("{}", format_table(&QueryResult { columns, rows }));
+        }
+        (Some("\\d"), Some(table_name)) => {
+            print!("{}", format_table(&db.query(&format!("DESCRIBE \"{}\"", table_name.replace('"', "\"\"")))?));
+            let table = db.tables.iter().find(|table| table.name == table_name).unwrap();
+            if let Some(partition_by) = &table.partition_by {
+                println!("Partition by: {}", partition_by);
+            }
+            if !table.order_by.is_empty() {
+                println!("Order by: {}", table.order_by.join(", "));
+            }
+            if !table.primary_key.is_empty() {
+                println!("Primary key: {}", table.primary_key.join(", "));
+            }
+            println!("Engine: {:?}", table.engine);
+        }
+        _ => println!("Unknown command {}, \\? lists the commands", command),
+    }
+    Ok(true)
+}
+
+// Run each statement, printing its result along with how long it took and what it read
+fn run_statements(db: &mut DB, text: &str) {
+    let statements = match sql::parse_script(text) {
+        Ok(statements) => statements,
+        Err(e) => return println!("Error: {:#}", e),
+    };
+    for statement in statements {
+        // a FORMAT clause prints the rows in that format instead of a table
+        if let sql::Statement::Select(sql::Select { format: Some(format), .. }) = &statement {
+            let format = *format;
+            if let Err(e) = db.export_statement(statement, format, io::stdout().lock()) {
+                return println!("Error: {:#}", e);
+            }
+            continue;
+        }
+        let stats = QueryStats::default();
+        let start = Instant::now();
+        let result = match db.execute_statement(statement, Some(&stats)) {
+            Ok(result) => result,
+            Err(e) => return println!("Error: {:#}", e),
+        };
+        let elapsed = start.elapsed();
+        if result.columns.is_empty() {
+            println!("OK in {:?}", elapsed);
+            continue;
+        }
+        print!("{}", format_table(&result));
+        let get = |counter: &std::sync::atomic::AtomicU64| counter.load(Ordering::Relaxed);
+        let mut summary = format!("{} in {:?}", plural(result.rows.len() as u64, "row"), elapsed);
+        if get(&stats.parts) > 0 {
+            summary.push_str(&format!(
+                ", read {} in {}, {} from disk and {} from the cache",
+                plural(get(&stats.rows_read), "row"),
+                plural(get(&stats.blocks_read), "block"),
+                format_bytes(get(&stats.bytes_read)),
+                plural(get(&stats.blocks_cached), "block")
+            ));
+        }
+        println!("{}", summary);
+    }
+}
+
+// The rows as an aligned table with a header, numbers aligned right and strings left
+fn format_table(result: &QueryResult) -> String {
+    let cells: Vec<Vec<String>> = result
+        .rows
+        .iter()
+        .map(|row| {
+            row.iter()
+                .map(|value| match value {
+                    DValue::Uint64(u) => u.to_string(),
+                    DValue::String(s) => s.clone(),
+                })
+                .collect()
+        })
+        .collect();
+    let widths: Vec<usize> = result
+        .columns
+        .iter()
+        .enumerate()
+        .map(|(i, (name, _))| cells.iter().map(|row| row[i].chars().count()).chain([name.chars().count()]).max().unwrap())
+        .collect();
+
+    let pad = |text: &str, width: usize, right: bool| {
+        let padding = " ".repeat(width - text.chars().count());
+        if right {
+            format!("{}{}", padding, text)
+        } else {
+            format!("{}{}", text, padding)
+        }
+    };
+    let line = |values: Vec<String>| format!(" {}\n", values.join(" | ").trim_end());
+    let mut table = line(result.columns.iter().zip(&widths).map(|((name, _), &width)| pad(name, width, false)).collect());
+    table.push_str(&format!("-{}-\n", widths.iter().map(|&width| "-".repeat(width)).collect::<Vec<String>>().join("-+-")));
+    for row in &cells {
+        let values = row
+            .iter()
+            .zip(&result.columns)
+            .zip(&widths)
+            .map(|((cell, (_, dtype)), &width)| pad(cell, width, *dtype == DType::Uint64))
+            .collect();
+        table.push_str(&line(values));
+    }
+    table
+}
+
+fn plural(n: u64, noun: &str) -> String {
+    match n {
+        1 => format!("1 {}", noun),
+        n => format!("{} {}s", n, noun),
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", size, units[unit]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_table() {
+        let result = QueryResult {
+            columns: vec![("event".to_string(), DType::String), ("count()".to_string(), DType::Uint64)],
+            rows: vec![
+                vec![DValue::String("click".to_string()), DValue::Uint64(12)],
+                vec![DValue::String("page_view".to_string()), DValue::Uint64(3)],
+            ],
+        };
+        assert_eq!(format_table(&result), concat!(
+            " event     | count()\n",
+            "-----------+---------\n",
+            " click     |      12\n",
+            " page_view |       3\n",
+        ));
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+
+        let helper = ShellHelper { words: vec!["SELECT".to_string(), "events".to_string(), "event".to_string()] };
+        assert_eq!(helper.completions("select * from EV", 16), (14, vec!["events".to_string(), "event".to_string()]));
+        assert_eq!(helper.completions("sel", 3), (0, vec!["SELECT".to_string()]));
+        // the word starts after the whole of a multibyte separator
+        assert_eq!(helper.completions("«ev", 4), (2, vec!["events".to_string(), "event".to_string()]));
+        assert_eq!(helper.completions("«", 2), (2, vec![]));
+    }
+}
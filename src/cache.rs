@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use anyhow::Result;
+use lru::LruCache;
+
+use crate::column::ColumnVector;
+use crate::metadata::TableMetaData;
+use crate::partition::PartitionList;
+use crate::storage::{file_id, PartFiles};
+
+pub const DEFAULT_CACHE_SIZE: usize = 64 * 1024 * 1024;
+
+// Identifies one block of one column. Blocks are keyed by where they are in the data file rather
+// than by block number, because an UPDATE moves a block to the end of the file while keeping its
+// number. Merges rewrite a part's files in place, so the key also has the identity of the index
+// file, which is replaced whenever the data file is.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BlockKey {
+    pub table: String,
+    pub data_path: PathBuf,
+    pub file_id: u64,
+    pub start_position: u64,
+    pub compressed_size: u64,
+}
+
+// A bounded LRU cache of decoded column blocks, shared by every scan through a DB handle.
+// The size is measured in bytes of decoded data.
+pub struct BlockCache {
+    capacity: usize,
+    state: Mutex<CacheState>,
+}
+
+struct CacheState {
+    blocks: LruCache<BlockKey, (Arc<ColumnVector>, usize)>,
+    size: usize,
+    hits: u64,
+    misses: u64,
+}
+
+impl BlockCache {
+    pub fn new(capacity: usize) -> BlockCache {
+        BlockCache {
+            capacity,
+            state: Mutex::new(CacheState { blocks: LruCache::unbounded(), size: 0, hits: 0, misses: 0 }),
+        }
+    }
+
+    pub fn get(&self, key: &BlockKey) -> Option<Arc<ColumnVector>> {
+        let mut state = self.state.lock().unwrap();
+        match state.blocks.get(key).map(|(values, _)| values.clone()) {
+            Some(values) => {
+                state.hits += 1;
+                Some(values)
+            }
+            None => {
+                state.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&self, key: BlockKey, values: Arc<ColumnVector>, size: usize) {
+        if size > self.capacity {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        if let Some((_, (_, old_size))) = state.blocks.push(key, (values, size)) {
+            state.size -= old_size;
+        }
+        state.size += size;
+        while state.size > self.capacity {
+            let (_, (_, evicted_size)) = state.blocks.pop_lru().unwrap();
+            state.size -= evicted_size;
+        }
+    }
+
+    // Forget every block of a table, for when its files have been changed
+    pub fn invalidate_table(&self, table_name: &str) {
+        let mut state = self.state.lock().unwrap();
+        let keys: Vec<BlockKey> = state
+            .blocks
+            .iter()
+            .filter(|(key, _)| key.table == table_name)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in keys {
+            let (_, size) = state.blocks.pop(&key).unwrap();
+            state.size -= size;
+        }
+    }
+
+    // The number of lookups that found the block, and that didn't
+    pub fn stats(&self) -> (u64, u64) {
+        let state = self.state.lock().unwrap();
+        (state.hits, state.misses)
+    }
+
+    // The total size of the cached blocks
+    pub fn size(&self) -> usize {
+        self.state.lock().unwrap().size
+    }
+}
+
+impl fmt::Debug for BlockCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "BlockCache {{ capacity: {}, size: {} }}", self.capacity, self.size())
+    }
+}
+
+// The length, modification time and inode of the files something was loaded from, taken before
+// loading it. Writing to a file or replacing it changes these, so a cached copy can be checked
+// against the files with a stat each rather than by reading them again.
+#[derive(Default)]
+pub(crate) struct FileStamps {
+    stamps: Vec<(PathBuf, Option<FileStamp>)>,
+}
+
+// length, modification time, inode
+type FileStamp = (u64, Option<SystemTime>, u64);
+
+impl FileStamps {
+    pub(crate) fn add(&mut self, path: PathBuf) {
+        let stamp = stamp(&path);
+        self.stamps.push((path, stamp));
+    }
+
+    pub(crate) fn are_current(&self) -> bool {
+        self.stamps.iter().all(|(path, old)| stamp(path) == *old)
+    }
+}
+
+// None if the file doesn't exist, so that creating it is noticed too
+fn stamp(path: &Path) -> Option<FileStamp> {
+    fs::metadata(path).ok().map(|metadata| (metadata.len(), metadata.modified().ok(), file_id(&metadata)))
+}
+
+// The opened index files, skip indexes, deleted masks and primary indexes of the parts scanned
+// through a DB handle, and the list of each partitioned table's partitions, so each query doesn't
+// load them again. Unlike blocks these change in place when a part is written to. DB forgets a
+// table's parts whenever it changes the table's files, and anything else writing to them (such as
+// another process) is caught by checking the files' stamps each time a part is used.
+pub struct PartCache {
+    state: Mutex<PartCacheState>,
+}
+
+struct PartCacheState {
+    parts: HashMap<(String, PathBuf), Arc<PartFiles>>,
+    partitions: HashMap<(String, PathBuf), Arc<PartitionList>>,
+    // bumped each time a table is invalidated, so parts loaded while it was being changed aren't kept
+    generations: HashMap<String, u64>,
+}
+
+impl PartCache {
+    pub fn new() -> PartCache {
+        PartCache {
+            state: Mutex::new(PartCacheState { parts: HashMap::new(), partitions: HashMap::new(), generations: HashMap::new() }),
+        }
+    }
+
+    pub(crate) fn get_or_load(&self, root_path: &Path, table: &TableMetaData) -> Result<Arc<PartFiles>> {
+        self.get_or_load_in(root_path, table, |state| &mut state.parts, |files| files.is_current(), || PartFiles::load(root_path, table))
+    }
+
+    // The table's partitions, for a partitioned table
+    pub(crate) fn get_or_list_partitions(&self, root_path: &Path, table: &TableMetaData) -> Result<Arc<PartitionList>> {
+        self.get_or_load_in(root_path, table, |state| &mut state.partitions, |list| list.is_current(), || PartitionList::load(root_path, table))
+    }
+
+    fn get_or_load_in<T>(
+        &self,
+        root_path: &Path,
+        table: &TableMetaData,
+        entries: impl Fn(&mut PartCacheState) -> &mut HashMap<(String, PathBuf), Arc<T>>,
+        is_current: impl Fn(&T) -> bool,
+        load: impl FnOnce() -> Result<T>,
+    ) -> Result<Arc<T>> {
+        let key = (table.name.clone(), root_path.to_path_buf());
+        let (generation, cached) = {
+            let mut state = self.state.lock().unwrap();
+            (state.generations.get(&table.name).copied().unwrap_or(0), entries(&mut state).get(&key).cloned())
+        };
+        // checked and loaded without holding the lock, so other scans aren't held up by the disk
+        if let Some(cached) = cached.filter(|cached| is_current(cached)) {
+            return Ok(cached);
+        }
+        let loaded = Arc::new(load()?);
+        let mut state = self.state.lock().unwrap();
+        if state.generations.get(&table.name).copied().unwrap_or(0) == generation {
+            entries(&mut state).insert(key, loaded.clone());
+        }
+        Ok(loaded)
+    }
+
+    // Forget every part of a table, for when its files have been changed
+    pub fn invalidate_table(&self, table_name: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.parts.retain(|(table, _), _| table != table_name);
+        state.partitions.retain(|(table, _), _| table != table_name);
+        *state.generations.entry(table_name.to_string()).or_default() += 1;
+    }
+
+    // The number of cached parts
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().parts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for PartCache {
+    fn default() -> PartCache {
+        PartCache::new()
+    }
+}
+
+impl fmt::Debug for PartCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PartCache {{ parts: {} }}", self.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(table: &str, start_position: u64) -> BlockKey {
+        BlockKey {
+            table: table.to_string(),
+            data_path: PathBuf::from(format!("{}.id.data", table)),
+            file_id: 1,
+            start_position,
+            compressed_size: 10,
+        }
+    }
+
+    #[test]
+    fn test_block_cache() {
+        let cache = BlockCache::new(100);
+        let values = Arc::new(ColumnVector::Uint64 { values: vec![1], validity: None });
+        cache.insert(key("a", 0), values.clone(), 40);
+        cache.insert(key("a", 10), values.clone(), 40);
+        assert!(cache.get(&key("a", 0)).is_some());
+        // the least recently used block is evicted to make room
+        cache.insert(key("b", 0), values.clone(), 40);
+        assert!(cache.get(&key("a", 10)).is_none());
+        assert_eq!(cache.size(), 80);
+        assert_eq!(cache.stats(), (1, 1));
+
+        cache.invalidate_table("a");
+        assert!(cache.get(&key("a", 0)).is_none());
+        assert!(cache.get(&key("b", 0)).is_some());
+        assert_eq!(cache.size(), 40);
+        cache.insert(key("c", 0), values, 101);
+        assert_eq!(cache.size(), 40);
+    }
+}
@@ -0,0 +1,353 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+
+use crate::{get_dtype, DType, DValue};
+
+// Blocks are decoded into typed column vectors rather than a DValue per cell, so filters and
+// aggregates can run in tight loops over a whole block, and strings don't need an allocation each.
+// Values are only turned into DValues when they are returned to the caller.
+
+// One bit per row of a block, e.g. which rows have a value, or which rows are selected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitmap {
+    words: Vec<u64>,
+    len: usize,
+}
+
+impl Bitmap {
+    pub fn new(len: usize, value: bool) -> Bitmap {
+        let word = if value { u64::MAX } else { 0 };
+        let mut bitmap = Bitmap { words: vec![word; len.div_ceil(64)], len };
+        bitmap.clear_unused();
+        bitmap
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, i: usize) -> bool {
+        self.words[i / 64] & (1 << (i % 64)) != 0
+    }
+
+    pub fn set(&mut self, i: usize, value: bool) {
+        if value {
+            self.words[i / 64] |= 1 << (i % 64);
+        } else {
+            self.words[i / 64] &= !(1 << (i % 64));
+        }
+    }
+
+    pub fn count_ones(&self) -> usize {
+        self.words.iter().map(|word| word.count_ones() as usize).sum()
+    }
+
+    pub fn and(&mut self, other: &Bitmap) {
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word &= other;
+        }
+    }
+
+    // The positions of the set bits, in order
+    pub fn ones(&self) -> impl Iterator<Item = usize> + '_ {
+        self.words.iter().enumerate().flat_map(|(i, &word)| {
+            let mut word = word;
+            std::iter::from_fn(move || {
+                if word == 0 {
+                    return None;
+                }
+                let bit = word.trailing_zeros() as usize;
+                word &= word - 1;
+                Some(i * 64 + bit)
+            })
+        })
+    }
+
+    // the bits past the end are always unset, so counting and iterating can ignore len
+    fn clear_unused(&mut self) {
+        if !self.len.is_multiple_of(64) {
+            if let Some(last) = self.words.last_mut() {
+                *last &= (1 << (self.len % 64)) - 1;
+            }
+        }
+    }
+}
+
+// The values of one column over the rows of a block. Rows without a value (e.g. the rows of a block
+// written before the column was added) are unset in the validity bitmap, which is None when every
+// row has a value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ColumnVector {
+    Uint64 {
+        values: Vec<u64>,
+        validity: Option<Bitmap>,
+    },
+    // the value of row i is data[offsets[i]..offsets[i + 1]]
+    String {
+        offsets: Vec<usize>,
+        data: String,
+        validity: Option<Bitmap>,
+    },
+}
+
+impl ColumnVector {
+    // Decode a decompressed block, as written by storage::write_dvalue_data
+    pub fn decode(bytes: &[u8], dtype: &DType) -> Result<ColumnVector> {
+        match dtype {
+            DType::Uint64 => {
+                if !bytes.len().is_multiple_of(8) {
+                    return Err(anyhow!("Uint64 block has {} bytes", bytes.len()));
+                }
+                let values = bytes.chunks_exact(8).map(|chunk| u64::from_be_bytes(chunk.try_into().unwrap())).collect();
+                Ok(ColumnVector::Uint64 { values, validity: None })
+            }
+            DType::String => {
+                let mut offsets = vec![0];
+                let mut data = Vec::with_capacity(bytes.len());
+                let mut rest = bytes;
+                while !rest.is_empty() {
+                    let length = rest.get(0..4).map(|length| u32::from_be_bytes(length.try_into().unwrap()) as usize);
+                    let value = length.and_then(|length| rest.get(4..4 + length));
+                    let Some(value) = value else {
+                        return Err(anyhow!("String block ends part way through a value"));
+                    };
+                    data.extend_from_slice(value);
+                    offsets.push(data.len());
+                    rest = &rest[4 + value.len()..];
+                }
+                // each value is valid UTF-8, so every offset is on a char boundary
+                let data = String::from_utf8(data).map_err(|_| anyhow!("String block isn't valid UTF-8"))?;
+                Ok(ColumnVector::String { offsets, data, validity: None })
+            }
+        }
+    }
+
+    // Build a column from values, where None is a row without a value
+    pub fn from_values(values: &[Option<DValue>], dtype: &DType) -> Result<ColumnVector> {
+        let mut validity = Bitmap::new(values.len(), true);
+        let mut column = match dtype {
+            DType::Uint64 => ColumnVector::Uint64 { values: Vec::with_capacity(values.len()), validity: None },
+            DType::String => ColumnVector::String { offsets: vec![0], data: String::new(), validity: None },
+        };
+        for (i, value) in values.iter().enumerate() {
+            match (&mut column, value) {
+                (ColumnVector::Uint64 { values, .. }, Some(DValue::Uint64(u))) => values.push(*u),
+                (ColumnVector::String { offsets, data, .. }, Some(DValue::String(s))) => {
+                    data.push_str(s);
+                    offsets.push(data.len());
+                }
+                (column, None) => {
+                    validity.set(i, false);
+                    match column {
+                        ColumnVector::Uint64 { values, .. } => values.push(0),
+                        ColumnVector::String { offsets, data, .. } => offsets.push(data.len()),
+                    }
+                }
+                (_, Some(value)) => return Err(anyhow!("Expected a {:?}, got {:?}", dtype, get_dtype(value))),
+            }
+        }
+        if validity.count_ones() != values.len() {
+            match &mut column {
+                ColumnVector::Uint64 { validity: v, .. } | ColumnVector::String { validity: v, .. } => *v = Some(validity),
+            }
+        }
+        Ok(column)
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            ColumnVector::Uint64 { values, .. } => values.len(),
+            ColumnVector::String { offsets, .. } => offsets.len() - 1,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn dtype(&self) -> DType {
+        match self {
+            ColumnVector::Uint64 { .. } => DType::Uint64,
+            ColumnVector::String { .. } => DType::String,
+        }
+    }
+
+    pub fn validity(&self) -> Option<&Bitmap> {
+        match self {
+            ColumnVector::Uint64 { validity, .. } | ColumnVector::String { validity, .. } => validity.as_ref(),
+        }
+    }
+
+    pub fn is_valid(&self, i: usize) -> bool {
+        self.validity().is_none_or(|validity| validity.get(i))
+    }
+
+    pub fn str_at(&self, i: usize) -> Option<&str> {
+        match self {
+            ColumnVector::String { offsets, data, .. } => Some(&data[offsets[i]..offsets[i + 1]]),
+            ColumnVector::Uint64 { .. } => None,
+        }
+    }
+
+    // The value of a row, converting it to a DValue. Rows without a value give the type's zero value.
+    pub fn get(&self, i: usize) -> DValue {
+        match self {
+            ColumnVector::Uint64 { values, .. } => DValue::Uint64(values[i]),
+            ColumnVector::String { .. } => DValue::String(self.str_at(i).unwrap().to_string()),
+        }
+    }
+
+    // Roughly how much memory the column takes, for the block cache
+    pub fn byte_size(&self) -> usize {
+        match self {
+            ColumnVector::Uint64 { values, .. } => values.len() * 8,
+            ColumnVector::String { offsets, data, .. } => offsets.len() * 8 + data.len(),
+        }
+    }
+}
+
+// The rows of a block group, as one column vector per table column (in the table's column order),
+// and which of those rows are selected. Rows are unselected when they are deleted or don't match a
+// filter.
+#[derive(Debug, Clone)]
+pub struct Batch {
+    pub columns: Vec<Arc<ColumnVector>>,
+    pub selection: Bitmap,
+}
+
+impl Batch {
+    // The selected rows, along with their position in the block
+    pub fn rows_with_positions(&self) -> Vec<(usize, Vec<DValue>)> {
+        self.selection.ones().map(|i| (i, self.row(i))).collect()
+    }
+
+    // The selected rows
+    pub fn rows(&self) -> Vec<Vec<DValue>> {
+        self.selection.ones().map(|i| self.row(i)).collect()
+    }
+
+    pub fn row(&self, i: usize) -> Vec<DValue> {
+        self.columns.iter().map(|column| column.get(i)).collect()
+    }
+}
+
+// The value of an expression over the rows of a batch: either a column vector, or one value for
+// every row (e.g. a literal)
+#[derive(Debug, Clone)]
+pub enum BatchValue {
+    Column(Arc<ColumnVector>),
+    Scalar(DValue),
+}
+
+impl BatchValue {
+    pub fn dtype(&self) -> DType {
+        match self {
+            BatchValue::Column(column) => column.dtype(),
+            BatchValue::Scalar(value) => get_dtype(value),
+        }
+    }
+
+    pub fn get(&self, i: usize) -> DValue {
+        match self {
+            BatchValue::Column(column) => column.get(i),
+            BatchValue::Scalar(value) => value.clone(),
+        }
+    }
+
+    pub fn uint64s(&self) -> Option<Uint64s<'_>> {
+        match self {
+            BatchValue::Column(column) => match column.as_ref() {
+                ColumnVector::Uint64 { values, .. } => Some(Uint64s::Column(values)),
+                ColumnVector::String { .. } => None,
+            },
+            BatchValue::Scalar(DValue::Uint64(u)) => Some(Uint64s::Scalar(*u)),
+            BatchValue::Scalar(DValue::String(_)) => None,
+        }
+    }
+
+    pub fn strs(&self) -> Option<Strs<'_>> {
+        match self {
+            BatchValue::Column(column) => match column.as_ref() {
+                ColumnVector::String { .. } => Some(Strs::Column(column)),
+                ColumnVector::Uint64 { .. } => None,
+            },
+            BatchValue::Scalar(DValue::String(s)) => Some(Strs::Scalar(s)),
+            BatchValue::Scalar(DValue::Uint64(_)) => None,
+        }
+    }
+}
+
+// Borrowed typed values of a BatchValue, for loops over the rows of a batch
+#[derive(Clone, Copy)]
+pub enum Uint64s<'a> {
+    Column(&'a [u64]),
+    Scalar(u64),
+}
+
+impl Uint64s<'_> {
+    #[inline]
+    pub fn at(&self, i: usize) -> u64 {
+        match self {
+            Uint64s::Column(values) => values[i],
+            Uint64s::Scalar(u) => *u,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Strs<'a> {
+    Column(&'a ColumnVector),
+    Scalar(&'a str),
+}
+
+impl<'a> Strs<'a> {
+    #[inline]
+    pub fn at(&self, i: usize) -> &'a str {
+        match self {
+            Strs::Column(column) => column.str_at(i).unwrap(),
+            Strs::Scalar(s) => s,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::write_dvalue_data;
+
+    #[test]
+    fn test_bitmap() {
+        let mut bitmap = Bitmap::new(130, true);
+        assert_eq!(bitmap.count_ones(), 130);
+        bitmap.set(0, false);
+        bitmap.set(100, false);
+        let mut other = Bitmap::new(130, false);
+        other.set(100, true);
+        other.set(129, true);
+        bitmap.and(&other);
+        assert_eq!(bitmap.ones().collect::<Vec<usize>>(), vec![129]);
+        assert!(Bitmap::new(0, true).is_empty());
+    }
+
+    #[test]
+    fn test_decode() {
+        let values = [DValue::String("ab".to_string()), DValue::String(String::new()), DValue::String("ü".to_string())];
+        let mut bytes = Vec::new();
+        for value in &values {
+            write_dvalue_data(&mut bytes, value);
+        }
+        let column = ColumnVector::decode(&bytes, &DType::String).unwrap();
+        assert_eq!((0..3).map(|i| column.get(i)).collect::<Vec<DValue>>(), values);
+        assert!(ColumnVector::decode(&bytes[..bytes.len() - 1], &DType::String).is_err());
+        assert!(ColumnVector::decode(&[0; 9], &DType::Uint64).is_err());
+
+        let column = ColumnVector::from_values(&[Some(DValue::Uint64(3)), None], &DType::Uint64).unwrap();
+        assert_eq!((column.get(0), column.is_valid(0), column.is_valid(1)), (DValue::Uint64(3), true, false));
+        assert!(ColumnVector::from_values(&[Some(DValue::Uint64(3))], &DType::String).is_err());
+    }
+}
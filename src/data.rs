@@ -6,7 +6,17 @@ pub enum DType {
     Uint64,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, PartialOrd)]
+impl DType {
+    // The value used for a column when neither the row nor the column's DEFAULT provides one
+    pub fn zero_value(&self) -> DValue {
+        match self {
+            DType::String => DValue::String(String::new()),
+            DType::Uint64 => DValue::Uint64(0),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, PartialOrd, Ord, Hash)]
 pub enum DValue {
     String(String),
     Uint64(u64),
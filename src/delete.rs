@@ -0,0 +1,158 @@
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::prelude::*;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::expr::{Expr, TableRow};
+use crate::format::{check_header, header, FileKind, HEADER_SIZE};
+use crate::metadata::TableMetaData;
+use crate::storage::{PartReader, ROWS_PER_BLOCK};
+
+// Deleting rows doesn't rewrite any column files. Instead each part has a <table>.deleted file next
+// to its column files, which marks the deleted rows of each block. Scans skip the marked rows, and
+// merging the part (see merge.rs) drops them for real.
+//
+// After the header, the file is a list of entries, each being the block number as 8 bytes big
+// endian then a bitmap with one bit per row of the block. Deletes append new entries to the end of
+// the file, and all the entries for a block are OR'd together when it is loaded.
+
+const BITMAP_SIZE: usize = ROWS_PER_BLOCK / 8;
+const ENTRY_SIZE: usize = 8 + BITMAP_SIZE;
+
+type Bitmap = [u8; BITMAP_SIZE];
+
+#[derive(Debug, Default)]
+pub struct DeletedMask {
+    blocks: BTreeMap<usize, Bitmap>,
+}
+
+pub(crate) fn mask_path(root_path: &Path, table_name: &str) -> PathBuf {
+    root_path.join(format!("{}.deleted", table_name))
+}
+
+impl DeletedMask {
+    pub fn load(root_path: &Path, table_name: &str) -> Result<DeletedMask> {
+        let path = mask_path(root_path, table_name);
+        let bytes = match fs::read(&path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(DeletedMask::default()),
+            result => result.with_context(|| format!("Couldn't read {}", path.to_string_lossy()))?,
+        };
+        check_header(&bytes, FileKind::Mask, &path)?;
+
+        let mut mask = DeletedMask::default();
+        for entry in bytes[HEADER_SIZE as usize..].chunks_exact(ENTRY_SIZE) {
+            let block = u64::from_be_bytes(entry[0..8].try_into().unwrap()) as usize;
+            mask.merge_bitmap(block, entry[8..].try_into().unwrap());
+        }
+        Ok(mask)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    pub fn is_deleted(&self, block: usize, row: usize) -> bool {
+        self.blocks
+            .get(&block)
+            .is_some_and(|bitmap| bitmap[row / 8] & (1 << (row % 8)) != 0)
+    }
+
+    // The number of rows marked as deleted
+    pub fn n_deleted(&self) -> u64 {
+        self.blocks.values().flatten().map(|byte| byte.count_ones() as u64).sum()
+    }
+
+    // Add the deletes of another mask to this one
+    pub fn merge(&mut self, other: &DeletedMask) {
+        for (block, bitmap) in &other.blocks {
+            self.merge_bitmap(*block, bitmap);
+        }
+    }
+
+    pub fn delete(&mut self, block: usize, row: usize) {
+        self.blocks.entry(block).or_insert([0; BITMAP_SIZE])[row / 8] |= 1 << (row % 8);
+    }
+
+    fn merge_bitmap(&mut self, block: usize, bitmap: &Bitmap) {
+        let existing = self.blocks.entry(block).or_insert([0; BITMAP_SIZE]);
+        for (existing, new) in existing.iter_mut().zip(bitmap.iter()) {
+            *existing |= new;
+        }
+    }
+
+    // Append these deletes to the part's mask file
+    pub fn append_to(&self, root_path: &Path, table_name: &str) -> Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        let path = mask_path(root_path, table_name);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)
+            .with_context(|| format!("Couldn't open {}", path.to_string_lossy()))?;
+
+        let mut bytes = Vec::with_capacity(self.blocks.len() * ENTRY_SIZE);
+        if file.metadata()?.len() == 0 {
+            bytes.extend_from_slice(&header(FileKind::Mask));
+        }
+        for (block, bitmap) in &self.blocks {
+            bytes.extend_from_slice(&(*block as u64).to_be_bytes());
+            bytes.extend_from_slice(bitmap);
+        }
+        // one write, so a crash can't leave a partial entry that would shift the ones after it
+        file.write_all(&bytes).with_context(|| format!("Couldn't write {}", path.to_string_lossy()))
+    }
+}
+
+// Mark the rows of a part that match the filter as deleted, returning how many were deleted
+pub fn delete_where(root_path: &Path, table: &TableMetaData, filter: &Expr) -> Result<u64> {
+    let mut reader = PartReader::open(root_path, table)?;
+    let mut deletes = DeletedMask::default();
+    let mut n_deleted = 0;
+
+    for block in 0..reader.n_blocks() {
+        if !filter.may_match(&reader.block_ranges(block)) {
+            continue;
+        }
+        for (row_index, row) in reader.read_block_group_with_positions(block)? {
+            if filter.matches(&TableRow { columns: &table.columns, values: &row })? {
+                deletes.delete(block, row_index);
+                n_deleted += 1;
+            }
+        }
+    }
+
+    deletes.append_to(root_path, &table.name)?;
+    Ok(n_deleted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deleted_mask() {
+        let mut mask = DeletedMask::default();
+        assert!(mask.is_empty());
+        mask.delete(3, 0);
+        mask.delete(3, 9);
+        mask.delete(5, ROWS_PER_BLOCK - 1);
+        assert!(mask.is_deleted(3, 0));
+        assert!(mask.is_deleted(3, 9));
+        assert!(!mask.is_deleted(3, 8));
+        assert!(!mask.is_deleted(4, 0));
+        assert!(mask.is_deleted(5, ROWS_PER_BLOCK - 1));
+
+        let mut other = DeletedMask::default();
+        other.delete(3, 8);
+        mask.merge(&other);
+        assert!(mask.is_deleted(3, 8));
+        assert!(mask.is_deleted(3, 9));
+        assert_eq!(mask.n_deleted(), 4);
+    }
+}
@@ -0,0 +1,262 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+
+use crate::partition::pruned_part_paths_cached;
+use crate::query::{QueryResult, SelectPlan};
+use crate::storage::PartReader;
+use crate::{DType, DValue, DB};
+
+// EXPLAIN shows the plan of a SELECT, and an estimate of how much it would read, worked out from the
+// partition directories and the index files without reading any data. EXPLAIN ANALYZE also runs the
+// query and reports what it actually read and where the time went. Both return one line of text per
+// row, in a column named explain.
+
+// What a query read and how long each stage took. Counters are shared by the scan threads.
+#[derive(Debug, Default)]
+pub struct QueryStats {
+    pub parts: AtomicU64,
+    // block groups, read from disk or the cache
+    pub blocks_read: AtomicU64,
+    // block groups the indexes showed can't match the filter
+    pub blocks_skipped: AtomicU64,
+    // column blocks found in the cache
+    pub blocks_cached: AtomicU64,
+    pub bytes_read: AtomicU64,
+    pub bytes_decompressed: AtomicU64,
+    // rows that aren't deleted in the blocks read, and how many of those matched the filter
+    pub rows_read: AtomicU64,
+    pub rows_matched: AtomicU64,
+    // time spent by all the scan threads together reading, filtering, then aggregating or projecting
+    pub read_nanos: AtomicU64,
+    pub filter_nanos: AtomicU64,
+    pub process_nanos: AtomicU64,
+    // the wall clock time of each stage of the query, in order
+    pub stages: Mutex<Vec<(&'static str, Duration)>>,
+}
+
+impl QueryStats {
+    pub(crate) fn add_time(counter: &AtomicU64, duration: Duration) {
+        counter.fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    // Record a stage that started at start and has just finished
+    pub(crate) fn add_stage(&self, name: &'static str, start: Instant) {
+        self.stages.lock().unwrap().push((name, start.elapsed()));
+    }
+}
+
+// How much of a table a scan would read, going by the indexes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanEstimate {
+    pub parts: (u64, u64),
+    pub blocks: (u64, u64),
+    // an upper bound, as the filter can only rule out whole blocks. None if a block only has String
+    // columns, as the number of rows in those can't be told without reading them.
+    pub rows: Option<(u64, u64)>,
+    pub columns: Vec<ColumnEstimate>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnEstimate {
+    pub name: String,
+    pub blocks: (u64, u64),
+    // compressed bytes
+    pub bytes: (u64, u64),
+}
+
+// Each pair above is what the scan would read, then the total for the table
+pub fn estimate(plan: &SelectPlan, db: &DB) -> Result<ScanEstimate> {
+    let table = db.tables.iter().find(|table| table.name == plan.table).ok_or(anyhow!("Table {} doesn't exist", plan.table))?;
+    let filter = plan.filter.as_ref();
+    let pruned = pruned_part_paths_cached(&db.path, table, filter, &db.parts)?;
+    let all = pruned_part_paths_cached(&db.path, table, None, &db.parts)?;
+
+    let mut estimate = ScanEstimate {
+        parts: (pruned.len() as u64, all.len() as u64),
+        blocks: (0, 0),
+        rows: Some((0, 0)),
+        columns: table.columns.iter().map(|col| ColumnEstimate { name: col.name.clone(), blocks: (0, 0), bytes: (0, 0) }).collect(),
+    };
+    for part_path in &all {
+        let reader = PartReader::open_cached(part_path, table, &db.parts)?;
+        let in_pruned = pruned.contains(part_path);
+        for block in 0..reader.n_blocks() {
+            let read = in_pruned && filter.is_none_or(|filter| filter.may_match(&reader.block_ranges(block)));
+            let n_rows = reader.indexed_n_rows(block);
+            let sizes = reader.compressed_sizes(block);
+            let add = |(selected, total): &mut (u64, u64), n: u64| {
+                *total += n;
+                if read {
+                    *selected += n;
+                }
+            };
+            add(&mut estimate.blocks, 1);
+            estimate.rows = estimate.rows.zip(n_rows).map(|(mut rows, n_rows)| {
+                add(&mut rows, n_rows);
+                rows
+            });
+            for (column, size) in estimate.columns.iter_mut().zip(sizes) {
+                // columns added after the block was written have nothing to read
+                add(&mut column.blocks, u64::from(size > 0));
+                add(&mut column.bytes, size);
+            }
+        }
+    }
+    Ok(estimate)
+}
+
+pub fn explain(plan: &SelectPlan, db: &DB) -> Result<QueryResult> {
+    let mut lines = plan_lines(plan);
+    lines.extend(estimate_lines(&estimate(plan, db)?));
+    Ok(to_result(lines))
+}
+
+pub fn explain_analyze(plan: &SelectPlan, db: &DB) -> Result<QueryResult> {
+    let mut lines = plan_lines(plan);
+    lines.extend(estimate_lines(&estimate(plan, db)?));
+
+    let stats = QueryStats::default();
+    let start = Instant::now();
+    let result = plan.execute_with_stats(db, Some(&stats))?;
+    let total = start.elapsed();
+
+    let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    let nanos = |counter: &AtomicU64| Duration::from_nanos(get(counter));
+    lines.push("Execution:".to_string());
+    lines.push(format!("  Rows returned: {}", result.rows.len()));
+    lines.push(format!("  Parts read: {}", get(&stats.parts)));
+    lines.push(format!(
+        "  Blocks read: {}, skipped by the indexes: {}, column blocks from the cache: {}",
+        get(&stats.blocks_read),
+        get(&stats.blocks_skipped),
+        get(&stats.blocks_cached)
+    ));
+    lines.push(format!(
+        "  Bytes read: {}, decompressed: {}",
+        get(&stats.bytes_read),
+        get(&stats.bytes_decompressed)
+    ));
+    let (rows_read, rows_matched) = (get(&stats.rows_read), get(&stats.rows_matched));
+    lines.push(format!(
+        "  Rows scanned: {}, filtered out: {}, matched: {}",
+        rows_read,
+        rows_read - rows_matched,
+        rows_matched
+    ));
+    lines.push(format!(
+        "  Scan thread time: read {:?}, filter {:?}, {} {:?}",
+        nanos(&stats.read_nanos),
+        nanos(&stats.filter_nanos),
+        if plan.aggregation.is_some() { "aggregate" } else { "project" },
+        nanos(&stats.process_nanos)
+    ));
+    for (stage, duration) in stats.stages.lock().unwrap().iter() {
+        lines.push(format!("  Stage {}: {:?}", stage, duration));
+    }
+    lines.push(format!("  Total: {:?}", total));
+    Ok(to_result(lines))
+}
+
+// The operators of the plan, from the last to run to the first
+fn plan_lines(plan: &SelectPlan) -> Vec<String> {
+    let mut lines = vec!["Plan:".to_string()];
+    if plan.limit.is_some() || plan.offset > 0 {
+        let limit = plan.limit.map_or("none".to_string(), |limit| limit.to_string());
+        lines.push(format!("  Limit {} offset {}", limit, plan.offset));
+    }
+    if !plan.order_by.is_empty() {
+        let keys = plan
+            .order_by
+            .iter()
+            .map(|&(i, descending)| format!("{}{}", plan.outputs[i], if descending { " DESC" } else { "" }))
+            .collect::<Vec<String>>();
+        lines.push(format!("  Sort by {}", keys.join(", ")));
+    }
+    let outputs = plan
+        .outputs
+        .iter()
+        .zip(&plan.columns)
+        .map(|(expr, (name, _))| match expr.to_string() == *name {
+            true => name.clone(),
+            false => format!("{} AS {}", expr, name),
+        })
+        .collect::<Vec<String>>();
+    lines.push(format!("  Project {}", outputs.join(", ")));
+    if let Some(having) = &plan.having {
+        lines.push(format!("  Having {}", having));
+    }
+    if let Some(aggregation) = &plan.aggregation {
+        let group_by = aggregation.group_by.iter().enumerate().map(|(i, expr)| format!("{} AS #group{}", expr, i));
+        let aggregates = aggregation.aggregates.iter().enumerate().map(|(i, (function, arg))| {
+            let arg = arg.as_ref().map_or(String::new(), |arg| arg.to_string());
+            format!("{}({}) AS #aggregate{}", function, arg, i)
+        });
+        lines.push(format!("  Aggregate {}", group_by.chain(aggregates).collect::<Vec<String>>().join(", ")));
+    }
+    if let Some(filter) = &plan.filter {
+        lines.push(format!("  Filter {}", filter));
+    }
+    lines.push(format!("  Scan {}, max_threads {}", plan.table, plan.max_threads));
+    lines
+}
+
+fn estimate_lines(estimate: &ScanEstimate) -> Vec<String> {
+    let mut lines = vec![
+        "Estimate:".to_string(),
+        format!("  Parts: {} of {}", estimate.parts.0, estimate.parts.1),
+        format!("  Blocks: {} of {}", estimate.blocks.0, estimate.blocks.1),
+        match estimate.rows {
+            Some((selected, total)) => format!("  Rows: at most {} of {}", selected, total),
+            None => "  Rows: unknown, some blocks only have String columns".to_string(),
+        },
+    ];
+    for column in &estimate.columns {
+        lines.push(format!(
+            "  Column {}: {} of {} blocks, {} of {} compressed bytes",
+            column.name, column.blocks.0, column.blocks.1, column.bytes.0, column.bytes.1
+        ));
+    }
+    lines
+}
+
+fn to_result(lines: Vec<String>) -> QueryResult {
+    QueryResult {
+        columns: vec![("explain".to_string(), DType::String)],
+        rows: lines.into_iter().map(|line| vec![DValue::String(line)]).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::{ColumnMetaData, TableMetaData};
+    use crate::query::plan_select;
+    use crate::sql::{parse, Statement};
+
+    #[test]
+    fn test_plan_lines() {
+        let table = TableMetaData::new(
+            "events",
+            vec![ColumnMetaData::new("event", DType::String), ColumnMetaData::new("id", DType::Uint64)],
+        );
+        let Statement::Select(select) =
+            parse("SELECT event, count() FROM events WHERE id % 2 = 0 GROUP BY event HAVING max(id) > 5 ORDER BY 2 DESC LIMIT 3").unwrap()
+        else {
+            panic!("Expected a SELECT");
+        };
+        let plan = plan_select(&select, &table).unwrap();
+        assert_eq!(plan_lines(&plan), vec![
+            "Plan:",
+            "  Limit 3 offset 0",
+            "  Sort by #aggregate0 DESC",
+            "  Project #group0 AS event, #aggregate0 AS count()",
+            "  Having #aggregate1 > 5",
+            "  Aggregate event AS #group0, count() AS #aggregate0, max(id) AS #aggregate1",
+            "  Filter (id % 2) = 0",
+            "  Scan events, max_threads 0",
+        ]);
+    }
+}
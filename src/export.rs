@@ -0,0 +1,183 @@
+use std::io::{BufWriter, Write};
+
+use anyhow::{anyhow, Result};
+
+use crate::query::QueryResult;
+use crate::DValue;
+
+// Writing rows out as text. Rows are written one at a time as they are produced, so exporting a scan
+// of any size takes constant memory.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    // a header row, then fields quoted when they contain a comma, a quote or a newline
+    Csv,
+    // a header row, then fields with tabs, newlines and backslashes escaped with a backslash
+    Tsv,
+    // one JSON object per row
+    JsonLines,
+    // an indented array of objects
+    Json,
+}
+
+impl ExportFormat {
+    // Names are case insensitive, as in the FORMAT clause
+    pub fn from_name(name: &str) -> Result<ExportFormat> {
+        match name.to_lowercase().as_str() {
+            "csv" => Ok(ExportFormat::Csv),
+            "tsv" | "tabseparated" => Ok(ExportFormat::Tsv),
+            "jsonl" | "jsonlines" | "jsoneachrow" => Ok(ExportFormat::JsonLines),
+            "json" => Ok(ExportFormat::Json),
+            _ => Err(anyhow!("Unknown format {}, expected CSV, TSV, JSONLines or JSON", name)),
+        }
+    }
+}
+
+// Writes rows with the given column names to out, buffering the writes. Each value is written by its
+// type, e.g. strings are quoted in CSV only when they need to be, and always in JSON.
+pub struct RowWriter<W: Write> {
+    out: BufWriter<W>,
+    format: ExportFormat,
+    names: Vec<String>,
+    n_rows: u64,
+}
+
+impl<W: Write> RowWriter<W> {
+    pub fn new(out: W, format: ExportFormat, names: &[String]) -> Result<RowWriter<W>> {
+        let mut out = BufWriter::new(out);
+        match format {
+            ExportFormat::Csv => writeln!(out, "{}", names.iter().map(|name| csv_field(name)).collect::<Vec<String>>().join(","))?,
+            ExportFormat::Tsv => writeln!(out, "{}", names.iter().map(|name| tsv_field(name)).collect::<Vec<String>>().join("\t"))?,
+            ExportFormat::JsonLines => {}
+            ExportFormat::Json => write!(out, "[")?,
+        }
+        // JSON object keys are written ahead of time
+        let names = match format {
+            ExportFormat::JsonLines | ExportFormat::Json => names.iter().map(|name| json_string(name)).collect(),
+            ExportFormat::Csv | ExportFormat::Tsv => names.to_vec(),
+        };
+        Ok(RowWriter { out, format, names, n_rows: 0 })
+    }
+
+    pub fn write_row(&mut self, row: &[DValue]) -> Result<()> {
+        let out = &mut self.out;
+        match self.format {
+            ExportFormat::Csv => {
+                let fields: Vec<String> = row.iter().map(|value| text_value(value, csv_field)).collect();
+                writeln!(out, "{}", fields.join(","))?;
+            }
+            ExportFormat::Tsv => {
+                let fields: Vec<String> = row.iter().map(|value| text_value(value, tsv_field)).collect();
+                writeln!(out, "{}", fields.join("\t"))?;
+            }
+            ExportFormat::JsonLines => {
+                let pairs: Vec<String> = self.names.iter().zip(row).map(|(name, value)| format!("{}:{}", name, json_value(value))).collect();
+                writeln!(out, "{{{}}}", pairs.join(","))?;
+            }
+            ExportFormat::Json => {
+                let pairs: Vec<String> = self.names.iter().zip(row).map(|(name, value)| format!("    {}: {}", name, json_value(value))).collect();
+                let separator = if self.n_rows == 0 { "" } else { "," };
+                write!(out, "{}\n  {{\n{}\n  }}", separator, pairs.join(",\n"))?;
+            }
+        }
+        self.n_rows += 1;
+        Ok(())
+    }
+
+    // Close the JSON array and flush the output, returning the number of rows written
+    pub fn finish(mut self) -> Result<u64> {
+        if self.format == ExportFormat::Json {
+            let end = if self.n_rows == 0 { "]" } else { "\n]" };
+            writeln!(self.out, "{}", end)?;
+        }
+        self.out.flush()?;
+        Ok(self.n_rows)
+    }
+}
+
+// Write a result that's already in memory, e.g. of SHOW TABLES or EXPLAIN
+pub fn export_result<W: Write>(result: &QueryResult, format: ExportFormat, out: W) -> Result<u64> {
+    let names: Vec<String> = result.columns.iter().map(|(name, _)| name.clone()).collect();
+    let mut writer = RowWriter::new(out, format, &names)?;
+    for row in &result.rows {
+        writer.write_row(row)?;
+    }
+    writer.finish()
+}
+
+fn text_value(value: &DValue, escape: fn(&str) -> String) -> String {
+    match value {
+        DValue::Uint64(u) => u.to_string(),
+        DValue::String(s) => escape(s),
+    }
+}
+
+fn csv_field(s: &str) -> String {
+    match s.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", s.replace('"', "\"\"")),
+        false => s.to_string(),
+    }
+}
+
+fn tsv_field(s: &str) -> String {
+    let mut field = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => field.push_str("\\\\"),
+            '\t' => field.push_str("\\t"),
+            '\n' => field.push_str("\\n"),
+            '\r' => field.push_str("\\r"),
+            c => field.push(c),
+        }
+    }
+    field
+}
+
+fn json_string(s: &str) -> String {
+    serde_json::to_string(s).unwrap()
+}
+
+// UInt64 values are written as JSON numbers
+fn json_value(value: &DValue) -> String {
+    match value {
+        DValue::Uint64(u) => u.to_string(),
+        DValue::String(s) => json_string(s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DType;
+
+    #[test]
+    fn test_row_writer() {
+        let result = QueryResult {
+            columns: vec![("event".to_string(), DType::String), ("id".to_string(), DType::Uint64)],
+            rows: vec![
+                vec![DValue::String("a, \"b\"".to_string()), DValue::Uint64(1)],
+                vec![DValue::String("tab\there\\\n".to_string()), DValue::Uint64(2)],
+            ],
+        };
+        let export = |format| {
+            let mut out = vec![];
+            assert_eq!(export_result(&result, format, &mut out).unwrap(), 2);
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(export(ExportFormat::Csv), "event,id\n\"a, \"\"b\"\"\",1\n\"tab\there\\\n\",2\n");
+        assert_eq!(export(ExportFormat::Tsv), "event\tid\na, \"b\"\t1\ntab\\there\\\\\\n\t2\n");
+        assert_eq!(export(ExportFormat::JsonLines), "{\"event\":\"a, \\\"b\\\"\",\"id\":1}\n{\"event\":\"tab\\there\\\\\\n\",\"id\":2}\n");
+        assert_eq!(export(ExportFormat::Json), concat!(
+            "[\n",
+            "  {\n    \"event\": \"a, \\\"b\\\"\",\n    \"id\": 1\n  },\n",
+            "  {\n    \"event\": \"tab\\there\\\\\\n\",\n    \"id\": 2\n  }\n",
+            "]\n",
+        ));
+        let empty = QueryResult { columns: result.columns.clone(), rows: vec![] };
+        let mut out = vec![];
+        export_result(&empty, ExportFormat::Json, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "[]\n");
+        assert_eq!(ExportFormat::from_name("JSONEachRow").unwrap(), ExportFormat::JsonLines);
+        assert!(ExportFormat::from_name("xml").is_err());
+    }
+}
@@ -0,0 +1,595 @@
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::column::{Batch, BatchValue, Bitmap, ColumnVector, Uint64s};
+use crate::metadata::ColumnMetaData;
+use crate::{get_dtype, DType, DValue};
+
+// Expressions are stored in metadata.json (e.g. as column defaults), so they need to be serializable
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Literal(DValue),
+    Column(String),
+    Now,
+    BinaryOp(Box<Expr>, BinOp, Box<Expr>),
+    Not(Box<Expr>),
+    Function(Function, Vec<Expr>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    // comparisons and boolean operators return 1 for true and 0 for false
+    Eq,
+    NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+    And,
+    Or,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Function {
+    Concat,
+    Lower,
+    Upper,
+    Length,
+    ToString,
+    ToUint64,
+    // date functions take a Uint64 unix timestamp in seconds
+    ToYYYYMM,
+    ToYYYYMMDD,
+    ToStartOfMonth,
+    ToStartOfDay,
+    ToStartOfHour,
+    // whether the first string contains the second
+    Contains,
+}
+
+// Expressions are shown as SQL, e.g. by EXPLAIN
+impl std::fmt::Display for Expr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // only nested operators need brackets to show the order they're evaluated in
+        let operand = |f: &mut std::fmt::Formatter<'_>, expr: &Expr| match expr {
+            Expr::BinaryOp(..) => write!(f, "({})", expr),
+            _ => write!(f, "{}", expr),
+        };
+        match self {
+            Expr::Literal(DValue::Uint64(u)) => write!(f, "{}", u),
+            Expr::Literal(DValue::String(s)) => write!(f, "'{}'", s.replace('\'', "''")),
+            Expr::Column(name) => write!(f, "{}", name),
+            Expr::Now => write!(f, "now()"),
+            Expr::BinaryOp(left, op, right) => {
+                operand(f, left)?;
+                write!(f, " {} ", op)?;
+                operand(f, right)
+            }
+            Expr::Not(expr) => {
+                write!(f, "NOT ")?;
+                operand(f, expr)
+            }
+            Expr::Function(function, args) => {
+                write!(f, "{}(", function)?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for BinOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let symbol = match self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Mod => "%",
+            BinOp::Eq => "=",
+            BinOp::NotEq => "!=",
+            BinOp::Lt => "<",
+            BinOp::LtEq => "<=",
+            BinOp::Gt => ">",
+            BinOp::GtEq => ">=",
+            BinOp::And => "AND",
+            BinOp::Or => "OR",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
+impl std::fmt::Display for Function {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Function::Concat => "concat",
+            Function::Lower => "lower",
+            Function::Upper => "upper",
+            Function::Length => "length",
+            Function::ToString => "toString",
+            Function::ToUint64 => "toUInt64",
+            Function::ToYYYYMM => "toYYYYMM",
+            Function::ToYYYYMMDD => "toYYYYMMDD",
+            Function::ToStartOfMonth => "toStartOfMonth",
+            Function::ToStartOfDay => "toStartOfDay",
+            Function::ToStartOfHour => "toStartOfHour",
+            Function::Contains => "contains",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// Anything that can look up a column value by name can be used to evaluate an expression
+pub trait Row {
+    fn get(&self, column: &str) -> Option<&DValue>;
+}
+
+impl Row for HashMap<String, DValue> {
+    fn get(&self, column: &str) -> Option<&DValue> {
+        HashMap::get(self, column)
+    }
+}
+
+// A row in the same order as a table's columns, as it is stored on disk
+pub struct TableRow<'a> {
+    pub columns: &'a [ColumnMetaData],
+    pub values: &'a [DValue],
+}
+
+impl<'a> Row for TableRow<'a> {
+    fn get(&self, column: &str) -> Option<&DValue> {
+        self.columns
+            .iter()
+            .position(|col| col.name == column)
+            .map(|index| &self.values[index])
+    }
+}
+
+impl Expr {
+    pub fn literal(value: DValue) -> Expr {
+        Expr::Literal(value)
+    }
+
+    pub fn column(name: &str) -> Expr {
+        Expr::Column(name.to_string())
+    }
+
+    pub fn binary(left: Expr, op: BinOp, right: Expr) -> Expr {
+        Expr::BinaryOp(Box::new(left), op, Box::new(right))
+    }
+
+    pub fn function(function: Function, args: Vec<Expr>) -> Expr {
+        Expr::Function(function, args)
+    }
+
+    pub fn and(self, other: Expr) -> Expr {
+        Expr::binary(self, BinOp::And, other)
+    }
+
+    pub fn or(self, other: Expr) -> Expr {
+        Expr::binary(self, BinOp::Or, other)
+    }
+
+    pub fn eval<R: Row + ?Sized>(&self, row: &R) -> Result<DValue> {
+        match self {
+            Expr::Literal(value) => Ok(value.clone()),
+            Expr::Column(name) => row
+                .get(name)
+                .cloned()
+                .ok_or(anyhow!("No value for column: {}", name)),
+            Expr::Now => Ok(DValue::Uint64(now_seconds())),
+            Expr::BinaryOp(left, op, right) => {
+                let left = left.eval(row)?;
+                let right = right.eval(row)?;
+                eval_binary_op(&left, *op, &right)
+            }
+            Expr::Not(expr) => Ok(bool_value(!is_true(&expr.eval(row)?)?)),
+            Expr::Function(function, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| arg.eval(row))
+                    .collect::<Result<Vec<DValue>>>()?;
+                eval_function(*function, &args)
+            }
+        }
+    }
+
+    // Names of all the columns this expression reads
+    pub fn columns(&self) -> Vec<&String> {
+        match self {
+            Expr::Literal(_) | Expr::Now => vec![],
+            Expr::Column(name) => vec![name],
+            Expr::BinaryOp(left, _, right) => {
+                let mut cols = left.columns();
+                cols.extend(right.columns());
+                cols
+            }
+            Expr::Not(expr) => expr.columns(),
+            Expr::Function(_, args) => args.iter().flat_map(|arg| arg.columns()).collect(),
+        }
+    }
+
+    // The expression with now() fixed to the given time
+    pub fn with_now(&self, now: u64) -> Expr {
+        match self {
+            Expr::Now => Expr::Literal(DValue::Uint64(now)),
+            Expr::Literal(_) | Expr::Column(_) => self.clone(),
+            Expr::BinaryOp(left, op, right) => Expr::binary(left.with_now(now), *op, right.with_now(now)),
+            Expr::Not(expr) => Expr::Not(Box::new(expr.with_now(now))),
+            Expr::Function(function, args) => Expr::function(*function, args.iter().map(|arg| arg.with_now(now)).collect()),
+        }
+    }
+
+    // Evaluate a filter against a row
+    pub fn matches<R: Row + ?Sized>(&self, row: &R) -> Result<bool> {
+        is_true(&self.eval(row)?)
+    }
+
+    // Whether any row could match this filter, given the range of values some expressions can take.
+    // This is used to skip data without reading it, so it only returns false if no row can match.
+    pub fn may_match<R: Ranges + ?Sized>(&self, ranges: &R) -> bool {
+        match self {
+            Expr::BinaryOp(left, BinOp::And, right) => left.may_match(ranges) && right.may_match(ranges),
+            Expr::BinaryOp(left, BinOp::Or, right) => left.may_match(ranges) || right.may_match(ranges),
+            Expr::BinaryOp(left, op, right) => match (left.as_ref(), right.as_ref()) {
+                (expr, Expr::Literal(value)) | (Expr::Literal(value), expr) if *op == BinOp::Eq && !ranges.may_equal(expr, value) => false,
+                (_, Expr::Literal(value)) => match ranges.range(left) {
+                    Some((min, max)) => range_may_match(&min, &max, *op, value),
+                    None => true,
+                },
+                (Expr::Literal(value), _) => match (ranges.range(right), op.flipped()) {
+                    (Some((min, max)), Some(op)) => range_may_match(&min, &max, op, value),
+                    _ => true,
+                },
+                _ => true,
+            },
+            Expr::Function(Function::Contains, args) => match args.as_slice() {
+                [expr, Expr::Literal(DValue::String(needle))] => ranges.may_contain(expr, needle),
+                _ => true,
+            },
+            Expr::Literal(value) => is_true(value).unwrap_or(true),
+            _ => true,
+        }
+    }
+}
+
+// Evaluating an expression over a whole batch at once runs each operator in one loop over the rows,
+// rather than walking the expression tree for every row. Operators without a batch version are
+// evaluated row by row over the selected rows.
+impl Expr {
+    // The value of the expression for the selected rows of a batch, which must have at least one
+    // selected row. Rows that aren't selected get an unspecified value, and can't cause an error.
+    pub(crate) fn eval_batch(&self, columns: &[ColumnMetaData], batch: &Batch) -> Result<BatchValue> {
+        match self {
+            Expr::Literal(value) => Ok(BatchValue::Scalar(value.clone())),
+            Expr::Column(name) => columns
+                .iter()
+                .position(|col| &col.name == name)
+                .map(|index| BatchValue::Column(batch.columns[index].clone()))
+                .ok_or(anyhow!("No value for column: {}", name)),
+            Expr::Now => Ok(BatchValue::Scalar(DValue::Uint64(now_seconds()))),
+            Expr::BinaryOp(left, op, right) => {
+                let left = left.eval_batch(columns, batch)?;
+                let right = right.eval_batch(columns, batch)?;
+                eval_binary_op_batch(&left, *op, &right, &batch.selection)
+            }
+            Expr::Not(expr) => match expr.eval_batch(columns, batch)? {
+                BatchValue::Scalar(value) => Ok(BatchValue::Scalar(bool_value(!is_true(&value)?))),
+                value => {
+                    let values = booleans(&value)?;
+                    Ok(uint64_column((0..batch.selection.len()).map(|i| (values.at(i) == 0) as u64).collect()))
+                }
+            },
+            Expr::Function(function, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| arg.eval_batch(columns, batch))
+                    .collect::<Result<Vec<BatchValue>>>()?;
+                eval_function_batch(*function, &args, &batch.selection)
+            }
+        }
+    }
+
+    // Unselect the rows of a batch that don't match this filter
+    pub(crate) fn filter_batch(&self, columns: &[ColumnMetaData], batch: &mut Batch) -> Result<()> {
+        if batch.selection.count_ones() == 0 {
+            return Ok(());
+        }
+        let value = self.eval_batch(columns, batch)?;
+        let matches = booleans(&value)?;
+        let mut selection = Bitmap::new(batch.selection.len(), false);
+        for i in batch.selection.ones() {
+            if matches.at(i) != 0 {
+                selection.set(i, true);
+            }
+        }
+        batch.selection = selection;
+        Ok(())
+    }
+}
+
+fn booleans(value: &BatchValue) -> Result<Uint64s<'_>> {
+    value.uint64s().ok_or(anyhow!("Expected a boolean, got a {:?}", value.dtype()))
+}
+
+fn uint64_column(values: Vec<u64>) -> BatchValue {
+    BatchValue::Column(std::sync::Arc::new(ColumnVector::Uint64 { values, validity: None }))
+}
+
+fn compare<T: PartialOrd + ?Sized>(left: &T, op: BinOp, right: &T) -> bool {
+    match op {
+        BinOp::Eq => left == right,
+        BinOp::NotEq => left != right,
+        BinOp::Lt => left < right,
+        BinOp::LtEq => left <= right,
+        BinOp::Gt => left > right,
+        _ => left >= right,
+    }
+}
+
+fn eval_binary_op_batch(left: &BatchValue, op: BinOp, right: &BatchValue, selection: &Bitmap) -> Result<BatchValue> {
+    if let (BatchValue::Scalar(left), BatchValue::Scalar(right)) = (left, right) {
+        return eval_binary_op(left, op, right).map(BatchValue::Scalar);
+    }
+    let n_rows = selection.len();
+    match op {
+        // comparisons can't fail once the types match, so they run over every row
+        BinOp::Eq | BinOp::NotEq | BinOp::Lt | BinOp::LtEq | BinOp::Gt | BinOp::GtEq => {
+            if left.dtype() != right.dtype() {
+                return Err(anyhow!("Can't compare {:?} and {:?}", left.dtype(), right.dtype()));
+            }
+            let values = match left.dtype() {
+                DType::Uint64 => {
+                    let (left, right) = (left.uint64s().unwrap(), right.uint64s().unwrap());
+                    (0..n_rows).map(|i| compare(&left.at(i), op, &right.at(i)) as u64).collect()
+                }
+                DType::String => {
+                    let (left, right) = (left.strs().unwrap(), right.strs().unwrap());
+                    (0..n_rows).map(|i| compare(left.at(i), op, right.at(i)) as u64).collect()
+                }
+            };
+            return Ok(uint64_column(values));
+        }
+        BinOp::And | BinOp::Or => {
+            let (left, right) = (booleans(left)?, booleans(right)?);
+            let values = match op {
+                BinOp::And => (0..n_rows).map(|i| (left.at(i) != 0 && right.at(i) != 0) as u64).collect(),
+                _ => (0..n_rows).map(|i| (left.at(i) != 0 || right.at(i) != 0) as u64).collect(),
+            };
+            return Ok(uint64_column(values));
+        }
+        _ => {}
+    }
+    let (l, r) = match (left.uint64s(), right.uint64s()) {
+        (Some(l), Some(r)) => (l, r),
+        _ => return Err(anyhow!("Can't apply {:?} to {:?} and {:?}", op, left.dtype(), right.dtype())),
+    };
+    // arithmetic can overflow, so only the selected rows are evaluated
+    let mut values = vec![0; n_rows];
+    for i in selection.ones() {
+        let (l, r) = (l.at(i), r.at(i));
+        let result = match op {
+            BinOp::Add => l.checked_add(r),
+            BinOp::Sub => l.checked_sub(r),
+            BinOp::Mul => l.checked_mul(r),
+            BinOp::Div => l.checked_div(r),
+            BinOp::Mod => l.checked_rem(r),
+            _ => unreachable!(),
+        };
+        values[i] = result.ok_or(anyhow!("Overflow evaluating {} {:?} {}", l, op, r))?;
+    }
+    Ok(uint64_column(values))
+}
+
+fn eval_function_batch(function: Function, args: &[BatchValue], selection: &Bitmap) -> Result<BatchValue> {
+    if args.iter().all(|arg| matches!(arg, BatchValue::Scalar(_))) {
+        let args: Vec<DValue> = args.iter().map(|arg| arg.get(0)).collect();
+        return eval_function(function, &args).map(BatchValue::Scalar);
+    }
+    match (function, args) {
+        (Function::Contains, [haystack, BatchValue::Scalar(DValue::String(needle))]) if haystack.dtype() == DType::String => {
+            let haystack = haystack.strs().unwrap();
+            return Ok(uint64_column((0..selection.len()).map(|i| haystack.at(i).contains(needle.as_str()) as u64).collect()));
+        }
+        // functions from Uint64 to Uint64, which don't need to allocate for each row
+        (
+            Function::ToUint64
+            | Function::ToYYYYMM
+            | Function::ToYYYYMMDD
+            | Function::ToStartOfMonth
+            | Function::ToStartOfDay
+            | Function::ToStartOfHour,
+            [arg],
+        ) if arg.dtype() == DType::Uint64 => {
+            let arg = arg.uint64s().unwrap();
+            let mut values = vec![0; selection.len()];
+            for i in selection.ones() {
+                if let DValue::Uint64(u) = eval_function(function, &[DValue::Uint64(arg.at(i))])? {
+                    values[i] = u;
+                }
+            }
+            return Ok(uint64_column(values));
+        }
+        _ => {}
+    }
+
+    let mut values = vec![None; selection.len()];
+    for i in selection.ones() {
+        let args: Vec<DValue> = args.iter().map(|arg| arg.get(i)).collect();
+        values[i] = Some(eval_function(function, &args)?);
+    }
+    let dtype = values.iter().flatten().next().map_or(DType::Uint64, get_dtype);
+    // the unselected rows have no value
+    Ok(BatchValue::Column(std::sync::Arc::new(ColumnVector::from_values(&values, &dtype)?)))
+}
+
+// The inclusive range of values an expression can take over some set of rows, if it's known
+pub trait Ranges {
+    fn range(&self, expr: &Expr) -> Option<(DValue, DValue)>;
+
+    // Whether the expression could equal the value, for when there is more to go on than the range
+    fn may_equal(&self, _expr: &Expr, _value: &DValue) -> bool {
+        true
+    }
+
+    // Whether the expression could be a string containing the substring
+    fn may_contain(&self, _expr: &Expr, _needle: &str) -> bool {
+        true
+    }
+}
+
+impl BinOp {
+    // The operator to use if the two sides of a comparison are swapped
+    fn flipped(self) -> Option<BinOp> {
+        match self {
+            BinOp::Eq | BinOp::NotEq => Some(self),
+            BinOp::Lt => Some(BinOp::Gt),
+            BinOp::LtEq => Some(BinOp::GtEq),
+            BinOp::Gt => Some(BinOp::Lt),
+            BinOp::GtEq => Some(BinOp::LtEq),
+            _ => None,
+        }
+    }
+}
+
+fn range_may_match(min: &DValue, max: &DValue, op: BinOp, value: &DValue) -> bool {
+    if get_dtype(min) != get_dtype(value) {
+        return true;
+    }
+    match op {
+        BinOp::Eq => min <= value && value <= max,
+        BinOp::NotEq => !(min == value && max == value),
+        BinOp::Lt => min < value,
+        BinOp::LtEq => min <= value,
+        BinOp::Gt => max > value,
+        BinOp::GtEq => max >= value,
+        _ => true,
+    }
+}
+
+pub fn is_true(value: &DValue) -> Result<bool> {
+    match value {
+        DValue::Uint64(u) => Ok(*u != 0),
+        DValue::String(_) => Err(anyhow!("Expected a boolean, got {:?}", value)),
+    }
+}
+
+fn bool_value(b: bool) -> DValue {
+    DValue::Uint64(b as u64)
+}
+
+pub fn now_seconds() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn eval_binary_op(left: &DValue, op: BinOp, right: &DValue) -> Result<DValue> {
+    match op {
+        BinOp::Eq | BinOp::NotEq | BinOp::Lt | BinOp::LtEq | BinOp::Gt | BinOp::GtEq => {
+            if get_dtype(left) != get_dtype(right) {
+                return Err(anyhow!("Can't compare {:?} and {:?}", left, right));
+            }
+            return Ok(bool_value(match op {
+                BinOp::Eq => left == right,
+                BinOp::NotEq => left != right,
+                BinOp::Lt => left < right,
+                BinOp::LtEq => left <= right,
+                BinOp::Gt => left > right,
+                _ => left >= right,
+            }));
+        }
+        BinOp::And => return Ok(bool_value(is_true(left)? && is_true(right)?)),
+        BinOp::Or => return Ok(bool_value(is_true(left)? || is_true(right)?)),
+        _ => {}
+    }
+    let (l, r) = match (left, right) {
+        (DValue::Uint64(l), DValue::Uint64(r)) => (*l, *r),
+        _ => return Err(anyhow!("Can't apply {:?} to {:?} and {:?}", op, left, right)),
+    };
+    let result = match op {
+        BinOp::Add => l.checked_add(r),
+        BinOp::Sub => l.checked_sub(r),
+        BinOp::Mul => l.checked_mul(r),
+        BinOp::Div => l.checked_div(r),
+        BinOp::Mod => l.checked_rem(r),
+        _ => unreachable!(),
+    };
+    result
+        .map(DValue::Uint64)
+        .ok_or(anyhow!("Overflow evaluating {} {:?} {}", l, op, r))
+}
+
+fn eval_function(function: Function, args: &[DValue]) -> Result<DValue> {
+    match (function, args) {
+        (Function::Concat, args) => {
+            let mut s = String::new();
+            for arg in args {
+                s.push_str(&to_string(arg));
+            }
+            Ok(DValue::String(s))
+        }
+        (Function::Lower, [DValue::String(s)]) => Ok(DValue::String(s.to_lowercase())),
+        (Function::Upper, [DValue::String(s)]) => Ok(DValue::String(s.to_uppercase())),
+        (Function::Length, [DValue::String(s)]) => Ok(DValue::Uint64(s.len() as u64)),
+        (Function::ToString, [value]) => Ok(DValue::String(to_string(value))),
+        (Function::ToUint64, [DValue::Uint64(u)]) => Ok(DValue::Uint64(*u)),
+        (Function::ToUint64, [DValue::String(s)]) => s
+            .parse::<u64>()
+            .map(DValue::Uint64)
+            .map_err(|_| anyhow!("Can't convert {:?} to Uint64", s)),
+        (Function::ToYYYYMM, [DValue::Uint64(t)]) => {
+            let (year, month, _) = civil_from_days(t / SECONDS_PER_DAY);
+            Ok(DValue::Uint64(year * 100 + month))
+        }
+        (Function::ToYYYYMMDD, [DValue::Uint64(t)]) => {
+            let (year, month, day) = civil_from_days(t / SECONDS_PER_DAY);
+            Ok(DValue::Uint64(year * 10000 + month * 100 + day))
+        }
+        (Function::ToStartOfMonth, [DValue::Uint64(t)]) => {
+            let (_, _, day) = civil_from_days(t / SECONDS_PER_DAY);
+            Ok(DValue::Uint64((t / SECONDS_PER_DAY - (day - 1)) * SECONDS_PER_DAY))
+        }
+        (Function::ToStartOfDay, [DValue::Uint64(t)]) => Ok(DValue::Uint64(t - t % SECONDS_PER_DAY)),
+        (Function::ToStartOfHour, [DValue::Uint64(t)]) => Ok(DValue::Uint64(t - t % 3600)),
+        (Function::Contains, [DValue::String(s), DValue::String(needle)]) => Ok(bool_value(s.contains(needle.as_str()))),
+        _ => Err(anyhow!("Invalid arguments for {:?}: {:?}", function, args)),
+    }
+}
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+// Convert days since 1970-01-01 into (year, month, day), see http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn to_string(value: &DValue) -> String {
+    match value {
+        DValue::String(s) => s.clone(),
+        DValue::Uint64(u) => u.to_string(),
+    }
+}
@@ -0,0 +1,249 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::delete::mask_path;
+use crate::metadata::TableMetaData;
+use crate::partition::{detached_part_paths, part_paths};
+use crate::primary_index::primary_index_path;
+use crate::skip_index::skip_index_path;
+use crate::storage::{data_path, index_path, INDEX_ENTRY_SIZE};
+
+// The version of the on-disk format written by this build. This is stored in metadata.json and in
+// the header of every file, and needs to be bumped whenever the layout of any file changes.
+//
+// Version 0: no headers, index entries are 40 bytes
+// Version 1: an 8 byte header at the start of every data and index file
+// Version 2: <table>.deleted files marking deleted rows, which older builds would ignore
+// Version 3: skip index files, which older builds wouldn't keep up to date
+// Version 4: index entries are 24 bytes, without the min and max, and <table>.primary files
+// Version 5: partitioned tables keep each part in a directory under <table>/partitions, with a
+//            partition.json, where older builds would not look for it
+pub const FORMAT_VERSION: u16 = 5;
+
+const MAGIC: &[u8; 4] = b"RTCD";
+pub const HEADER_SIZE: u64 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    Data,
+    Index,
+    Mask,
+    Skip,
+    Primary,
+}
+
+impl FileKind {
+    fn to_byte(self) -> u8 {
+        match self {
+            FileKind::Data => b'D',
+            FileKind::Index => b'I',
+            FileKind::Mask => b'M',
+            FileKind::Skip => b'S',
+            FileKind::Primary => b'P',
+        }
+    }
+}
+
+// The header is the magic bytes, then the format version as a 2 byte big endian, then the kind of
+// file, then a reserved byte.
+pub fn header(kind: FileKind) -> [u8; HEADER_SIZE as usize] {
+    header_with_version(kind, FORMAT_VERSION)
+}
+
+fn header_with_version(kind: FileKind, version: u16) -> [u8; HEADER_SIZE as usize] {
+    let mut bytes = [0; HEADER_SIZE as usize];
+    bytes[0..4].copy_from_slice(MAGIC);
+    bytes[4..6].copy_from_slice(&version.to_be_bytes());
+    bytes[6] = kind.to_byte();
+    bytes
+}
+
+pub fn check_header(bytes: &[u8], kind: FileKind, path: &Path) -> Result<()> {
+    if bytes.len() < HEADER_SIZE as usize || &bytes[0..4] != MAGIC {
+        return Err(anyhow!("{} is not an rtcdb file", path.to_string_lossy()));
+    }
+    let version = u16::from_be_bytes(bytes[4..6].try_into().unwrap());
+    check_version(version, &path.to_string_lossy())?;
+    if bytes[6] != kind.to_byte() {
+        return Err(anyhow!("{} is not a {:?} file", path.to_string_lossy(), kind));
+    }
+    Ok(())
+}
+
+// Read the header from the start of a file, leaving the file positioned at the first byte after it
+pub fn read_header(file: &mut File, kind: FileKind, path: &Path) -> Result<()> {
+    let mut bytes = [0; HEADER_SIZE as usize];
+    file.read_exact(&mut bytes)
+        .with_context(|| format!("Couldn't read header of {}", path.to_string_lossy()))?;
+    check_header(&bytes, kind, path)
+}
+
+pub fn check_version(version: u16, what: &str) -> Result<()> {
+    if version > FORMAT_VERSION {
+        return Err(anyhow!(
+            "{} uses format version {}, but this build of rtcdb only supports up to version {}",
+            what, version, FORMAT_VERSION
+        ));
+    }
+    if version < FORMAT_VERSION {
+        return Err(anyhow!(
+            "{} uses format version {}, run DB::upgrade to rewrite it in version {}",
+            what, version, FORMAT_VERSION
+        ));
+    }
+    Ok(())
+}
+
+// Rewrite a table's files from the given version into the next one. Each file is checked before it
+// is rewritten, so this can be run again if it is interrupted part way through.
+pub fn upgrade_table_files(root_path: &Path, table: &TableMetaData, from_version: u16) -> Result<()> {
+    match from_version {
+        0 => {
+            for col in &table.columns {
+                upgrade_v0_data_file(&data_path(root_path, &table.name, &col.name))?;
+                upgrade_v0_index_file(&index_path(root_path, &table.name, &col.name))?;
+            }
+        }
+        // no existing files change, the new versions only add mask and skip index files
+        1 | 2 => {}
+        // tables written by older builds can't be partitioned, so their files stay where they are
+        4 => {}
+        3 => {
+            for part_path in upgrade_part_paths(root_path, table)? {
+                for col in &table.columns {
+                    upgrade_v3_index_file(&index_path(&part_path, &table.name, &col.name))?;
+                }
+            }
+        }
+        _ => return Err(anyhow!("Don't know how to upgrade from format version {}", from_version)),
+    }
+
+    // every file's header has the version it is in
+    for part_path in upgrade_part_paths(root_path, table)? {
+        let mut paths = vec![mask_path(&part_path, &table.name), primary_index_path(&part_path, &table.name)];
+        for col in &table.columns {
+            paths.push(data_path(&part_path, &table.name, &col.name));
+            paths.push(index_path(&part_path, &table.name, &col.name));
+            for index in &col.skip_indexes {
+                paths.push(skip_index_path(&part_path, &table.name, &col.name, index));
+            }
+        }
+        for path in paths {
+            set_header_version(&path, from_version + 1)?;
+        }
+    }
+    Ok(())
+}
+
+// Detached partitions are upgraded too, so they can still be attached afterwards
+fn upgrade_part_paths(root_path: &Path, table: &TableMetaData) -> Result<Vec<PathBuf>> {
+    let mut paths = part_paths(root_path, table)?;
+    paths.extend(detached_part_paths(root_path, table)?);
+    Ok(paths)
+}
+
+fn set_header_version(path: &Path, version: u16) -> Result<()> {
+    let mut file = match OpenOptions::new().read(true).write(true).open(path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        result => result.with_context(|| format!("Couldn't open {}", path.to_string_lossy()))?,
+    };
+    let mut bytes = [0; HEADER_SIZE as usize];
+    file.read_exact(&mut bytes)?;
+    if !has_magic(&bytes) {
+        return Err(anyhow!("{} is not an rtcdb file", path.to_string_lossy()));
+    }
+    file.seek(SeekFrom::Start(4))?;
+    file.write_all(&version.to_be_bytes())
+        .with_context(|| format!("Couldn't write header of {}", path.to_string_lossy()))
+}
+
+fn has_magic(bytes: &[u8]) -> bool {
+    bytes.len() >= HEADER_SIZE as usize && &bytes[0..4] == MAGIC
+}
+
+fn upgrade_v0_data_file(path: &Path) -> Result<()> {
+    let bytes = read_if_exists(path)?;
+    if has_magic(&bytes) {
+        return Ok(());
+    }
+    let mut upgraded = header_with_version(FileKind::Data, 1).to_vec();
+    upgraded.extend_from_slice(&bytes);
+    replace_file(path, &upgraded)
+}
+
+fn upgrade_v0_index_file(path: &Path) -> Result<()> {
+    let bytes = read_if_exists(path)?;
+    if has_magic(&bytes) {
+        return Ok(());
+    }
+    let mut upgraded = header_with_version(FileKind::Index, 1).to_vec();
+    for entry in bytes.chunks_exact(40) {
+        let mut entry: [u8; 40] = entry.try_into().unwrap();
+        // every block has moved along by the size of the data file header, apart from placeholder
+        // entries (with a compressed size of 0) which don't point at any data
+        let start_position = u64::from_be_bytes(entry[0..8].try_into().unwrap());
+        let compressed_size = u64::from_be_bytes(entry[8..16].try_into().unwrap());
+        if compressed_size != 0 {
+            entry[0..8].copy_from_slice(&(start_position + HEADER_SIZE).to_be_bytes());
+        }
+        upgraded.extend_from_slice(&entry);
+    }
+    replace_file(path, &upgraded)
+}
+
+// Drop the min and max from the end of each entry. Files that have already been upgraded have the
+// new version in their header.
+fn upgrade_v3_index_file(path: &Path) -> Result<()> {
+    let bytes = read_if_exists(path)?;
+    if bytes.len() < HEADER_SIZE as usize || bytes[4..6] != 3u16.to_be_bytes() {
+        return Ok(());
+    }
+    let mut upgraded = header_with_version(FileKind::Index, 4).to_vec();
+    for entry in bytes[HEADER_SIZE as usize..].chunks_exact(40) {
+        upgraded.extend_from_slice(&entry[..INDEX_ENTRY_SIZE]);
+    }
+    replace_file(path, &upgraded)
+}
+
+fn read_if_exists(path: &Path) -> Result<Vec<u8>> {
+    match fs::read(path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+        result => result.with_context(|| format!("Couldn't read {}", path.to_string_lossy())),
+    }
+}
+
+// Write to a temporary file then rename it over the old one
+pub(crate) fn replace_file(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    fs::write(&tmp_path, contents)
+        .with_context(|| format!("Couldn't write {}", path.to_string_lossy()))?;
+    fs::rename(&tmp_path, path)
+        .with_context(|| format!("Couldn't replace {}", path.to_string_lossy()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header() {
+        let bytes = header(FileKind::Index);
+        assert_eq!(bytes[0..4], *b"RTCD");
+        assert_eq!(bytes[4..6], FORMAT_VERSION.to_be_bytes());
+        assert_eq!(bytes[6..8], [b'I', 0]);
+        let path = Path::new("test.index");
+        assert!(check_header(&bytes, FileKind::Index, path).is_ok());
+        assert!(check_header(&bytes, FileKind::Data, path).is_err());
+        assert!(check_header(&[0; 8], FileKind::Index, path).is_err());
+
+        let mut newer = bytes;
+        newer[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_be_bytes());
+        let err = check_header(&newer, FileKind::Index, path).unwrap_err();
+        assert!(err.to_string().contains("only supports up to"));
+    }
+}
@@ -0,0 +1,461 @@
+use std::collections::HashMap;
+use std::io::BufRead;
+
+use anyhow::{anyhow, Result};
+
+use serde_json::{Map, Value};
+
+use crate::metadata::{ColumnMetaData, TableMetaData};
+use crate::storage::ROWS_PER_BLOCK;
+use crate::{DType, DValue, DB};
+
+// Bulk loading from text formats. The input is read a record at a time and written a batch of whole
+// blocks at a time, so a file is never held in memory.
+
+// A multiple of the block size, so every write but the last fills its blocks
+pub(crate) const IMPORT_BATCH_ROWS: usize = ROWS_PER_BLOCK * 16;
+
+// Collects rows and writes them to the table a batch at a time
+struct BatchWriter<'a> {
+    db: &'a DB,
+    table: &'a TableMetaData,
+    rows: Vec<Vec<DValue>>,
+    n_written: u64,
+}
+
+impl<'a> BatchWriter<'a> {
+    fn new(db: &'a DB, table: &'a TableMetaData) -> BatchWriter<'a> {
+        BatchWriter { db, table, rows: Vec::with_capacity(IMPORT_BATCH_ROWS), n_written: 0 }
+    }
+
+    // Columns that aren't given get their default
+    fn push(&mut self, values: HashMap<String, DValue>) -> Result<()> {
+        self.rows.push(self.table.row_from_named(values)?);
+        if self.rows.len() == IMPORT_BATCH_ROWS {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if !self.rows.is_empty() {
+            self.db.write_data(&self.table.name, &self.rows)?;
+            self.n_written += self.rows.len() as u64;
+            self.rows.clear();
+        }
+        Ok(())
+    }
+
+    // Write the last rows, returning how many were written in total
+    fn finish(mut self) -> Result<u64> {
+        self.flush()?;
+        Ok(self.n_written)
+    }
+}
+
+// How to read a CSV file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CsvOptions {
+    pub delimiter: char,
+    pub quote: char,
+    // whether the first record names the columns, otherwise the fields are in the table's column order
+    pub has_header: bool,
+    // header names that aren't the name of the column they go into
+    pub column_mapping: HashMap<String, String>,
+    // rows that fail to parse are skipped and reported, until there are more than this many
+    pub max_errors: usize,
+}
+
+impl Default for CsvOptions {
+    fn default() -> CsvOptions {
+        CsvOptions { delimiter: ',', quote: '"', has_header: true, column_mapping: HashMap::new(), max_errors: 0 }
+    }
+}
+
+impl CsvOptions {
+    pub fn new() -> CsvOptions {
+        CsvOptions::default()
+    }
+
+    pub fn with_delimiter(mut self, delimiter: char) -> CsvOptions {
+        self.delimiter = delimiter;
+        self
+    }
+
+    pub fn with_quote(mut self, quote: char) -> CsvOptions {
+        self.quote = quote;
+        self
+    }
+
+    pub fn without_header(mut self) -> CsvOptions {
+        self.has_header = false;
+        self
+    }
+
+    pub fn with_column_mapping(mut self, header_name: &str, column_name: &str) -> CsvOptions {
+        self.column_mapping.insert(header_name.to_string(), column_name.to_string());
+        self
+    }
+
+    pub fn with_max_errors(mut self, max_errors: usize) -> CsvOptions {
+        self.max_errors = max_errors;
+        self
+    }
+}
+
+// How to read JSON Lines
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct JsonlOptions {
+    // dotted paths into nested objects, like properties.$browser, and the column each is read into.
+    // Other columns are read from the top level key with their name.
+    pub path_mapping: HashMap<String, String>,
+    // a String column that gets the keys not read into any column, as a JSON object. Without one
+    // they're dropped.
+    pub catch_all_column: Option<String>,
+    // lines that fail to parse are skipped and reported, until there are more than this many
+    pub max_errors: usize,
+}
+
+impl JsonlOptions {
+    pub fn new() -> JsonlOptions {
+        JsonlOptions::default()
+    }
+
+    pub fn with_path_mapping(mut self, path: &str, column_name: &str) -> JsonlOptions {
+        self.path_mapping.insert(path.to_string(), column_name.to_string());
+        self
+    }
+
+    pub fn with_catch_all_column(mut self, column_name: &str) -> JsonlOptions {
+        self.catch_all_column = Some(column_name.to_string());
+        self
+    }
+
+    pub fn with_max_errors(mut self, max_errors: usize) -> JsonlOptions {
+        self.max_errors = max_errors;
+        self
+    }
+}
+
+// A row that couldn't be imported
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportError {
+    pub line: usize,
+    // the column whose value was bad, if it was one value
+    pub column: Option<String>,
+    pub message: String,
+}
+
+impl std::fmt::Display for ImportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.column {
+            Some(column) => write!(f, "Line {}, column {}: {}", self.line, column, self.message),
+            None => write!(f, "Line {}: {}", self.line, self.message),
+        }
+    }
+}
+
+// The number of rows written, and the rows that were skipped because of errors
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImportSummary {
+    pub rows_written: u64,
+    pub errors: Vec<ImportError>,
+}
+
+// Collects the errors of an import, failing it once there are too many. Batches already written by
+// then stay written.
+struct ErrorLog {
+    errors: Vec<ImportError>,
+    max_errors: usize,
+}
+
+impl ErrorLog {
+    fn add(&mut self, error: ImportError) -> Result<()> {
+        self.errors.push(error);
+        if self.errors.len() > self.max_errors {
+            return Err(anyhow!("Too many errors ({}), the last: {}", self.errors.len(), self.errors.last().unwrap()));
+        }
+        Ok(())
+    }
+}
+
+// Reads CSV records one at a time. A field can be quoted, which lets it contain the delimiter,
+// newlines and quotes (written twice).
+pub struct CsvReader<R> {
+    reader: R,
+    delimiter: char,
+    quote: char,
+    line: usize,
+}
+
+impl<R: BufRead> CsvReader<R> {
+    pub fn new(reader: R, delimiter: char, quote: char) -> CsvReader<R> {
+        CsvReader { reader, delimiter, quote, line: 0 }
+    }
+
+    // The next record and the line it starts on, or None at the end of the input
+    pub fn next_record(&mut self) -> Result<Option<(usize, Vec<String>)>> {
+        let mut start_line = self.line + 1;
+        let mut fields = vec![];
+        let mut field = String::new();
+        let mut in_quotes = false;
+        let mut buf = String::new();
+        loop {
+            buf.clear();
+            if self.reader.read_line(&mut buf)? == 0 {
+                if in_quotes {
+                    return Err(anyhow!("Line {}: unterminated quote", start_line));
+                }
+                // the end of the input, or a last line without a newline
+                if fields.is_empty() && field.is_empty() {
+                    return Ok(None);
+                }
+                fields.push(field);
+                return Ok(Some((start_line, fields)));
+            }
+            self.line += 1;
+            let line = buf.strip_suffix('\n').map_or(buf.as_str(), |line| line.strip_suffix('\r').unwrap_or(line));
+            let mut chars = line.chars().peekable();
+            while let Some(c) = chars.next() {
+                if in_quotes && c == self.quote {
+                    if chars.peek() == Some(&self.quote) {
+                        chars.next();
+                        field.push(c);
+                    } else {
+                        in_quotes = false;
+                    }
+                } else if !in_quotes && c == self.quote && field.is_empty() {
+                    in_quotes = true;
+                } else if !in_quotes && c == self.delimiter {
+                    fields.push(std::mem::take(&mut field));
+                } else {
+                    field.push(c);
+                }
+            }
+            if in_quotes {
+                // the newline is part of the quoted field
+                field.push('\n');
+            } else if line.is_empty() && fields.is_empty() && field.is_empty() {
+                // skip blank lines, the record starts on the line after
+                start_line = self.line + 1;
+            } else {
+                fields.push(field);
+                return Ok(Some((start_line, fields)));
+            }
+        }
+    }
+}
+
+// Parse a field of a text format into the column's type
+pub fn parse_value(text: &str, dtype: &DType) -> Result<DValue> {
+    match dtype {
+        DType::String => Ok(DValue::String(text.to_string())),
+        DType::Uint64 => text
+            .trim()
+            .parse()
+            .map(DValue::Uint64)
+            .map_err(|_| anyhow!("Expected a UInt64, got '{}'", text)),
+    }
+}
+
+// Import CSV into a table. Columns left out of the file get their default.
+pub fn import_csv<R: BufRead>(db: &DB, table: &TableMetaData, reader: R, options: &CsvOptions) -> Result<ImportSummary> {
+    let mut csv = CsvReader::new(reader, options.delimiter, options.quote);
+    let columns = match options.has_header {
+        true => {
+            let (_, header) = csv.next_record()?.ok_or(anyhow!("Expected a header row"))?;
+            header
+                .iter()
+                .map(|name| {
+                    let column_name = options.column_mapping.get(name).unwrap_or(name);
+                    table.get_column(column_name).ok_or(anyhow!("No column {} in table {}", column_name, table.name))
+                })
+                .collect::<Result<Vec<_>>>()?
+        }
+        false => table.columns.iter().collect(),
+    };
+    if let Some((i, col)) = columns.iter().enumerate().find(|(i, col)| columns[..*i].iter().any(|other| other.name == col.name)) {
+        return Err(anyhow!("Column {} given more than once (field {})", col.name, i + 1));
+    }
+
+    let mut writer = BatchWriter::new(db, table);
+    let mut errors = ErrorLog { errors: vec![], max_errors: options.max_errors };
+    while let Some((line, fields)) = csv.next_record()? {
+        if fields.len() != columns.len() {
+            let message = format!("Expected {} fields, got {}", columns.len(), fields.len());
+            errors.add(ImportError { line, column: None, message })?;
+            continue;
+        }
+        let values = fields
+            .iter()
+            .zip(&columns)
+            .map(|(field, col)| match parse_value(field, &col.dtype) {
+                Ok(value) => Ok((col.name.clone(), value)),
+                Err(e) => Err(ImportError { line, column: Some(col.name.clone()), message: e.to_string() }),
+            })
+            .collect::<std::result::Result<HashMap<String, DValue>, ImportError>>();
+        match values {
+            Ok(values) => writer.push(values)?,
+            Err(error) => errors.add(error)?,
+        }
+    }
+    Ok(ImportSummary { rows_written: writer.finish()?, errors: errors.errors })
+}
+
+// Import one JSON object per line. Each column is read from the top level key of its name, or from
+// the path mapped onto it, and columns that aren't in the object get their default.
+pub fn import_jsonl<R: BufRead>(db: &DB, table: &TableMetaData, reader: R, options: &JsonlOptions) -> Result<ImportSummary> {
+    let catch_all = match &options.catch_all_column {
+        Some(name) => match table.get_column(name) {
+            Some(col) if col.dtype == DType::String => Some(col),
+            Some(_) => return Err(anyhow!("The catch-all column {} isn't a String", name)),
+            None => return Err(anyhow!("No column {} in table {}", name, table.name)),
+        },
+        None => None,
+    };
+    for column_name in options.path_mapping.values() {
+        if table.get_column(column_name).is_none() {
+            return Err(anyhow!("No column {} in table {}", column_name, table.name));
+        }
+    }
+    let mut reads = vec![];
+    for col in &table.columns {
+        if catch_all.is_some_and(|catch_all| catch_all.name == col.name) {
+            if options.path_mapping.values().any(|column_name| *column_name == col.name) {
+                return Err(anyhow!("Column {} is the catch-all column, a path can't be mapped onto it", col.name));
+            }
+            continue;
+        }
+        let mut paths = options.path_mapping.iter().filter(|(_, column_name)| **column_name == col.name);
+        let path = match (paths.next(), paths.next()) {
+            (None, _) => vec![col.name.as_str()],
+            (Some((path, _)), None) => path.split('.').collect(),
+            (Some(_), Some(_)) => return Err(anyhow!("More than one path is mapped onto column {}", col.name)),
+        };
+        reads.push((path, col));
+    }
+
+    let mut writer = BatchWriter::new(db, table);
+    let mut errors = ErrorLog { errors: vec![], max_errors: options.max_errors };
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match json_row(i + 1, &line, &reads, catch_all) {
+            Ok(values) => writer.push(values)?,
+            Err(error) => errors.add(error)?,
+        }
+    }
+    Ok(ImportSummary { rows_written: writer.finish()?, errors: errors.errors })
+}
+
+fn json_row(
+    line: usize,
+    text: &str,
+    reads: &[(Vec<&str>, &ColumnMetaData)],
+    catch_all: Option<&ColumnMetaData>,
+) -> std::result::Result<HashMap<String, DValue>, ImportError> {
+    let mut object = match serde_json::from_str(text) {
+        Ok(Value::Object(object)) => object,
+        Ok(_) => return Err(ImportError { line, column: None, message: "Expected a JSON object".to_string() }),
+        Err(e) => return Err(ImportError { line, column: None, message: format!("Invalid JSON: {}", e) }),
+    };
+    let mut values = HashMap::new();
+    for (path, col) in reads {
+        let Some(value) = take_path(&mut object, path) else {
+            continue;
+        };
+        match json_value(value, &col.dtype) {
+            Ok(Some(value)) => {
+                values.insert(col.name.clone(), value);
+            }
+            Ok(None) => {}
+            Err(e) => return Err(ImportError { line, column: Some(col.name.clone()), message: e.to_string() }),
+        }
+    }
+    // whatever wasn't read into a column
+    if let Some(col) = catch_all {
+        if !object.is_empty() {
+            values.insert(col.name.clone(), DValue::String(Value::Object(object).to_string()));
+        }
+    }
+    Ok(values)
+}
+
+// Remove the value at the path of keys, along with any object left empty by removing it
+fn take_path(object: &mut Map<String, Value>, path: &[&str]) -> Option<Value> {
+    let (key, rest) = path.split_first()?;
+    if rest.is_empty() {
+        return object.remove(*key);
+    }
+    let Some(Value::Object(inner)) = object.get_mut(*key) else {
+        return None;
+    };
+    let value = take_path(inner, rest);
+    if value.is_some() && inner.is_empty() {
+        object.remove(*key);
+    }
+    value
+}
+
+// Convert a JSON value into the column's type. null is the same as leaving the column out. Strings
+// holding a number are parsed like a CSV field, and anything can go into a String column, with
+// objects and arrays written as JSON.
+fn json_value(value: Value, dtype: &DType) -> Result<Option<DValue>> {
+    match (dtype, value) {
+        (_, Value::Null) => Ok(None),
+        (DType::String, Value::String(s)) => Ok(Some(DValue::String(s))),
+        (DType::String, value) => Ok(Some(DValue::String(value.to_string()))),
+        (DType::Uint64, Value::Number(n)) if n.is_u64() => Ok(Some(DValue::Uint64(n.as_u64().unwrap()))),
+        (DType::Uint64, Value::String(s)) => parse_value(&s, dtype).map(Some),
+        (DType::Uint64, value) => Err(anyhow!("Expected a UInt64, got {}", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_csv_reader() {
+        let input = "a,b\n1,\"x, \"\"y\"\"\"\r\n\n2,\"multi\nline\"\n3,";
+        let mut csv = CsvReader::new(input.as_bytes(), ',', '"');
+        let mut records = vec![];
+        while let Some(record) = csv.next_record().unwrap() {
+            records.push(record);
+        }
+        let strings = |fields: &[&str]| fields.iter().map(|s| s.to_string()).collect::<Vec<String>>();
+        assert_eq!(records, vec![
+            (1, strings(&["a", "b"])),
+            (2, strings(&["1", "x, \"y\""])),
+            (4, strings(&["2", "multi\nline"])),
+            (6, strings(&["3", ""])),
+        ]);
+        assert!(CsvReader::new("\"open".as_bytes(), ',', '"').next_record().is_err());
+        // a long run of blank lines is skipped without recursing once per line
+        let blank = format!("a{}b", "\n".repeat(1_000_000));
+        let mut csv = CsvReader::new(blank.as_bytes(), ',', '"');
+        assert_eq!(csv.next_record().unwrap(), Some((1, strings(&["a"]))));
+        assert_eq!(csv.next_record().unwrap(), Some((1_000_001, strings(&["b"]))));
+        assert_eq!(csv.next_record().unwrap(), None);
+        let mut tsv = CsvReader::new("a\t'b\tc''d'\n".as_bytes(), '\t', '\'');
+        assert_eq!(tsv.next_record().unwrap(), Some((1, strings(&["a", "b\tc'd"]))));
+        assert_eq!(parse_value(" 12 ", &DType::Uint64).unwrap(), DValue::Uint64(12));
+        assert!(parse_value("-1", &DType::Uint64).is_err());
+    }
+
+    #[test]
+    fn test_take_path() {
+        let Value::Object(mut object) = serde_json::json!({"a": 1, "properties": {"$browser": "x", "os": {"name": "y"}}}) else {
+            panic!("Expected an object");
+        };
+        assert_eq!(take_path(&mut object, &["properties", "os", "name"]), Some(Value::from("y")));
+        assert_eq!(take_path(&mut object, &["properties", "missing"]), None);
+        assert_eq!(take_path(&mut object, &["a", "b"]), None);
+        assert_eq!(Value::Object(object), serde_json::json!({"a": 1, "properties": {"$browser": "x"}}));
+        assert_eq!(json_value(Value::from("12"), &DType::Uint64).unwrap(), Some(DValue::Uint64(12)));
+        assert_eq!(json_value(serde_json::json!([1]), &DType::String).unwrap(), Some(DValue::String("[1]".to_string())));
+        assert!(json_value(Value::from(1.5), &DType::Uint64).is_err());
+    }
+}
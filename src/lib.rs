@@ -1,52 +1,684 @@
 pub mod metadata;
 pub mod storage;
 pub mod data;
+pub mod expr;
+pub mod format;
+pub mod partition;
+pub mod ttl;
+pub mod delete;
+pub mod merge;
+pub mod mutation;
+pub mod aggregate;
+pub mod skip_index;
+pub mod primary_index;
+pub mod cache;
+pub mod column;
+pub mod parallel;
+pub mod sql;
+pub mod query;
+pub mod explain;
+pub mod import;
+pub mod export;
+#[cfg(feature = "arrow")]
+pub mod arrow;
+#[cfg(feature = "parquet")]
+pub mod parquet;
 
-use anyhow::{Result, anyhow};
-use metadata::create_metadata_file;
+use anyhow::{Context, Result, anyhow};
+use metadata::{create_metadata_file, save_metadata_file};
+use format::{check_version, upgrade_table_files, FORMAT_VERSION};
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use metadata::load_metadata_file;
-use storage::write_data;
+use partition::{part_paths, pruned_part_paths, pruned_part_paths_cached};
+use storage::{add_column, drop_column, finish_rewrite, link_column, PartReader};
+use cache::{BlockCache, PartCache, DEFAULT_CACHE_SIZE};
+use column::Batch;
+use parallel::ParallelScan;
 
-pub use metadata::{ColumnMetaData, MetaData, TableMetaData};
+pub use metadata::{AlterOperation, ColumnMetaData, MaterializedView, MetaData, Mutation, TableEngine, TableMetaData, TtlRule};
+pub use aggregate::{AggregateFunction, Aggregation, ViewAggregate};
 pub use data::{DType, DValue, get_dtype};
+pub use expr::{BinOp, Expr, Function, TableRow};
+pub use skip_index::SkipIndex;
+pub use query::QueryResult;
 
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug)]
 pub struct DB {
     pub path: PathBuf,
     pub tables: Vec<TableMetaData>,
+    pub mutations: Vec<Mutation>,
+    pub views: Vec<MaterializedView>,
+    // decompressed blocks read by scans through this handle
+    pub cache: BlockCache,
+    // the index files, masks and primary indexes of the parts scanned through this handle
+    pub parts: PartCache,
 }
 
+// Two handles are the same database if they have the same metadata, whatever they have cached
+impl PartialEq for DB {
+    fn eq(&self, other: &DB) -> bool {
+        self.path == other.path && self.tables == other.tables && self.mutations == other.mutations && self.views == other.views
+    }
+}
+
+impl Eq for DB {}
+
 impl DB {
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let meta = load_metadata_file(&path)?;
+        let mut meta = load_metadata_file(&path)?;
+        check_version(meta.format_version, &format!("Database {}", path.as_ref().to_string_lossy()))?;
+        // finish any part rewrite that was interrupted after it was committed
+        for table in &meta.tables {
+            for part_path in part_paths(path.as_ref(), table)? {
+                finish_rewrite(&part_path, table)?;
+            }
+        }
+        if mutation::commit_staged_parts(path.as_ref(), &meta.tables, &mut meta.mutations)? {
+            save_metadata_file(&path, &meta)?;
+        }
 
         Ok(DB {
             path: path.as_ref().to_path_buf(),
             tables: meta.tables,
+            mutations: meta.mutations,
+            views: meta.views,
+            cache: BlockCache::new(DEFAULT_CACHE_SIZE),
+            parts: PartCache::new(),
         })
     }
 
+    // Set the most decompressed block data to keep in memory, in bytes
+    pub fn with_cache_size(mut self, cache_size: usize) -> DB {
+        self.cache = BlockCache::new(cache_size);
+        self
+    }
+
+    // Rewrite a database written by an older build into the current format, then open it
+    pub fn upgrade<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut meta = load_metadata_file(&path)?;
+        while meta.format_version < FORMAT_VERSION {
+            for table in &meta.tables {
+                upgrade_table_files(path.as_ref(), table, meta.format_version)?;
+            }
+            // only record the new version once every file has been rewritten
+            meta.format_version += 1;
+            save_metadata_file(&path, &meta)?;
+        }
+        DB::open(path)
+    }
+
     pub fn init<P: AsRef<Path>>(path: P, tables: Vec<TableMetaData>) -> Result<Self> {
+        if let Some((i, table)) = tables.iter().enumerate().find(|(i, table)| {
+            tables[..*i].iter().any(|other| other.name == table.name)
+        }) {
+            return Err(anyhow!("Table {} defined more than once (position {})", table.name, i));
+        }
+        for table in &tables {
+            table.validate()?;
+        }
         let meta = create_metadata_file(&path, &tables)?;
+        for table in &meta.tables {
+            partition::create_table(path.as_ref(), table)?;
+        }
 
         Ok(DB {
             path: path.as_ref().to_path_buf(),
             tables: meta.tables,
+            mutations: meta.mutations,
+            views: meta.views,
+            cache: BlockCache::new(DEFAULT_CACHE_SIZE),
+            parts: PartCache::new(),
         })
     }
 
-    pub fn write_data(&self, table_name: &String, rows: &Vec<Vec<DValue>>) ->  Result<()> {
-        // get table metadata
-        let table = self.tables.iter().find(|table| {
-            table.name.eq(table_name)
-        }).ok_or(anyhow!("No table with name: {}", table_name))?;
+    // Write rows to a table, and the aggregated rows to the target of every view on it
+    pub fn write_data(&self, table_name: &str, rows: &[Vec<DValue>]) ->  Result<()> {
+        let table = self.get_table(table_name)?;
+
+        // work out what to write to the views first, so a bad row can't leave them out of step
+        let mut view_writes = Vec::new();
+        for view in self.views.iter().filter(|view| view.source == table_name) {
+            let target = self.get_table(&view.target)?;
+            let view_rows = aggregate::view_rows(view, table, rows)?
+                .into_iter()
+                .map(|row| {
+                    let row = target.row_from_named(row)?;
+                    if let Some((value, col)) = row.iter().zip(&target.columns).find(|(value, col)| get_dtype(value) != col.dtype) {
+                        return Err(anyhow!("Column {} is a {:?}, got {:?}", col.name, col.dtype, value));
+                    }
+                    Ok(row)
+                })
+                .collect::<Result<Vec<Vec<DValue>>>>()
+                .with_context(|| format!("Failed to compute rows for view {}", view.name))?;
+            view_writes.push((&view.target, view_rows));
+        }
 
-        write_data(&self.path, &table, rows)?;
+        self.changing_table(table_name, || match &table.partition_by {
+            Some(partition_by) => partition::write_partitioned(&self.path, table, partition_by, rows),
+            None => storage::write_data(&self.path, table, rows),
+        })?;
+        for (target, view_rows) in view_writes {
+            self.write_data(target, &view_rows)?;
+        }
 
         Ok(())
     }
 
+    // Write rows given as a map of column name to value, missing columns are filled from their defaults
+    pub fn write_named(&self, table_name: &str, rows: Vec<HashMap<String, DValue>>) -> Result<()> {
+        let table = self.get_table(table_name)?;
+
+        let rows = rows
+            .into_iter()
+            .map(|row| table.row_from_named(row))
+            .collect::<Result<Vec<Vec<DValue>>>>()?;
+
+        self.write_data(table_name, &rows)
+    }
+
+    // Write rows that only contain the named subset of columns, in the given order
+    pub fn write_columns(&self, table_name: &str, column_names: &[&str], rows: &[Vec<DValue>]) -> Result<()> {
+        if let Some((i, name)) = column_names.iter().enumerate().find(|(i, name)| column_names[..*i].contains(name)) {
+            return Err(anyhow!("Column {} given more than once (position {})", name, i));
+        }
+        let named_rows = rows
+            .iter()
+            .map(|row| {
+                if row.len() != column_names.len() {
+                    return Err(anyhow!("Expected {} values, got {}", column_names.len(), row.len()));
+                }
+                Ok(column_names
+                    .iter()
+                    .map(|name| name.to_string())
+                    .zip(row.iter().cloned())
+                    .collect())
+            })
+            .collect::<Result<Vec<HashMap<String, DValue>>>>()?;
+
+        self.write_named(table_name, named_rows)
+    }
+
+    // Write an Arrow RecordBatch, whose columns are matched to the table's by name. Columns left out
+    // and null values get their default.
+    #[cfg(feature = "arrow")]
+    pub fn write_arrow(&self, table_name: &str, batch: &arrow_array::RecordBatch) -> Result<()> {
+        let batch = arrow::record_batch_columns(self.get_table(table_name)?, batch)?;
+        self.write_batch(table_name, &batch)
+    }
+
+    // Like write_data, for the selected rows of a batch with a column vector for each of the table's
+    // columns, whose values are written without going through rows. Views aggregate rows, so the
+    // rows of a table with views are written by write_data.
+    pub fn write_batch(&self, table_name: &str, batch: &Batch) -> Result<()> {
+        let table = self.get_table(table_name)?;
+        if self.views.iter().any(|view| view.source == table_name) {
+            return self.write_data(table_name, &batch.rows());
+        }
+        self.changing_table(table_name, || match &table.partition_by {
+            Some(partition_by) => partition::write_partitioned_batch(&self.path, table, partition_by, batch),
+            None => storage::write_batch(&self.path, table, batch),
+        })
+    }
+
+    // Import a Parquet file, returning the number of rows written. See parquet.rs for how types map.
+    #[cfg(feature = "parquet")]
+    pub fn import_parquet<R: ::parquet::file::reader::ChunkReader + 'static>(&self, table_name: &str, reader: R) -> Result<u64> {
+        parquet::import_parquet(self, self.get_table(table_name)?, reader)
+    }
+
+    // Write the result of a read-only statement as a Parquet file, returning the number of rows
+    #[cfg(feature = "parquet")]
+    pub fn export_parquet<W: std::io::Write + Send>(&self, sql: &str, out: W) -> Result<u64> {
+        parquet::export_parquet(self, sql, out)
+    }
+
+    #[cfg(feature = "parquet")]
+    pub fn export_table_parquet<W: std::io::Write + Send>(&self, table_name: &str, filter: Option<&Expr>, out: W) -> Result<u64> {
+        parquet::export_table_parquet(self, table_name, filter, out)
+    }
+
+    // Import CSV a batch of whole blocks at a time, returning how many rows were written and the
+    // errors in any rows that were skipped. See CsvOptions for how the file is read.
+    pub fn import_csv<R: std::io::BufRead>(&self, table_name: &str, reader: R, options: &import::CsvOptions) -> Result<import::ImportSummary> {
+        import::import_csv(self, self.get_table(table_name)?, reader, options)
+    }
+
+    // Import JSON Lines, one object per row, the same way. See JsonlOptions for how keys map to columns.
+    pub fn import_jsonl<R: std::io::BufRead>(&self, table_name: &str, reader: R, options: &import::JsonlOptions) -> Result<import::ImportSummary> {
+        import::import_jsonl(self, self.get_table(table_name)?, reader, options)
+    }
 
+    pub fn read_all(&self, table_name: &str) -> Result<Vec<Vec<DValue>>> {
+        self.scan(table_name, None)
+    }
+
+    // Read the rows matching the filter. Partitions that can't contain any matching rows are skipped
+    // without being read.
+    pub fn scan(&self, table_name: &str, filter: Option<&Expr>) -> Result<Vec<Vec<DValue>>> {
+        self.scan_parallel(table_name, filter, 1)
+    }
+
+    // Like scan, but the blocks are split across up to max_threads threads (0 for one per core). The
+    // rows are returned in the same order as by scan.
+    pub fn scan_parallel(&self, table_name: &str, filter: Option<&Expr>, max_threads: usize) -> Result<Vec<Vec<DValue>>> {
+        let table = self.get_table(table_name)?;
+
+        let scan = ParallelScan::new(self, table, filter)?;
+        scan.collect_rows(max_threads, |batch| Ok(batch.rows()))
+    }
+
+    // Like scan, but the rows are returned as an Arrow RecordBatch per block group, read as the
+    // iterator is advanced
+    #[cfg(feature = "arrow")]
+    pub fn scan_arrow<'a>(&'a self, table_name: &str, filter: Option<&'a Expr>) -> Result<impl Iterator<Item = Result<arrow_array::RecordBatch>> + 'a> {
+        arrow::scan_arrow(self, table_name, filter)
+    }
+
+    // Aggregate the rows matching the filter, on up to max_threads threads (0 for one per core).
+    // Each thread aggregates the blocks it reads into its own partial groups, which are merged at
+    // the end. Like scan, this doesn't combine rows the way the table engine would.
+    pub fn aggregate(&self, table_name: &str, aggregation: &Aggregation, filter: Option<&Expr>, max_threads: usize) -> Result<Vec<Vec<DValue>>> {
+        let table = self.get_table(table_name)?;
+        aggregation.validate()?;
+
+        let scan = ParallelScan::new(self, table, filter)?;
+        scan.aggregate(max_threads, aggregation)
+    }
+
+    // Like scan, but rows the table engine would combine during a merge are combined first, so the
+    // result is the same as scanning after optimize_table. For a Replacing table that means only the
+    // latest version of each key is returned, and for an Aggregating table the merged states.
+    pub fn scan_final(&self, table_name: &str, filter: Option<&Expr>) -> Result<Vec<Vec<DValue>>> {
+        let table = self.get_table(table_name)?;
+        if let Some(filter) = filter {
+            query::check_boolean(filter, &table.columns, "The filter")?;
+        }
+        // a block can only be skipped if the filter is on the key alone, otherwise the block could
+        // hold a newer version of a key that doesn't match while an older version in another does
+        let key_only = match table.engine.key() {
+            None => true,
+            Some(key) => filter.is_some_and(|filter| filter.columns().iter().all(|name| key.contains(name))),
+        };
+
+        let mut rows = Vec::new();
+        for part_path in pruned_part_paths_cached(&self.path, table, filter, &self.parts)? {
+            let mut reader = PartReader::open_cached(&part_path, table, &self.parts)?.with_cache(&self.cache);
+            let mut part_rows = Vec::new();
+            for block in 0..reader.n_blocks() {
+                if key_only && filter.is_some_and(|filter| !filter.may_match(&reader.block_ranges(block))) {
+                    continue;
+                }
+                part_rows.extend(reader.read_block_group(block)?);
+            }
+            for row in merge::combine_rows(table, part_rows)? {
+                let matches = match filter {
+                    Some(filter) => filter.matches(&TableRow { columns: &table.columns, values: &row })?,
+                    None => true,
+                };
+                if matches {
+                    rows.push(row);
+                }
+            }
+        }
+        Ok(rows)
+    }
+
+    // The index entry and range of values of every block of a column, for each part of the table
+    pub fn inspect_column(&self, table_name: &str, column_name: &str) -> Result<Vec<(PathBuf, Vec<storage::BlockInfo>)>> {
+        let table = self.get_table(table_name)?;
+        let mut parts = vec![];
+        for part_path in pruned_part_paths_cached(&self.path, table, None, &self.parts)? {
+            let mut reader = PartReader::open_cached(&part_path, table, &self.parts)?;
+            let blocks = (0..reader.n_blocks())
+                .map(|block| reader.block_info(block, column_name))
+                .collect::<Result<Vec<storage::BlockInfo>>>()?;
+            parts.push((part_path, blocks));
+        }
+        Ok(parts)
+    }
+
+    // Run a SQL statement that doesn't change the database, i.e. SELECT, EXPLAIN, SHOW TABLES or DESCRIBE.
+    // See sql.rs and query.rs for what is supported.
+    pub fn query(&self, sql: &str) -> Result<QueryResult> {
+        self.run_query(sql::parse(sql)?, None)
+    }
+
+    // Run any SQL statement, including CREATE TABLE, INSERT and DROP TABLE
+    pub fn execute(&mut self, sql: &str) -> Result<QueryResult> {
+        self.execute_statement(sql::parse(sql)?, None)
+    }
+
+    // Run statements separated by semicolons in order, stopping at the first that fails
+    pub fn execute_script(&mut self, sql: &str) -> Result<Vec<QueryResult>> {
+        sql::parse_script(sql)?
+            .into_iter()
+            .enumerate()
+            .map(|(i, statement)| self.execute_statement(statement, None).with_context(|| format!("Statement {} failed", i + 1)))
+            .collect()
+    }
+
+    // Write the rows of a statement that doesn't change the database to out, in the format given by
+    // its FORMAT clause or else the format given here, returning the number of rows written. A SELECT
+    // without GROUP BY or ORDER BY is written as it's scanned, so this takes constant memory however
+    // big the result is.
+    pub fn export<W: std::io::Write>(&self, sql: &str, format: export::ExportFormat, out: W) -> Result<u64> {
+        self.export_statement(sql::parse(sql)?, format, out)
+    }
+
+    pub fn export_statement<W: std::io::Write>(&self, statement: sql::Statement, format: export::ExportFormat, out: W) -> Result<u64> {
+        match statement {
+            sql::Statement::Select(select) => {
+                let plan = query::plan_select(&select, self.get_table(&select.from)?)?;
+                let names: Vec<String> = plan.columns.iter().map(|(name, _)| name.clone()).collect();
+                let mut writer = export::RowWriter::new(out, select.format.unwrap_or(format), &names)?;
+                plan.for_each_row(self, |row| writer.write_row(&row))?;
+                writer.finish()
+            }
+            statement => export::export_result(&self.run_query(statement, None)?, format, out),
+        }
+    }
+
+    // Write the rows of a table that match the filter to out as they're scanned, in constant memory
+    pub fn export_table<W: std::io::Write>(&self, table_name: &str, filter: Option<&Expr>, format: export::ExportFormat, out: W) -> Result<u64> {
+        let table = self.get_table(table_name)?;
+        let names: Vec<String> = table.columns.iter().map(|col| col.name.clone()).collect();
+        let mut writer = export::RowWriter::new(out, format, &names)?;
+        let scan = ParallelScan::new(self, table, filter)?;
+        scan.for_each_batch(|batch| {
+            for row in batch.rows() {
+                writer.write_row(&row)?;
+            }
+            Ok(true)
+        })?;
+        writer.finish()
+    }
+
+    // Run a parsed statement. If stats are given, a SELECT records what it reads in them.
+    pub fn execute_statement(&mut self, statement: sql::Statement, stats: Option<&explain::QueryStats>) -> Result<QueryResult> {
+        match statement {
+            sql::Statement::CreateTable(create) => {
+                if !(create.if_not_exists && self.get_table(&create.name).is_ok()) {
+                    self.create_table(query::table_from_create(&create)?)?;
+                }
+            }
+            sql::Statement::Insert(insert) => {
+                let (columns, rows) = query::insert_rows(&insert, self.get_table(&insert.table)?)?;
+                let columns: Vec<&str> = columns.iter().map(String::as_str).collect();
+                self.write_columns(&insert.table, &columns, &rows)?;
+            }
+            sql::Statement::DropTable { name, if_exists } => {
+                if !(if_exists && self.get_table(&name).is_err()) {
+                    self.drop_table(&name)?;
+                }
+            }
+            statement => return self.run_query(statement, stats),
+        }
+        Ok(QueryResult::default())
+    }
+
+    fn run_query(&self, statement: sql::Statement, stats: Option<&explain::QueryStats>) -> Result<QueryResult> {
+        match statement {
+            sql::Statement::Select(select) => {
+                query::plan_select(&select, self.get_table(&select.from)?)?.execute_with_stats(self, stats)
+            }
+            sql::Statement::ShowTables => Ok(query::show_tables(&self.tables)),
+            sql::Statement::Describe(name) => Ok(query::describe(self.get_table(&name)?)),
+            sql::Statement::Explain { analyze, select } => {
+                let plan = query::plan_select(&select, self.get_table(&select.from)?)?;
+                match analyze {
+                    true => explain::explain_analyze(&plan, self),
+                    false => explain::explain(&plan, self),
+                }
+            }
+            _ => Err(anyhow!("Statement changes the database, use DB::execute")),
+        }
+    }
+
+    // Mark the rows matching the filter as deleted, returning how many were deleted. The rows are
+    // skipped by every scan straight away, but are only removed from disk by optimize_table.
+    pub fn delete_where(&self, table_name: &str, filter: &Expr) -> Result<u64> {
+        let table = self.get_table(table_name)?;
+        query::check_boolean(filter, &table.columns, "The filter")?;
+
+        self.changing_table(table_name, || {
+            let mut n_deleted = 0;
+            for part_path in pruned_part_paths(&self.path, table, Some(filter))? {
+                n_deleted += delete::delete_where(&part_path, table, filter)?;
+            }
+            Ok(n_deleted)
+        })
+    }
+
+    // Set the assigned columns of the rows matching the filter. This only records the mutation and
+    // returns its id: nothing is updated until run_mutations is called, which then only updates the
+    // rows written before this.
+    pub fn update_where(&mut self, table_name: &str, assignments: &[(&str, Expr)], filter: &Expr) -> Result<u64> {
+        let table = self.get_table(table_name)?;
+        let mut mutation = Mutation {
+            id: self.mutations.iter().map(|mutation| mutation.id + 1).max().unwrap_or(1),
+            table: table_name.to_string(),
+            assignments: assignments.iter().map(|(name, expr)| (name.to_string(), expr.clone())).collect(),
+            filter: filter.clone(),
+            part_blocks: None,
+            parts_done: vec![],
+            is_done: false,
+        };
+        mutation::check_mutation(table, &mutation)?;
+        mutation.part_blocks = Some(mutation::part_blocks(&self.path, table)?);
+
+        let id = mutation.id;
+        self.mutations.push(mutation);
+        self.save_metadata()?;
+        Ok(id)
+    }
+
+    // Apply all the mutations that haven't finished yet, in the order they were created. Progress
+    // is saved after each part, so if this is interrupted the next run carries on from there.
+    pub fn run_mutations(&mut self) -> Result<()> {
+        for i in 0..self.mutations.len() {
+            if self.mutations[i].is_done {
+                continue;
+            }
+            let table = self.get_table(&self.mutations[i].table)?.clone();
+            let mut mutation = self.mutations[i].clone();
+            let mut mutations = self.mutations.clone();
+            let result = mutation::run_mutation(&self.path, &table, &mut mutation, |mutation| {
+                mutations[i] = mutation.clone();
+                save_metadata_file(&self.path, &MetaData::new(self.tables.clone(), mutations.clone(), self.views.clone()))
+            });
+            self.invalidate_table(&table.name);
+            result?;
+            self.mutations[i] = mutation;
+        }
+        Ok(())
+    }
+
+    // Remove a mutation that hasn't finished, such as one that fails every time it is run. The parts
+    // it has already updated stay updated.
+    pub fn drop_mutation(&mut self, id: u64) -> Result<()> {
+        let i = self.mutations.iter().position(|mutation| mutation.id == id).ok_or(anyhow!("No mutation {}", id))?;
+        if self.mutations[i].is_done {
+            return Err(anyhow!("Mutation {} has already finished", id));
+        }
+        let table = self.get_table(&self.mutations[i].table)?;
+        self.changing_table(&table.name, || mutation::drop_staged_parts(&self.path, table))?;
+
+        self.mutations.remove(i);
+        self.save_metadata()
+    }
+
+    // Merge every part of the table, see merge.rs
+    pub fn optimize_table(&self, table_name: &str) -> Result<()> {
+        let table = self.get_table(table_name)?;
+        self.check_no_mutations(table_name)?;
+
+        self.changing_table(table_name, || {
+            for part_path in part_paths(&self.path, table)? {
+                merge::merge_part(&part_path, table)?;
+            }
+            Ok(())
+        })
+    }
+
+    pub fn create_table(&mut self, table: TableMetaData) -> Result<()> {
+        if self.get_table(&table.name).is_ok() {
+            return Err(anyhow!("Table {} already exists", table.name));
+        }
+        table.validate()?;
+
+        partition::create_table(&self.path, &table)?;
+        self.tables.push(table);
+        self.save_metadata()
+    }
+
+    pub fn drop_table(&mut self, table_name: &str) -> Result<()> {
+        let table = self.get_table(table_name)?.clone();
+        if let Some(view) = self.views.iter().find(|view| view.source == table_name || view.target == table_name) {
+            return Err(anyhow!("Can't drop table {}, view {} uses it", table_name, view.name));
+        }
+
+        // remove the table from the metadata first, so a crash can only leave unused files behind
+        self.tables.retain(|table| table.name != table_name);
+        self.mutations.retain(|mutation| mutation.table != table_name);
+        self.save_metadata()?;
+        self.invalidate_table(table_name);
+        partition::remove_table(&self.path, &table)
+    }
+
+    // Only rows written after the view is created are aggregated into its target
+    pub fn create_view(&mut self, view: MaterializedView) -> Result<()> {
+        if self.views.iter().any(|other| other.name == view.name) {
+            return Err(anyhow!("View {} already exists", view.name));
+        }
+        view.validate(self.get_table(&view.source)?, self.get_table(&view.target)?)?;
+
+        self.views.push(view);
+        self.save_metadata()
+    }
+
+    // The target table is kept, with the rows written to it so far
+    pub fn drop_view(&mut self, view_name: &str) -> Result<()> {
+        if !self.views.iter().any(|view| view.name == view_name) {
+            return Err(anyhow!("No view with name: {}", view_name));
+        }
+        self.views.retain(|view| view.name != view_name);
+        self.save_metadata()
+    }
+
+    // Remove all the rows from a table, but keep its definition
+    pub fn truncate_table(&self, table_name: &str) -> Result<()> {
+        let table = self.get_table(table_name)?;
+        self.check_no_mutations(table_name)?;
+
+        self.changing_table(table_name, || partition::truncate_table(&self.path, table))
+    }
+
+    // Partitions are directories, so these only have to move or remove one directory
+    pub fn drop_partition(&self, table_name: &str, value: &DValue) -> Result<()> {
+        let table = self.get_table(table_name)?;
+        self.check_no_mutations(table_name)?;
+        self.changing_table(table_name, || partition::drop_partition(&self.path, table, value))
+    }
+
+    pub fn detach_partition(&self, table_name: &str, value: &DValue) -> Result<()> {
+        let table = self.get_table(table_name)?;
+        self.check_no_mutations(table_name)?;
+        self.changing_table(table_name, || partition::detach_partition(&self.path, table, value))
+    }
+
+    pub fn attach_partition(&self, table_name: &str, value: &DValue) -> Result<()> {
+        let table = self.get_table(table_name)?;
+        self.changing_table(table_name, || partition::attach_partition(&self.path, table, value))
+    }
+
+    // Delete expired rows and reset expired columns, for every table with a TTL. Tables with
+    // mutations that haven't finished are left until the next time, as expiring rows can rewrite
+    // their parts.
+    pub fn apply_ttl(&self) -> Result<()> {
+        let now = expr::now_seconds();
+        for table in &self.tables {
+            if self.check_no_mutations(&table.name).is_err() {
+                continue;
+            }
+            self.changing_table(&table.name, || ttl::apply_ttl(&self.path, table, now))?;
+        }
+        Ok(())
+    }
+
+    // Apply each operation in turn, the metadata is saved after each one. The new files of an
+    // operation are written before the metadata is switched over to them, and the old files are only
+    // removed after, so if an operation fails part way through the table is left as it was, apart
+    // from some unused files.
+    pub fn alter_table(&mut self, table_name: &str, ops: &[AlterOperation]) -> Result<()> {
+        self.check_no_mutations(table_name)?;
+        for op in ops {
+            let table = self.get_table(table_name)?.clone();
+            let altered = table.altered(op)?;
+            // views refer to their source's columns by name
+            if let AlterOperation::DropColumn(name) | AlterOperation::RenameColumn { from: name, .. } = op {
+                if let Some(view) = self.views.iter().find(|view| view.source == table_name && view.columns().contains(&name)) {
+                    return Err(anyhow!("Can't alter column {}, view {} uses it", name, view.name));
+                }
+            }
+
+            let part_paths = part_paths(&self.path, &table)?;
+            for part_path in &part_paths {
+                match op {
+                    AlterOperation::AddColumn(col) => add_column(part_path, &table, col)?,
+                    AlterOperation::RenameColumn { from, to } => link_column(part_path, table_name, table.get_column(from).unwrap(), to)?,
+                    AlterOperation::DropColumn(_) => {}
+                }
+            }
+
+            let index = self.tables.iter().position(|table| table.name == table_name).unwrap();
+            self.tables[index] = altered;
+            self.save_metadata()?;
+            self.invalidate_table(table_name);
+
+            for part_path in &part_paths {
+                match op {
+                    AlterOperation::DropColumn(name) | AlterOperation::RenameColumn { from: name, .. } => {
+                        drop_column(part_path, table_name, table.get_column(name).unwrap())?
+                    }
+                    AlterOperation::AddColumn(_) => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn save_metadata(&self) -> Result<()> {
+        save_metadata_file(&self.path, &MetaData::new(self.tables.clone(), self.mutations.clone(), self.views.clone()))
+    }
+
+    fn get_table(&self, table_name: &str) -> Result<&TableMetaData> {
+        self.tables.iter().find(|table| {
+            table.name.eq(table_name)
+        }).ok_or(anyhow!("No table with name: {}", table_name))
+    }
+
+    // A mutation only updates the blocks each part had when it was created, so nothing may rewrite or
+    // remove a part of the table until it has run
+    fn check_no_mutations(&self, table_name: &str) -> Result<()> {
+        if self.mutations.iter().any(|mutation| mutation.table == table_name && !mutation.is_done) {
+            return Err(anyhow!("Table {} has mutations that haven't finished", table_name));
+        }
+        Ok(())
+    }
+
+    // Forget the blocks and part files cached for a table, for when its files have been changed
+    fn invalidate_table(&self, table_name: &str) {
+        self.cache.invalidate_table(table_name);
+        self.parts.invalidate_table(table_name);
+    }
+
+    // Change a table's files, then invalidate what's cached for it whether or not the change
+    // finished, since a failed change can still have written to some parts
+    fn changing_table<T>(&self, table_name: &str, change: impl FnOnce() -> Result<T>) -> Result<T> {
+        let result = change();
+        self.invalidate_table(table_name);
+        result
+    }
 }
@@ -0,0 +1,105 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::aggregate::aggregate_rows;
+use crate::metadata::{TableEngine, TableMetaData};
+use crate::storage::{rewrite_part, PartReader};
+use crate::DValue;
+
+// Merging rewrites a part's column files from scratch, which is when the work that is put off by
+// cheaper operations gets done: rows marked as deleted are dropped for good, and the table engine
+// gets to combine rows (e.g. a Replacing table keeps only the latest version of each key, and an
+// Aggregating table merges the aggregate states of each key).
+//
+// Returns whether the part was rewritten, parts with nothing to do are left alone.
+pub fn merge_part(root_path: &Path, table: &TableMetaData) -> Result<bool> {
+    let mut reader = PartReader::open(root_path, table)?;
+    // each write is sorted on its own, merging sorts the whole part so fewer blocks overlap
+    let unsorted = !table.order_by.is_empty() && !reader.is_sorted();
+    if !reader.has_deleted_rows() && !unsorted && table.engine == TableEngine::MergeTree {
+        return Ok(false);
+    }
+
+    let mut rows = Vec::new();
+    for block in 0..reader.n_blocks() {
+        rows.extend(reader.read_block_group(block)?);
+    }
+    let n_rows = rows.len();
+    let rows = combine_rows(table, rows)?;
+    if rows.len() == n_rows && !reader.has_deleted_rows() && !unsorted {
+        return Ok(false);
+    }
+    rewrite_part(root_path, table, &rows)?;
+    Ok(true)
+}
+
+// Combine the rows of a part according to the table engine. The rows must be in the order they
+// were written: a part's blocks are, and rows of the same key within one write are combined by
+// storage::write_data before the write is sorted.
+pub fn combine_rows(table: &TableMetaData, rows: Vec<Vec<DValue>>) -> Result<Vec<Vec<DValue>>> {
+    match &table.engine {
+        TableEngine::MergeTree => Ok(rows),
+        TableEngine::Replacing { key, version } => {
+            let key_indexes: Vec<usize> = key.iter().map(|name| column_index(table, name)).collect();
+            let version_index = version.as_ref().map(|name| column_index(table, name));
+            Ok(deduplicate(rows, &key_indexes, version_index))
+        }
+        TableEngine::Aggregating { key, states } => {
+            let key_indexes: Vec<usize> = key.iter().map(|name| column_index(table, name)).collect();
+            let states: Vec<_> = states.iter().map(|(name, function)| (column_index(table, name), *function)).collect();
+            aggregate_rows(rows, &key_indexes, &states)
+        }
+    }
+}
+
+// the engine's columns are checked to exist when the table is created or altered
+fn column_index(table: &TableMetaData, name: &str) -> usize {
+    table.columns.iter().position(|col| col.name == name).unwrap()
+}
+
+// Keep the row with the highest version for each key, or the last one when the versions are equal.
+// The result is sorted by key.
+fn deduplicate(rows: Vec<Vec<DValue>>, key_indexes: &[usize], version_index: Option<usize>) -> Vec<Vec<DValue>> {
+    let key_of = |row: &Vec<DValue>| -> Vec<DValue> { key_indexes.iter().map(|&i| row[i].clone()).collect() };
+
+    let mut latest: HashMap<Vec<DValue>, Vec<DValue>> = HashMap::new();
+    for row in rows {
+        let key = key_of(&row);
+        let replace = match (latest.get(&key), version_index) {
+            (Some(existing), Some(version_index)) => row[version_index] >= existing[version_index],
+            _ => true,
+        };
+        if replace {
+            latest.insert(key, row);
+        }
+    }
+
+    let mut rows: Vec<Vec<DValue>> = latest.into_values().collect();
+    rows.sort_by(|a, b| {
+        key_indexes
+            .iter()
+            .map(|&i| a[i].cmp(&b[i]))
+            .find(|ordering| *ordering != Ordering::Equal)
+            .unwrap_or(Ordering::Equal)
+    });
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(key: u64, version: u64, value: &str) -> Vec<DValue> {
+        vec![DValue::Uint64(key), DValue::Uint64(version), DValue::String(value.to_string())]
+    }
+
+    #[test]
+    fn test_deduplicate() {
+        let rows = vec![row(2, 1, "a"), row(1, 5, "b"), row(2, 3, "c"), row(1, 4, "d"), row(2, 3, "e")];
+        assert_eq!(deduplicate(rows.clone(), &[0], Some(1)), vec![row(1, 5, "b"), row(2, 3, "e")]);
+        assert_eq!(deduplicate(rows, &[0], None), vec![row(1, 4, "d"), row(2, 3, "e")]);
+    }
+}
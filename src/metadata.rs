@@ -1,15 +1,86 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::path::{Path, PathBuf};
 
-use crate::DType;
+use crate::aggregate::{AggregateFunction, ViewAggregate};
+use crate::expr::Expr;
+use crate::format::FORMAT_VERSION;
+use crate::query::dtype_of;
+use crate::skip_index::SkipIndex;
+use crate::{get_dtype, DType, DValue};
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct TableMetaData {
     pub name: String,
     pub columns: Vec<ColumnMetaData>,
+    // rows are split into a directory per value of this expression, see partition.rs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub partition_by: Option<Expr>,
+    // rows are deleted once they expire, by DB::apply_ttl
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<TtlRule>,
+    #[serde(default, skip_serializing_if = "TableEngine::is_default")]
+    pub engine: TableEngine,
+    // rows are sorted by these columns as they are written, see primary_index.rs
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub order_by: Vec<String>,
+    // a prefix of order_by to keep in the primary index, all of order_by if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub primary_key: Vec<String>,
+}
+
+// How rows are combined when parts are merged
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub enum TableEngine {
+    // rows are kept as they are
+    #[default]
+    MergeTree,
+    // rows with the same key are collapsed into the one with the highest version, or the one
+    // inserted last if there is no version column
+    Replacing { key: Vec<String>, version: Option<String> },
+    // rows with the same key are combined by merging the partial aggregate state in each of the
+    // state columns, see aggregate.rs
+    Aggregating { key: Vec<String>, states: Vec<(String, AggregateFunction)> },
+}
+
+impl TableEngine {
+    fn is_default(&self) -> bool {
+        *self == TableEngine::MergeTree
+    }
+
+    pub fn replacing(key: &[&str], version: Option<&str>) -> TableEngine {
+        TableEngine::Replacing {
+            key: key.iter().map(|name| name.to_string()).collect(),
+            version: version.map(|name| name.to_string()),
+        }
+    }
+
+    pub fn aggregating(key: &[&str], states: &[(&str, AggregateFunction)]) -> TableEngine {
+        TableEngine::Aggregating {
+            key: key.iter().map(|name| name.to_string()).collect(),
+            states: states.iter().map(|(name, function)| (name.to_string(), *function)).collect(),
+        }
+    }
+
+    // The columns rows are combined by, if the engine combines rows
+    pub fn key(&self) -> Option<&Vec<String>> {
+        match self {
+            TableEngine::MergeTree => None,
+            TableEngine::Replacing { key, .. } | TableEngine::Aggregating { key, .. } => Some(key),
+        }
+    }
+
+    // The columns the engine refers to by name
+    pub(crate) fn columns(&self) -> Vec<&String> {
+        match self {
+            TableEngine::MergeTree => vec![],
+            TableEngine::Replacing { key, version } => key.iter().chain(version.iter()).collect(),
+            TableEngine::Aggregating { key, states } => key.iter().chain(states.iter().map(|(name, _)| name)).collect(),
+        }
+    }
 }
 
 impl TableMetaData {
@@ -17,14 +88,239 @@ impl TableMetaData {
         TableMetaData {
             name: name.to_string(),
             columns,
+            partition_by: None,
+            ttl: None,
+            engine: TableEngine::MergeTree,
+            order_by: vec![],
+            primary_key: vec![],
+        }
+    }
+
+    pub fn with_order_by(mut self, column_names: &[&str]) -> TableMetaData {
+        self.order_by = column_names.iter().map(|name| name.to_string()).collect();
+        self
+    }
+
+    pub fn with_primary_key(mut self, column_names: &[&str]) -> TableMetaData {
+        self.primary_key = column_names.iter().map(|name| name.to_string()).collect();
+        self
+    }
+
+    // The columns in the primary index
+    pub fn primary_key_columns(&self) -> &[String] {
+        if self.primary_key.is_empty() {
+            &self.order_by
+        } else {
+            &self.primary_key
         }
     }
+
+    // The positions of the ORDER BY columns in a row
+    pub fn order_by_indexes(&self) -> Vec<usize> {
+        self.order_by
+            .iter()
+            .map(|name| self.columns.iter().position(|col| &col.name == name).unwrap())
+            .collect()
+    }
+
+    pub fn with_engine(mut self, engine: TableEngine) -> TableMetaData {
+        self.engine = engine;
+        self
+    }
+
+    // Check the table definition makes sense, before it is created
+    pub fn validate(&self) -> Result<()> {
+        check_name("Table", &self.name)?;
+        if self.columns.is_empty() {
+            return Err(anyhow!("Table {} has no columns", self.name));
+        }
+        if let Some((i, col)) = self.columns.iter().enumerate().find(|(i, col)| {
+            self.columns[..*i].iter().any(|other| other.name == col.name)
+        }) {
+            return Err(anyhow!("Column {} defined more than once (position {})", col.name, i));
+        }
+        for col in &self.columns {
+            col.validate()?;
+        }
+        if let Some((i, name)) = self.order_by.iter().enumerate().find(|(i, name)| self.order_by[..*i].contains(name)) {
+            return Err(anyhow!("Column {} is in ORDER BY more than once (position {})", name, i));
+        }
+        if !self.order_by.starts_with(&self.primary_key) {
+            return Err(anyhow!("The primary key of table {} must be a prefix of ORDER BY", self.name));
+        }
+        for name in self.engine.columns().into_iter().chain(&self.order_by).chain(self.exprs().into_iter().flat_map(|(_, expr)| expr.columns())) {
+            self.get_column(name).ok_or(anyhow!("No column {} in table {}", name, self.name))?;
+        }
+        // rows expire some seconds after the TTL expression's value, so it has to be a timestamp
+        let ttls = self.columns.iter().filter_map(|col| col.ttl.as_ref().map(|ttl| (format!("The TTL for {}", col.name), ttl)));
+        for (what, ttl) in ttls.chain(self.ttl.iter().map(|ttl| ("The table TTL".to_string(), ttl))) {
+            let dtype = dtype_of(&ttl.expr, &self.columns)?;
+            if dtype != DType::Uint64 {
+                return Err(anyhow!("{} should be a Uint64 timestamp, not a {:?}", what, dtype));
+            }
+        }
+        if self.engine.key().is_some_and(|key| key.is_empty()) {
+            return Err(anyhow!("Table {} needs at least one key column", self.name));
+        }
+        if let TableEngine::Aggregating { key, states } = &self.engine {
+            for (name, function) in states {
+                if key.contains(name) {
+                    return Err(anyhow!("Column {} can't be both a key and a state column", name));
+                }
+                let dtype = &self.get_column(name).unwrap().dtype;
+                if function.state_dtype().is_some_and(|state_dtype| state_dtype != *dtype) {
+                    return Err(anyhow!("State column {} of {:?} should be a {:?}", name, function, function.state_dtype().unwrap()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn with_partition_by(mut self, partition_by: Expr) -> TableMetaData {
+        self.partition_by = Some(partition_by);
+        self
+    }
+
+    pub fn with_ttl(mut self, ttl: TtlRule) -> TableMetaData {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    // Build a full row, in column order, from a map of column name to value. Any column that
+    // isn't in the map is filled from its DEFAULT expression, which can refer to earlier columns.
+    pub fn row_from_named(&self, mut values: HashMap<String, DValue>) -> Result<Vec<DValue>> {
+        if let Some(unknown) = values
+            .keys()
+            .find(|name| !self.columns.iter().any(|col| &col.name == *name))
+        {
+            return Err(anyhow!("No column {} in table {}", unknown, self.name));
+        }
+
+        for col in &self.columns {
+            if !values.contains_key(&col.name) {
+                let value = col.default_value(&values)?;
+                values.insert(col.name.clone(), value);
+            }
+        }
+
+        Ok(self
+            .columns
+            .iter()
+            .map(|col| values.remove(&col.name).unwrap())
+            .collect())
+    }
+
+    pub fn get_column(&self, column_name: &str) -> Option<&ColumnMetaData> {
+        self.columns.iter().find(|col| col.name == column_name)
+    }
+
+    // The table metadata after applying the operation, or an error if the operation isn't valid
+    pub fn altered(&self, op: &AlterOperation) -> Result<TableMetaData> {
+        let mut table = self.clone();
+        match op {
+            AlterOperation::AddColumn(col) => {
+                if self.get_column(&col.name).is_some() {
+                    return Err(anyhow!("Column {} already exists in table {}", col.name, self.name));
+                }
+                table.columns.push(col.clone());
+            }
+            AlterOperation::DropColumn(name) => {
+                self.get_column(name).ok_or(anyhow!("No column {} in table {}", name, self.name))?;
+                if self.columns.len() == 1 {
+                    return Err(anyhow!("Can't drop the only column of table {}", self.name));
+                }
+                self.check_unused(name, "drop")?;
+                table.columns.retain(|col| &col.name != name);
+            }
+            AlterOperation::RenameColumn { from, to } => {
+                if self.get_column(to).is_some() {
+                    return Err(anyhow!("Column {} already exists in table {}", to, self.name));
+                }
+                self.check_unused(from, "rename")?;
+                let col = table
+                    .columns
+                    .iter_mut()
+                    .find(|col| &col.name == from)
+                    .ok_or(anyhow!("No column {} in table {}", from, self.name))?;
+                col.name = to.clone();
+            }
+        }
+        table.validate()?;
+        Ok(table)
+    }
+
+    // All the expressions stored in the table's metadata, with a description of what each is for
+    fn exprs(&self) -> Vec<(String, &Expr)> {
+        let mut exprs = Vec::new();
+        for col in &self.columns {
+            if let Some(expr) = &col.default {
+                exprs.push((format!("the default for {}", col.name), expr));
+            }
+            if let Some(ttl) = &col.ttl {
+                exprs.push((format!("the TTL for {}", col.name), &ttl.expr));
+            }
+        }
+        if let Some(expr) = &self.partition_by {
+            exprs.push(("the partition expression".to_string(), expr));
+        }
+        if let Some(ttl) = &self.ttl {
+            exprs.push(("the table TTL".to_string(), &ttl.expr));
+        }
+        exprs
+    }
+
+    // Check that no expression stored in the table's metadata refers to the column
+    fn check_unused(&self, column_name: &String, action: &str) -> Result<()> {
+        if let Some((what, _)) = self.exprs().iter().find(|(_, expr)| expr.columns().contains(&column_name)) {
+            return Err(anyhow!("Can't {} column {}, {} uses it", action, column_name, what));
+        }
+        if self.engine.columns().contains(&column_name) {
+            return Err(anyhow!("Can't {} column {}, the table engine uses it", action, column_name));
+        }
+        if self.order_by.contains(column_name) {
+            return Err(anyhow!("Can't {} column {}, ORDER BY uses it", action, column_name));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AlterOperation {
+    // New columns go at the end of the table, existing rows read as the column's default
+    AddColumn(ColumnMetaData),
+    DropColumn(String),
+    RenameColumn { from: String, to: String },
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct ColumnMetaData {
     pub name: String,
     pub dtype: DType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<Expr>,
+    // once a row expires the column is reset to its default, by DB::apply_ttl
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<TtlRule>,
+    // used to skip blocks that can't match a filter, see skip_index.rs
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub skip_indexes: Vec<SkipIndex>,
+}
+
+// Rows expire `interval` seconds after the value of `expr`, which should be a unix timestamp in seconds
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TtlRule {
+    pub expr: Expr,
+    pub interval: u64,
+}
+
+impl TtlRule {
+    pub fn new(expr: Expr, interval: u64) -> TtlRule {
+        TtlRule { expr, interval }
+    }
+
+    pub fn days(column_name: &str, days: u64) -> TtlRule {
+        TtlRule::new(Expr::column(column_name), days * 24 * 60 * 60)
+    }
 }
 
 impl ColumnMetaData {
@@ -32,13 +328,167 @@ impl ColumnMetaData {
         ColumnMetaData {
             name: name.to_string(),
             dtype,
+            default: None,
+            ttl: None,
+            skip_indexes: vec![],
+        }
+    }
+
+    pub fn with_skip_index(mut self, index: SkipIndex) -> ColumnMetaData {
+        self.skip_indexes.push(index);
+        self
+    }
+
+    fn validate(&self) -> Result<()> {
+        check_name("Column", &self.name)?;
+        for (i, index) in self.skip_indexes.iter().enumerate() {
+            if self.skip_indexes[..i].iter().any(|other| other.kind() == index.kind()) {
+                return Err(anyhow!("Column {} has more than one {} index", self.name, index.kind()));
+            }
+            index.validate(self)?;
         }
+        Ok(())
+    }
+
+    pub fn with_default(mut self, default: Expr) -> ColumnMetaData {
+        self.default = Some(default);
+        self
+    }
+
+    pub fn with_ttl(mut self, ttl: TtlRule) -> ColumnMetaData {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    // Evaluate the DEFAULT expression against the rest of the row, falling back to the zero value for the type
+    pub fn default_value(&self, row: &HashMap<String, DValue>) -> Result<DValue> {
+        let value = match &self.default {
+            Some(expr) => expr
+                .eval(row)
+                .with_context(|| format!("Failed to evaluate default for column {}", self.name))?,
+            None => self.dtype.zero_value(),
+        };
+        if get_dtype(&value) != self.dtype {
+            return Err(anyhow!("Default for column {} is not a {:?}", self.name, self.dtype));
+        }
+        Ok(value)
     }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MetaData {
+    // files written before format versions were introduced don't have this, and are version 0
+    #[serde(default)]
+    pub(crate) format_version: u16,
     pub(crate) tables: Vec<TableMetaData>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) mutations: Vec<Mutation>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) views: Vec<MaterializedView>,
+}
+
+impl MetaData {
+    pub fn new(tables: Vec<TableMetaData>, mutations: Vec<Mutation>, views: Vec<MaterializedView>) -> MetaData {
+        MetaData {
+            format_version: FORMAT_VERSION,
+            tables,
+            mutations,
+            views,
+        }
+    }
+}
+
+// An UPDATE that is recorded when it's created and applied later by DB::run_mutations, see
+// mutation.rs
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Mutation {
+    pub id: u64,
+    pub table: String,
+    pub assignments: Vec<(String, Expr)>,
+    pub filter: Expr,
+    // the number of block groups in each part when the mutation was created, by the part's path
+    // relative to the database root. Only these are updated, so rows written later aren't. None for
+    // mutations recorded before this was kept, which update every block.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub part_blocks: Option<BTreeMap<String, usize>>,
+    // paths of the parts that have been updated so far, relative to the database root
+    pub parts_done: Vec<String>,
+    pub is_done: bool,
+}
+
+// Rows written to the source table are also aggregated into the target table, which should have
+// an Aggregating engine with the view's key and aggregate columns
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MaterializedView {
+    pub name: String,
+    pub source: String,
+    pub target: String,
+    // target column name, and the expression over the source row it's set to
+    pub key: Vec<(String, Expr)>,
+    pub aggregates: Vec<ViewAggregate>,
+}
+
+impl MaterializedView {
+    pub fn new(name: &str, source: &str, target: &str) -> MaterializedView {
+        MaterializedView {
+            name: name.to_string(),
+            source: source.to_string(),
+            target: target.to_string(),
+            key: vec![],
+            aggregates: vec![],
+        }
+    }
+
+    pub fn with_key(mut self, column_name: &str, expr: Expr) -> MaterializedView {
+        self.key.push((column_name.to_string(), expr));
+        self
+    }
+
+    pub fn with_aggregate(mut self, aggregate: ViewAggregate) -> MaterializedView {
+        self.aggregates.push(aggregate);
+        self
+    }
+
+    // The source columns the view reads
+    pub fn columns(&self) -> Vec<&String> {
+        let exprs = self.key.iter().map(|(_, expr)| expr).chain(self.aggregates.iter().filter_map(|aggregate| aggregate.arg.as_ref()));
+        exprs.flat_map(|expr| expr.columns()).collect()
+    }
+
+    // Check the view fits its source and target tables
+    pub fn validate(&self, source: &TableMetaData, target: &TableMetaData) -> Result<()> {
+        if source.name == target.name {
+            return Err(anyhow!("View {} can't write into its own source table", self.name));
+        }
+        for name in self.columns() {
+            source.get_column(name).ok_or(anyhow!("No column {} in table {}", name, source.name))?;
+        }
+        for aggregate in &self.aggregates {
+            if aggregate.arg.is_some() == (aggregate.function == AggregateFunction::Count) {
+                return Err(anyhow!("Wrong arguments for {:?} in view {}", aggregate.function, self.name));
+            }
+        }
+
+        let (key, states) = match &target.engine {
+            TableEngine::Aggregating { key, states } => (key, states),
+            _ => return Err(anyhow!("Target table {} of view {} needs an Aggregating engine", target.name, self.name)),
+        };
+        let mut view_key: Vec<&String> = self.key.iter().map(|(name, _)| name).collect();
+        let mut target_key: Vec<&String> = key.iter().collect();
+        view_key.sort();
+        target_key.sort();
+        if view_key != target_key {
+            return Err(anyhow!("View {} has key {:?} but table {} has key {:?}", self.name, view_key, target.name, target_key));
+        }
+        let mut view_states: Vec<(&String, AggregateFunction)> = self.aggregates.iter().map(|aggregate| (&aggregate.column, aggregate.function)).collect();
+        let mut target_states: Vec<(&String, AggregateFunction)> = states.iter().map(|(name, function)| (name, *function)).collect();
+        view_states.sort_by_key(|(name, _)| *name);
+        target_states.sort_by_key(|(name, _)| *name);
+        if view_states != target_states {
+            return Err(anyhow!("View {} has aggregates {:?} but table {} has states {:?}", self.name, view_states, target.name, target_states));
+        }
+        Ok(())
+    }
 }
 
 pub fn load_metadata_file<P: AsRef<Path>>(path: P) -> Result<MetaData>  {
@@ -56,21 +506,40 @@ pub fn load_metadata_file<P: AsRef<Path>>(path: P) -> Result<MetaData>  {
             meta_path.to_string_lossy()
         )
     })?;
-    return Ok(meta);
+    Ok(meta)
 }
 
-pub fn create_metadata_file<P: AsRef<Path>>(path: P, tables: &Vec<TableMetaData>) -> Result<MetaData> {
-    let meta = MetaData {
-        tables: tables.clone(),
-    };
+pub fn create_metadata_file<P: AsRef<Path>>(path: P, tables: &[TableMetaData]) -> Result<MetaData> {
+    if meta_path(&path).exists() {
+        return Err(anyhow!("A database already exists at {}", path.as_ref().to_string_lossy()));
+    }
+    let meta = MetaData::new(tables.to_vec(), vec![], vec![]);
+    save_metadata_file(&path, &meta)?;
+    Ok(meta)
+}
+
+// Write to a temporary file then rename it over the old one, so readers never see a partially written file
+pub fn save_metadata_file<P: AsRef<Path>>(path: P, meta: &MetaData) -> Result<()> {
     let obj = json!(meta);
     let contents = serde_json::to_string_pretty(&obj).unwrap();
 
-    fs::write(meta_path(&path), contents)?;
-    return Ok(meta)
+    let tmp_path = path.as_ref().join("metadata.json.tmp");
+    fs::write(&tmp_path, contents).with_context(|| {
+        format!("Failed to write file: {}", tmp_path.to_string_lossy())
+    })?;
+    fs::rename(&tmp_path, meta_path(&path))?;
+    Ok(())
 }
 
-
 fn meta_path<P: AsRef<Path>>(root_path: P) -> PathBuf {
     root_path.as_ref().join("metadata.json")
-}
\ No newline at end of file
+}
+
+// Table and column names end up in file names, as {table}.{column}.data, so they can't have path
+// separators or dots in them
+fn check_name(kind: &str, name: &str) -> Result<()> {
+    if name.is_empty() || name.contains(['/', '\\', '.', '\0']) {
+        return Err(anyhow!("{} name {:?} can't be empty or contain '/', '\\', '.' or NUL", kind, name));
+    }
+    Ok(())
+}
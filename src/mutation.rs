@@ -0,0 +1,188 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+
+use crate::expr::TableRow;
+use crate::metadata::{Mutation, TableMetaData};
+use crate::partition::part_paths;
+use crate::query::{check_boolean, dtype_of};
+use crate::storage::{commit_staged_indexes, finish_staging, stage_column_blocks, PartReader};
+use crate::{get_dtype, DValue};
+
+// Mutations rewrite existing rows. They are recorded in metadata.json when they are created, and
+// applied later by DB::run_mutations, one part at a time. Nothing runs them until then. Only the
+// rows that were there when the mutation was created are updated: it records how many block groups
+// each part had, and writes only ever add new blocks. Merges and anything that removes a part would
+// move rows in or out of those blocks, so they are refused while a table has unfinished mutations.
+//
+// Only the blocks with rows that match the filter are rewritten, and only for the assigned
+// columns: the new blocks are appended to those columns' data files and their index files are
+// replaced. The new index files are all staged before any are moved into place, so if a mutation
+// is interrupted it can carry on from where it was without applying any part twice: parts that had
+// finished staging are committed by DB::open, and the rest are staged again from scratch.
+
+pub fn check_mutation(table: &TableMetaData, mutation: &Mutation) -> Result<()> {
+    if mutation.assignments.is_empty() {
+        return Err(anyhow!("No columns to update"));
+    }
+    check_boolean(&mutation.filter, &table.columns, "The filter")?;
+    for (name, expr) in &mutation.assignments {
+        let col = table.get_column(name).ok_or(anyhow!("No column {} in table {}", name, table.name))?;
+        let dtype = dtype_of(expr, &table.columns)?;
+        if dtype != col.dtype {
+            return Err(anyhow!("Can't set column {} of type {:?} to a {:?}", name, col.dtype, dtype));
+        }
+        if table.partition_by.as_ref().is_some_and(|expr| expr.columns().contains(&name)) {
+            return Err(anyhow!("Can't update column {}, the partition expression uses it", name));
+        }
+        // the blocks would need re-sorting, and the primary index rewriting
+        if table.order_by.contains(name) {
+            return Err(anyhow!("Can't update column {}, ORDER BY uses it", name));
+        }
+        // rows that were already combined by a merge couldn't be combined again with the new value
+        if table.engine.columns().contains(&name) {
+            return Err(anyhow!("Can't update column {}, the table engine uses it", name));
+        }
+    }
+    Ok(())
+}
+
+// Apply the mutation to every part it hasn't been applied to yet. `on_part_done` is called after
+// each part, so that the progress can be saved.
+pub fn run_mutation<F: FnMut(&Mutation) -> Result<()>>(
+    root_path: &Path,
+    table: &TableMetaData,
+    mutation: &mut Mutation,
+    mut on_part_done: F,
+) -> Result<()> {
+    for part_path in part_paths(root_path, table)? {
+        let part_name = part_name(root_path, &part_path);
+        if mutation.parts_done.contains(&part_name) {
+            continue;
+        }
+        // parts made after the mutation was created only hold rows written after it
+        let n_blocks = match &mutation.part_blocks {
+            Some(part_blocks) => match part_blocks.get(&part_name) {
+                Some(&n_blocks) => Some(n_blocks),
+                None => continue,
+            },
+            None => None,
+        };
+
+        // if a run on this handle failed after staging this part, only the last step is left to do
+        if !commit_staged_indexes(&part_path, table)? {
+            mutate_part(&part_path, table, mutation, n_blocks)?;
+            finish_staging(&part_path, &table.name)?;
+            commit_staged_indexes(&part_path, table)?;
+        }
+
+        mutation.parts_done.push(part_name);
+        on_part_done(mutation)?;
+    }
+    mutation.is_done = true;
+    on_part_done(mutation)
+}
+
+// Move into place the index files of every part whose staging finished before a mutation was
+// interrupted, and record those parts as done. This has to happen when the database is opened,
+// before anything else writes to the part: the staged index files are copies taken before the
+// mutation, so moving them into place after a write or merge would lose or corrupt its blocks.
+// Returns whether any mutation was changed.
+pub(crate) fn commit_staged_parts(root_path: &Path, tables: &[TableMetaData], mutations: &mut [Mutation]) -> Result<bool> {
+    let mut changed = false;
+    for table in tables {
+        for part_path in part_paths(root_path, table)? {
+            if !commit_staged_indexes(&part_path, table)? {
+                continue;
+            }
+            // mutations run in order, so only the first unfinished one can have been staging
+            if let Some(mutation) = mutations.iter_mut().find(|mutation| mutation.table == table.name && !mutation.is_done) {
+                mutation.parts_done.push(part_name(root_path, &part_path));
+                changed = true;
+            }
+        }
+    }
+    Ok(changed)
+}
+
+// Finish off a mutation that is being dropped, so that none of its staged index files are left to
+// be committed for a later one. Parts that had finished staging are committed, as some of their
+// index files may already have been moved into place.
+pub(crate) fn drop_staged_parts(root_path: &Path, table: &TableMetaData) -> Result<()> {
+    for part_path in part_paths(root_path, table)? {
+        commit_staged_indexes(&part_path, table)?;
+    }
+    Ok(())
+}
+
+// The path of a part relative to the database root, which is how mutations record them
+fn part_name(root_path: &Path, part_path: &Path) -> String {
+    part_path.strip_prefix(root_path).unwrap_or(part_path).to_string_lossy().to_string()
+}
+
+// The number of block groups in each part, for a new mutation to record
+pub(crate) fn part_blocks(root_path: &Path, table: &TableMetaData) -> Result<BTreeMap<String, usize>> {
+    part_paths(root_path, table)?
+        .iter()
+        .map(|part_path| Ok((part_name(root_path, part_path), PartReader::open(part_path, table)?.n_blocks())))
+        .collect()
+}
+
+// Update the first n_blocks block groups of a part, or all of them if None
+fn mutate_part(root_path: &Path, table: &TableMetaData, mutation: &Mutation, n_blocks: Option<usize>) -> Result<()> {
+    let assigned: Vec<usize> = mutation
+        .assignments
+        .iter()
+        .map(|(name, _)| table.columns.iter().position(|col| &col.name == name).unwrap())
+        .collect();
+
+    let mut reader = PartReader::open(root_path, table)?;
+    // the new blocks for each assigned column
+    let mut new_blocks: Vec<Vec<(usize, Vec<DValue>)>> = vec![vec![]; assigned.len()];
+    for block in 0..n_blocks.unwrap_or(usize::MAX).min(reader.n_blocks()) {
+        if !mutation.filter.may_match(&reader.block_ranges(block)) {
+            continue;
+        }
+
+        // deleted rows are kept, so the rows stay in the same positions
+        let rows = reader.read_all_rows(block)?;
+        let mut columns: Vec<Vec<DValue>> = assigned
+            .iter()
+            .map(|&i| rows.iter().map(|row| row[i].clone()).collect())
+            .collect();
+        let mut changed = false;
+        for (row_index, row) in rows.iter().enumerate() {
+            if reader.is_deleted(block, row_index) {
+                continue;
+            }
+            let table_row = TableRow { columns: &table.columns, values: row };
+            if !mutation.filter.matches(&table_row)? {
+                continue;
+            }
+            // every assignment is evaluated against the row as it was before the update
+            for (i, (name, expr)) in mutation.assignments.iter().enumerate() {
+                let value = expr.eval(&table_row)?;
+                let col = &table.columns[assigned[i]];
+                if get_dtype(&value) != col.dtype {
+                    return Err(anyhow!("Can't set column {} to {:?}", name, value));
+                }
+                columns[i][row_index] = value;
+            }
+            changed = true;
+        }
+
+        if changed {
+            for (i, values) in columns.into_iter().enumerate() {
+                new_blocks[i].push((block, values));
+            }
+        }
+    }
+
+    for (i, blocks) in new_blocks.iter().enumerate() {
+        if !blocks.is_empty() {
+            stage_column_blocks(root_path, &table.name, &table.columns[assigned[i]], blocks)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,230 @@
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use anyhow::Result;
+
+use crate::cache::BlockCache;
+use crate::aggregate::{Aggregation, Groups};
+use crate::column::Batch;
+use crate::explain::QueryStats;
+use crate::expr::Expr;
+use crate::metadata::TableMetaData;
+use crate::partition::pruned_part_paths_cached;
+use crate::query::check_boolean;
+use crate::storage::{PartFiles, PartReader};
+use crate::{DValue, DB};
+
+// The number of block groups a thread takes at a time. Small enough that the threads finish at
+// about the same time, big enough that they rarely have to reopen a part.
+const BLOCKS_PER_TASK: usize = 4;
+
+// A scan of some parts of a table, split into tasks of a few block groups each. Threads take the
+// next task from a shared counter until there are none left, and each one folds the matching rows
+// of its tasks into its own state (e.g. partial aggregates), so they never wait on each other
+// except to share the block cache.
+pub(crate) struct ParallelScan<'a> {
+    table: &'a TableMetaData,
+    filter: Option<&'a Expr>,
+    cache: &'a BlockCache,
+    stats: Option<&'a QueryStats>,
+    // the files of each part that can hold matching rows, from the DB's part cache
+    parts: Vec<Arc<PartFiles>>,
+    // the index into parts and the block groups of each task, in scan order
+    tasks: Vec<(usize, Range<usize>)>,
+}
+
+impl<'a> ParallelScan<'a> {
+    // A scan of the parts of the table that the filter doesn't rule out
+    pub fn new(db: &'a DB, table: &'a TableMetaData, filter: Option<&'a Expr>) -> Result<ParallelScan<'a>> {
+        // a comparison between different types would rule out every block
+        if let Some(filter) = filter {
+            check_boolean(filter, &table.columns, "The filter")?;
+        }
+        let parts = pruned_part_paths_cached(&db.path, table, filter, &db.parts)?
+            .iter()
+            .map(|part_path| db.parts.get_or_load(part_path, table))
+            .collect::<Result<Vec<Arc<PartFiles>>>>()?;
+        let mut tasks = Vec::new();
+        for (part, files) in parts.iter().enumerate() {
+            let n_blocks = files.n_blocks();
+            for start in (0..n_blocks).step_by(BLOCKS_PER_TASK) {
+                tasks.push((part, start..(start + BLOCKS_PER_TASK).min(n_blocks)));
+            }
+        }
+        Ok(ParallelScan { table, filter, cache: &db.cache, stats: None, parts, tasks })
+    }
+
+    // Count what the scan reads and the time each thread spends on it, for EXPLAIN ANALYZE
+    pub fn with_stats(mut self, stats: &'a QueryStats) -> ParallelScan<'a> {
+        stats.parts.fetch_add(self.parts.len() as u64, Ordering::Relaxed);
+        self.stats = Some(stats);
+        self
+    }
+
+    // Call visit with every block group, with the rows that match the filter selected, along with the
+    // index of the task it is from, on up to max_threads threads (0 for one per core). Returns the
+    // state of each thread.
+    pub fn run<S, N, V>(&self, max_threads: usize, new_state: N, visit: V) -> Result<Vec<S>>
+    where
+        S: Send,
+        N: Fn() -> S + Sync,
+        V: Fn(&mut S, usize, Batch) -> Result<()> + Sync,
+    {
+        let max_threads = match max_threads {
+            0 => thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
+        };
+        let n_threads = max_threads.min(self.tasks.len()).max(1);
+        let next_task = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+        let worker = || -> Result<S> {
+            let mut state = new_state();
+            let mut reader: Option<(usize, PartReader)> = None;
+            while !failed.load(Ordering::Relaxed) {
+                let task = next_task.fetch_add(1, Ordering::Relaxed);
+                let Some((part, blocks)) = self.tasks.get(task) else {
+                    break;
+                };
+                let result = self.run_task(&mut reader, *part, blocks.clone(), |batch| visit(&mut state, task, batch));
+                if result.is_err() {
+                    // stop the other threads early, the scan has failed anyway
+                    failed.store(true, Ordering::Relaxed);
+                    result?;
+                }
+            }
+            Ok(state)
+        };
+
+        if n_threads == 1 {
+            return Ok(vec![worker()?]);
+        }
+        thread::scope(|scope| {
+            let handles: Vec<_> = (0..n_threads).map(|_| scope.spawn(worker)).collect();
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        })
+    }
+
+    // The rows returned by row_values for each batch, in the same order as a single threaded scan
+    pub fn collect_rows<R>(&self, max_threads: usize, row_values: R) -> Result<Vec<Vec<DValue>>>
+    where
+        R: Fn(&Batch) -> Result<Vec<Vec<DValue>>> + Sync,
+    {
+        // each thread keeps the rows of each of its tasks, which are put back in task order
+        let states = self.run(max_threads, Vec::new, |tasks: &mut Vec<(usize, Vec<Vec<DValue>>)>, task, batch| {
+            let rows = row_values(&batch)?;
+            match tasks.last_mut() {
+                Some((last, task_rows)) if *last == task => task_rows.extend(rows),
+                _ => tasks.push((task, rows)),
+            }
+            Ok(())
+        })?;
+        let mut tasks: Vec<_> = states.into_iter().flatten().collect();
+        tasks.sort_by_key(|(task, _)| *task);
+        Ok(tasks.into_iter().flat_map(|(_, rows)| rows).collect())
+    }
+
+    // Call visit with every block group in scan order on the calling thread, e.g. to write the rows
+    // out as they're read rather than collecting them. The scan stops at the end of the task in which
+    // visit returns false.
+    pub fn for_each_batch<V>(&self, mut visit: V) -> Result<()>
+    where
+        V: FnMut(Batch) -> Result<bool>,
+    {
+        let mut reader = None;
+        let mut more = true;
+        for (part, blocks) in &self.tasks {
+            self.run_task(&mut reader, *part, blocks.clone(), |batch| {
+                if more {
+                    more = visit(batch)?;
+                }
+                Ok(())
+            })?;
+            if !more {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // The block groups with matching rows one at a time, in scan order, for callers that pull rows
+    // rather than have them pushed (e.g. an iterator of Arrow batches)
+    #[cfg(feature = "arrow")]
+    pub fn into_batches(self) -> impl Iterator<Item = Result<Batch>> + 'a {
+        let blocks: Vec<(usize, usize)> =
+            self.tasks.iter().flat_map(|(part, blocks)| blocks.clone().map(move |block| (*part, block))).collect();
+        let mut reader = None;
+        blocks.into_iter().filter_map(move |(part, block)| {
+            let mut found = None;
+            let result = self.run_task(&mut reader, part, block..block + 1, |batch| {
+                found = Some(batch);
+                Ok(())
+            });
+            match result {
+                Ok(()) => found.filter(|batch| batch.selection.count_ones() > 0).map(Ok),
+                Err(e) => Some(Err(e)),
+            }
+        })
+    }
+
+    // Each thread aggregates the blocks it reads into its own partial groups, which are merged at
+    // the end
+    pub fn aggregate(&self, max_threads: usize, aggregation: &Aggregation) -> Result<Vec<Vec<DValue>>> {
+        let mut states = self.run(max_threads, Groups::new, |groups, _, batch| {
+            aggregation.add_batch(groups, &self.table.columns, &batch)
+        })?;
+        let mut groups = states.pop().unwrap_or_default();
+        for other in states {
+            aggregation.merge_groups(&mut groups, other)?;
+        }
+        Ok(aggregation.finish(groups))
+    }
+
+    fn run_task(
+        &self,
+        reader: &mut Option<(usize, PartReader<'a>)>,
+        part: usize,
+        blocks: Range<usize>,
+        mut visit: impl FnMut(Batch) -> Result<()>,
+    ) -> Result<()> {
+        // consecutive tasks are usually from the same part, so keep its reader open
+        if reader.as_ref().map(|(open_part, _)| *open_part) != Some(part) {
+            let mut part_reader = PartReader::from_files(self.table, self.parts[part].clone()).with_cache(self.cache);
+            if let Some(stats) = self.stats {
+                part_reader = part_reader.with_stats(stats);
+            }
+            *reader = Some((part, part_reader));
+        }
+        let reader = &mut reader.as_mut().unwrap().1;
+        for block in blocks {
+            // skip blocks where the indexes show no row can match
+            if self.filter.is_some_and(|filter| !filter.may_match(&reader.block_ranges(block))) {
+                if let Some(stats) = self.stats {
+                    stats.blocks_skipped.fetch_add(1, Ordering::Relaxed);
+                }
+                continue;
+            }
+            let start = Instant::now();
+            let mut batch = reader.read_batch(block)?;
+            let read = Instant::now();
+            let n_rows = batch.selection.count_ones();
+            if let Some(filter) = self.filter {
+                filter.filter_batch(&self.table.columns, &mut batch)?;
+            }
+            let filtered = Instant::now();
+            let n_matched = batch.selection.count_ones();
+            visit(batch)?;
+            if let Some(stats) = self.stats {
+                stats.blocks_read.fetch_add(1, Ordering::Relaxed);
+                stats.rows_read.fetch_add(n_rows as u64, Ordering::Relaxed);
+                stats.rows_matched.fetch_add(n_matched as u64, Ordering::Relaxed);
+                QueryStats::add_time(&stats.read_nanos, read - start);
+                QueryStats::add_time(&stats.filter_nanos, filtered - read);
+                QueryStats::add_time(&stats.process_nanos, filtered.elapsed());
+            }
+        }
+        Ok(())
+    }
+}
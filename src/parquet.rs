@@ -0,0 +1,108 @@
+use std::io::Write;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::arrow::ArrowWriter;
+use parquet::file::properties::{EnabledStatistics, WriterProperties};
+use parquet::file::reader::ChunkReader;
+
+use crate::arrow::{record_batch_columns, result_schema, result_to_record_batch, scan_arrow, table_from_arrow_schema, to_arrow_schema};
+use crate::expr::Expr;
+use crate::import::IMPORT_BATCH_ROWS;
+use crate::metadata::TableMetaData;
+use crate::query::{plan_select, QueryResult};
+use crate::sql::{parse, Statement};
+use crate::storage::ROWS_PER_BLOCK;
+use crate::DB;
+
+// Parquet import and export, behind the parquet feature. Files are read and written through Arrow,
+// so the types map the same way as in arrow.rs: Parquet strings are Strings, and integers,
+// timestamps, dates and booleans are UInt64s. Exports limit data pages to a block's worth of rows and
+// compute page statistics, which go in the Parquet column index, where readers use them to skip
+// pages the same way scans skip blocks. Pages aren't closed at block boundaries though: the writer
+// only splits pages by row count, so after a partly full block or deleted rows a page can hold rows
+// from two blocks.
+
+// Row groups are made of this many blocks
+const ROW_GROUP_BLOCKS: usize = 128;
+
+// A table with a column for each column of the file
+pub fn table_from_parquet<R: ChunkReader + 'static>(name: &str, reader: R) -> Result<TableMetaData> {
+    let builder = ParquetRecordBatchReaderBuilder::try_new(reader)?;
+    table_from_arrow_schema(name, builder.schema())
+}
+
+// Import a file a batch of whole blocks at a time, returning the number of rows written. Columns are
+// matched by name, and columns left out of the file and null values get their default.
+pub fn import_parquet<R: ChunkReader + 'static>(db: &DB, table: &TableMetaData, reader: R) -> Result<u64> {
+    let reader = ParquetRecordBatchReaderBuilder::try_new(reader)?.with_batch_size(IMPORT_BATCH_ROWS).build()?;
+    let mut n_written = 0;
+    for batch in reader {
+        let batch = batch?;
+        let columns = record_batch_columns(table, &batch).with_context(|| format!("Failed to import the rows after row {}", n_written))?;
+        db.write_batch(&table.name, &columns)?;
+        n_written += batch.num_rows() as u64;
+    }
+    Ok(n_written)
+}
+
+fn writer_properties() -> WriterProperties {
+    WriterProperties::builder()
+        .set_statistics_enabled(EnabledStatistics::Page)
+        .set_write_batch_size(ROWS_PER_BLOCK)
+        .set_data_page_row_count_limit(ROWS_PER_BLOCK)
+        .set_max_row_group_size(ROWS_PER_BLOCK * ROW_GROUP_BLOCKS)
+        .build()
+}
+
+// Write the rows of a table that match the filter as they're scanned, returning how many were written
+pub fn export_table_parquet<W: Write + Send>(db: &DB, table_name: &str, filter: Option<&Expr>, out: W) -> Result<u64> {
+    let table = db.tables.iter().find(|table| table.name == table_name).ok_or(anyhow!("Table {} doesn't exist", table_name))?;
+    let mut writer = ArrowWriter::try_new(out, Arc::new(to_arrow_schema(table)), Some(writer_properties()))?;
+    let mut n_written = 0;
+    for batch in scan_arrow(db, table_name, filter)? {
+        let batch = batch?;
+        writer.write(&batch)?;
+        n_written += batch.num_rows() as u64;
+    }
+    writer.close()?;
+    Ok(n_written)
+}
+
+// Write the result of a statement that doesn't change the database. A SELECT's rows are written a
+// block at a time as they're worked out, see SelectPlan::for_each_row.
+pub fn export_parquet<W: Write + Send>(db: &DB, sql: &str, out: W) -> Result<u64> {
+    let Statement::Select(select) = parse(sql)? else {
+        let result = db.query(sql)?;
+        let mut writer = ArrowWriter::try_new(out, Arc::new(result_schema(&result.columns)), Some(writer_properties()))?;
+        writer.write(&result_to_record_batch(&result)?)?;
+        writer.close()?;
+        return Ok(result.rows.len() as u64);
+    };
+    let table = db.tables.iter().find(|table| table.name == select.from).ok_or(anyhow!("Table {} doesn't exist", select.from))?;
+    let plan = plan_select(&select, table)?;
+    let mut writer = ArrowWriter::try_new(out, Arc::new(result_schema(&plan.columns)), Some(writer_properties()))?;
+    let mut block = QueryResult { columns: plan.columns.clone(), rows: Vec::with_capacity(ROWS_PER_BLOCK) };
+    let mut n_written = 0;
+    plan.for_each_row(db, |row| {
+        block.rows.push(row);
+        if block.rows.len() == ROWS_PER_BLOCK {
+            n_written += write_rows(&mut writer, &mut block)?;
+        }
+        Ok(())
+    })?;
+    n_written += write_rows(&mut writer, &mut block)?;
+    writer.close()?;
+    Ok(n_written)
+}
+
+// Write the rows and clear them, returning how many there were
+fn write_rows<W: Write + Send>(writer: &mut ArrowWriter<W>, result: &mut QueryResult) -> Result<u64> {
+    if !result.rows.is_empty() {
+        writer.write(&result_to_record_batch(result)?)?;
+    }
+    let n_rows = result.rows.len() as u64;
+    result.rows.clear();
+    Ok(n_rows)
+}
@@ -0,0 +1,362 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::cache::{FileStamps, PartCache};
+use crate::column::{Batch, Bitmap};
+use crate::data::{get_max, get_min};
+use crate::expr::{Expr, Ranges, TableRow};
+use crate::format::replace_file;
+use crate::metadata::TableMetaData;
+use crate::storage::{create_table_files, index_path, remove_table_files, write_batch, write_data};
+use crate::DValue;
+
+// Partitioned tables keep the column files for each partition in their own directory:
+//   <root>/<table>/partitions/<partition>/<table>.<column>.data
+// Detached partitions are moved to <root>/<table>/detached/<partition>, where they are ignored.
+//
+// Each partition directory also has a partition.json, with the value of the partition expression
+// and the range of the columns the expression uses. This lets queries skip whole partitions.
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PartitionInfo {
+    pub value: DValue,
+    pub min: HashMap<String, DValue>,
+    pub max: HashMap<String, DValue>,
+}
+
+#[derive(Debug)]
+pub struct Partition {
+    pub info: PartitionInfo,
+    pub path: PathBuf,
+}
+
+struct PartitionRanges<'a> {
+    partition_by: &'a Expr,
+    info: &'a PartitionInfo,
+}
+
+impl<'a> Ranges for PartitionRanges<'a> {
+    fn range(&self, expr: &Expr) -> Option<(DValue, DValue)> {
+        if expr == self.partition_by {
+            return Some((self.info.value.clone(), self.info.value.clone()));
+        }
+        match expr {
+            Expr::Column(name) => Some((self.info.min.get(name)?.clone(), self.info.max.get(name)?.clone())),
+            _ => None,
+        }
+    }
+}
+
+fn table_path(root_path: &Path, table_name: &str) -> PathBuf {
+    root_path.join(table_name)
+}
+
+fn partitions_path(root_path: &Path, table_name: &str) -> PathBuf {
+    table_path(root_path, table_name).join("partitions")
+}
+
+fn detached_path(root_path: &Path, table_name: &str) -> PathBuf {
+    table_path(root_path, table_name).join("detached")
+}
+
+fn info_path(partition_path: &Path) -> PathBuf {
+    partition_path.join("partition.json")
+}
+
+// Partition values are used as directory names, so anything other than [A-Za-z0-9_-] is escaped
+pub fn partition_dir_name(value: &DValue) -> String {
+    match value {
+        DValue::Uint64(u) => u.to_string(),
+        // can't be produced by escaping a non-empty string, as % is always escaped
+        DValue::String(s) if s.is_empty() => "%".to_string(),
+        DValue::String(s) => s
+            .bytes()
+            .map(|b| {
+                if b.is_ascii_alphanumeric() || b == b'-' || b == b'_' {
+                    (b as char).to_string()
+                } else {
+                    format!("%{:02X}", b)
+                }
+            })
+            .collect(),
+    }
+}
+
+fn load_info(partition_path: &Path) -> Result<Option<PartitionInfo>> {
+    let path = info_path(partition_path);
+    match fs::read_to_string(&path) {
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        result => {
+            let contents = result.with_context(|| format!("Failed to read file: {}", path.to_string_lossy()))?;
+            let info = serde_json::from_str(&contents)
+                .with_context(|| format!("Failed to parse JSON file: {}", path.to_string_lossy()))?;
+            Ok(Some(info))
+        }
+    }
+}
+
+fn save_info(partition_path: &Path, info: &PartitionInfo) -> Result<()> {
+    let contents = serde_json::to_string_pretty(info).unwrap();
+    replace_file(&info_path(partition_path), contents.as_bytes())
+}
+
+// All the attached partitions of a table, in order of partition value
+pub fn list_partitions(root_path: &Path, table: &TableMetaData) -> Result<Vec<Partition>> {
+    Ok(PartitionList::load(root_path, table)?.partitions)
+}
+
+// A table's partitions, along with the stamps of the partitions directory and of partition.json in
+// each directory in it, so DB's PartCache can tell when a partition has been added, removed or
+// widened
+pub(crate) struct PartitionList {
+    partitions: Vec<Partition>,
+    stamps: FileStamps,
+}
+
+impl PartitionList {
+    pub(crate) fn load(root_path: &Path, table: &TableMetaData) -> Result<PartitionList> {
+        let dir = partitions_path(root_path, &table.name);
+        let mut stamps = FileStamps::default();
+        stamps.add(dir.clone());
+        let entries = match fs::read_dir(&dir) {
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(PartitionList { partitions: vec![], stamps }),
+            result => result.with_context(|| format!("Couldn't list {}", dir.to_string_lossy()))?,
+        };
+        let mut partitions = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            // a directory without partition.json yet is stamped too, so the partition is seen once it has one
+            stamps.add(info_path(&path));
+            if let Some(info) = load_info(&path)? {
+                partitions.push(Partition { info, path });
+            }
+        }
+        partitions.sort_by(|a, b| a.info.value.cmp(&b.info.value));
+        Ok(PartitionList { partitions, stamps })
+    }
+
+    pub(crate) fn is_current(&self) -> bool {
+        self.stamps.are_current()
+    }
+}
+
+// The directories holding the table's column files, one per partition for partitioned tables
+pub fn part_paths(root_path: &Path, table: &TableMetaData) -> Result<Vec<PathBuf>> {
+    pruned_part_paths(root_path, table, None)
+}
+
+// The directories of the table's detached partitions
+pub fn detached_part_paths(root_path: &Path, table: &TableMetaData) -> Result<Vec<PathBuf>> {
+    let dir = detached_path(root_path, &table.name);
+    let entries = match fs::read_dir(&dir) {
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        result => result.with_context(|| format!("Couldn't list {}", dir.to_string_lossy()))?,
+    };
+    entries.map(|entry| Ok(entry?.path())).collect()
+}
+
+// Like part_paths, but leaving out any partitions that can't contain rows matching the filter
+pub fn pruned_part_paths(root_path: &Path, table: &TableMetaData, filter: Option<&Expr>) -> Result<Vec<PathBuf>> {
+    match &table.partition_by {
+        Some(partition_by) => Ok(prune(&list_partitions(root_path, table)?, partition_by, filter)),
+        None => Ok(vec![root_path.to_path_buf()]),
+    }
+}
+
+// Like pruned_part_paths, listing the partitions through the cache
+pub(crate) fn pruned_part_paths_cached(
+    root_path: &Path,
+    table: &TableMetaData,
+    filter: Option<&Expr>,
+    parts: &PartCache,
+) -> Result<Vec<PathBuf>> {
+    match &table.partition_by {
+        Some(partition_by) => Ok(prune(&parts.get_or_list_partitions(root_path, table)?.partitions, partition_by, filter)),
+        None => Ok(vec![root_path.to_path_buf()]),
+    }
+}
+
+fn prune(partitions: &[Partition], partition_by: &Expr, filter: Option<&Expr>) -> Vec<PathBuf> {
+    partitions
+        .iter()
+        .filter(|partition| {
+            filter.is_none_or(|filter| filter.may_match(&PartitionRanges { partition_by, info: &partition.info }))
+        })
+        .map(|partition| partition.path.clone())
+        .collect()
+}
+
+pub fn write_partitioned(root_path: &Path, table: &TableMetaData, partition_by: &Expr, rows: &[Vec<DValue>]) -> Result<()> {
+    let mut partitions: BTreeMap<DValue, Vec<Vec<DValue>>> = BTreeMap::new();
+    for row in rows {
+        let value = partition_by.eval(&TableRow { columns: &table.columns, values: row })?;
+        partitions.entry(value).or_default().push(row.clone());
+    }
+
+    for (value, rows) in partitions {
+        let path = widen_partition(root_path, table, partition_by, value, |index| rows.iter().map(|row| row[index].clone()).collect())?;
+        write_data(&path, table, &rows)?;
+    }
+    Ok(())
+}
+
+// Like write_partitioned, for the selected rows of a batch. Each partition's rows are written
+// straight from the batch's column vectors by selecting just them.
+pub fn write_partitioned_batch(root_path: &Path, table: &TableMetaData, partition_by: &Expr, batch: &Batch) -> Result<()> {
+    if batch.selection.count_ones() == 0 {
+        return Ok(());
+    }
+    let values = partition_by.eval_batch(&table.columns, batch)?;
+    let mut partitions: BTreeMap<DValue, Bitmap> = BTreeMap::new();
+    for i in batch.selection.ones() {
+        partitions.entry(values.get(i)).or_insert_with(|| Bitmap::new(batch.selection.len(), false)).set(i, true);
+    }
+
+    for (value, selection) in partitions {
+        let rows = Batch { columns: batch.columns.clone(), selection };
+        let path = widen_partition(root_path, table, partition_by, value, |index| {
+            rows.selection.ones().map(|i| rows.columns[index].get(i)).collect()
+        })?;
+        write_batch(&path, table, &rows)?;
+    }
+    Ok(())
+}
+
+// Create a partition's directory if it's new, and widen the ranges in its partition.json to take in
+// the values about to be written, given by key_values for the index of each column the partition
+// expression uses. The ranges are widened before the rows are written, so a partial write can't lead
+// to rows being skipped.
+fn widen_partition(
+    root_path: &Path,
+    table: &TableMetaData,
+    partition_by: &Expr,
+    value: DValue,
+    key_values: impl Fn(usize) -> Vec<DValue>,
+) -> Result<PathBuf> {
+    let path = partitions_path(root_path, &table.name).join(partition_dir_name(&value));
+    fs::create_dir_all(&path).with_context(|| format!("Couldn't create {}", path.to_string_lossy()))?;
+
+    let mut info = load_info(&path)?.unwrap_or(PartitionInfo {
+        value,
+        min: HashMap::new(),
+        max: HashMap::new(),
+    });
+    for name in partition_by.columns() {
+        let index = table.columns.iter().position(|col| &col.name == name)
+            .ok_or(anyhow!("No column {} in table {}", name, table.name))?;
+        for value in &key_values(index) {
+            let min = info.min.get(name).map_or(value, |min| get_min(min, value)).clone();
+            let max = info.max.get(name).map_or(value, |max| get_max(max, value)).clone();
+            info.min.insert(name.to_string(), min);
+            info.max.insert(name.to_string(), max);
+        }
+    }
+    save_info(&path, &info)?;
+    Ok(path)
+}
+
+pub fn create_table(root_path: &Path, table: &TableMetaData) -> Result<()> {
+    match table.partition_by {
+        // partition directories are created when rows are first written to them
+        Some(_) => fs::create_dir_all(partitions_path(root_path, &table.name))
+            .with_context(|| format!("Couldn't create directory for table {}", table.name)),
+        None => create_table_files(root_path, table),
+    }
+}
+
+pub fn remove_table(root_path: &Path, table: &TableMetaData) -> Result<()> {
+    match table.partition_by {
+        Some(_) => remove_dir_if_exists(&table_path(root_path, &table.name)),
+        None => remove_table_files(root_path, table),
+    }
+}
+
+// Remove all the attached partitions, but leave the detached ones
+pub fn truncate_table(root_path: &Path, table: &TableMetaData) -> Result<()> {
+    match table.partition_by {
+        Some(_) => {
+            remove_dir_if_exists(&partitions_path(root_path, &table.name))?;
+            create_table(root_path, table)
+        }
+        None => {
+            remove_table_files(root_path, table)?;
+            create_table_files(root_path, table)
+        }
+    }
+}
+
+fn remove_dir_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_dir_all(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("Couldn't remove {}", path.to_string_lossy()))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn check_partitioned(table: &TableMetaData) -> Result<()> {
+    if table.partition_by.is_none() {
+        return Err(anyhow!("Table {} is not partitioned", table.name));
+    }
+    Ok(())
+}
+
+pub fn drop_partition(root_path: &Path, table: &TableMetaData, value: &DValue) -> Result<()> {
+    check_partitioned(table)?;
+    let path = partitions_path(root_path, &table.name).join(partition_dir_name(value));
+    if !path.exists() {
+        return Err(anyhow!("No partition {:?} in table {}", value, table.name));
+    }
+    fs::remove_dir_all(&path).with_context(|| format!("Couldn't remove {}", path.to_string_lossy()))
+}
+
+pub fn detach_partition(root_path: &Path, table: &TableMetaData, value: &DValue) -> Result<()> {
+    check_partitioned(table)?;
+    let dir_name = partition_dir_name(value);
+    let from = partitions_path(root_path, &table.name).join(&dir_name);
+    let to = detached_path(root_path, &table.name).join(&dir_name);
+    if !from.exists() {
+        return Err(anyhow!("No partition {:?} in table {}", value, table.name));
+    }
+    if to.exists() {
+        return Err(anyhow!("Partition {:?} of table {} is already detached", value, table.name));
+    }
+    fs::create_dir_all(detached_path(root_path, &table.name))?;
+    fs::rename(&from, &to).with_context(|| format!("Couldn't detach {}", from.to_string_lossy()))
+}
+
+pub fn attach_partition(root_path: &Path, table: &TableMetaData, value: &DValue) -> Result<()> {
+    check_partitioned(table)?;
+    let dir_name = partition_dir_name(value);
+    let from = detached_path(root_path, &table.name).join(&dir_name);
+    let to = partitions_path(root_path, &table.name).join(&dir_name);
+    if !from.exists() {
+        return Err(anyhow!("No detached partition {:?} in table {}", value, table.name));
+    }
+    if to.exists() {
+        return Err(anyhow!("Partition {:?} of table {} already exists", value, table.name));
+    }
+    // the schema could have changed while the partition was detached
+    if let Some(col) = table.columns.iter().find(|col| !index_path(&from, &table.name, &col.name).exists()) {
+        return Err(anyhow!("Detached partition {:?} has no files for column {}", value, col.name));
+    }
+    fs::rename(&from, &to).with_context(|| format!("Couldn't attach {}", from.to_string_lossy()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partition_dir_name() {
+        assert_eq!(partition_dir_name(&DValue::Uint64(202401)), "202401");
+        assert_eq!(partition_dir_name(&DValue::String("team-1_a".to_string())), "team-1_a");
+        assert_eq!(partition_dir_name(&DValue::String("../a%".to_string())), "%2E%2E%2Fa%25");
+        assert_eq!(partition_dir_name(&DValue::String("".to_string())), "%");
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::prelude::*;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::format::{check_header, header, FileKind, HEADER_SIZE};
+use crate::metadata::TableMetaData;
+use crate::storage::{read_dvalue_data, write_dvalue_data};
+use crate::DValue;
+
+// Rows are sorted by the table's ORDER BY columns as they are written, so each block holds a range
+// of sort keys. The primary index of a part is a <table>.primary file holding, for each block, the
+// PRIMARY KEY (a prefix of the ORDER BY columns) of the block's first and last rows. It's small
+// enough to keep in memory, and gives the range of the key columns in each block.
+//
+// After the header, the file is a list of entries, each being the block number as 8 bytes big
+// endian, then the length of the rest of the entry as 4 bytes big endian, then the first row's key
+// values followed by the last row's.
+
+pub(crate) fn primary_index_path(root_path: &Path, table_name: &str) -> PathBuf {
+    root_path.join(format!("{}.primary", table_name))
+}
+
+// The first and last key of a block
+pub(crate) type KeyRange = (Vec<DValue>, Vec<DValue>);
+
+#[derive(Debug, Default)]
+pub struct PrimaryIndex {
+    columns: Vec<String>,
+    blocks: HashMap<usize, KeyRange>,
+}
+
+impl PrimaryIndex {
+    pub fn load(root_path: &Path, table: &TableMetaData) -> Result<PrimaryIndex> {
+        let columns = table.primary_key_columns().to_vec();
+        let path = primary_index_path(root_path, &table.name);
+        let bytes = match fs::read(&path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(PrimaryIndex { columns, blocks: HashMap::new() }),
+            result => result.with_context(|| format!("Couldn't read {}", path.to_string_lossy()))?,
+        };
+        check_header(&bytes, FileKind::Primary, &path)?;
+
+        let dtypes: Vec<_> = columns.iter().map(|name| &table.get_column(name).unwrap().dtype).collect();
+        let mut blocks = HashMap::new();
+        let mut rest = &bytes[HEADER_SIZE as usize..];
+        // stop at a partly written entry at the end of the file
+        while rest.len() >= 12 {
+            let block = u64::from_be_bytes(rest[0..8].try_into().unwrap()) as usize;
+            let len = u32::from_be_bytes(rest[8..12].try_into().unwrap()) as usize;
+            if rest.len() < 12 + len {
+                break;
+            }
+            let mut values = &rest[12..12 + len];
+            let mut read_key = || {
+                dtypes
+                    .iter()
+                    .map(|dtype| {
+                        let (n_bytes, value) = read_dvalue_data(values, dtype);
+                        values = &values[n_bytes..];
+                        value
+                    })
+                    .collect::<Vec<DValue>>()
+            };
+            let first = read_key();
+            let last = read_key();
+            blocks.insert(block, (first, last));
+            rest = &rest[12 + len..];
+        }
+        Ok(PrimaryIndex { columns, blocks })
+    }
+
+    // The range of a key column in a block. This is only known for a column if every key column
+    // before it has the same value in the first and last rows, as the rows in between are only
+    // sorted by the later columns where the earlier ones are equal.
+    pub fn range(&self, block: usize, column_name: &str) -> Option<(DValue, DValue)> {
+        let position = self.columns.iter().position(|name| name == column_name)?;
+        let (first, last) = self.blocks.get(&block)?;
+        if first[..position] != last[..position] {
+            return None;
+        }
+        Some((first[position].clone(), last[position].clone()))
+    }
+
+    // Whether the blocks are in key order, i.e. the whole part is sorted and not just each block
+    pub fn is_sorted(&self, n_blocks: usize) -> bool {
+        (1..n_blocks).all(|block| match (self.blocks.get(&(block - 1)), self.blocks.get(&block)) {
+            (Some((_, previous_last)), Some((first, _))) => previous_last <= first,
+            _ => false,
+        })
+    }
+}
+
+// Append entries for newly written blocks
+pub(crate) fn append_entries(root_path: &Path, table_name: &str, entries: &[(usize, KeyRange)]) -> Result<()> {
+    let path = primary_index_path(root_path, table_name);
+    let mut file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(&path)
+        .with_context(|| format!("Couldn't open {}", path.to_string_lossy()))?;
+
+    let mut bytes = Vec::new();
+    if file.metadata()?.len() == 0 {
+        bytes.extend_from_slice(&header(FileKind::Primary));
+    }
+    for (block, (first, last)) in entries {
+        let mut values = Vec::new();
+        for value in first.iter().chain(last) {
+            write_dvalue_data(&mut values, value);
+        }
+        bytes.extend_from_slice(&(*block as u64).to_be_bytes());
+        bytes.extend_from_slice(&(values.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&values);
+    }
+    file.write_all(&bytes).with_context(|| format!("Couldn't write {}", path.to_string_lossy()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_range() {
+        let key = |a: &str, b: u64| vec![DValue::String(a.to_string()), DValue::Uint64(b)];
+        let index = PrimaryIndex {
+            columns: vec!["a".to_string(), "b".to_string()],
+            blocks: HashMap::from([(0, (key("x", 5), key("x", 9))), (1, (key("x", 10), key("y", 2)))]),
+        };
+        assert_eq!(index.range(0, "b"), Some((DValue::Uint64(5), DValue::Uint64(9))));
+        assert_eq!(index.range(1, "a"), Some((DValue::String("x".to_string()), DValue::String("y".to_string()))));
+        assert_eq!(index.range(1, "b"), None);
+        assert_eq!(index.range(2, "a"), None);
+        assert_eq!(index.range(0, "c"), None);
+        assert!(index.is_sorted(2));
+        assert!(!index.is_sorted(3));
+    }
+}
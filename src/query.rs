@@ -0,0 +1,627 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::time::Instant;
+
+use anyhow::{anyhow, Result};
+
+use crate::aggregate::{AggregateFunction, Aggregation};
+use crate::column::Batch;
+use crate::explain::QueryStats;
+use crate::expr::{BinOp, Expr, Function, TableRow};
+use crate::metadata::{ColumnMetaData, TableMetaData};
+use crate::parallel::ParallelScan;
+use crate::sql::{CreateTable, Insert, Select, SelectItem, SqlExpr};
+use crate::{get_dtype, DType, DValue, DB};
+
+// A SELECT is bound against the table's metadata into a plan made of the operators the rest of the
+// crate already has: a parallel scan with a filter (which can skip partitions and blocks), an
+// optional Aggregation, then the HAVING filter, the output expressions, the sort and the limit,
+// which run on the (usually few) rows that are left.
+
+// The result of a query: the name and type of each column, and the rows. Statements that don't
+// return anything (e.g. CREATE TABLE) have no columns.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueryResult {
+    pub columns: Vec<(String, DType)>,
+    pub rows: Vec<Vec<DValue>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelectPlan {
+    pub table: String,
+    pub filter: Option<Expr>,
+    pub aggregation: Option<Aggregation>,
+    // evaluated against the rows of the aggregation, whose columns are named by aggregate_column
+    pub having: Option<Expr>,
+    // the result columns followed by any ORDER BY keys that aren't result columns, evaluated
+    // against the table's rows, or the aggregation's rows if there is one
+    pub outputs: Vec<Expr>,
+    pub columns: Vec<(String, DType)>,
+    // the index into outputs of each sort key, and whether it is descending
+    pub order_by: Vec<(usize, bool)>,
+    pub limit: Option<usize>,
+    pub offset: usize,
+    pub max_threads: usize,
+}
+
+// The columns of the aggregation's rows: the GROUP BY values then the aggregates
+fn aggregate_column(kind: &str, i: usize) -> String {
+    format!("#{}{}", kind, i)
+}
+
+// The table a CREATE TABLE describes, defaults and the partition key can use any of its columns
+pub fn table_from_create(create: &CreateTable) -> Result<TableMetaData> {
+    let columns = create.columns.iter().map(|col| ColumnMetaData::new(&col.name, col.dtype.clone())).collect();
+    let mut table = TableMetaData::new(&create.name, columns);
+    for (i, col) in create.columns.iter().enumerate() {
+        if let Some(default) = &col.default {
+            let default = bind_scalar(default, &table)?;
+            table.columns[i] = table.columns[i].clone().with_default(default);
+        }
+    }
+    if let Some(partition_by) = &create.partition_by {
+        let partition_by = bind_scalar(partition_by, &table)?;
+        table = table.with_partition_by(partition_by);
+    }
+    let order_by: Vec<&str> = create.order_by.iter().map(String::as_str).collect();
+    let primary_key: Vec<&str> = create.primary_key.iter().map(String::as_str).collect();
+    Ok(table.with_order_by(&order_by).with_primary_key(&primary_key))
+}
+
+// The columns an INSERT writes and the rows of values, which must be constant expressions
+pub fn insert_rows(insert: &Insert, table: &TableMetaData) -> Result<(Vec<String>, Vec<Vec<DValue>>)> {
+    let column_names = match &insert.columns {
+        Some(names) => names.clone(),
+        None => table.columns.iter().map(|col| col.name.clone()).collect(),
+    };
+    let columns = column_names
+        .iter()
+        .map(|name| table.get_column(name).ok_or(anyhow!("Unknown column {} in table {}", name, table.name)))
+        .collect::<Result<Vec<&ColumnMetaData>>>()?;
+    // bound against a table without columns, so any column is an error
+    let constants = TableMetaData::new(&table.name, vec![]);
+    let mut rows = Vec::with_capacity(insert.rows.len());
+    for (i, values) in insert.rows.iter().enumerate() {
+        if values.len() != columns.len() {
+            return Err(anyhow!("Row {} has {} values, expected {}", i + 1, values.len(), columns.len()));
+        }
+        let row = values
+            .iter()
+            .zip(&columns)
+            .map(|(value, col)| {
+                let value = bind_scalar(value, &constants)
+                    .map_err(|e| anyhow!("Values must be constant: {}", e))?
+                    .eval(&HashMap::new())?;
+                if get_dtype(&value) != col.dtype {
+                    return Err(anyhow!("Column {} is a {:?}, got {:?}", col.name, col.dtype, value));
+                }
+                Ok(value)
+            })
+            .collect::<Result<Vec<DValue>>>()
+            .map_err(|e| anyhow!("Row {}: {}", i + 1, e))?;
+        rows.push(row);
+    }
+    Ok((column_names, rows))
+}
+
+// SHOW TABLES
+pub fn show_tables(tables: &[TableMetaData]) -> QueryResult {
+    QueryResult {
+        columns: vec![("name".to_string(), DType::String)],
+        rows: tables.iter().map(|table| vec![DValue::String(table.name.clone())]).collect(),
+    }
+}
+
+// DESCRIBE, the name and type of each column
+pub fn describe(table: &TableMetaData) -> QueryResult {
+    let type_name = |dtype: &DType| match dtype {
+        DType::String => "String",
+        DType::Uint64 => "UInt64",
+    };
+    QueryResult {
+        columns: vec![("name".to_string(), DType::String), ("type".to_string(), DType::String)],
+        rows: table
+            .columns
+            .iter()
+            .map(|col| vec![DValue::String(col.name.clone()), DValue::String(type_name(&col.dtype).to_string())])
+            .collect(),
+    }
+}
+
+pub fn plan_select(select: &Select, table: &TableMetaData) -> Result<SelectPlan> {
+    // the result columns, with their names
+    let mut items: Vec<(SqlExpr, String)> = vec![];
+    for item in &select.items {
+        match item {
+            SelectItem::Wildcard => items.extend(table.columns.iter().map(|col| (SqlExpr::Column(col.name.clone()), col.name.clone()))),
+            SelectItem::Expr { expr, alias, text } => items.push((expr.clone(), alias.clone().unwrap_or(text.clone()))),
+        }
+    }
+
+    let filter = select.filter.as_ref().map(|filter| bind_scalar(filter, table)).transpose()?;
+    if let Some(filter) = &filter {
+        check_boolean(filter, &table.columns, "WHERE")?;
+    }
+
+    // GROUP BY can refer to the result columns by position or alias
+    let group_by = select
+        .group_by
+        .iter()
+        .map(|expr| bind_scalar(resolve_item(expr, &items, table)?.unwrap_or(expr), table))
+        .collect::<Result<Vec<Expr>>>()?;
+    let has_aggregates = items.iter().map(|(expr, _)| expr)
+        .chain(&select.having)
+        .chain(select.order_by.iter().map(|item| &item.expr))
+        .any(contains_aggregate);
+
+    let is_aggregate = has_aggregates || !group_by.is_empty();
+    let mut binder = if is_aggregate {
+        Some(AggregateBinder { table, group_by, aggregates: vec![] })
+    } else {
+        None
+    };
+    let mut bind = |expr: &SqlExpr| match binder.as_mut() {
+        Some(binder) => binder.bind(expr),
+        None => bind_scalar(expr, table),
+    };
+
+    let mut outputs = items.iter().map(|(expr, _)| bind(expr)).collect::<Result<Vec<Expr>>>()?;
+    let mut order_by = vec![];
+    for item in &select.order_by {
+        let index = match resolve_item(&item.expr, &items, table)? {
+            Some(expr) => items.iter().position(|(item, _)| item == expr).unwrap(),
+            None => {
+                let expr = bind(&item.expr)?;
+                match outputs.iter().position(|output| *output == expr) {
+                    Some(index) => index,
+                    None => {
+                        outputs.push(expr);
+                        outputs.len() - 1
+                    }
+                }
+            }
+        };
+        order_by.push((index, item.descending));
+    }
+    let having = match &select.having {
+        Some(having) if is_aggregate => Some(bind(having)?),
+        Some(_) => return Err(anyhow!("HAVING needs GROUP BY or an aggregate")),
+        None => None,
+    };
+
+    let (aggregation, output_columns) = match binder {
+        Some(binder) => {
+            let columns = binder.columns()?;
+            (Some(Aggregation { group_by: binder.group_by, aggregates: binder.aggregates }), columns)
+        }
+        None => (None, table.columns.clone()),
+    };
+    if let Some(having) = &having {
+        check_boolean(having, &output_columns, "HAVING")?;
+    }
+    let columns = items
+        .iter()
+        .zip(&outputs)
+        .map(|((_, name), expr)| Ok((name.clone(), dtype_of(expr, &output_columns)?)))
+        .collect::<Result<Vec<(String, DType)>>>()?;
+    // the sort keys are checked too, so a bad one fails before anything is read
+    for expr in &outputs[columns.len()..] {
+        dtype_of(expr, &output_columns)?;
+    }
+
+    let mut max_threads = 0;
+    for (name, value) in &select.settings {
+        match name.to_lowercase().as_str() {
+            "max_threads" => max_threads = *value as usize,
+            _ => return Err(anyhow!("Unknown setting {}", name)),
+        }
+    }
+
+    Ok(SelectPlan {
+        table: table.name.clone(),
+        filter,
+        aggregation,
+        having,
+        outputs,
+        columns,
+        order_by,
+        limit: select.limit.map(|limit| limit as usize),
+        offset: select.offset.unwrap_or(0) as usize,
+        max_threads,
+    })
+}
+
+impl SelectPlan {
+    pub fn execute(&self, db: &DB) -> Result<QueryResult> {
+        self.execute_with_stats(db, None)
+    }
+
+    // Execute the plan, recording what it reads and how long each stage takes if stats are given
+    pub fn execute_with_stats(&self, db: &DB, stats: Option<&QueryStats>) -> Result<QueryResult> {
+        let table = db.tables.iter().find(|table| table.name == self.table).ok_or(anyhow!("Table {} doesn't exist", self.table))?;
+        let filter = self.filter.as_ref();
+        let mut scan = ParallelScan::new(db, table, filter)?;
+        if let Some(stats) = stats {
+            scan = scan.with_stats(stats);
+        }
+        let stage = |name, start| {
+            if let Some(stats) = stats {
+                stats.add_stage(name, start);
+            }
+        };
+
+        let start = Instant::now();
+        let mut rows = match &self.aggregation {
+            // the outputs are computed a batch at a time as the blocks are scanned
+            None => {
+                let rows = scan.collect_rows(self.max_threads, |batch| self.project(table, batch))?;
+                stage("scan and project", start);
+                rows
+            }
+            Some(aggregation) => {
+                let columns = aggregation_columns(aggregation, table)?;
+                let groups = scan.aggregate(self.max_threads, aggregation)?;
+                stage("scan and aggregate", start);
+                let start = Instant::now();
+                let mut rows = vec![];
+                for values in groups {
+                    let row = TableRow { columns: &columns, values: &values };
+                    if let Some(having) = &self.having {
+                        if !having.matches(&row)? {
+                            continue;
+                        }
+                    }
+                    rows.push(self.outputs.iter().map(|expr| expr.eval(&row)).collect::<Result<Vec<DValue>>>()?);
+                }
+                stage("having and project", start);
+                rows
+            }
+        };
+
+        let start = Instant::now();
+        if !self.order_by.is_empty() {
+            rows.sort_by(|a, b| {
+                self.order_by
+                    .iter()
+                    .map(|&(index, descending)| match descending {
+                        true => b[index].cmp(&a[index]),
+                        false => a[index].cmp(&b[index]),
+                    })
+                    .find(|ordering| *ordering != Ordering::Equal)
+                    .unwrap_or(Ordering::Equal)
+            });
+            stage("sort", start);
+        }
+        let rows = rows
+            .into_iter()
+            .skip(self.offset)
+            .take(self.limit.unwrap_or(usize::MAX))
+            .map(|mut row| {
+                // drop the sort keys that aren't result columns
+                row.truncate(self.columns.len());
+                row
+            })
+            .collect();
+        Ok(QueryResult { columns: self.columns.clone(), rows })
+    }
+
+    // Call visit with each result row, in order. Without an aggregation or a sort, the rows are worked
+    // out a block group at a time on one thread as they're visited, so a result of any size can be
+    // written out in constant memory. Otherwise the whole result is worked out by execute first.
+    pub fn for_each_row(&self, db: &DB, mut visit: impl FnMut(Vec<DValue>) -> Result<()>) -> Result<()> {
+        if self.aggregation.is_some() || !self.order_by.is_empty() {
+            return self.execute(db)?.rows.into_iter().try_for_each(visit);
+        }
+        let table = db.tables.iter().find(|table| table.name == self.table).ok_or(anyhow!("Table {} doesn't exist", self.table))?;
+        let filter = self.filter.as_ref();
+        let scan = ParallelScan::new(db, table, filter)?;
+        let mut n_skip = self.offset;
+        let mut n_take = self.limit.unwrap_or(usize::MAX);
+        if n_take == 0 {
+            return Ok(());
+        }
+        scan.for_each_batch(|batch| {
+            let n_rows = batch.selection.count_ones();
+            if n_skip >= n_rows {
+                n_skip -= n_rows;
+                return Ok(true);
+            }
+            for row in self.project(table, &batch)?.into_iter().skip(n_skip).take(n_take) {
+                n_take -= 1;
+                visit(row)?;
+            }
+            n_skip = 0;
+            Ok(n_take > 0)
+        })
+    }
+
+    // The outputs of the selected rows of a batch, when there is no aggregation
+    fn project(&self, table: &TableMetaData, batch: &Batch) -> Result<Vec<Vec<DValue>>> {
+        if batch.selection.count_ones() == 0 {
+            return Ok(vec![]);
+        }
+        let values = self
+            .outputs
+            .iter()
+            .map(|expr| expr.eval_batch(&table.columns, batch))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(batch.selection.ones().map(|i| values.iter().map(|value| value.get(i)).collect()).collect())
+    }
+}
+
+// The ORDER BY or GROUP BY item that is a result column given by position (counting from 1) or by
+// alias, if it is one. Names of the table's columns take precedence over aliases.
+fn resolve_item<'a>(expr: &SqlExpr, items: &'a [(SqlExpr, String)], table: &TableMetaData) -> Result<Option<&'a SqlExpr>> {
+    match expr {
+        SqlExpr::Literal(DValue::Uint64(position)) => match items.get((*position as usize).wrapping_sub(1)) {
+            Some((expr, _)) => Ok(Some(expr)),
+            None => Err(anyhow!("Position {} is out of range, there are {} result columns", position, items.len())),
+        },
+        SqlExpr::Column(name) if table.get_column(name).is_none() => {
+            Ok(items.iter().find(|(_, alias)| alias == name).map(|(expr, _)| expr))
+        }
+        _ => Ok(None),
+    }
+}
+
+fn contains_aggregate(expr: &SqlExpr) -> bool {
+    match expr {
+        SqlExpr::Function(name, args) => aggregate_function(name).is_some() || args.iter().any(contains_aggregate),
+        SqlExpr::Binary(left, _, right) => contains_aggregate(left) || contains_aggregate(right),
+        SqlExpr::Not(expr) => contains_aggregate(expr),
+        SqlExpr::Literal(_) | SqlExpr::Column(_) | SqlExpr::Star => false,
+    }
+}
+
+fn aggregate_function(name: &str) -> Option<AggregateFunction> {
+    match name.to_lowercase().as_str() {
+        "count" => Some(AggregateFunction::Count),
+        "sum" => Some(AggregateFunction::Sum),
+        "min" => Some(AggregateFunction::Min),
+        "max" => Some(AggregateFunction::Max),
+        _ => None,
+    }
+}
+
+fn scalar_function(name: &str) -> Option<Function> {
+    match name.to_lowercase().as_str() {
+        "concat" => Some(Function::Concat),
+        "lower" => Some(Function::Lower),
+        "upper" => Some(Function::Upper),
+        "length" => Some(Function::Length),
+        "tostring" => Some(Function::ToString),
+        "touint64" => Some(Function::ToUint64),
+        "toyyyymm" => Some(Function::ToYYYYMM),
+        "toyyyymmdd" => Some(Function::ToYYYYMMDD),
+        "tostartofmonth" => Some(Function::ToStartOfMonth),
+        "tostartofday" => Some(Function::ToStartOfDay),
+        "tostartofhour" => Some(Function::ToStartOfHour),
+        "contains" => Some(Function::Contains),
+        _ => None,
+    }
+}
+
+// Bind an expression that is evaluated against the table's rows, so can't have aggregates
+fn bind_scalar(expr: &SqlExpr, table: &TableMetaData) -> Result<Expr> {
+    match expr {
+        SqlExpr::Literal(value) => Ok(Expr::Literal(value.clone())),
+        SqlExpr::Column(name) => match table.get_column(name) {
+            Some(_) => Ok(Expr::Column(name.clone())),
+            None => Err(anyhow!("Unknown column {} in table {}", name, table.name)),
+        },
+        SqlExpr::Star => Err(anyhow!("* is only allowed in count(*)")),
+        SqlExpr::Binary(left, op, right) => Ok(Expr::binary(bind_scalar(left, table)?, *op, bind_scalar(right, table)?)),
+        SqlExpr::Not(expr) => Ok(Expr::Not(Box::new(bind_scalar(expr, table)?))),
+        SqlExpr::Function(name, args) => {
+            if aggregate_function(name).is_some() {
+                return Err(anyhow!("Aggregate {} isn't allowed here", name));
+            }
+            bind_function(name, args, |arg| bind_scalar(arg, table))
+        }
+    }
+}
+
+fn bind_function(name: &str, args: &[SqlExpr], mut bind_arg: impl FnMut(&SqlExpr) -> Result<Expr>) -> Result<Expr> {
+    if name.eq_ignore_ascii_case("now") && args.is_empty() {
+        return Ok(Expr::Now);
+    }
+    let function = scalar_function(name).ok_or(anyhow!("Unknown function {}", name))?;
+    let args = args.iter().map(&mut bind_arg).collect::<Result<Vec<Expr>>>()?;
+    Ok(Expr::function(function, args))
+}
+
+// Binds the expressions of an aggregating query, which are evaluated against the aggregation's rows.
+// Any part of an expression that is a GROUP BY expression becomes that group column, and every
+// aggregate becomes an aggregate column, adding it to the aggregation if it's new.
+struct AggregateBinder<'a> {
+    table: &'a TableMetaData,
+    group_by: Vec<Expr>,
+    aggregates: Vec<(AggregateFunction, Option<Expr>)>,
+}
+
+impl<'a> AggregateBinder<'a> {
+    fn bind(&mut self, expr: &SqlExpr) -> Result<Expr> {
+        if !contains_aggregate(expr) {
+            if let Ok(bound) = bind_scalar(expr, self.table) {
+                if let Some(i) = self.group_by.iter().position(|group| *group == bound) {
+                    return Ok(Expr::Column(aggregate_column("group", i)));
+                }
+            }
+        }
+        match expr {
+            SqlExpr::Literal(value) => Ok(Expr::Literal(value.clone())),
+            SqlExpr::Column(name) => match self.table.get_column(name) {
+                Some(_) => Err(anyhow!("Column {} must be in GROUP BY or inside an aggregate", name)),
+                None => Err(anyhow!("Unknown column {} in table {}", name, self.table.name)),
+            },
+            SqlExpr::Star => Err(anyhow!("* is only allowed in count(*)")),
+            SqlExpr::Binary(left, op, right) => Ok(Expr::binary(self.bind(left)?, *op, self.bind(right)?)),
+            SqlExpr::Not(expr) => Ok(Expr::Not(Box::new(self.bind(expr)?))),
+            SqlExpr::Function(name, args) => match aggregate_function(name) {
+                Some(function) => {
+                    let arg = match (function, args.as_slice()) {
+                        (AggregateFunction::Count, [] | [SqlExpr::Star]) => None,
+                        // there are no NULLs, so count(x) counts every row
+                        (AggregateFunction::Count, [arg]) => {
+                            bind_scalar(arg, self.table)?;
+                            None
+                        }
+                        (_, [arg]) => Some(bind_scalar(arg, self.table)?),
+                        _ => return Err(anyhow!("{} takes one argument", name)),
+                    };
+                    let aggregate = (function, arg);
+                    let i = match self.aggregates.iter().position(|existing| *existing == aggregate) {
+                        Some(i) => i,
+                        None => {
+                            self.aggregates.push(aggregate);
+                            self.aggregates.len() - 1
+                        }
+                    };
+                    Ok(Expr::Column(aggregate_column("aggregate", i)))
+                }
+                None => bind_function(name, args, |arg| self.bind(arg)),
+            },
+        }
+    }
+
+    fn columns(&self) -> Result<Vec<ColumnMetaData>> {
+        let aggregation = Aggregation { group_by: self.group_by.clone(), aggregates: self.aggregates.clone() };
+        aggregation_columns(&aggregation, self.table)
+    }
+}
+
+fn aggregation_columns(aggregation: &Aggregation, table: &TableMetaData) -> Result<Vec<ColumnMetaData>> {
+    let mut columns = vec![];
+    for (i, expr) in aggregation.group_by.iter().enumerate() {
+        columns.push(ColumnMetaData::new(&aggregate_column("group", i), dtype_of(expr, &table.columns)?));
+    }
+    for (i, (function, arg)) in aggregation.aggregates.iter().enumerate() {
+        let arg_dtype = arg.as_ref().map(|arg| dtype_of(arg, &table.columns)).transpose()?;
+        let dtype = match (function, arg_dtype) {
+            (AggregateFunction::Sum, Some(DType::String)) => return Err(anyhow!("Can't sum a String")),
+            (function, arg_dtype) => function.state_dtype().or(arg_dtype).unwrap(),
+        };
+        columns.push(ColumnMetaData::new(&aggregate_column("aggregate", i), dtype));
+    }
+    Ok(columns)
+}
+
+pub(crate) fn check_boolean(expr: &Expr, columns: &[ColumnMetaData], clause: &str) -> Result<()> {
+    match dtype_of(expr, columns)? {
+        DType::Uint64 => Ok(()),
+        DType::String => Err(anyhow!("{} must be a boolean, not a String", clause)),
+    }
+}
+
+// The type of an expression's value, checking the types of its operands
+pub(crate) fn dtype_of(expr: &Expr, columns: &[ColumnMetaData]) -> Result<DType> {
+    match expr {
+        Expr::Literal(value) => Ok(get_dtype(value)),
+        Expr::Column(name) => columns
+            .iter()
+            .find(|col| &col.name == name)
+            .map(|col| col.dtype.clone())
+            .ok_or(anyhow!("Unknown column {}", name)),
+        Expr::Now => Ok(DType::Uint64),
+        Expr::BinaryOp(left, op, right) => {
+            let (left, right) = (dtype_of(left, columns)?, dtype_of(right, columns)?);
+            let is_comparison = matches!(op, BinOp::Eq | BinOp::NotEq | BinOp::Lt | BinOp::LtEq | BinOp::Gt | BinOp::GtEq);
+            if is_comparison && left != right {
+                return Err(anyhow!("Can't compare {:?} and {:?}", left, right));
+            }
+            if !is_comparison && (left != DType::Uint64 || right != DType::Uint64) {
+                return Err(anyhow!("Can't apply {:?} to {:?} and {:?}", op, left, right));
+            }
+            Ok(DType::Uint64)
+        }
+        Expr::Not(expr) => match dtype_of(expr, columns)? {
+            DType::Uint64 => Ok(DType::Uint64),
+            DType::String => Err(anyhow!("Can't apply NOT to a String")),
+        },
+        Expr::Function(function, args) => {
+            let args = args.iter().map(|arg| dtype_of(arg, columns)).collect::<Result<Vec<DType>>>()?;
+            let (expected, result): (Option<&[DType]>, DType) = match function {
+                Function::Concat if !args.is_empty() => (None, DType::String),
+                Function::ToString => (Some(&[]), DType::String),
+                Function::ToUint64 => (Some(&[]), DType::Uint64),
+                Function::Lower | Function::Upper => (Some(&[DType::String]), DType::String),
+                Function::Length => (Some(&[DType::String]), DType::Uint64),
+                Function::Contains => (Some(&[DType::String, DType::String]), DType::Uint64),
+                Function::ToYYYYMM
+                | Function::ToYYYYMMDD
+                | Function::ToStartOfMonth
+                | Function::ToStartOfDay
+                | Function::ToStartOfHour => (Some(&[DType::Uint64]), DType::Uint64),
+                Function::Concat => return Err(anyhow!("{:?} takes at least one argument", function)),
+            };
+            let valid = match expected {
+                None => true,
+                // ToString and ToUint64 take one argument of either type
+                Some([]) => args.len() == 1,
+                Some(expected) => args == expected,
+            };
+            if !valid {
+                return Err(anyhow!("Invalid arguments for {:?}: {:?}", function, args));
+            }
+            Ok(result)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql::{parse, Statement};
+
+    fn plan(sql: &str) -> Result<SelectPlan> {
+        let table = TableMetaData::new(
+            "events",
+            vec![
+                ColumnMetaData::new("event", DType::String),
+                ColumnMetaData::new("timestamp", DType::Uint64),
+                ColumnMetaData::new("id", DType::Uint64),
+            ],
+        );
+        let Statement::Select(select) = parse(sql)? else {
+            return Err(anyhow!("Expected a SELECT"));
+        };
+        plan_select(&select, &table)
+    }
+
+    #[test]
+    fn test_plan_select() {
+        let grouped = plan("SELECT event, count() c, max(id) + 1 FROM events WHERE id > 5 GROUP BY 1 HAVING count() > 2 ORDER BY c DESC, min(id)").unwrap();
+        let column = |name: &str| Expr::column(name);
+        assert_eq!(grouped.aggregation, Some(Aggregation::new(vec![column("event")])
+            .with_count()
+            .with_aggregate(AggregateFunction::Max, column("id"))
+            .with_aggregate(AggregateFunction::Min, column("id"))));
+        assert_eq!(grouped.outputs, vec![
+            column("#group0"),
+            column("#aggregate0"),
+            Expr::binary(column("#aggregate1"), BinOp::Add, Expr::literal(DValue::Uint64(1))),
+            column("#aggregate2"),
+        ]);
+        assert_eq!(grouped.order_by, vec![(1, true), (3, false)]);
+        assert_eq!(grouped.columns, vec![
+            ("event".to_string(), DType::String),
+            ("c".to_string(), DType::Uint64),
+            ("max(id) + 1".to_string(), DType::Uint64),
+        ]);
+
+        assert_eq!(plan("SELECT * FROM events ORDER BY id").unwrap().order_by, vec![(2, false)]);
+    }
+
+    #[test]
+    fn test_plan_errors() {
+        assert!(plan("SELECT missing FROM events").is_err());
+        assert!(plan("SELECT event, count() FROM events").is_err());
+        assert!(plan("SELECT id FROM events WHERE event").is_err());
+        assert!(plan("SELECT id FROM events WHERE count() > 1").is_err());
+        assert!(plan("SELECT id FROM events HAVING id > 1").is_err());
+        assert!(plan("SELECT sum(event) FROM events").is_err());
+        assert!(plan("SELECT lower(id) FROM events").is_err());
+        assert!(plan("SELECT id FROM events ORDER BY 2").is_err());
+        assert!(plan("SELECT id FROM events SETTINGS unknown = 1").is_err());
+    }
+}
@@ -0,0 +1,344 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::prelude::*;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::format::{check_header, header, FileKind, HEADER_SIZE};
+use crate::metadata::ColumnMetaData;
+use crate::storage::{read_dvalue_data, write_dvalue_data};
+use crate::{DType, DValue};
+
+// Skip indexes let a scan rule out a block of a column without reading it. The only other ranges a
+// scan has are those of the primary key columns, from the first and last keys of each block in the
+// <table>.primary file (see primary_index.rs), so skip indexes are for filters on other columns or
+// that ranges can't help with (e.g. an equality on an unsorted column). Each index of a column is
+// stored in its own <table>.<column>.<kind> file next to the column's index file.
+//
+// After the header, the file is a list of entries, each being the block number as 8 bytes big
+// endian, then the length of the entry as 4 bytes big endian, then the entry. Entries are appended
+// as blocks are written, and a later entry for a block replaces an earlier one. An empty entry
+// means nothing is known about the block, so it can't be skipped.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum SkipIndex {
+    // a bloom filter of the values in the block, `size` bytes long, answers equality
+    Bloom { size: usize, hashes: u32 },
+    // a bloom filter of every n byte substring of the values in the block, answers substring search
+    NgramBloom { n: usize, size: usize, hashes: u32 },
+    // the distinct values in the block, if there are no more than `max_values` of them
+    Set { max_values: usize },
+    // the exact min and max of the block, which the primary index only has for its own columns
+    MinMax,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SkipEntry {
+    Bloom(Vec<u8>),
+    Set(Vec<DValue>),
+    MinMax(DValue, DValue),
+}
+
+impl SkipIndex {
+    pub fn bloom(size: usize, hashes: u32) -> SkipIndex {
+        SkipIndex::Bloom { size, hashes }
+    }
+
+    pub fn ngram_bloom(n: usize, size: usize, hashes: u32) -> SkipIndex {
+        SkipIndex::NgramBloom { n, size, hashes }
+    }
+
+    pub fn set(max_values: usize) -> SkipIndex {
+        SkipIndex::Set { max_values }
+    }
+
+    // Used in the index's file name, so a column can have one index of each kind
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SkipIndex::Bloom { .. } => "bloom",
+            SkipIndex::NgramBloom { .. } => "ngrambloom",
+            SkipIndex::Set { .. } => "set",
+            SkipIndex::MinMax => "minmax",
+        }
+    }
+
+    pub fn validate(&self, col: &ColumnMetaData) -> Result<()> {
+        match self {
+            SkipIndex::Bloom { size, hashes } | SkipIndex::NgramBloom { size, hashes, .. } if *size == 0 || *hashes == 0 => {
+                Err(anyhow!("Bloom filter index on column {} needs a size and a number of hashes", col.name))
+            }
+            SkipIndex::NgramBloom { n, .. } if *n == 0 => Err(anyhow!("N-gram index on column {} needs n > 0", col.name)),
+            SkipIndex::NgramBloom { .. } if col.dtype != DType::String => {
+                Err(anyhow!("N-gram index on column {} needs a String column", col.name))
+            }
+            SkipIndex::Set { max_values: 0 } => Err(anyhow!("Set index on column {} needs max_values > 0", col.name)),
+            _ => Ok(()),
+        }
+    }
+
+    // The entry for one block of values, or None if the index can't describe it
+    pub fn build(&self, values: &[DValue]) -> Option<SkipEntry> {
+        match self {
+            SkipIndex::Bloom { size, hashes } => {
+                let mut bloom = vec![0; *size];
+                for value in values {
+                    bloom_insert(&mut bloom, *hashes, &value_bytes(value));
+                }
+                Some(SkipEntry::Bloom(bloom))
+            }
+            SkipIndex::NgramBloom { n, size, hashes } => {
+                let mut bloom = vec![0; *size];
+                for value in values {
+                    for ngram in value_bytes(value).windows(*n) {
+                        bloom_insert(&mut bloom, *hashes, ngram);
+                    }
+                }
+                Some(SkipEntry::Bloom(bloom))
+            }
+            SkipIndex::Set { max_values } => {
+                let mut set: Vec<DValue> = values.to_vec();
+                set.sort();
+                set.dedup();
+                (set.len() <= *max_values).then_some(SkipEntry::Set(set))
+            }
+            SkipIndex::MinMax => {
+                let min = values.iter().min()?;
+                let max = values.iter().max()?;
+                Some(SkipEntry::MinMax(min.clone(), max.clone()))
+            }
+        }
+    }
+
+    // Whether a block with this entry could hold the value
+    pub fn may_equal(&self, entry: &SkipEntry, value: &DValue) -> bool {
+        match (self, entry) {
+            (SkipIndex::Bloom { hashes, .. }, SkipEntry::Bloom(bloom)) => bloom_may_contain(bloom, *hashes, &value_bytes(value)),
+            // a value shorter than n has no n-grams to look for
+            (SkipIndex::NgramBloom { .. }, SkipEntry::Bloom(_)) => match value {
+                DValue::String(s) => self.may_contain(entry, s),
+                DValue::Uint64(_) => true,
+            },
+            (SkipIndex::Set { .. }, SkipEntry::Set(set)) => set.binary_search(value).is_ok(),
+            (SkipIndex::MinMax, SkipEntry::MinMax(min, max)) => min <= value && value <= max,
+            _ => true,
+        }
+    }
+
+    // Whether a block with this entry could hold a value containing the substring
+    pub fn may_contain(&self, entry: &SkipEntry, needle: &str) -> bool {
+        match (self, entry) {
+            (SkipIndex::NgramBloom { n, hashes, .. }, SkipEntry::Bloom(bloom)) => needle
+                .as_bytes()
+                .windows(*n)
+                .all(|ngram| bloom_may_contain(bloom, *hashes, ngram)),
+            (SkipIndex::Set { .. }, SkipEntry::Set(set)) => set.iter().any(|value| match value {
+                DValue::String(s) => s.contains(needle),
+                DValue::Uint64(_) => true,
+            }),
+            _ => true,
+        }
+    }
+
+    // The exact range of values in a block with this entry
+    pub fn range(&self, entry: &SkipEntry) -> Option<(DValue, DValue)> {
+        match entry {
+            SkipEntry::MinMax(min, max) => Some((min.clone(), max.clone())),
+            SkipEntry::Set(set) => Some((set.first()?.clone(), set.last()?.clone())),
+            SkipEntry::Bloom(_) => None,
+        }
+    }
+
+    fn entry_to_bytes(&self, entry: &SkipEntry) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match entry {
+            SkipEntry::Bloom(bloom) => bytes.extend_from_slice(bloom),
+            SkipEntry::Set(set) => {
+                for value in set {
+                    write_dvalue_data(&mut bytes, value);
+                }
+            }
+            SkipEntry::MinMax(min, max) => {
+                write_dvalue_data(&mut bytes, min);
+                write_dvalue_data(&mut bytes, max);
+            }
+        }
+        bytes
+    }
+
+    fn entry_from_bytes(&self, mut bytes: &[u8], dtype: &DType) -> SkipEntry {
+        match self {
+            SkipIndex::Bloom { .. } | SkipIndex::NgramBloom { .. } => SkipEntry::Bloom(bytes.to_vec()),
+            SkipIndex::Set { .. } => {
+                let mut set = Vec::new();
+                while !bytes.is_empty() {
+                    let (n_bytes, value) = read_dvalue_data(bytes, dtype);
+                    set.push(value);
+                    bytes = &bytes[n_bytes..];
+                }
+                SkipEntry::Set(set)
+            }
+            SkipIndex::MinMax => {
+                let (n_bytes, min) = read_dvalue_data(bytes, dtype);
+                let (_, max) = read_dvalue_data(&bytes[n_bytes..], dtype);
+                SkipEntry::MinMax(min, max)
+            }
+        }
+    }
+}
+
+// The bytes a value is hashed as, which is how it is stored in a data file
+fn value_bytes(value: &DValue) -> Vec<u8> {
+    match value {
+        DValue::String(s) => s.as_bytes().to_vec(),
+        DValue::Uint64(u) => u.to_be_bytes().to_vec(),
+    }
+}
+
+// FNV-1a, which unlike the std hashers is guaranteed to give the same result in every build
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// The bits to set for a key, using double hashing to get `hashes` bit positions from one hash
+fn bloom_bits(bloom_size: usize, hashes: u32, key: &[u8]) -> impl Iterator<Item = usize> {
+    let hash = fnv1a(key);
+    let (h1, h2) = (hash & 0xffffffff, (hash >> 32) | 1);
+    let n_bits = bloom_size as u64 * 8;
+    (0..hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % n_bits) as usize)
+}
+
+fn bloom_insert(bloom: &mut [u8], hashes: u32, key: &[u8]) {
+    for bit in bloom_bits(bloom.len(), hashes, key) {
+        bloom[bit / 8] |= 1 << (bit % 8);
+    }
+}
+
+fn bloom_may_contain(bloom: &[u8], hashes: u32, key: &[u8]) -> bool {
+    bloom_bits(bloom.len(), hashes, key).all(|bit| bloom[bit / 8] & (1 << (bit % 8)) != 0)
+}
+
+pub(crate) fn skip_index_path(root_path: &Path, table_name: &str, column_name: &str, index: &SkipIndex) -> PathBuf {
+    root_path.join(format!("{}.{}.{}", table_name, column_name, index.kind()))
+}
+
+pub(crate) fn staged_skip_index_path(root_path: &Path, table_name: &str, column_name: &str, index: &SkipIndex) -> PathBuf {
+    root_path.join(format!("{}.{}.{}.staged", table_name, column_name, index.kind()))
+}
+
+// Encode entries for the given blocks, ready to be appended to an index file
+pub(crate) fn entries_to_bytes(index: &SkipIndex, entries: &[(usize, Option<SkipEntry>)]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for (block, entry) in entries {
+        let entry_bytes = entry.as_ref().map(|entry| index.entry_to_bytes(entry)).unwrap_or_default();
+        bytes.extend_from_slice(&(*block as u64).to_be_bytes());
+        bytes.extend_from_slice(&(entry_bytes.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&entry_bytes);
+    }
+    bytes
+}
+
+// Append encoded entries to an index file, creating it if needed
+pub(crate) fn append_entry_bytes(path: &Path, entry_bytes: &[u8]) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path)
+        .with_context(|| format!("Couldn't open {}", path.to_string_lossy()))?;
+    let mut bytes = Vec::with_capacity(HEADER_SIZE as usize + entry_bytes.len());
+    if file.metadata()?.len() == 0 {
+        bytes.extend_from_slice(&header(FileKind::Skip));
+    }
+    bytes.extend_from_slice(entry_bytes);
+    file.write_all(&bytes).with_context(|| format!("Couldn't write {}", path.to_string_lossy()))
+}
+
+// The known entries of one skip index of a column, by block
+#[derive(Debug)]
+pub struct SkipIndexReader {
+    pub index: SkipIndex,
+    entries: HashMap<usize, SkipEntry>,
+}
+
+impl SkipIndexReader {
+    pub fn load(root_path: &Path, table_name: &str, col: &ColumnMetaData, index: &SkipIndex) -> Result<SkipIndexReader> {
+        let path = skip_index_path(root_path, table_name, &col.name, index);
+        let bytes = match fs::read(&path) {
+            // nothing is known about any block
+            Err(e) if e.kind() == ErrorKind::NotFound => vec![],
+            result => result.with_context(|| format!("Couldn't read {}", path.to_string_lossy()))?,
+        };
+        let mut entries = HashMap::new();
+        if !bytes.is_empty() {
+            check_header(&bytes, FileKind::Skip, &path)?;
+            let mut rest = &bytes[HEADER_SIZE as usize..];
+            // stop at a partly written entry at the end of the file
+            while rest.len() >= 12 {
+                let block = u64::from_be_bytes(rest[0..8].try_into().unwrap()) as usize;
+                let len = u32::from_be_bytes(rest[8..12].try_into().unwrap()) as usize;
+                if rest.len() < 12 + len {
+                    break;
+                }
+                if len == 0 {
+                    entries.remove(&block);
+                } else {
+                    entries.insert(block, index.entry_from_bytes(&rest[12..12 + len], &col.dtype));
+                }
+                rest = &rest[12 + len..];
+            }
+        }
+        Ok(SkipIndexReader { index: index.clone(), entries })
+    }
+
+    pub fn entry(&self, block: usize) -> Option<&SkipEntry> {
+        self.entries.get(&block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<DValue> {
+        values.iter().map(|s| DValue::String(s.to_string())).collect()
+    }
+
+    #[test]
+    fn test_bloom() {
+        let index = SkipIndex::bloom(64, 3);
+        let entry = index.build(&strings(&["abc", "def"])).unwrap();
+        assert!(index.may_equal(&entry, &DValue::String("abc".to_string())));
+        assert!(index.may_equal(&entry, &DValue::String("def".to_string())));
+        let misses = (0..100).filter(|i| !index.may_equal(&entry, &DValue::String(format!("x{}", i)))).count();
+        assert!(misses > 90);
+
+        let index = SkipIndex::ngram_bloom(3, 64, 3);
+        let entry = index.build(&strings(&["hello world"])).unwrap();
+        assert!(index.may_contain(&entry, "lo wo"));
+        assert!(index.may_contain(&entry, "he"));
+        assert!(!index.may_contain(&entry, "xyz"));
+    }
+
+    #[test]
+    fn test_set_and_min_max() {
+        let index = SkipIndex::set(2);
+        assert_eq!(index.build(&strings(&["a", "b", "c"])), None);
+        let entry = index.build(&strings(&["b", "a", "b"])).unwrap();
+        assert_eq!(entry, SkipEntry::Set(strings(&["a", "b"])));
+        assert!(!index.may_equal(&entry, &DValue::String("c".to_string())));
+        assert_eq!(index.range(&entry), Some((DValue::String("a".to_string()), DValue::String("b".to_string()))));
+
+        let index = SkipIndex::MinMax;
+        let entry = index.build(&strings(&["longer than eight bytes 2", "longer than eight bytes 1"])).unwrap();
+        let bytes = entries_to_bytes(&index, &[(7, Some(entry.clone()))]);
+        assert_eq!(index.entry_from_bytes(&bytes[12..], &DType::String), entry);
+        assert!(!index.may_equal(&entry, &DValue::String("longer than eight bytes 3".to_string())));
+    }
+}
@@ -0,0 +1,631 @@
+use anyhow::{anyhow, Context, Result};
+
+use crate::export::ExportFormat;
+use crate::expr::BinOp;
+use crate::{DType, DValue};
+
+// A parser for the subset of SQL we support. Statements are parsed into the AST below, which
+// query.rs binds against the table metadata and plans onto the storage operators.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Statement {
+    Select(Select),
+    CreateTable(CreateTable),
+    Insert(Insert),
+    DropTable { name: String, if_exists: bool },
+    ShowTables,
+    Describe(String),
+    // EXPLAIN shows the plan, EXPLAIN ANALYZE also runs it
+    Explain { analyze: bool, select: Select },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Select {
+    pub items: Vec<SelectItem>,
+    pub from: String,
+    pub filter: Option<SqlExpr>,
+    pub group_by: Vec<SqlExpr>,
+    pub having: Option<SqlExpr>,
+    pub order_by: Vec<OrderByItem>,
+    pub limit: Option<u64>,
+    pub offset: Option<u64>,
+    pub settings: Vec<(String, u64)>,
+    // how DB::export writes the result
+    pub format: Option<ExportFormat>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CreateTable {
+    pub name: String,
+    pub if_not_exists: bool,
+    pub columns: Vec<ColumnDef>,
+    pub partition_by: Option<SqlExpr>,
+    pub order_by: Vec<String>,
+    pub primary_key: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnDef {
+    pub name: String,
+    pub dtype: DType,
+    pub default: Option<SqlExpr>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Insert {
+    pub table: String,
+    // every column in order, if they aren't named
+    pub columns: Option<Vec<String>>,
+    pub rows: Vec<Vec<SqlExpr>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SelectItem {
+    Wildcard,
+    // the text is the expression as written, which names the result column if there is no alias
+    Expr { expr: SqlExpr, alias: Option<String>, text: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OrderByItem {
+    pub expr: SqlExpr,
+    pub descending: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SqlExpr {
+    Literal(DValue),
+    Column(String),
+    // only valid as the argument of count
+    Star,
+    Binary(Box<SqlExpr>, BinOp, Box<SqlExpr>),
+    Not(Box<SqlExpr>),
+    // function names aren't looked up until the statement is bound
+    Function(String, Vec<SqlExpr>),
+}
+
+// Parse a single statement, optionally ending with a semicolon
+pub fn parse(sql: &str) -> Result<Statement> {
+    match parse_script(sql)?.as_slice() {
+        [statement] => Ok(statement.clone()),
+        statements => Err(anyhow!("Expected one statement, got {}", statements.len())),
+    }
+}
+
+// Parse statements separated by semicolons
+pub fn parse_script(sql: &str) -> Result<Vec<Statement>> {
+    let mut parser = Parser { sql, tokens: tokenize(sql)?, position: 0 };
+    let mut statements = vec![];
+    loop {
+        while parser.consume(&Token::Symbol(";")) {}
+        if parser.peek().is_none() {
+            return Ok(statements);
+        }
+        statements.push(parser.statement()?);
+        if parser.peek().is_some() {
+            parser.expect(&Token::Symbol(";"))?;
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    // identifiers and keywords, keywords are matched case insensitively
+    Word(String),
+    // a "quoted" or `quoted` identifier, which is never a keyword
+    QuotedWord(String),
+    Number(u64),
+    String(String),
+    Symbol(&'static str),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Word(word) | Token::QuotedWord(word) => write!(f, "'{}'", word),
+            Token::Number(n) => write!(f, "{}", n),
+            Token::String(s) => write!(f, "string '{}'", s),
+            Token::Symbol(symbol) => write!(f, "'{}'", symbol),
+        }
+    }
+}
+
+// longest first, so <= isn't read as < then =
+const SYMBOLS: [&str; 15] = ["<=", ">=", "!=", "<>", "=", "<", ">", "+", "-", "*", "/", "%", "(", ")", ","];
+
+// Words that end an expression, so they can't be used as an alias without AS
+const KEYWORDS: [&str; 17] = [
+    "SELECT", "FROM", "WHERE", "GROUP", "BY", "HAVING", "ORDER", "LIMIT", "OFFSET", "AS", "ASC", "DESC", "AND", "OR",
+    "NOT", "SETTINGS", "DISTINCT",
+];
+
+// Each token with its byte offset in the statement
+fn tokenize(sql: &str) -> Result<Vec<(usize, Token)>> {
+    let mut tokens = Vec::new();
+    let mut chars = sql.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '-' && sql[start..].starts_with("--") {
+            // a comment to the end of the line
+            while chars.next_if(|&(_, c)| c != '\n').is_some() {}
+        } else if c.is_ascii_digit() {
+            let mut end = start;
+            while let Some((i, c)) = chars.next_if(|(_, c)| c.is_ascii_digit()) {
+                end = i + c.len_utf8();
+            }
+            let number = sql[start..end].parse().map_err(|_| anyhow!("Number too big at position {}", start))?;
+            tokens.push((start, Token::Number(number)));
+        } else if c.is_alphabetic() || c == '_' {
+            let mut end = start;
+            while let Some((i, c)) = chars.next_if(|(_, c)| c.is_alphanumeric() || *c == '_') {
+                end = i + c.len_utf8();
+            }
+            tokens.push((start, Token::Word(sql[start..end].to_string())));
+        } else if c == '\'' || c == '"' || c == '`' {
+            // quotes are escaped by doubling them
+            chars.next();
+            let mut value = String::new();
+            loop {
+                match chars.next() {
+                    Some((_, next)) if next == c => match chars.next_if(|&(_, after)| after == c) {
+                        Some(_) => value.push(c),
+                        None => break,
+                    },
+                    Some((_, next)) => value.push(next),
+                    None => return Err(anyhow!("Unterminated quote starting at position {}", start)),
+                }
+            }
+            tokens.push((start, if c == '\'' { Token::String(value) } else { Token::QuotedWord(value) }));
+        } else if c == ';' {
+            chars.next();
+            tokens.push((start, Token::Symbol(";")));
+        } else {
+            let symbol = SYMBOLS
+                .iter()
+                .find(|symbol| sql[start..].starts_with(**symbol))
+                .ok_or(anyhow!("Unexpected character '{}' at position {}", c, start))?;
+            for _ in 0..symbol.len() {
+                chars.next();
+            }
+            tokens.push((start, Token::Symbol(symbol)));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    sql: &'a str,
+    tokens: Vec<(usize, Token)>,
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn statement(&mut self) -> Result<Statement> {
+        if self.consume_keyword("SELECT") {
+            self.select().map(Statement::Select)
+        } else if self.consume_keyword("EXPLAIN") {
+            let analyze = self.consume_keyword("ANALYZE");
+            self.expect_keyword("SELECT")?;
+            Ok(Statement::Explain { analyze, select: self.select()? })
+        } else if self.consume_keyword("CREATE") {
+            self.expect_keyword("TABLE")?;
+            self.create_table().map(Statement::CreateTable)
+        } else if self.consume_keyword("INSERT") {
+            self.expect_keyword("INTO")?;
+            self.insert().map(Statement::Insert)
+        } else if self.consume_keyword("DROP") {
+            self.expect_keyword("TABLE")?;
+            let if_exists = self.consume_keyword("IF");
+            if if_exists {
+                self.expect_keyword("EXISTS")?;
+            }
+            Ok(Statement::DropTable { name: self.identifier()?, if_exists })
+        } else if self.consume_keyword("SHOW") {
+            self.expect_keyword("TABLES")?;
+            Ok(Statement::ShowTables)
+        } else if self.consume_keyword("DESCRIBE") || self.consume_keyword("DESC") {
+            self.consume_keyword("TABLE");
+            Ok(Statement::Describe(self.identifier()?))
+        } else {
+            Err(self.unexpected("a statement"))
+        }
+    }
+
+    fn create_table(&mut self) -> Result<CreateTable> {
+        let if_not_exists = self.consume_keyword("IF");
+        if if_not_exists {
+            self.expect_keyword("NOT")?;
+            self.expect_keyword("EXISTS")?;
+        }
+        let name = self.identifier()?;
+        self.expect(&Token::Symbol("("))?;
+        let columns = self.comma_separated(|parser| {
+            let name = parser.identifier()?;
+            let offset = parser.offset();
+            let dtype = match parser.identifier()?.to_lowercase().as_str() {
+                "string" => DType::String,
+                "uint64" => DType::Uint64,
+                other => return Err(anyhow!("Unknown type {} at position {}, expected String or UInt64", other, offset)),
+            };
+            let default = if parser.consume_keyword("DEFAULT") { Some(parser.expr()?) } else { None };
+            Ok(ColumnDef { name, dtype, default })
+        })?;
+        self.expect(&Token::Symbol(")"))?;
+
+        let mut create = CreateTable { name, if_not_exists, columns, partition_by: None, order_by: vec![], primary_key: vec![] };
+        loop {
+            if self.consume_keyword("PARTITION") {
+                self.expect_keyword("BY")?;
+                create.partition_by = Some(self.expr()?);
+            } else if self.consume_keyword("ORDER") {
+                self.expect_keyword("BY")?;
+                create.order_by = self.column_list()?;
+            } else if self.consume_keyword("PRIMARY") {
+                self.expect_keyword("KEY")?;
+                create.primary_key = self.column_list()?;
+            } else {
+                return Ok(create);
+            }
+        }
+    }
+
+    // Either one column name, or a list of them in brackets
+    fn column_list(&mut self) -> Result<Vec<String>> {
+        if !self.consume(&Token::Symbol("(")) {
+            return Ok(vec![self.identifier()?]);
+        }
+        let names = self.comma_separated(|parser| parser.identifier())?;
+        self.expect(&Token::Symbol(")"))?;
+        Ok(names)
+    }
+
+    fn insert(&mut self) -> Result<Insert> {
+        let table = self.identifier()?;
+        let columns = if self.peek() == Some(&Token::Symbol("(")) { Some(self.column_list()?) } else { None };
+        self.expect_keyword("VALUES")?;
+        let rows = self.comma_separated(|parser| {
+            parser.expect(&Token::Symbol("("))?;
+            let values = parser.comma_separated(|parser| parser.expr())?;
+            parser.expect(&Token::Symbol(")"))?;
+            Ok(values)
+        })?;
+        Ok(Insert { table, columns, rows })
+    }
+
+    fn select(&mut self) -> Result<Select> {
+        let items = self.comma_separated(|parser| parser.select_item())?;
+        self.expect_keyword("FROM")?;
+        let from = self.identifier()?;
+        let filter = if self.consume_keyword("WHERE") { Some(self.expr()?) } else { None };
+        let mut group_by = vec![];
+        if self.consume_keyword("GROUP") {
+            self.expect_keyword("BY")?;
+            group_by = self.comma_separated(|parser| parser.expr())?;
+        }
+        let having = if self.consume_keyword("HAVING") { Some(self.expr()?) } else { None };
+        let mut order_by = vec![];
+        if self.consume_keyword("ORDER") {
+            self.expect_keyword("BY")?;
+            order_by = self.comma_separated(|parser| {
+                let expr = parser.expr()?;
+                let descending = parser.consume_keyword("DESC");
+                if !descending {
+                    parser.consume_keyword("ASC");
+                }
+                Ok(OrderByItem { expr, descending })
+            })?;
+        }
+        let limit = if self.consume_keyword("LIMIT") { Some(self.number()?) } else { None };
+        let offset = if self.consume_keyword("OFFSET") { Some(self.number()?) } else { None };
+        let mut settings = vec![];
+        if self.consume_keyword("SETTINGS") {
+            settings = self.comma_separated(|parser| {
+                let name = parser.identifier()?;
+                parser.expect(&Token::Symbol("="))?;
+                Ok((name, parser.number()?))
+            })?;
+        }
+        let format = if self.consume_keyword("FORMAT") {
+            let offset = self.offset();
+            Some(ExportFormat::from_name(&self.identifier()?).with_context(|| format!("At position {}", offset))?)
+        } else {
+            None
+        };
+        Ok(Select { items, from, filter, group_by, having, order_by, limit, offset, settings, format })
+    }
+
+    fn select_item(&mut self) -> Result<SelectItem> {
+        if self.consume(&Token::Symbol("*")) {
+            return Ok(SelectItem::Wildcard);
+        }
+        let start = self.offset();
+        let expr = self.expr()?;
+        let text = self.sql[start..self.previous_end()].to_string();
+        let alias = if self.consume_keyword("AS") {
+            Some(self.identifier()?)
+        } else {
+            match self.peek() {
+                Some(Token::Word(word)) if !is_keyword(word) => Some(self.identifier()?),
+                Some(Token::QuotedWord(_)) => Some(self.identifier()?),
+                _ => None,
+            }
+        };
+        Ok(SelectItem::Expr { expr, alias, text })
+    }
+
+    // Operators from the loosest binding to the tightest
+    fn expr(&mut self) -> Result<SqlExpr> {
+        let mut expr = self.and_expr()?;
+        while self.consume_keyword("OR") {
+            expr = SqlExpr::Binary(Box::new(expr), BinOp::Or, Box::new(self.and_expr()?));
+        }
+        Ok(expr)
+    }
+
+    fn and_expr(&mut self) -> Result<SqlExpr> {
+        let mut expr = self.not_expr()?;
+        while self.consume_keyword("AND") {
+            expr = SqlExpr::Binary(Box::new(expr), BinOp::And, Box::new(self.not_expr()?));
+        }
+        Ok(expr)
+    }
+
+    fn not_expr(&mut self) -> Result<SqlExpr> {
+        if self.consume_keyword("NOT") {
+            return Ok(SqlExpr::Not(Box::new(self.not_expr()?)));
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<SqlExpr> {
+        let left = self.additive()?;
+        let op = match self.peek() {
+            Some(Token::Symbol("=")) => BinOp::Eq,
+            Some(Token::Symbol("!=" | "<>")) => BinOp::NotEq,
+            Some(Token::Symbol("<")) => BinOp::Lt,
+            Some(Token::Symbol("<=")) => BinOp::LtEq,
+            Some(Token::Symbol(">")) => BinOp::Gt,
+            Some(Token::Symbol(">=")) => BinOp::GtEq,
+            _ => return Ok(left),
+        };
+        self.position += 1;
+        Ok(SqlExpr::Binary(Box::new(left), op, Box::new(self.additive()?)))
+    }
+
+    fn additive(&mut self) -> Result<SqlExpr> {
+        let mut expr = self.multiplicative()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Symbol("+")) => BinOp::Add,
+                Some(Token::Symbol("-")) => BinOp::Sub,
+                _ => return Ok(expr),
+            };
+            self.position += 1;
+            expr = SqlExpr::Binary(Box::new(expr), op, Box::new(self.multiplicative()?));
+        }
+    }
+
+    fn multiplicative(&mut self) -> Result<SqlExpr> {
+        let mut expr = self.primary()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Symbol("*")) => BinOp::Mul,
+                Some(Token::Symbol("/")) => BinOp::Div,
+                Some(Token::Symbol("%")) => BinOp::Mod,
+                _ => return Ok(expr),
+            };
+            self.position += 1;
+            expr = SqlExpr::Binary(Box::new(expr), op, Box::new(self.primary()?));
+        }
+    }
+
+    fn primary(&mut self) -> Result<SqlExpr> {
+        let offset = self.offset();
+        match self.next() {
+            Some(Token::Number(n)) => Ok(SqlExpr::Literal(DValue::Uint64(n))),
+            Some(Token::String(s)) => Ok(SqlExpr::Literal(DValue::String(s))),
+            Some(Token::Symbol("(")) => {
+                let expr = self.expr()?;
+                self.expect(&Token::Symbol(")"))?;
+                Ok(expr)
+            }
+            Some(Token::QuotedWord(name)) => Ok(SqlExpr::Column(name)),
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case("TRUE") => Ok(SqlExpr::Literal(DValue::Uint64(1))),
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case("FALSE") => Ok(SqlExpr::Literal(DValue::Uint64(0))),
+            Some(Token::Word(word)) if !is_keyword(&word) => {
+                if !self.consume(&Token::Symbol("(")) {
+                    return Ok(SqlExpr::Column(word));
+                }
+                let mut args = vec![];
+                if !self.consume(&Token::Symbol(")")) {
+                    args = self.comma_separated(|parser| {
+                        if parser.consume(&Token::Symbol("*")) {
+                            return Ok(SqlExpr::Star);
+                        }
+                        parser.expr()
+                    })?;
+                    self.expect(&Token::Symbol(")"))?;
+                }
+                Ok(SqlExpr::Function(word, args))
+            }
+            Some(token) => Err(anyhow!("Expected an expression, got {} at position {}", token, offset)),
+            None => Err(anyhow!("Expected an expression, got the end of the statement")),
+        }
+    }
+
+    fn comma_separated<T>(&mut self, mut parse: impl FnMut(&mut Self) -> Result<T>) -> Result<Vec<T>> {
+        let mut items = vec![parse(self)?];
+        while self.consume(&Token::Symbol(",")) {
+            items.push(parse(self)?);
+        }
+        Ok(items)
+    }
+
+    fn identifier(&mut self) -> Result<String> {
+        let offset = self.offset();
+        match self.next() {
+            Some(Token::Word(word)) if !is_keyword(&word) => Ok(word),
+            Some(Token::QuotedWord(word)) => Ok(word),
+            Some(token) => Err(anyhow!("Expected a name, got {} at position {}", token, offset)),
+            None => Err(anyhow!("Expected a name, got the end of the statement")),
+        }
+    }
+
+    fn number(&mut self) -> Result<u64> {
+        let offset = self.offset();
+        match self.next() {
+            Some(Token::Number(n)) => Ok(n),
+            Some(token) => Err(anyhow!("Expected a number, got {} at position {}", token, offset)),
+            None => Err(anyhow!("Expected a number, got the end of the statement")),
+        }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(_, token)| token)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        self.position += 1;
+        token
+    }
+
+    // The byte offset of the next token, for error messages
+    fn offset(&self) -> usize {
+        self.tokens.get(self.position).map_or(self.sql.len(), |(offset, _)| *offset)
+    }
+
+    // The byte offset of the end of the last token
+    fn previous_end(&self) -> usize {
+        let next = self.offset();
+        self.sql[..next].trim_end().len()
+    }
+
+    fn consume(&mut self, token: &Token) -> bool {
+        if self.peek() == Some(token) {
+            self.position += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, token: &Token) -> Result<()> {
+        if !self.consume(token) {
+            return Err(self.unexpected(&token.to_string()));
+        }
+        Ok(())
+    }
+
+    fn consume_keyword(&mut self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword) => {
+                self.position += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<()> {
+        if !self.consume_keyword(keyword) {
+            return Err(self.unexpected(keyword));
+        }
+        Ok(())
+    }
+
+    fn unexpected(&self, expected: &str) -> anyhow::Error {
+        match self.peek() {
+            Some(token) => anyhow!("Expected {}, got {} at position {}", expected, token, self.offset()),
+            None => anyhow!("Expected {}, got the end of the statement", expected),
+        }
+    }
+}
+
+fn is_keyword(word: &str) -> bool {
+    KEYWORDS.iter().any(|keyword| keyword.eq_ignore_ascii_case(word))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_select() {
+        let statement = parse("SELECT event, count() AS n FROM events WHERE id > 1 + 2 * 3 AND NOT event = 'it''s' GROUP BY event ORDER BY 2 DESC, event LIMIT 10;").unwrap();
+        let Statement::Select(select) = statement else {
+            panic!("Expected a SELECT");
+        };
+        let column = |name: &str| Box::new(SqlExpr::Column(name.to_string()));
+        let uint = |u: u64| Box::new(SqlExpr::Literal(DValue::Uint64(u)));
+        assert_eq!(select.items[1], SelectItem::Expr {
+            expr: SqlExpr::Function("count".to_string(), vec![]),
+            alias: Some("n".to_string()),
+            text: "count()".to_string(),
+        });
+        assert_eq!(select.filter, Some(SqlExpr::Binary(
+            Box::new(SqlExpr::Binary(column("id"), BinOp::Gt, Box::new(SqlExpr::Binary(uint(1), BinOp::Add, Box::new(SqlExpr::Binary(uint(2), BinOp::Mul, uint(3))))))),
+            BinOp::And,
+            Box::new(SqlExpr::Not(Box::new(SqlExpr::Binary(column("event"), BinOp::Eq, Box::new(SqlExpr::Literal(DValue::String("it's".to_string()))))))),
+        )));
+        assert_eq!(select.order_by, vec![
+            OrderByItem { expr: *uint(2), descending: true },
+            OrderByItem { expr: *column("event"), descending: false },
+        ]);
+        assert_eq!((select.limit, select.offset, select.format), (Some(10), None, None));
+        let Statement::Select(select) = parse("SELECT * FROM events SETTINGS max_threads = 2 FORMAT JSONEachRow").unwrap() else {
+            panic!("Expected a SELECT");
+        };
+        assert_eq!(select.format, Some(ExportFormat::JsonLines));
+        assert!(parse("SELECT * FROM events FORMAT xml").is_err());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse("SELECT FROM events").is_err());
+        assert!(parse("SELECT id FROM events WHERE").is_err());
+        assert!(parse("SELECT id FROM events LIMIT x").is_err());
+        assert!(parse("SELECT 'abc FROM events").is_err());
+        assert!(parse("SELECT id FROM events extra").is_err());
+        assert_eq!(parse("SELECT id id2 FROM events -- comment").unwrap(), parse("select id as id2 from events").unwrap());
+        assert!(parse("SELECT id FROM events; SELECT id FROM events").is_err());
+        assert!(parse("CREATE TABLE t (id Int32)").is_err());
+        assert!(parse("INSERT INTO t VALUES 1, 2").is_err());
+    }
+
+    #[test]
+    fn test_parse_script() {
+        let statements = parse_script("
+            CREATE TABLE IF NOT EXISTS events (event String, id UInt64 DEFAULT 1 + 1) PARTITION BY id % 10 ORDER BY (event, id) PRIMARY KEY event;
+            INSERT INTO events (id, event) VALUES (1, 'a'), (2, 'b');;
+            DROP TABLE IF EXISTS old;
+            SHOW TABLES;
+            DESC events
+        ").unwrap();
+        let uint = |u: u64| SqlExpr::Literal(DValue::Uint64(u));
+        assert_eq!(statements[0], Statement::CreateTable(CreateTable {
+            name: "events".to_string(),
+            if_not_exists: true,
+            columns: vec![
+                ColumnDef { name: "event".to_string(), dtype: DType::String, default: None },
+                ColumnDef { name: "id".to_string(), dtype: DType::Uint64, default: Some(SqlExpr::Binary(Box::new(uint(1)), BinOp::Add, Box::new(uint(1)))) },
+            ],
+            partition_by: Some(SqlExpr::Binary(Box::new(SqlExpr::Column("id".to_string())), BinOp::Mod, Box::new(uint(10)))),
+            order_by: vec!["event".to_string(), "id".to_string()],
+            primary_key: vec!["event".to_string()],
+        }));
+        assert_eq!(statements[1], Statement::Insert(Insert {
+            table: "events".to_string(),
+            columns: Some(vec!["id".to_string(), "event".to_string()]),
+            rows: vec![
+                vec![uint(1), SqlExpr::Literal(DValue::String("a".to_string()))],
+                vec![uint(2), SqlExpr::Literal(DValue::String("b".to_string()))],
+            ],
+        }));
+        assert_eq!(statements[2..], [
+            Statement::DropTable { name: "old".to_string(), if_exists: true },
+            Statement::ShowTables,
+            Statement::Describe("events".to_string()),
+        ]);
+    }
+}
@@ -1,140 +1,75 @@
-use std::path::PathBuf;
-use std::{u8, io};
+use std::cmp::Ordering;
+use std::io;
+use std::path::{Path, PathBuf};
 
 use anyhow::Context;
-use anyhow::__private::kind::TraitKind;
 
-use crate::data::{get_max, get_min};
-use crate::metadata::{ColumnMetaData, TableMetaData};
+use crate::cache::{BlockCache, BlockKey, FileStamps, PartCache};
+use crate::column::{Batch, Bitmap, ColumnVector};
+use crate::delete::{mask_path, DeletedMask};
+use crate::explain::QueryStats;
+use crate::format::{check_header, header, read_header, replace_file, FileKind, HEADER_SIZE};
+use crate::expr::{Expr, Ranges};
+use crate::merge::combine_rows;
+use crate::metadata::{ColumnMetaData, TableEngine, TableMetaData};
+use crate::primary_index::{self, primary_index_path, PrimaryIndex};
+use crate::skip_index::{
+    append_entry_bytes, entries_to_bytes, skip_index_path, staged_skip_index_path, SkipEntry, SkipIndexReader,
+};
 use crate::{get_dtype, DValue, DType};
-use anyhow::{anyhow, Result, Error};
-use std::fs::{File, OpenOptions};
+use anyhow::{anyhow, Result};
+use std::fs::{self, File, OpenOptions};
 use std::io::prelude::*;
 use std::io::ErrorKind;
+use std::sync::atomic::Ordering as AtomicOrdering;
+use std::sync::Arc;
+use memmap2::Mmap;
 type IndexSize = u64;
 use lz4_flex;
 
-const ROWS_PER_BLOCK: usize = 1024;
-
-
-#[derive(PartialEq, Debug)]
-
-enum IndexValue {
-    Uint64(u64),
-    String([u8; 8])
-}
-
-impl IndexValue {
-    fn from_dvalue(value: &DValue) -> IndexValue {
-        match value {
-            DValue::String(s) => {
-                let mut bytes = [0; 8];
-                // use the first 8 bytes of the string
-                let s_bytes = &s.as_bytes();
-                let n_to_copy = std::cmp::min(s_bytes.len(), bytes.len());
-                bytes[0..n_to_copy].copy_from_slice(&s_bytes[0..n_to_copy]);
-                IndexValue::String(bytes)
-            },
-            DValue::Uint64(n) => IndexValue::Uint64(*n),
-        }
-    }
-
-    fn write_bytes(&self, bytes: &mut [u8], ) ->() {
-        match self {
-            IndexValue::String(s) => {
-                bytes.copy_from_slice(s);
-            }
-            IndexValue::Uint64(u) => {
-                bytes.copy_from_slice(&u.to_be_bytes());
-            }
-        };
-    }
-
-    fn from_bytes(bytes: &[u8], dtype: &DType) -> IndexValue {
-        match dtype {
-            DType::String => {
-                let mut s_bytes = [0; 8];
-                s_bytes.copy_from_slice(bytes);
-                IndexValue::String(s_bytes)
-            },
-            DType::Uint64 => {
-                let array: [u8; 8] = bytes.try_into().expect("Slice with incorrect length");
-                let u = u64::from_be_bytes(array);
-                IndexValue::Uint64(u)
-            }
-        }
-    }
-    
-}
+pub(crate) const ROWS_PER_BLOCK: usize = 1024;
 
 
+// Each column's index file maps a block number to where the block is in the data file. The range
+// of values in each block comes from the primary index or the column's skip indexes instead.
+pub(crate) const INDEX_ENTRY_SIZE: usize = 24;
 
+#[derive(PartialEq, Debug)]
 struct IndexEntry {
     start_position: IndexSize, // stored as 8 bytes big endian
     compressed_size: IndexSize, // stored as 8 bytes big endian. In reality you don't need this but it's much simpler if you have it.
     decompressed_size: IndexSize, // stored as 8 bytes big endian
-    min: IndexValue, // stored as 8 bytes, either the u64 in big endian or the first 8 bytes of the string
-    max: IndexValue, // stored as 8 bytes, either the u64 in big endian or the first 8 bytes of the string
 }
 impl IndexEntry {
-    fn to_bytes(&self) -> [u8; 40]{
-        let mut bytes = [0; 40];
+    fn to_bytes(&self) -> [u8; INDEX_ENTRY_SIZE] {
+        let mut bytes = [0; INDEX_ENTRY_SIZE];
         bytes[0..8].copy_from_slice(&self.start_position.to_be_bytes());
         bytes[8..16].copy_from_slice(&self.compressed_size.to_be_bytes());
         bytes[16..24].copy_from_slice(&self.decompressed_size.to_be_bytes());
-        self.min.write_bytes(&mut bytes[24..32]);
-        self.max.write_bytes(&mut bytes[32..40]);
         bytes
     }
-    fn from_bytes(bytes: &[u8; 40], dtype: &DType) -> IndexEntry {
-        let start_position = u64::from_be_bytes(bytes[0..8].try_into().unwrap());
-        let compressed_size = u64::from_be_bytes(bytes[8..16].try_into().unwrap());
-        let decompressed_size = u64::from_be_bytes(bytes[16..24].try_into().unwrap());
-
-        let min = IndexValue::from_bytes(&bytes[24..32], dtype);
-        let max = IndexValue::from_bytes(&bytes[32..40], dtype);
+    fn from_bytes(bytes: &[u8; INDEX_ENTRY_SIZE]) -> IndexEntry {
         IndexEntry {
-            start_position,
-
-            compressed_size,
-            decompressed_size,
-            min,
-            max,
+            start_position: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            compressed_size: u64::from_be_bytes(bytes[8..16].try_into().unwrap()),
+            decompressed_size: u64::from_be_bytes(bytes[16..24].try_into().unwrap()),
         }
     }
-    
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_index_entry_to_bytes() {
-        let entry = IndexEntry {
-            start_position: 1,
-            compressed_size: 2,
-            decompressed_size: 3,
-            min: IndexValue::Uint64(4),
-            max: IndexValue::from_dvalue(&DValue::String("longlonglong".to_string())),
-        };
-        let bytes = entry.to_bytes();
-        let expected: [u8; 40] = [
-            0, 0, 0, 0, 0, 0, 0, 1,
-            0, 0, 0, 0, 0, 0, 0, 2,
-            0, 0, 0, 0, 0, 0, 0, 3,
-            0, 0, 0, 0, 0, 0, 0, 4,
-            b'l', b'o', b'n', b'g', b'l', b'o', b'n', b'g',
-        ];
-        assert_eq!(bytes, expected);
+    // A block that was never written for this column, because the column was added after the block.
+    // These have no data, the row count is kept in decompressed_size and the values are read as the
+    // column default.
+    fn placeholder(row_count: u64) -> IndexEntry {
+        IndexEntry {
+            start_position: 0,
+            compressed_size: 0,
+            decompressed_size: row_count,
+        }
     }
-    #[test]
-    fn test_index_entry_from_dvalue() {
-        assert_eq!(IndexValue::from_dvalue(&DValue::Uint64(42)), IndexValue::Uint64(42));
-        assert_eq!(IndexValue::from_dvalue(&DValue::String("".to_string())), IndexValue::String([0, 0, 0, 0, 0 ,0 ,0 ,0]));
-        assert_eq!(IndexValue::from_dvalue(&DValue::String("a".to_string())), IndexValue::String([b'a', 0, 0, 0, 0 ,0 ,0 ,0]));
-        assert_eq!(IndexValue::from_dvalue(&DValue::String("longlonglong".to_string())), IndexValue::String([b'l', b'o', b'n', b'g', b'l', b'o', b'n', b'g',]));
 
+    // Real blocks always contain at least one row, so always compress to at least one byte
+    fn is_placeholder(&self) -> bool {
+        self.compressed_size == 0
     }
 }
 
@@ -143,143 +78,280 @@ struct ColumnWriter<'a> {
     index_file: File,
     col: &'a ColumnMetaData,
     position: IndexSize,
+    // the number of the next block, which skip index entries are keyed by
+    block: usize,
 }
 fn create_writers<'a>(
-    root_path: &PathBuf,
+    root_path: &Path,
     table: &'a TableMetaData,
 ) -> Result<Vec<ColumnWriter<'a>>> {
+    truncate_to_common_blocks(root_path, table)?;
     table
         .columns
         .iter()
         .map(|col| {
-            let data_file = OpenOptions::new()
-                .write(true)
-                .append(true)
-                .create(true)
-                .open(data_path(&root_path, &table.name, &col.name))
+            let data_file = open_for_append(&data_path(root_path, &table.name, &col.name), FileKind::Data)
                 .with_context(|| "Couldn't open data file")?;
-            let index_file = OpenOptions::new()
-                .write(true)
-                .append(true)
-                .create(true)
-                .open(index_path(&root_path, &table.name, &col.name))
+            let index_file = open_for_append(&index_path(root_path, &table.name, &col.name), FileKind::Index)
                 .with_context(|| "Couldn't open index file")?;
 
             let data_file_metadata = data_file
                 .metadata()
                 .with_context(|| "Couldn't get metadata")?;
+            let index_len = index_file.metadata().with_context(|| "Couldn't get metadata")?.len();
 
             Ok(ColumnWriter {
                 data_file,
                 index_file,
                 col,
                 position: data_file_metadata.len(),
+                block: ((index_len - HEADER_SIZE) / INDEX_ENTRY_SIZE as u64) as usize,
             })
         })
         .collect::<Result<Vec<ColumnWriter>>>()
 }
 
-struct ColumnReader<'a> {
-    data_file: File,
-    index_file: File,
-    col: &'a ColumnMetaData,
-    position: IndexSize,
+// A write that was interrupted can leave some columns with more blocks than others, or an entry
+// partly written at the end of a file. Appending after that would put blocks from different writes
+// in the same block group, so before writing, every column's files are cut back to the blocks that
+// all the columns have, and the skip index and primary index entries for the blocks cut off are
+// dropped.
+fn truncate_to_common_blocks(root_path: &Path, table: &TableMetaData) -> Result<()> {
+    let index_lens = table
+        .columns
+        .iter()
+        .map(|col| file_len(&index_path(root_path, &table.name, &col.name)))
+        .collect::<Result<Vec<u64>>>()?;
+    let n_blocks = index_lens
+        .iter()
+        .map(|len| len.saturating_sub(HEADER_SIZE) / INDEX_ENTRY_SIZE as u64)
+        .min()
+        .unwrap_or(0);
+
+    for (col, &index_len) in table.columns.iter().zip(&index_lens) {
+        // a file without a whole header has nothing in it, and gets a new header when it is opened
+        let len = if index_len < HEADER_SIZE { 0 } else { HEADER_SIZE + n_blocks * INDEX_ENTRY_SIZE as u64 };
+        if index_len > len {
+            truncate_file(&index_path(root_path, &table.name, &col.name), len)?;
+            // only the blocks that are left, and any a mutation has finished staging, are kept in the
+            // data file
+            let mut entries = if len == 0 { vec![] } else { read_index(root_path, &table.name, col)? };
+            if staged_marker_path(root_path, &table.name).exists() {
+                entries.extend(read_index_file(&staged_index_path(root_path, &table.name, &col.name))?);
+            }
+            let data_len = entries
+                .iter()
+                .filter(|entry| !entry.is_placeholder())
+                .map(|entry| entry.start_position + entry.compressed_size)
+                .max()
+                .unwrap_or(if len == 0 { 0 } else { HEADER_SIZE });
+            let data_path = data_path(root_path, &table.name, &col.name);
+            if file_len(&data_path)? > data_len {
+                truncate_file(&data_path, data_len)?;
+            }
+        }
+        let data_path = data_path(root_path, &table.name, &col.name);
+        if (1..HEADER_SIZE).contains(&file_len(&data_path)?) {
+            truncate_file(&data_path, 0)?;
+        }
+        for skip_index in &col.skip_indexes {
+            truncate_block_entries(&skip_index_path(root_path, &table.name, &col.name, skip_index), FileKind::Skip, n_blocks as usize)?;
+        }
+    }
+    truncate_block_entries(&primary_index_path(root_path, &table.name), FileKind::Primary, n_blocks as usize)
 }
 
-fn create_readers<'a>(
-    root_path: &PathBuf,
-    table: &TableMetaData,
-    columns: &'a Vec<ColumnMetaData>,
-) -> Result<Vec<ColumnReader<'a>>> {
-    columns
-        .iter()
-        .map(|col| {
-            let data_file = OpenOptions::new()
-                .write(false)
-                .append(false)
-                .create(false)
-                .open(data_path(&root_path, &table.name, &col.name))
-                .with_context(|| "Couldn't open data file")?;
-            let index_file = OpenOptions::new()
-                .write(false)
-                .append(false)
-                .create(false)
-                .open(index_path(&root_path, &table.name, &col.name))
-                .with_context(|| "Couldn't open index file")?;
+// Keep only the entries for blocks before n_blocks in a file of entries keyed by block, which is how
+// the skip index and primary index files are laid out, dropping a partly written entry at the end
+fn truncate_block_entries(path: &Path, kind: FileKind, n_blocks: usize) -> Result<()> {
+    let bytes = match fs::read(path) {
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        result => result.with_context(|| format!("Couldn't read {}", path.to_string_lossy()))?,
+    };
+    if bytes.len() < HEADER_SIZE as usize {
+        return remove_file_if_exists(path);
+    }
+    check_header(&bytes, kind, path)?;
 
-            let data_file_metadata = data_file
-                .metadata()
-                .with_context(|| "Couldn't get metadata")?;
+    let mut kept = bytes[..HEADER_SIZE as usize].to_vec();
+    let mut rest = &bytes[HEADER_SIZE as usize..];
+    while rest.len() >= 12 {
+        let block = u64::from_be_bytes(rest[0..8].try_into().unwrap()) as usize;
+        let len = 12 + u32::from_be_bytes(rest[8..12].try_into().unwrap()) as usize;
+        if rest.len() < len {
+            break;
+        }
+        if block < n_blocks {
+            kept.extend_from_slice(&rest[..len]);
+        }
+        rest = &rest[len..];
+    }
+    if kept.len() < bytes.len() {
+        replace_file(path, &kept)?;
+    }
+    Ok(())
+}
 
-            Ok(ColumnReader {
-                data_file,
-                index_file,
-                col,
-                position: data_file_metadata.len(),
-            })
-        })
-        .collect::<Result<Vec<ColumnReader>>>()
+// The length of a file, 0 if it doesn't exist
+fn file_len(path: &Path) -> Result<u64> {
+    match fs::metadata(path) {
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(0),
+        result => Ok(result.with_context(|| format!("Couldn't read {}", path.to_string_lossy()))?.len()),
+    }
 }
 
+fn truncate_file(path: &Path, len: u64) -> Result<()> {
+    let file = OpenOptions::new().write(true).open(path).with_context(|| format!("Couldn't open {}", path.to_string_lossy()))?;
+    file.set_len(len).with_context(|| format!("Couldn't truncate {}", path.to_string_lossy()))?;
+    file.sync_all().with_context(|| format!("Couldn't truncate {}", path.to_string_lossy()))
+}
+
+// Open a file for appending, writing the header if the file is new and checking it if not
+fn open_for_append(path: &Path, kind: FileKind) -> Result<File> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path)?;
+    if file.metadata()?.len() == 0 {
+        file.write_all(&header(kind))?;
+    } else {
+        read_header(&mut file, kind, path)?;
+    }
+    Ok(file)
+}
+
+// Append rows to a part, sorted by the table's ORDER BY columns
 pub fn write_data(
-    root_path: &PathBuf,
+    root_path: &Path,
     table: &TableMetaData,
-    data: &Vec<Vec<DValue>>,
+    data: &[Vec<DValue>],
 ) -> Result<()> {
-    let mut writers = create_writers(root_path, table)?;
+    if let Some(row) = data.iter().find(|row| row.len() != table.columns.len()) {
+        return Err(anyhow!("Expected {} values, got {}", table.columns.len(), row.len()));
+    }
+    if data.iter().any(|row| row.iter().zip(&table.columns).any(|(value, col)| get_dtype(value) != col.dtype)) {
+        return Err(anyhow!("Mismatched data type"));
+    }
 
-    for block in data.chunks(ROWS_PER_BLOCK) {
-        struct BlockColumnState {
-            min: DValue,
-            max: DValue,
-            buf: Vec<u8>,
+    // sorting loses the order the rows were written in, which a Replacing table needs to tell which
+    // row of a key is the latest, so the rows of each key are combined first
+    match &table.engine {
+        TableEngine::Replacing { .. } if !table.order_by.is_empty() => {
+            let combined = combine_rows(table, data.to_vec())?;
+            write_rows(root_path, table, &WriteSource::Rows(&combined))
         }
-        let mut block_column_states: Vec<BlockColumnState> = block[0]
-            .iter()
-            .map(|val| BlockColumnState {
-                min: val.clone(),
-                max: val.clone(),
-                buf: Vec::new(),
-            })
-            .collect();
+        _ => write_rows(root_path, table, &WriteSource::Rows(data)),
+    }
+}
+
+// Append the selected rows of a batch to a part, sorted by the table's ORDER BY columns. The values
+// are encoded straight from the column vectors, which must all have a value for every row.
+pub fn write_batch(root_path: &Path, table: &TableMetaData, batch: &Batch) -> Result<()> {
+    if batch.columns.len() != table.columns.len() {
+        return Err(anyhow!("Expected {} columns, got {}", table.columns.len(), batch.columns.len()));
+    }
+    for (column, col) in batch.columns.iter().zip(&table.columns) {
+        if column.dtype() != col.dtype || column.len() != batch.selection.len() || column.validity().is_some() {
+            return Err(anyhow!("Column {} doesn't have a {:?} for every row", col.name, col.dtype));
+        }
+    }
+    match &table.engine {
+        // combining rows works on whole rows, see write_data
+        TableEngine::Replacing { .. } if !table.order_by.is_empty() => write_data(root_path, table, &batch.rows()),
+        _ => write_rows(root_path, table, &WriteSource::Batch(batch)),
+    }
+}
+
+// The rows a write appends, in the table's column order
+enum WriteSource<'a> {
+    Rows(&'a [Vec<DValue>]),
+    Batch(&'a Batch),
+}
+
+impl WriteSource<'_> {
+    // the positions of the rows to write
+    fn positions(&self) -> Vec<usize> {
+        match self {
+            WriteSource::Rows(rows) => (0..rows.len()).collect(),
+            WriteSource::Batch(batch) => batch.selection.ones().collect(),
+        }
+    }
+
+    fn value(&self, row: usize, column: usize) -> DValue {
+        match self {
+            WriteSource::Rows(rows) => rows[row][column].clone(),
+            WriteSource::Batch(batch) => batch.columns[column].get(row),
+        }
+    }
+
+    fn cmp(&self, a: usize, b: usize, column: usize) -> Ordering {
+        match self {
+            WriteSource::Rows(rows) => rows[a][column].cmp(&rows[b][column]),
+            WriteSource::Batch(batch) => match batch.columns[column].as_ref() {
+                ColumnVector::Uint64 { values, .. } => values[a].cmp(&values[b]),
+                column => column.str_at(a).cmp(&column.str_at(b)),
+            },
+        }
+    }
 
-        for row in block {
-            for (index, col) in row.iter().enumerate() {
-                let col_writer = &writers[index];
-                let col_state = &mut block_column_states[index];
-                if get_dtype(&col) != col_writer.col.dtype {
-                    return Err(anyhow!("Mismatched data type"));
+    // Encode a value the way write_dvalue_data does
+    fn write_value(&self, bytes: &mut Vec<u8>, row: usize, column: usize) {
+        match self {
+            WriteSource::Rows(rows) => write_dvalue_data(bytes, &rows[row][column]),
+            WriteSource::Batch(batch) => match batch.columns[column].as_ref() {
+                ColumnVector::Uint64 { values, .. } => bytes.extend_from_slice(&values[row].to_be_bytes()),
+                column => {
+                    let s = column.str_at(row).unwrap();
+                    if s.len() > u32::MAX as usize {
+                        panic!("String too long");
+                    }
+                    bytes.extend_from_slice(&(s.len() as u32).to_be_bytes());
+                    bytes.extend_from_slice(s.as_bytes());
                 }
-                write_dvalue_data(&mut col_state.buf, &col);
-                col_state.min = get_min(&col_state.min, &col).clone();
-                col_state.max = get_max(&col_state.max, &col).clone();
+            },
+        }
+    }
+}
+
+fn write_rows(root_path: &Path, table: &TableMetaData, source: &WriteSource) -> Result<()> {
+    let mut writers = create_writers(root_path, table)?;
+    let sort_indexes = table.order_by_indexes();
+    let mut rows = source.positions();
+    if !sort_indexes.is_empty() {
+        rows.sort_by(|&a, &b| sort_indexes.iter().map(|&i| source.cmp(a, b, i)).find(|ordering| ordering.is_ne()).unwrap_or(Ordering::Equal));
+    }
+    let key_indexes = &sort_indexes[..table.primary_key_columns().len()];
+
+    for block in rows.chunks(ROWS_PER_BLOCK) {
+        let mut bufs: Vec<Vec<u8>> = vec![Vec::new(); writers.len()];
+        for (index, buf) in bufs.iter_mut().enumerate() {
+            for &row in block {
+                source.write_value(buf, row, index);
             }
         }
 
+        let block_number = writers[0].block;
         for (index, writer) in writers.iter_mut().enumerate() {
-            let col_state = &mut block_column_states[index];
-            let buf = &mut col_state.buf;
+            let buf = &mut bufs[index];
             let buf_size = buf.len();
 
             // compress the data
             let prealloc_size = lz4_flex::block::get_maximum_output_size(buf_size);
             let mut compress_output = vec![0; prealloc_size];
-            let compressed_len = lz4_flex::block::compress_into(&buf, &mut compress_output)
+            let compressed_len = lz4_flex::block::compress_into(buf, &mut compress_output)
                 .with_context(|| "Couldn't compress data")?;
-            col_state.buf.clear();
 
             // write the compressed data
             writer
                 .data_file
-                .write_all(&compress_output)
+                .write_all(&compress_output[..compressed_len])
                 .with_context(|| "Couldn't write compressed data")?;
 
             let index_entry = IndexEntry {
                 start_position: writer.position,
                 compressed_size: compressed_len as IndexSize,
                 decompressed_size: buf_size as IndexSize,
-                min: IndexValue::from_dvalue(&col_state.min),
-                max: IndexValue::from_dvalue(&col_state.max),
             };
             let index_bytes = index_entry.to_bytes();
 
@@ -291,74 +363,780 @@ pub fn write_data(
 
             // increment the positions
             writer.position += compressed_len as IndexSize;
+
+            if !writer.col.skip_indexes.is_empty() {
+                let values: Vec<DValue> = block.iter().map(|&row| source.value(row, index)).collect();
+                for skip_index in &writer.col.skip_indexes {
+                    let entry_bytes = entries_to_bytes(skip_index, &[(writer.block, skip_index.build(&values))]);
+                    append_entry_bytes(&skip_index_path(root_path, &table.name, &writer.col.name, skip_index), &entry_bytes)?;
+                }
+            }
+            writer.block += 1;
+        }
+
+        if !key_indexes.is_empty() {
+            let key = |row: usize| key_indexes.iter().map(|&i| source.value(row, i)).collect();
+            let key_range = (key(block[0]), key(block[block.len() - 1]));
+            primary_index::append_entries(root_path, &table.name, &[(block_number, key_range)])?;
         }
     }
     Ok(())
 }
 
-fn read_all(root_path: &PathBuf, table: &TableMetaData) -> Result<Vec<Vec<DValue>>> {
-    let mut rows = Vec::new();
-    let mut readers = create_readers(root_path, table, &table.columns)?;
-    loop {
-        let read_results: Result<Vec<IndexEntry>> = readers.iter_mut().map(|reader| {
-            let mut buffer = [0; 40];
-            reader.index_file.read_exact(&mut buffer)?;
-            let index_entry = IndexEntry::from_bytes(&buffer, &reader.col.dtype);
-            Ok(index_entry)
-        }).collect();
-        match read_results {
-            Ok(indexes) => {
-                read_all_from_block_group(&mut rows, &mut readers, indexes)?;
-            },
-            Err(e) => {
-                let error: Error = e.into();
-                match error.downcast_ref::<io::Error>() {
-                    Some(io_error) if io_error.kind() == ErrorKind::UnexpectedEof => {
-                        // Reached the end of the one of the index files
-                        break;
-                    }
-                    _ => return Err(error),
+struct ColumnReader<'a> {
+    data_path: PathBuf,
+    // only opened when a block isn't in the cache
+    data_file: Option<File>,
+    col: &'a ColumnMetaData,
+}
+
+impl<'a> ColumnReader<'a> {
+    fn read_block(
+        &mut self,
+        table_name: &str,
+        index: &ColumnIndex,
+        block: usize,
+        cache: Option<&BlockCache>,
+        stats: Option<&QueryStats>,
+    ) -> Result<Arc<ColumnVector>> {
+        let index_entry = index.get(block);
+        let key = cache.map(|cache| {
+            let key = BlockKey {
+                table: table_name.to_string(),
+                data_path: self.data_path.clone(),
+                file_id: index.file_id,
+                start_position: index_entry.start_position,
+                compressed_size: index_entry.compressed_size,
+            };
+            (cache, key)
+        });
+        if let Some(values) = key.as_ref().and_then(|(cache, key)| cache.get(key)) {
+            if let Some(stats) = stats {
+                stats.blocks_cached.fetch_add(1, AtomicOrdering::Relaxed);
+            }
+            return Ok(values);
+        }
+
+        if self.data_file.is_none() {
+            let mut data_file = OpenOptions::new()
+                .read(true)
+                .open(&self.data_path)
+                .with_context(|| "Couldn't open data file")?;
+            read_header(&mut data_file, FileKind::Data, &self.data_path)?;
+            self.data_file = Some(data_file);
+        }
+        let values = Arc::new(read_block(self.data_file.as_mut().unwrap(), &index_entry, &self.col.dtype)?);
+        if let Some(stats) = stats {
+            stats.bytes_read.fetch_add(index_entry.compressed_size, AtomicOrdering::Relaxed);
+            stats.bytes_decompressed.fetch_add(index_entry.decompressed_size, AtomicOrdering::Relaxed);
+        }
+        if let Some((cache, key)) = key {
+            cache.insert(key, values.clone(), values.byte_size());
+        }
+        Ok(values)
+    }
+}
+
+// A column's index file, memory mapped so opening a part doesn't read every entry
+struct ColumnIndex {
+    mmap: Option<Mmap>,
+    // identifies the file, which is replaced rather than changed in place whenever existing
+    // entries change
+    file_id: u64,
+}
+
+impl ColumnIndex {
+    fn open(path: &Path) -> Result<ColumnIndex> {
+        let file = File::open(path).with_context(|| format!("Couldn't open index file: {}", path.to_string_lossy()))?;
+        let metadata = file.metadata()?;
+        if metadata.len() == 0 {
+            return Ok(ColumnIndex { mmap: None, file_id: file_id(&metadata) });
+        }
+        // Safety: index files are only ever appended to or replaced by renaming a new file over
+        // them, never truncated or rewritten in place, so the mapped bytes can't change under us
+        let mmap = unsafe { Mmap::map(&file) }.with_context(|| format!("Couldn't map {}", path.to_string_lossy()))?;
+        check_header(&mmap, FileKind::Index, path)?;
+        Ok(ColumnIndex { mmap: Some(mmap), file_id: file_id(&metadata) })
+    }
+
+    fn len(&self) -> usize {
+        self.mmap.as_ref().map_or(0, |mmap| (mmap.len() - HEADER_SIZE as usize) / INDEX_ENTRY_SIZE)
+    }
+
+    fn get(&self, block: usize) -> IndexEntry {
+        let start = HEADER_SIZE as usize + block * INDEX_ENTRY_SIZE;
+        let bytes = &self.mmap.as_ref().unwrap()[start..start + INDEX_ENTRY_SIZE];
+        IndexEntry::from_bytes(bytes.try_into().unwrap())
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn file_id(metadata: &fs::Metadata) -> u64 {
+    std::os::unix::fs::MetadataExt::ino(metadata)
+}
+
+// without inode numbers, the cache relies on DB invalidating a table's blocks when it changes them
+#[cfg(not(unix))]
+pub(crate) fn file_id(_metadata: &fs::Metadata) -> u64 {
+    0
+}
+
+// Everything a PartReader needs from a part's files apart from the blocks themselves: the mapped
+// index files, the skip indexes, the deleted mask and the primary index. These only change when the
+// part is written to, so DB keeps them in a PartCache between scans, checking the stamps of the
+// files they were loaded from each time.
+pub(crate) struct PartFiles {
+    root_path: PathBuf,
+    columns: Vec<ColumnFiles>,
+    n_blocks: usize,
+    deleted: DeletedMask,
+    primary_index: PrimaryIndex,
+    stamps: FileStamps,
+}
+
+struct ColumnFiles {
+    index: ColumnIndex,
+    skip_indexes: Vec<SkipIndexReader>,
+}
+
+impl PartFiles {
+    pub(crate) fn load(root_path: &Path, table: &TableMetaData) -> Result<PartFiles> {
+        // taken first, so a write made while loading is noticed the next time
+        let mut stamps = FileStamps::default();
+        for col in &table.columns {
+            stamps.add(index_path(root_path, &table.name, &col.name));
+            for skip_index in &col.skip_indexes {
+                stamps.add(skip_index_path(root_path, &table.name, &col.name, skip_index));
+            }
+        }
+        stamps.add(mask_path(root_path, &table.name));
+        stamps.add(primary_index_path(root_path, &table.name));
+
+        let columns = table
+            .columns
+            .iter()
+            .map(|col| {
+                let data_path = data_path(root_path, &table.name, &col.name);
+                if !data_path.exists() {
+                    return Err(anyhow!("Couldn't open data file: {}", data_path.to_string_lossy()));
                 }
+                let index = ColumnIndex::open(&index_path(root_path, &table.name, &col.name))?;
+                let skip_indexes = col
+                    .skip_indexes
+                    .iter()
+                    .map(|skip_index| SkipIndexReader::load(root_path, &table.name, col, skip_index))
+                    .collect::<Result<Vec<SkipIndexReader>>>()?;
+                Ok(ColumnFiles { index, skip_indexes })
+            })
+            .collect::<Result<Vec<ColumnFiles>>>()?;
+
+        // stop at the end of the shortest index file, in case a write was interrupted part way through
+        let n_blocks = columns.iter().map(|column| column.index.len()).min().unwrap_or(0);
+        let deleted = DeletedMask::load(root_path, &table.name)?;
+        let primary_index = PrimaryIndex::load(root_path, table)?;
+        Ok(PartFiles { root_path: root_path.to_path_buf(), columns, n_blocks, deleted, primary_index, stamps })
+    }
+
+    // Whether the files haven't changed since they were loaded
+    pub(crate) fn is_current(&self) -> bool {
+        self.stamps.are_current()
+    }
+
+    pub(crate) fn n_blocks(&self) -> usize {
+        self.n_blocks
+    }
+}
+
+// Reads a part (the directory holding one copy of each of the table's column files) block group by
+// block group. A block group is the block at the same position in each column's file, which all
+// hold the same rows.
+pub struct PartReader<'a> {
+    table: &'a TableMetaData,
+    files: Arc<PartFiles>,
+    readers: Vec<ColumnReader<'a>>,
+    cache: Option<&'a BlockCache>,
+    stats: Option<&'a QueryStats>,
+}
+
+impl<'a> PartReader<'a> {
+    pub fn open(root_path: &Path, table: &'a TableMetaData) -> Result<PartReader<'a>> {
+        Ok(PartReader::from_files(table, Arc::new(PartFiles::load(root_path, table)?)))
+    }
+
+    // Open a part with the files the cache has for it, loading them if it has none
+    pub(crate) fn open_cached(root_path: &Path, table: &'a TableMetaData, parts: &PartCache) -> Result<PartReader<'a>> {
+        Ok(PartReader::from_files(table, parts.get_or_load(root_path, table)?))
+    }
+
+    pub(crate) fn from_files(table: &'a TableMetaData, files: Arc<PartFiles>) -> PartReader<'a> {
+        let readers = table
+            .columns
+            .iter()
+            .map(|col| ColumnReader { data_path: data_path(&files.root_path, &table.name, &col.name), data_file: None, col })
+            .collect();
+        PartReader { table, files, readers, cache: None, stats: None }
+    }
+
+    // Read blocks through a cache, so blocks read before aren't read from disk again
+    pub fn with_cache(mut self, cache: &'a BlockCache) -> PartReader<'a> {
+        self.cache = Some(cache);
+        self
+    }
+
+    // Count the bytes read and the blocks found in the cache, for EXPLAIN ANALYZE
+    pub fn with_stats(mut self, stats: &'a QueryStats) -> PartReader<'a> {
+        self.stats = Some(stats);
+        self
+    }
+
+    pub fn n_blocks(&self) -> usize {
+        self.files.n_blocks
+    }
+
+    // The number of rows in a block group, including deleted rows. The block is only read if the
+    // index entries don't give it.
+    pub fn n_rows(&mut self, block: usize) -> Result<u64> {
+        match self.indexed_n_rows(block) {
+            Some(n_rows) => Ok(n_rows),
+            None => Ok(self.read_all_rows(block)?.len() as u64),
+        }
+    }
+
+    // The number of rows in a block group if the index entries give it, which they don't when every
+    // column of the block is a String
+    pub fn indexed_n_rows(&self, block: usize) -> Option<u64> {
+        let mut columns = self.files.columns.iter().zip(&self.table.columns);
+        columns.find_map(|(column, col)| block_row_count(&column.index.get(block), &col.dtype))
+    }
+
+    // The compressed size of each column's block in a block group, which is 0 for columns added after
+    // the block was written
+    pub fn compressed_sizes(&self, block: usize) -> Vec<u64> {
+        self.files.columns.iter().map(|column| column.index.get(block).compressed_size).collect()
+    }
+
+    // The index entry of a column's block, reading the block to find its range of values
+    pub fn block_info(&mut self, block: usize, column_name: &str) -> Result<BlockInfo> {
+        let (table_name, cache, stats) = (&self.table.name, self.cache, self.stats);
+        let i = self
+            .table
+            .columns
+            .iter()
+            .position(|col| col.name == column_name)
+            .ok_or(anyhow!("No column {} in table {}", column_name, table_name))?;
+        let index = &self.files.columns[i].index;
+        let entry = index.get(block);
+        let mut info = BlockInfo {
+            block,
+            start_position: entry.start_position,
+            compressed_size: entry.compressed_size,
+            decompressed_size: entry.decompressed_size,
+            n_rows: entry.decompressed_size as usize,
+            range: None,
+        };
+        if entry.is_placeholder() {
+            return Ok(info);
+        }
+        let values = self.readers[i].read_block(table_name, index, block, cache, stats)?;
+        info.n_rows = values.len();
+        for i in 0..values.len() {
+            let value = values.get(i);
+            info.range = Some(match info.range {
+                None => (value.clone(), value),
+                Some((min, max)) => (min.min(value.clone()), max.max(value)),
+            });
+        }
+        Ok(info)
+    }
+
+    pub fn has_deleted_rows(&self) -> bool {
+        !self.files.deleted.is_empty()
+    }
+
+    // The range of values of a column in a block, if the primary index or a skip index gives it
+    pub fn block_range(&self, block: usize, column_name: &str) -> Option<(DValue, DValue)> {
+        if let Some(range) = self.files.primary_index.range(block, column_name) {
+            return Some(range);
+        }
+        let skip_indexes = self.skip_indexes(column_name)?;
+        skip_indexes.iter().find_map(|skip| skip.index.range(skip.entry(block)?))
+    }
+
+    fn skip_indexes(&self, column_name: &str) -> Option<&[SkipIndexReader]> {
+        let i = self.table.columns.iter().position(|col| col.name == column_name)?;
+        Some(&self.files.columns[i].skip_indexes)
+    }
+
+    // Whether the whole part is sorted by the table's ORDER BY, rather than only each block
+    pub fn is_sorted(&self) -> bool {
+        self.files.primary_index.is_sorted(self.files.n_blocks)
+    }
+
+    // Ranges of the columns for one block, for checking filters with Expr::may_match
+    pub fn block_ranges(&self, block: usize) -> BlockRanges<'_> {
+        BlockRanges { reader: self, block }
+    }
+
+    // The rows of a block group that haven't been deleted
+    pub fn read_block_group(&mut self, block: usize) -> Result<Vec<Vec<DValue>>> {
+        Ok(self.read_batch(block)?.rows())
+    }
+
+    // The rows of a block group that haven't been deleted, along with their position in the block
+    pub fn read_block_group_with_positions(&mut self, block: usize) -> Result<Vec<(usize, Vec<DValue>)>> {
+        Ok(self.read_batch(block)?.rows_with_positions())
+    }
+
+    // A block group as column vectors, with the rows that haven't been deleted selected
+    pub fn read_batch(&mut self, block: usize) -> Result<Batch> {
+        let columns = self.read_columns(block)?;
+        let n_rows = columns.first().map_or(0, |column| column.len());
+        let mut selection = Bitmap::new(n_rows, true);
+        for i in 0..n_rows {
+            if self.files.deleted.is_deleted(block, i) {
+                selection.set(i, false);
+            }
+        }
+        Ok(Batch { columns, selection })
+    }
+
+    pub(crate) fn is_deleted(&self, block: usize, row: usize) -> bool {
+        self.files.deleted.is_deleted(block, row)
+    }
+
+    // Every row of a block group, including deleted ones, so their positions match the mask
+    pub(crate) fn read_all_rows(&mut self, block: usize) -> Result<Vec<Vec<DValue>>> {
+        let columns = self.read_columns(block)?;
+        let n_rows = columns.first().map_or(0, |column| column.len());
+        Ok((0..n_rows).map(|i| columns.iter().map(|column| column.get(i)).collect()).collect())
+    }
+
+    fn read_columns(&mut self, block: usize) -> Result<Vec<Arc<ColumnVector>>> {
+        // map over the readers, get all the column data for that reader. Placeholder blocks have no data.
+        let (table_name, cache, stats) = (&self.table.name, self.cache, self.stats);
+        let block_group_columns = self.readers.iter_mut().zip(&self.files.columns).map(|(reader, column)| -> Result<Option<Arc<ColumnVector>>> {
+            if column.index.get(block).is_placeholder() {
+                return Ok(None);
+            }
+            reader.read_block(table_name, &column.index, block, cache, stats).map(Some)
+        }).collect::<Result<Vec<Option<Arc<ColumnVector>>>>>()?;
+
+        let n_rows = match block_group_columns.iter().flatten().next() {
+            Some(col) => col.len(),
+            None => self.files.columns[0].index.get(block).decompressed_size as usize,
+        };
+        if block_group_columns.iter().flatten().any(|col| col.len() != n_rows) {
+            return Err(anyhow!("Blocks in block group have different row counts"));
+        }
+        if block_group_columns.iter().all(|col| col.is_some()) {
+            return Ok(block_group_columns.into_iter().flatten().collect());
+        }
+
+        // fill in the columns that didn't exist when this block was written from their defaults,
+        // which can depend on the other columns of the row
+        let mut filled: Vec<Vec<Option<DValue>>> = vec![Vec::with_capacity(n_rows); self.table.columns.len()];
+        for row_index in 0..n_rows {
+            let named = self.table.columns.iter().zip(block_group_columns.iter()).filter_map(|(col, values)| {
+                values.as_ref().map(|values| (col.name.clone(), values.get(row_index)))
+            }).collect();
+            for (values, value) in filled.iter_mut().zip(self.table.row_from_named(named)?) {
+                values.push(Some(value));
             }
         }
+        block_group_columns
+            .into_iter()
+            .zip(filled)
+            .zip(&self.table.columns)
+            .map(|((column, values), col)| match column {
+                Some(column) => Ok(column),
+                None => ColumnVector::from_values(&values, &col.dtype).map(Arc::new),
+            })
+            .collect()
+    }
+}
+
+// One column's index entry for a block, along with the range of values in the block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockInfo {
+    pub block: usize,
+    pub start_position: u64,
+    pub compressed_size: u64,
+    pub decompressed_size: u64,
+    pub n_rows: usize,
+    // None for blocks written before the column was added, which have no data
+    pub range: Option<(DValue, DValue)>,
+}
+
+pub struct BlockRanges<'a> {
+    reader: &'a PartReader<'a>,
+    block: usize,
+}
+
+impl<'a> BlockRanges<'a> {
+    // The skip index entries for the block of a column, for the skip indexes that have one
+    fn skip_entries(&self, expr: &Expr) -> Vec<(&SkipIndexReader, &SkipEntry)> {
+        let skip_indexes = match expr {
+            Expr::Column(name) => self.reader.skip_indexes(name),
+            _ => None,
+        };
+        skip_indexes
+            .map(|skip_indexes| {
+                skip_indexes
+                    .iter()
+                    .filter_map(|skip| skip.entry(self.block).map(|entry| (skip, entry)))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+impl<'a> Ranges for BlockRanges<'a> {
+    fn range(&self, expr: &Expr) -> Option<(DValue, DValue)> {
+        match expr {
+            Expr::Column(name) => self.reader.block_range(self.block, name),
+            _ => None,
+        }
     }
 
-    return Ok(rows);
+    fn may_equal(&self, expr: &Expr, value: &DValue) -> bool {
+        self.skip_entries(expr).iter().all(|(skip, entry)| skip.index.may_equal(entry, value))
+    }
+
+    fn may_contain(&self, expr: &Expr, needle: &str) -> bool {
+        self.skip_entries(expr).iter().all(|(skip, entry)| skip.index.may_contain(entry, needle))
+    }
+}
+
+// The number of rows in a block, if it can be worked out without reading the block
+fn block_row_count(entry: &IndexEntry, dtype: &DType) -> Option<u64> {
+    match dtype {
+        _ if entry.is_placeholder() => Some(entry.decompressed_size),
+        DType::Uint64 => Some(entry.decompressed_size / 8),
+        DType::String => None,
+    }
+}
+
+pub fn read_all(root_path: &Path, table: &TableMetaData) -> Result<Vec<Vec<DValue>>> {
+    let mut reader = PartReader::open(root_path, table)?;
+    let mut rows = Vec::new();
+    for block in 0..reader.n_blocks() {
+        rows.extend(reader.read_block_group(block)?);
+    }
+    Ok(rows)
 }
 
-fn read_all_from_block_group(rows: &mut Vec<Vec<DValue>>, readers: &mut Vec<ColumnReader>, indexes: Vec<IndexEntry>) -> Result<()> {
-    // map over the readers, get all the column data for that reader
-    let block_group_rows = readers.iter_mut().enumerate().map(|(i, reader)| -> Result<()> {
-        let index_entry = &indexes[i];
-        // load the next block from the data file
-        let mut buffer = vec![0; index_entry.compressed_size as usize];
-        reader.data_file.seek(io::SeekFrom::Start(index_entry.start_position))?;
-        reader.data_file.read_exact(&mut buffer)?;
-        // decompress the data
-        let mut decompress_output = vec![0; index_entry.decompressed_size as usize];
-        lz4_flex::block::decompress_into(&buffer, &mut decompress_output)?;
-        // convert the bytes to DValues
-        let mut block_rows = Vec::new();
-        let mut bytes = decompress_output.as_slice(); 
-        while bytes.len() > 0 {
-            let (n_bytes, dvalue) = read_dvalue_data(bytes, &reader.col.dtype);
-            block_rows.push(dvalue);
-            bytes = &bytes[n_bytes..];
+// Replace all of a part's column files with new ones holding the given rows. The new files are
+// written to a temporary directory, which is renamed once they are all there. That one rename is
+// what commits the rewrite: if moving the files over the old ones is interrupted after it,
+// finish_rewrite moves the rest when the database is next opened, so a part never ends up with some
+// old columns and some new ones.
+pub fn rewrite_part(root_path: &Path, table: &TableMetaData, rows: &[Vec<DValue>]) -> Result<()> {
+    let tmp_path = rewrite_path(root_path, &table.name);
+    if tmp_path.exists() {
+        fs::remove_dir_all(&tmp_path)?;
+    }
+    fs::create_dir_all(&tmp_path).with_context(|| format!("Couldn't create {}", tmp_path.to_string_lossy()))?;
+    create_table_files(&tmp_path, table)?;
+    write_data(&tmp_path, table, rows)?;
+
+    let committed_path = committed_rewrite_path(root_path, &table.name);
+    fs::rename(&tmp_path, &committed_path).with_context(|| format!("Couldn't commit {}", tmp_path.to_string_lossy()))?;
+    finish_rewrite(root_path, table)
+}
+
+// Move the files of a committed rewrite over the old ones, and throw away a rewrite that wasn't
+// committed. Files that were already moved are skipped, so this can be run again if it fails.
+pub(crate) fn finish_rewrite(root_path: &Path, table: &TableMetaData) -> Result<()> {
+    let tmp_path = rewrite_path(root_path, &table.name);
+    if tmp_path.exists() {
+        fs::remove_dir_all(&tmp_path).with_context(|| format!("Couldn't remove {}", tmp_path.to_string_lossy()))?;
+    }
+    let committed_path = committed_rewrite_path(root_path, &table.name);
+    if !committed_path.exists() {
+        return Ok(());
+    }
+
+    let mut paths = vec![(primary_index_path(&committed_path, &table.name), primary_index_path(root_path, &table.name))];
+    for col in &table.columns {
+        paths.push((data_path(&committed_path, &table.name, &col.name), data_path(root_path, &table.name, &col.name)));
+        paths.push((index_path(&committed_path, &table.name, &col.name), index_path(root_path, &table.name, &col.name)));
+        for index in &col.skip_indexes {
+            paths.push((
+                skip_index_path(&committed_path, &table.name, &col.name, index),
+                skip_index_path(root_path, &table.name, &col.name, index),
+            ));
         }
-        Ok(())
-    });
+    }
+    for (from, to) in paths {
+        if from.exists() {
+            fs::rename(&from, &to).with_context(|| format!("Couldn't replace {}", to.to_string_lossy()))?;
+        }
+    }
+    // the deleted rows weren't copied to the new files
+    remove_file_if_exists(&mask_path(root_path, &table.name))?;
+    fs::remove_dir_all(&committed_path).with_context(|| format!("Couldn't remove {}", committed_path.to_string_lossy()))
+}
+
+fn rewrite_path(root_path: &Path, table_name: &str) -> PathBuf {
+    root_path.join(format!("{}.rewrite", table_name))
+}
+
+fn committed_rewrite_path(root_path: &Path, table_name: &str) -> PathBuf {
+    root_path.join(format!("{}.rewritten", table_name))
+}
+
+// Compress one block of a column, returning the compressed bytes and an index entry for them
+fn compress_block(values: &[DValue], col: &ColumnMetaData, start_position: IndexSize) -> Result<(Vec<u8>, IndexEntry)> {
+    if values.is_empty() {
+        return Err(anyhow!("Can't write an empty block"));
+    }
+    let mut buf = Vec::new();
+    for value in values {
+        if get_dtype(value) != col.dtype {
+            return Err(anyhow!("Mismatched data type for column {}", col.name));
+        }
+        write_dvalue_data(&mut buf, value);
+    }
+    let compressed = lz4_flex::block::compress(&buf);
+    let index_entry = IndexEntry {
+        start_position,
+        compressed_size: compressed.len() as IndexSize,
+        decompressed_size: buf.len() as IndexSize,
+    };
+    Ok((compressed, index_entry))
+}
+
+// Replace some blocks of one column, without rewriting the rest of the column or any other column.
+// The new blocks are appended to the data file, and a new copy of the index file pointing at them
+// is staged next to the old one, for commit_staged_indexes to move into place. The old blocks stay
+// in the data file until the part is next merged.
+//
+// Skip index entries for the new blocks are staged too. Until they are committed, the blocks are
+// marked as unknown in the skip indexes, so the old entries can't be used to skip the new blocks.
+pub(crate) fn stage_column_blocks(
+    root_path: &Path,
+    table_name: &str,
+    col: &ColumnMetaData,
+    blocks: &[(usize, Vec<DValue>)],
+) -> Result<()> {
+    let mut index = read_index(root_path, table_name, col)?;
+    let path = data_path(root_path, table_name, &col.name);
+    let mut data_file = open_for_append(&path, FileKind::Data).with_context(|| "Couldn't open data file")?;
+    let mut position = data_file.metadata()?.len();
+
+    for skip_index in &col.skip_indexes {
+        let unknown: Vec<_> = blocks.iter().map(|(block, _)| (*block, None)).collect();
+        append_entry_bytes(&skip_index_path(root_path, table_name, &col.name, skip_index), &entries_to_bytes(skip_index, &unknown))?;
+        let entries: Vec<_> = blocks.iter().map(|(block, values)| (*block, skip_index.build(values))).collect();
+        fs::write(staged_skip_index_path(root_path, table_name, &col.name, skip_index), entries_to_bytes(skip_index, &entries))
+            .with_context(|| "Couldn't write staged skip index file")?;
+    }
+
+    for (block, values) in blocks {
+        let (compressed, index_entry) = compress_block(values, col, position)?;
+        data_file.write_all(&compressed).with_context(|| "Couldn't write compressed data")?;
+        position += compressed.len() as IndexSize;
+        index[*block] = index_entry;
+    }
+    data_file.sync_data()?;
+
+    let mut index_bytes = header(FileKind::Index).to_vec();
+    for index_entry in index {
+        index_bytes.extend_from_slice(&index_entry.to_bytes());
+    }
+    fs::write(staged_index_path(root_path, table_name, &col.name), index_bytes)
+        .with_context(|| "Couldn't write staged index file")
+}
+
+// Mark that every index file of a part has been staged, so they can all be moved into place
+pub(crate) fn finish_staging(root_path: &Path, table_name: &str) -> Result<()> {
+    fs::write(staged_marker_path(root_path, table_name), []).with_context(|| "Couldn't write staged marker")
+}
+
+// Move the staged index files into place, returning whether there were any. If staging didn't
+// finish, the staged files are thrown away instead.
+pub(crate) fn commit_staged_indexes(root_path: &Path, table: &TableMetaData) -> Result<bool> {
+    let marker_path = staged_marker_path(root_path, &table.name);
+    if !marker_path.exists() {
+        for col in &table.columns {
+            remove_file_if_exists(&staged_index_path(root_path, &table.name, &col.name))?;
+            for skip_index in &col.skip_indexes {
+                remove_file_if_exists(&staged_skip_index_path(root_path, &table.name, &col.name, skip_index))?;
+            }
+        }
+        return Ok(false);
+    }
+
+    for col in &table.columns {
+        let staged_path = staged_index_path(root_path, &table.name, &col.name);
+        if staged_path.exists() {
+            fs::rename(&staged_path, index_path(root_path, &table.name, &col.name))
+                .with_context(|| format!("Couldn't replace index file for column {}", col.name))?;
+        }
+        // only once the index points at the new blocks can their skip index entries be used
+        for skip_index in &col.skip_indexes {
+            let staged_path = staged_skip_index_path(root_path, &table.name, &col.name, skip_index);
+            if staged_path.exists() {
+                let entry_bytes = fs::read(&staged_path).with_context(|| "Couldn't read staged skip index file")?;
+                append_entry_bytes(&skip_index_path(root_path, &table.name, &col.name, skip_index), &entry_bytes)?;
+                fs::remove_file(&staged_path).with_context(|| "Couldn't remove staged skip index file")?;
+            }
+        }
+    }
+    fs::remove_file(&marker_path).with_context(|| "Couldn't remove staged marker")?;
+    Ok(true)
+}
+
+fn staged_marker_path(root_path: &Path, table_name: &str) -> PathBuf {
+    root_path.join(format!("{}.staged", table_name))
+}
+
+fn staged_index_path(root_path: &Path, table_name: &str, column_name: &str) -> PathBuf {
+    root_path.join(format!("{}.{}.index.staged", table_name, column_name))
+}
+
+fn read_block(data_file: &mut File, index_entry: &IndexEntry, dtype: &DType) -> Result<ColumnVector> {
+    // load the block from the data file
+    let mut buffer = vec![0; index_entry.compressed_size as usize];
+    data_file.seek(io::SeekFrom::Start(index_entry.start_position))?;
+    data_file.read_exact(&mut buffer)?;
+    // decompress the data
+    let mut decompress_output = vec![0; index_entry.decompressed_size as usize];
+    lz4_flex::block::decompress_into(&buffer, &mut decompress_output)?;
+    ColumnVector::decode(&decompress_output, dtype)
+}
+
+fn read_index(root_path: &Path, table_name: &str, col: &ColumnMetaData) -> Result<Vec<IndexEntry>> {
+    read_index_file(&index_path(root_path, table_name, &col.name))
+}
+
+fn read_index_file(path: &Path) -> Result<Vec<IndexEntry>> {
+    let bytes = match fs::read(path) {
+        // nothing has been written to the column yet
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        result => result.with_context(|| "Couldn't read index file")?,
+    };
+    check_header(&bytes, FileKind::Index, path)?;
+    Ok(bytes[HEADER_SIZE as usize..]
+        .chunks_exact(INDEX_ENTRY_SIZE)
+        .map(|chunk| IndexEntry::from_bytes(chunk.try_into().unwrap()))
+        .collect())
+}
+
+// Add the files for a new column. Existing blocks aren't rewritten, instead the column gets a
+// placeholder index entry for each of them, so they are read as the column default.
+pub fn add_column(root_path: &Path, table: &TableMetaData, col: &ColumnMetaData) -> Result<()> {
+    let mut reader = PartReader::open(root_path, table)?;
+    let row_counts = (0..reader.n_blocks())
+        .map(|block| reader.n_rows(block))
+        .collect::<Result<Vec<u64>>>()?;
+
+    // files left behind by an earlier ALTER can be links to another column's files, so they are
+    // removed rather than written over
+    drop_column(root_path, &table.name, col)?;
+
+    fs::write(data_path(root_path, &table.name, &col.name), header(FileKind::Data))
+        .with_context(|| "Couldn't create data file")?;
+    let mut index_bytes = header(FileKind::Index).to_vec();
+    for row_count in row_counts {
+        index_bytes.extend_from_slice(&IndexEntry::placeholder(row_count).to_bytes());
+    }
+    fs::write(index_path(root_path, &table.name, &col.name), index_bytes)
+        .with_context(|| "Couldn't write index file")?;
+    // nothing is known about the placeholder blocks, so the skip indexes start empty
+    for skip_index in &col.skip_indexes {
+        fs::write(skip_index_path(root_path, &table.name, &col.name, skip_index), header(FileKind::Skip))
+            .with_context(|| "Couldn't create skip index file")?;
+    }
     Ok(())
 }
 
-fn index_path(root_path: &PathBuf, table_name: &String, column_name: &String) -> PathBuf {
+// Create empty files for every column, so a new table can be read before anything is written to it
+pub fn create_table_files(root_path: &Path, table: &TableMetaData) -> Result<()> {
+    for col in &table.columns {
+        let mut paths = vec![
+            (data_path(root_path, &table.name, &col.name), FileKind::Data),
+            (index_path(root_path, &table.name, &col.name), FileKind::Index),
+        ];
+        for index in &col.skip_indexes {
+            paths.push((skip_index_path(root_path, &table.name, &col.name, index), FileKind::Skip));
+        }
+        for (path, kind) in paths {
+            fs::write(&path, header(kind)).with_context(|| format!("Couldn't create {}", path.to_string_lossy()))?;
+        }
+    }
+    if !table.primary_key_columns().is_empty() {
+        let path = primary_index_path(root_path, &table.name);
+        fs::write(&path, header(FileKind::Primary)).with_context(|| format!("Couldn't create {}", path.to_string_lossy()))?;
+    }
+    Ok(())
+}
+
+pub fn remove_table_files(root_path: &Path, table: &TableMetaData) -> Result<()> {
+    for col in &table.columns {
+        drop_column(root_path, &table.name, col)?;
+    }
+    remove_file_if_exists(&primary_index_path(root_path, &table.name))?;
+    remove_file_if_exists(&mask_path(root_path, &table.name))
+}
+
+pub fn drop_column(root_path: &Path, table_name: &str, col: &ColumnMetaData) -> Result<()> {
+    let mut paths = vec![
+        data_path(root_path, table_name, &col.name),
+        index_path(root_path, table_name, &col.name),
+        staged_index_path(root_path, table_name, &col.name),
+    ];
+    for index in &col.skip_indexes {
+        paths.push(skip_index_path(root_path, table_name, &col.name, index));
+        paths.push(staged_skip_index_path(root_path, table_name, &col.name, index));
+    }
+    for path in paths {
+        remove_file_if_exists(&path)?;
+    }
+    Ok(())
+}
+
+// Give a column's files their names under the new column name as well, by hard linking them. The
+// old names are removed with drop_column once nothing refers to them, so the column can be read
+// under either name until then.
+pub fn link_column(root_path: &Path, table_name: &str, col: &ColumnMetaData, to: &str) -> Result<()> {
+    let from = &col.name;
+    let mut paths = vec![
+        (data_path(root_path, table_name, from), data_path(root_path, table_name, to)),
+        (index_path(root_path, table_name, from), index_path(root_path, table_name, to)),
+    ];
+    for index in &col.skip_indexes {
+        paths.push((skip_index_path(root_path, table_name, from, index), skip_index_path(root_path, table_name, to, index)));
+    }
+    for (from_path, to_path) in paths {
+        if from_path.exists() {
+            // left behind by an earlier attempt that failed
+            remove_file_if_exists(&to_path)?;
+            fs::hard_link(&from_path, &to_path)
+                .with_context(|| format!("Couldn't link {}", from_path.to_string_lossy()))?;
+        }
+    }
+    Ok(())
+}
+
+fn remove_file_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("Couldn't remove {}", path.to_string_lossy()))
+        }
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn index_path(root_path: &Path, table_name: &str, column_name: &str) -> PathBuf {
     root_path.join(format!("{}.{}.index", table_name, column_name))
 }
 
-fn data_path(root_path: &PathBuf, table_name: &String, column_name: &String) -> PathBuf {
+pub(crate) fn data_path(root_path: &Path, table_name: &str, column_name: &str) -> PathBuf {
     root_path.join(format!("{}.{}.data", table_name, column_name))
 }
 
-fn write_dvalue_data(bytes: &mut Vec<u8>, value: &DValue) {
+pub(crate) fn write_dvalue_data(bytes: &mut Vec<u8>, value: &DValue) {
     match value {
         DValue::String(s) => {
             // use length prefixed format, with a u32 for string length
@@ -375,7 +1153,7 @@ fn write_dvalue_data(bytes: &mut Vec<u8>, value: &DValue) {
     };
 }
 
-fn read_dvalue_data(bytes: &[u8], dtype: &DType) -> (usize, DValue) {
+pub(crate) fn read_dvalue_data(bytes: &[u8], dtype: &DType) -> (usize, DValue) {
     match dtype {
         DType::String => {
             let length_bytes: [u8; 4] = bytes[0..4].try_into().unwrap();
@@ -393,3 +1171,25 @@ fn read_dvalue_data(bytes: &[u8], dtype: &DType) -> (usize, DValue) {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_index_entry_to_bytes() {
+        let entry = IndexEntry {
+            start_position: 1,
+            compressed_size: 2,
+            decompressed_size: 3,
+        };
+        let bytes = entry.to_bytes();
+        let expected: [u8; INDEX_ENTRY_SIZE] = [
+            0, 0, 0, 0, 0, 0, 0, 1,
+            0, 0, 0, 0, 0, 0, 0, 2,
+            0, 0, 0, 0, 0, 0, 0, 3,
+        ];
+        assert_eq!(bytes, expected);
+        assert_eq!(IndexEntry::from_bytes(&bytes), entry);
+        assert!(IndexEntry::placeholder(5).is_placeholder());
+    }
+}
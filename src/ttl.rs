@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::delete::DeletedMask;
+use crate::expr::{Expr, TableRow};
+use crate::metadata::{ColumnMetaData, TableMetaData, TtlRule};
+use crate::partition::{part_paths, pruned_part_paths};
+use crate::storage::{rewrite_part, PartReader};
+use crate::{BinOp, DValue};
+
+impl TtlRule {
+    // Matches rows that have expired at `now`
+    pub fn expired_filter(&self, now: u64) -> Expr {
+        match now.checked_sub(self.interval) {
+            Some(cutoff) => Expr::binary(self.expr.clone(), BinOp::LtEq, Expr::literal(DValue::Uint64(cutoff))),
+            None => Expr::literal(DValue::Uint64(0)),
+        }
+    }
+
+    // Matches rows that haven't expired yet at `now`
+    pub fn live_filter(&self, now: u64) -> Expr {
+        match now.checked_sub(self.interval) {
+            Some(cutoff) => Expr::binary(self.expr.clone(), BinOp::Gt, Expr::literal(DValue::Uint64(cutoff))),
+            None => Expr::literal(DValue::Uint64(1)),
+        }
+    }
+}
+
+// A part is rewritten without its expired rows once at least 1 in this many of its rows are deleted
+const REWRITE_DELETED_FRACTION: u64 = 2;
+
+// Delete the expired rows of a table and reset expired columns to their defaults.
+//
+// Whole partitions are dropped without being read if the partition info shows every row in them has
+// expired. Otherwise expired rows are marked as deleted, like DB::delete_where, which for blocks the
+// index shows have expired doesn't need them to be read. A part is only rewritten when expired
+// columns have to be reset, or when most of its rows are deleted.
+pub fn apply_ttl(root_path: &Path, table: &TableMetaData, now: u64) -> Result<()> {
+    if table.ttl.is_none() && table.columns.iter().all(|col| col.ttl.is_none()) {
+        return Ok(());
+    }
+
+    let mut parts = part_paths(root_path, table)?;
+    if let (Some(ttl), Some(_)) = (&table.ttl, &table.partition_by) {
+        let live_parts = pruned_part_paths(root_path, table, Some(&ttl.live_filter(now)))?;
+        for part_path in parts.iter().filter(|part_path| !live_parts.contains(part_path)) {
+            fs::remove_dir_all(part_path)
+                .with_context(|| format!("Couldn't remove {}", part_path.to_string_lossy()))?;
+        }
+        parts = live_parts;
+    }
+
+    for part_path in parts {
+        apply_ttl_to_part(&part_path, table, now)?;
+    }
+    Ok(())
+}
+
+fn apply_ttl_to_part(part_path: &Path, table: &TableMetaData, now: u64) -> Result<()> {
+    let mut reader = PartReader::open(part_path, table)?;
+    let table_ttl = table.ttl.as_ref().map(|ttl| (ttl.live_filter(now), ttl.expired_filter(now)));
+    let column_ttls: Vec<(usize, Expr)> = table
+        .columns
+        .iter()
+        .enumerate()
+        .filter_map(|(i, col)| col.ttl.as_ref().map(|ttl| (i, ttl.expired_filter(now))))
+        .collect();
+
+    // work out what could have expired from the index, before reading anything
+    struct BlockStatus {
+        all_expired: bool,
+        table_may_expire: bool,
+        columns_may_expire: Vec<usize>,
+    }
+    let statuses: Vec<BlockStatus> = (0..reader.n_blocks())
+        .map(|block| {
+            let ranges = reader.block_ranges(block);
+            BlockStatus {
+                all_expired: table_ttl.as_ref().is_some_and(|(live, _)| !live.may_match(&ranges)),
+                table_may_expire: table_ttl.as_ref().is_some_and(|(_, expired)| expired.may_match(&ranges)),
+                columns_may_expire: column_ttls
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, (_, expired))| expired.may_match(&ranges))
+                    .map(|(i, _)| i)
+                    .collect(),
+            }
+        })
+        .collect();
+    if statuses.iter().all(|status| !status.table_may_expire && status.columns_may_expire.is_empty()) {
+        return Ok(());
+    }
+
+    // expired rows are marked as deleted, and rows with expired columns are replaced
+    let mut deletes = DeletedMask::default();
+    let mut reset_rows = HashMap::new();
+    for (block, status) in statuses.iter().enumerate() {
+        if status.all_expired {
+            for row_index in 0..reader.n_rows(block)? as usize {
+                deletes.delete(block, row_index);
+            }
+            continue;
+        }
+        if !status.table_may_expire && status.columns_may_expire.is_empty() {
+            continue;
+        }
+        for (row_index, row) in reader.read_block_group_with_positions(block)? {
+            let table_row = TableRow { columns: &table.columns, values: &row };
+            if status.table_may_expire && table_ttl.as_ref().unwrap().1.matches(&table_row)? {
+                deletes.delete(block, row_index);
+                continue;
+            }
+
+            let mut reset_row = row.clone();
+            for &i in &status.columns_may_expire {
+                let (col_index, expired) = &column_ttls[i];
+                if expired.matches(&table_row)? {
+                    reset_row[*col_index] = reset_value(table, *col_index, &row)?;
+                }
+            }
+            // columns that were already reset don't need rewriting again
+            if reset_row != row {
+                reset_rows.insert((block, row_index), reset_row);
+            }
+        }
+    }
+
+    let mut deleted = DeletedMask::load(part_path, &table.name)?;
+    let n_deleted_before = deleted.n_deleted();
+    deleted.merge(&deletes);
+    if deleted.n_deleted() == n_deleted_before && reset_rows.is_empty() {
+        return Ok(());
+    }
+    let n_rows = (0..reader.n_blocks()).map(|block| reader.n_rows(block)).sum::<Result<u64>>()?;
+    if deleted.n_deleted() == n_rows && table.partition_by.is_some() {
+        return fs::remove_dir_all(part_path)
+            .with_context(|| format!("Couldn't remove {}", part_path.to_string_lossy()));
+    }
+    // the rows left are only rewritten when there are reset columns to write, or when enough rows
+    // are deleted that scans would mostly be reading deleted rows
+    if reset_rows.is_empty() && deleted.n_deleted() * REWRITE_DELETED_FRACTION < n_rows {
+        return deletes.append_to(part_path, &table.name);
+    }
+
+    let mut rows = Vec::new();
+    for (block, status) in statuses.iter().enumerate() {
+        if status.all_expired {
+            continue;
+        }
+        for (row_index, row) in reader.read_block_group_with_positions(block)? {
+            if deleted.is_deleted(block, row_index) {
+                continue;
+            }
+            rows.push(reset_rows.remove(&(block, row_index)).unwrap_or(row));
+        }
+    }
+    rewrite_part(part_path, table, &rows)
+}
+
+// The value an expired column is reset to: its default, with now() being the time the column
+// expired. That way resetting an already reset column gives the same value again, and the part
+// doesn't get rewritten every time the TTL is applied.
+fn reset_value(table: &TableMetaData, col_index: usize, row: &[DValue]) -> Result<DValue> {
+    let col = &table.columns[col_index];
+    let ttl = col.ttl.as_ref().unwrap();
+    let table_row = TableRow { columns: &table.columns, values: row };
+    let expired_at = match ttl.expr.eval(&table_row)? {
+        DValue::Uint64(timestamp) => timestamp.saturating_add(ttl.interval),
+        value => return Err(anyhow!("TTL of column {} is not a Uint64: {:?}", col.name, value)),
+    };
+    let col = ColumnMetaData { default: col.default.as_ref().map(|default| default.with_now(expired_at)), ..col.clone() };
+    let named = table
+        .columns
+        .iter()
+        .zip(row)
+        .enumerate()
+        .filter(|(i, _)| *i != col_index)
+        .map(|(_, (col, value))| (col.name.clone(), value.clone()))
+        .collect();
+    col.default_value(&named)
+}
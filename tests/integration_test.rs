@@ -4,7 +4,12 @@ mod tests {
     use tempdir::TempDir;
 
     extern crate rtcdb;
-    use rtcdb::{ColumnMetaData, DType, TableMetaData, DB, DValue};
+    use std::collections::HashMap;
+
+    use rtcdb::{AggregateFunction, Aggregation, AlterOperation, BinOp, ColumnMetaData, DType, Expr, Function, MaterializedView, SkipIndex, TableEngine, TableMetaData, TableRow, TtlRule, ViewAggregate, DB, DValue};
+    use rtcdb::export::ExportFormat;
+    use rtcdb::import::{CsvOptions, JsonlOptions};
+    use rtcdb::storage::PartReader;
 
     const TEST_TABLE_NAME: &str = "events";
     fn get_test_tables () -> Vec<TableMetaData> {
@@ -26,6 +31,19 @@ mod tests {
         let db_init = DB::init(tmp_dir.path(), tables.clone()).unwrap();
 
         assert_eq!(db_init.tables, tables);
+        assert!(tmp_dir.path().join("events.event.data").exists());
+        assert!(tmp_dir.path().join("events.id.index").exists());
+        assert!(DB::open(tmp_dir.path()).unwrap().read_all(TEST_TABLE_NAME).unwrap().is_empty());
+    }
+
+    #[test]
+    #[named]
+    fn test_init_existing_db() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        DB::init(tmp_dir.path(), get_test_tables()).unwrap();
+
+        assert!(DB::init(tmp_dir.path(), vec![]).is_err());
+        assert_eq!(DB::open(tmp_dir.path()).unwrap().tables, get_test_tables());
     }
 
 
@@ -45,7 +63,7 @@ mod tests {
         let tmp_dir = TempDir::new(function_name!()).unwrap();
         let db = DB::init(tmp_dir.path(), get_test_tables()).unwrap();
 
-        db.write_data(&TEST_TABLE_NAME.to_string(), &vec![
+        db.write_data(TEST_TABLE_NAME, &[
             vec![
                 DValue::String("test".to_string()),
                 DValue::Uint64(123),
@@ -59,4 +77,1716 @@ mod tests {
         ]).unwrap()
 
     }
+
+    #[test]
+    #[named]
+    fn test_read_all() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let db = DB::init(tmp_dir.path(), get_test_tables()).unwrap();
+
+        let rows: Vec<Vec<DValue>> = (0..3000).map(|i| vec![
+            DValue::String(format!("event{}", i % 7)),
+            DValue::Uint64(i),
+            DValue::Uint64(i * 2),
+        ]).collect();
+        db.write_data(TEST_TABLE_NAME, &rows).unwrap();
+
+        assert_eq!(db.read_all(TEST_TABLE_NAME).unwrap(), rows);
+    }
+
+    #[test]
+    #[named]
+    fn test_write_named_with_defaults() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let tables = vec![TableMetaData::new(
+            TEST_TABLE_NAME,
+            vec![
+                ColumnMetaData::new("event", DType::String),
+                ColumnMetaData::new("timestamp", DType::Uint64).with_default(Expr::Now),
+                ColumnMetaData::new("source", DType::String)
+                    .with_default(Expr::literal(DValue::String("web".to_string()))),
+                ColumnMetaData::new("event_upper", DType::String)
+                    .with_default(Expr::function(Function::Upper, vec![Expr::column("event")])),
+                ColumnMetaData::new("next_id", DType::Uint64)
+                    .with_default(Expr::binary(Expr::column("id"), BinOp::Add, Expr::literal(DValue::Uint64(1)))),
+                ColumnMetaData::new("id", DType::Uint64),
+            ],
+        )];
+        let db = DB::init(tmp_dir.path(), tables).unwrap();
+
+        db.write_named(TEST_TABLE_NAME, vec![HashMap::from([
+            ("event".to_string(), DValue::String("click".to_string())),
+            ("id".to_string(), DValue::Uint64(41)),
+            ("source".to_string(), DValue::String("ios".to_string())),
+        ])]).unwrap();
+        db.write_columns(TEST_TABLE_NAME, &["id", "event", "timestamp"], &[vec![
+            DValue::Uint64(1),
+            DValue::String("view".to_string()),
+            DValue::Uint64(1000),
+        ]]).unwrap();
+
+        let rows = db.read_all(TEST_TABLE_NAME).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert!(matches!(rows[0][1], DValue::Uint64(t) if t > 1_600_000_000));
+        assert_eq!(rows[0][2..], [
+            DValue::String("ios".to_string()),
+            DValue::String("CLICK".to_string()),
+            DValue::Uint64(42),
+            DValue::Uint64(41),
+        ]);
+        assert_eq!(rows[1], vec![
+            DValue::String("view".to_string()),
+            DValue::Uint64(1000),
+            DValue::String("web".to_string()),
+            DValue::String("VIEW".to_string()),
+            DValue::Uint64(2),
+            DValue::Uint64(1),
+        ]);
+    }
+
+    #[test]
+    #[named]
+    fn test_write_named_errors() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let db = DB::init(tmp_dir.path(), get_test_tables()).unwrap();
+
+        // unknown column
+        assert!(db.write_columns(TEST_TABLE_NAME, &["nope"], &[vec![DValue::Uint64(1)]]).is_err());
+        // wrong number of values
+        assert!(db.write_columns(TEST_TABLE_NAME, &["id", "event"], &[vec![DValue::Uint64(1)]]).is_err());
+        // missing columns without a default use the zero value for the type
+        db.write_columns(TEST_TABLE_NAME, &["id"], &[vec![DValue::Uint64(7)]]).unwrap();
+        assert_eq!(db.read_all(TEST_TABLE_NAME).unwrap(), vec![vec![
+            DValue::String("".to_string()),
+            DValue::Uint64(0),
+            DValue::Uint64(7),
+        ]]);
+    }
+
+    #[test]
+    #[named]
+    fn test_alter_table_add_column() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let mut db = DB::init(tmp_dir.path(), get_test_tables()).unwrap();
+
+        let old_rows: Vec<Vec<DValue>> = (0..1500).map(|i| vec![
+            DValue::String("old".to_string()),
+            DValue::Uint64(i),
+            DValue::Uint64(i),
+        ]).collect();
+        db.write_data(TEST_TABLE_NAME, &old_rows).unwrap();
+
+        db.alter_table(TEST_TABLE_NAME, &[
+            AlterOperation::AddColumn(ColumnMetaData::new("source", DType::String)
+                .with_default(Expr::literal(DValue::String("web".to_string())))),
+            AlterOperation::AddColumn(ColumnMetaData::new("id_plus_one", DType::Uint64)
+                .with_default(Expr::binary(Expr::column("id"), BinOp::Add, Expr::literal(DValue::Uint64(1))))),
+        ]).unwrap();
+        db.write_data(TEST_TABLE_NAME, &[vec![
+            DValue::String("new".to_string()),
+            DValue::Uint64(5000),
+            DValue::Uint64(5000),
+            DValue::String("ios".to_string()),
+            DValue::Uint64(0),
+        ]]).unwrap();
+
+        let db = DB::open(tmp_dir.path()).unwrap();
+        let rows = db.read_all(TEST_TABLE_NAME).unwrap();
+        assert_eq!(rows.len(), 1501);
+        assert_eq!(rows[1499], vec![
+            DValue::String("old".to_string()),
+            DValue::Uint64(1499),
+            DValue::Uint64(1499),
+            DValue::String("web".to_string()),
+            DValue::Uint64(1500),
+        ]);
+        assert_eq!(rows[1500][3..], [DValue::String("ios".to_string()), DValue::Uint64(0)]);
+    }
+
+    #[test]
+    #[named]
+    fn test_alter_table_drop_and_rename_column() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let mut db = DB::init(tmp_dir.path(), get_test_tables()).unwrap();
+        db.write_data(TEST_TABLE_NAME, &[vec![
+            DValue::String("test".to_string()),
+            DValue::Uint64(123),
+            DValue::Uint64(456),
+        ]]).unwrap();
+
+        db.alter_table(TEST_TABLE_NAME, &[
+            AlterOperation::DropColumn("timestamp".to_string()),
+            AlterOperation::RenameColumn { from: "id".to_string(), to: "event_id".to_string() },
+        ]).unwrap();
+        assert!(!tmp_dir.path().join("events.timestamp.data").exists());
+        assert!(tmp_dir.path().join("events.event_id.index").exists());
+
+        let db = DB::open(tmp_dir.path()).unwrap();
+        assert_eq!(db.tables[0].columns, vec![
+            ColumnMetaData::new("event", DType::String),
+            ColumnMetaData::new("event_id", DType::Uint64),
+        ]);
+        assert_eq!(db.read_all(TEST_TABLE_NAME).unwrap(), vec![vec![
+            DValue::String("test".to_string()),
+            DValue::Uint64(456),
+        ]]);
+    }
+
+    #[test]
+    #[named]
+    fn test_alter_table_errors() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let mut db = DB::init(tmp_dir.path(), get_test_tables()).unwrap();
+
+        assert!(db.alter_table(TEST_TABLE_NAME, &[AlterOperation::AddColumn(ColumnMetaData::new("id", DType::Uint64))]).is_err());
+        assert!(db.alter_table(TEST_TABLE_NAME, &[AlterOperation::DropColumn("nope".to_string())]).is_err());
+        assert!(db.alter_table(TEST_TABLE_NAME, &[
+            AlterOperation::RenameColumn { from: "id".to_string(), to: "event".to_string() },
+        ]).is_err());
+        assert_eq!(db.tables, get_test_tables());
+    }
+
+    #[test]
+    #[named]
+    fn test_alter_table_failure() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let mut db = DB::init(tmp_dir.path(), vec![get_partitioned_table()]).unwrap();
+        let rows = get_partitioned_rows();
+        db.write_data(TEST_TABLE_NAME, &rows).unwrap();
+
+        // a directory in the way of one partition's new file makes the rename fail part way through
+        let mut partitions: Vec<_> = std::fs::read_dir(tmp_dir.path().join("events/partitions")).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        partitions.sort();
+        let blocker = partitions.last().unwrap().join("events.event_id.data");
+        std::fs::create_dir(&blocker).unwrap();
+        let rename = [AlterOperation::RenameColumn { from: "id".to_string(), to: "event_id".to_string() }];
+        assert!(db.alter_table(TEST_TABLE_NAME, &rename).is_err());
+
+        let reopened = DB::open(tmp_dir.path()).unwrap();
+        assert_eq!(reopened.tables, vec![get_partitioned_table()]);
+        let mut read = reopened.read_all(TEST_TABLE_NAME).unwrap();
+        read.sort();
+        let mut expected = rows.clone();
+        expected.sort();
+        assert_eq!(read, expected);
+
+        std::fs::remove_dir(&blocker).unwrap();
+        db.alter_table(TEST_TABLE_NAME, &rename).unwrap();
+        let mut read = DB::open(tmp_dir.path()).unwrap().read_all(TEST_TABLE_NAME).unwrap();
+        read.sort();
+        assert_eq!(read, expected);
+        assert!(partitions.iter().all(|partition| !partition.join("events.id.data").exists()));
+    }
+
+    #[test]
+    #[named]
+    fn test_create_drop_and_truncate_table() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let mut db = DB::init(tmp_dir.path(), get_test_tables()).unwrap();
+        let people = TableMetaData::new("people", vec![ColumnMetaData::new("name", DType::String)]);
+
+        db.create_table(people.clone()).unwrap();
+        assert!(db.create_table(people.clone()).is_err());
+        db.write_data("people", &[vec![DValue::String("robbie".to_string())]]).unwrap();
+        db.write_data(TEST_TABLE_NAME, &[vec![
+            DValue::String("test".to_string()),
+            DValue::Uint64(123),
+            DValue::Uint64(456),
+        ]]).unwrap();
+        assert_eq!(DB::open(tmp_dir.path()).unwrap().tables, vec![get_test_tables()[0].clone(), people]);
+
+        db.truncate_table("people").unwrap();
+        assert!(db.read_all("people").unwrap().is_empty());
+
+        db.drop_table(TEST_TABLE_NAME).unwrap();
+        assert!(db.drop_table(TEST_TABLE_NAME).is_err());
+        assert!(!tmp_dir.path().join("events.event.data").exists());
+        let db = DB::open(tmp_dir.path()).unwrap();
+        assert_eq!(db.tables.len(), 1);
+        assert!(db.read_all(TEST_TABLE_NAME).is_err());
+    }
+
+    #[test]
+    #[named]
+    fn test_upgrade_from_v0() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let path = tmp_dir.path();
+
+        // a database as written before format versions existed: no version in the metadata and no file headers
+        std::fs::write(path.join("metadata.json"),
+            r#"{"tables": [{"name": "events", "columns": [{"name": "id", "dtype": "Uint64"}]}]}"#).unwrap();
+        let mut data = Vec::new();
+        let mut index = Vec::new();
+        for block in [[1u64, 2], [3, 4]] {
+            let decompressed: Vec<u8> = block.iter().flat_map(|u| u.to_be_bytes()).collect();
+            let compressed = lz4_flex::block::compress(&decompressed);
+            index.extend_from_slice(&(data.len() as u64).to_be_bytes());
+            index.extend_from_slice(&(compressed.len() as u64).to_be_bytes());
+            index.extend_from_slice(&(decompressed.len() as u64).to_be_bytes());
+            index.extend_from_slice(&block[0].to_be_bytes());
+            index.extend_from_slice(&block[1].to_be_bytes());
+            data.extend_from_slice(&compressed);
+        }
+        std::fs::write(path.join("events.id.data"), data).unwrap();
+        std::fs::write(path.join("events.id.index"), index).unwrap();
+
+        let err = DB::open(path).unwrap_err();
+        assert!(err.to_string().contains("DB::upgrade"), "{}", err);
+
+        let db = DB::upgrade(path).unwrap();
+        // the index entries no longer have the min and max
+        assert_eq!(std::fs::metadata(path.join("events.id.index")).unwrap().len(), 8 + 2 * 24);
+        // running it again is a no-op
+        let db_again = DB::upgrade(path).unwrap();
+        assert_eq!(db, db_again);
+        db.write_data(TEST_TABLE_NAME, &[vec![DValue::Uint64(5)]]).unwrap();
+        assert_eq!(DB::open(path).unwrap().read_all(TEST_TABLE_NAME).unwrap(), (1..=5).map(|i| vec![DValue::Uint64(i)]).collect::<Vec<_>>());
+    }
+
+    // Set the format version of the metadata and of every file under path
+    fn set_format_version(path: &std::path::Path, version: u16) {
+        let meta_path = path.join("metadata.json");
+        let mut meta: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&meta_path).unwrap()).unwrap();
+        meta["format_version"] = serde_json::json!(version);
+        std::fs::write(&meta_path, meta.to_string()).unwrap();
+        fn set_headers(dir: &std::path::Path, version: u16) {
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    set_headers(&path, version);
+                    continue;
+                }
+                let mut bytes = std::fs::read(&path).unwrap();
+                if bytes.starts_with(b"RTCD") {
+                    bytes[4..6].copy_from_slice(&version.to_be_bytes());
+                    std::fs::write(&path, bytes).unwrap();
+                }
+            }
+        }
+        set_headers(path, version);
+    }
+
+    #[test]
+    #[named]
+    fn test_upgrade_partitioned_from_v4() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let db = DB::init(tmp_dir.path(), vec![get_partitioned_table()]).unwrap();
+        db.write_data(TEST_TABLE_NAME, &get_partitioned_rows()).unwrap();
+        db.detach_partition(TEST_TABLE_NAME, &DValue::Uint64(202402)).unwrap();
+        set_format_version(tmp_dir.path(), 4);
+        assert!(DB::open(tmp_dir.path()).is_err());
+
+        // detached partitions are upgraded too, so they can be attached again
+        let db = DB::upgrade(tmp_dir.path()).unwrap();
+        db.attach_partition(TEST_TABLE_NAME, &DValue::Uint64(202402)).unwrap();
+        assert_eq!(db.read_all(TEST_TABLE_NAME).unwrap().len(), 30);
+    }
+
+    #[test]
+    #[named]
+    fn test_open_newer_format() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        DB::init(tmp_dir.path(), get_test_tables()).unwrap();
+
+        let meta_path = tmp_dir.path().join("metadata.json");
+        let mut meta: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&meta_path).unwrap()).unwrap();
+        meta["format_version"] = serde_json::json!(rtcdb::format::FORMAT_VERSION + 1);
+        std::fs::write(&meta_path, meta.to_string()).unwrap();
+
+        let err = DB::open(tmp_dir.path()).unwrap_err();
+        assert!(err.to_string().contains("only supports up to"), "{}", err);
+        assert!(DB::upgrade(tmp_dir.path()).is_err());
+    }
+
+    fn get_partitioned_table() -> TableMetaData {
+        get_test_tables()[0].clone()
+            .with_partition_by(Expr::function(Function::ToYYYYMM, vec![Expr::column("timestamp")]))
+    }
+
+    fn get_partitioned_rows() -> Vec<Vec<DValue>> {
+        // 2024-01-01, 2024-02-01 and 2024-03-01, 10 rows each
+        [1704067200, 1706745600, 1709251200].iter().flat_map(|start| (0..10).map(move |i| vec![
+            DValue::String("test".to_string()),
+            DValue::Uint64(start + i * 3600),
+            DValue::Uint64(i),
+        ])).collect()
+    }
+
+    #[test]
+    #[named]
+    fn test_partitioned_write_and_scan() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let db = DB::init(tmp_dir.path(), vec![get_partitioned_table()]).unwrap();
+        let rows = get_partitioned_rows();
+        db.write_data(TEST_TABLE_NAME, &rows).unwrap();
+
+        let partitions_path = tmp_dir.path().join("events").join("partitions");
+        assert!(partitions_path.join("202401").join("events.timestamp.data").exists());
+        assert!(partitions_path.join("202403").join("events.timestamp.data").exists());
+        assert_eq!(db.read_all(TEST_TABLE_NAME).unwrap(), rows);
+
+        // break the January partition, so the scan can only succeed if it is pruned. The files are
+        // changed behind the handle's back, so open a new one that hasn't cached them.
+        std::fs::remove_file(partitions_path.join("202401").join("events.id.index")).unwrap();
+        let db = DB::open(tmp_dir.path()).unwrap();
+        let filter = Expr::binary(Expr::column("timestamp"), BinOp::GtEq, Expr::literal(DValue::Uint64(1706745600 + 5 * 3600)))
+            .and(Expr::binary(Expr::column("id"), BinOp::NotEq, Expr::literal(DValue::Uint64(9))));
+        assert_eq!(db.scan(TEST_TABLE_NAME, Some(&filter)).unwrap(), [&rows[15..19], &rows[20..29]].concat());
+        let filter = Expr::binary(
+            Expr::function(Function::ToYYYYMM, vec![Expr::column("timestamp")]), BinOp::Eq, Expr::literal(DValue::Uint64(202402)));
+        assert_eq!(db.scan(TEST_TABLE_NAME, Some(&filter)).unwrap(), rows[10..20]);
+        assert!(db.read_all(TEST_TABLE_NAME).is_err());
+    }
+
+    #[test]
+    #[named]
+    fn test_drop_detach_attach_partition() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let db = DB::init(tmp_dir.path(), vec![get_partitioned_table()]).unwrap();
+        let rows = get_partitioned_rows();
+        db.write_data(TEST_TABLE_NAME, &rows).unwrap();
+
+        db.drop_partition(TEST_TABLE_NAME, &DValue::Uint64(202401)).unwrap();
+        assert!(db.drop_partition(TEST_TABLE_NAME, &DValue::Uint64(202401)).is_err());
+        db.detach_partition(TEST_TABLE_NAME, &DValue::Uint64(202402)).unwrap();
+        assert_eq!(db.read_all(TEST_TABLE_NAME).unwrap(), rows[20..]);
+
+        db.attach_partition(TEST_TABLE_NAME, &DValue::Uint64(202402)).unwrap();
+        assert_eq!(db.read_all(TEST_TABLE_NAME).unwrap(), rows[10..]);
+
+        db.truncate_table(TEST_TABLE_NAME).unwrap();
+        assert!(db.read_all(TEST_TABLE_NAME).unwrap().is_empty());
+        db.write_data(TEST_TABLE_NAME, &rows[..1]).unwrap();
+        assert_eq!(db.read_all(TEST_TABLE_NAME).unwrap(), rows[..1]);
+
+        let other_dir = TempDir::new(function_name!()).unwrap();
+        let unpartitioned = DB::init(other_dir.path(), get_test_tables()).unwrap();
+        assert!(unpartitioned.drop_partition(TEST_TABLE_NAME, &DValue::Uint64(1)).is_err());
+    }
+
+    fn now() -> u64 {
+        std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    #[test]
+    #[named]
+    fn test_apply_ttl() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let day = 24 * 60 * 60;
+        let table = TableMetaData::new(TEST_TABLE_NAME, vec![
+            ColumnMetaData::new("timestamp", DType::Uint64),
+            ColumnMetaData::new("email", DType::String)
+                .with_default(Expr::literal(DValue::String("redacted".to_string())))
+                .with_ttl(TtlRule::days("timestamp", 30)),
+        ]).with_ttl(TtlRule::days("timestamp", 90));
+        // the TTL has to be a timestamp from columns that exist
+        for ttl in [TtlRule::days("email", 1), TtlRule::days("missing", 1)] {
+            assert!(DB::init(tmp_dir.path(), vec![table.clone().with_ttl(ttl.clone())]).is_err());
+            let mut column_ttl = table.clone();
+            column_ttl.columns[1].ttl = Some(ttl);
+            assert!(DB::init(tmp_dir.path(), vec![column_ttl]).is_err());
+        }
+        let db = DB::init(tmp_dir.path(), vec![table]).unwrap();
+
+        let now = now();
+        let row = |days_ago: u64| vec![DValue::Uint64(now - days_ago * day), DValue::String("a@b.com".to_string())];
+        // one block that has all expired, then one that is partly expired
+        db.write_data(TEST_TABLE_NAME, &(0..10).map(|_| row(100)).collect::<Vec<_>>()).unwrap();
+        db.write_data(TEST_TABLE_NAME, &[row(95), row(60), row(1)]).unwrap();
+
+        db.apply_ttl().unwrap();
+        let rows = db.read_all(TEST_TABLE_NAME).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0][1], DValue::String("redacted".to_string()));
+        assert_eq!(rows[1], row(1));
+
+        // nothing else has expired, so running it again doesn't change anything
+        db.apply_ttl().unwrap();
+        assert_eq!(db.read_all(TEST_TABLE_NAME).unwrap(), rows);
+    }
+
+    #[test]
+    #[named]
+    fn test_apply_ttl_without_rewriting() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let day = 24 * 60 * 60;
+        let table = TableMetaData::new(TEST_TABLE_NAME, vec![
+            ColumnMetaData::new("timestamp", DType::Uint64),
+            ColumnMetaData::new("seen_at", DType::Uint64)
+                .with_default(Expr::Now)
+                .with_ttl(TtlRule::days("timestamp", 30)),
+        ]).with_ttl(TtlRule::days("timestamp", 90));
+        let db = DB::init(tmp_dir.path(), vec![table]).unwrap();
+
+        let now = now();
+        let mut rows: Vec<Vec<DValue>> = (0..100).map(|i| vec![DValue::Uint64(now - i), DValue::Uint64(now)]).collect();
+        rows[0] = vec![DValue::Uint64(now - 100 * day), DValue::Uint64(now)];
+        db.write_data(TEST_TABLE_NAME, &rows).unwrap();
+
+        // one expired row is only marked as deleted
+        let data_path = tmp_dir.path().join("events.timestamp.data");
+        let data = std::fs::read(&data_path).unwrap();
+        db.apply_ttl().unwrap();
+        assert_eq!(std::fs::read(&data_path).unwrap(), data);
+        assert!(tmp_dir.path().join("events.deleted").exists());
+        assert_eq!(db.read_all(TEST_TABLE_NAME).unwrap().len(), 99);
+
+        // an expired column is reset to its default as of when it expired, so it's only rewritten once
+        let expired_at = now - 60 * day;
+        db.write_data(TEST_TABLE_NAME, &[vec![DValue::Uint64(expired_at), DValue::Uint64(now)]]).unwrap();
+        db.apply_ttl().unwrap();
+        let reset = DValue::Uint64(expired_at + 30 * day);
+        assert!(db.read_all(TEST_TABLE_NAME).unwrap().iter().any(|row| row[1] == reset));
+        assert!(!tmp_dir.path().join("events.deleted").exists());
+        let data = std::fs::read(&data_path).unwrap();
+        db.apply_ttl().unwrap();
+        assert_eq!(std::fs::read(&data_path).unwrap(), data);
+        assert_eq!(db.read_all(TEST_TABLE_NAME).unwrap().len(), 100);
+    }
+
+    #[test]
+    #[named]
+    fn test_apply_ttl_drops_partitions() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let table = get_partitioned_table().with_ttl(TtlRule::days("timestamp", 1));
+        let db = DB::init(tmp_dir.path(), vec![table]).unwrap();
+        let mut rows = get_partitioned_rows();
+        rows.push(vec![DValue::String("new".to_string()), DValue::Uint64(now()), DValue::Uint64(1)]);
+        db.write_data(TEST_TABLE_NAME, &rows).unwrap();
+
+        db.apply_ttl().unwrap();
+        let partitions_path = tmp_dir.path().join("events").join("partitions");
+        assert!(!partitions_path.join("202401").exists());
+        assert_eq!(std::fs::read_dir(&partitions_path).unwrap().count(), 1);
+        assert_eq!(db.read_all(TEST_TABLE_NAME).unwrap(), rows[30..]);
+    }
+
+    #[test]
+    #[named]
+    fn test_delete_where() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let db = DB::init(tmp_dir.path(), get_test_tables()).unwrap();
+        let rows: Vec<Vec<DValue>> = (0..3000).map(|i| vec![
+            DValue::String(format!("user{}", i % 3)),
+            DValue::Uint64(i),
+            DValue::Uint64(i),
+        ]).collect();
+        db.write_data(TEST_TABLE_NAME, &rows).unwrap();
+
+        let filter = Expr::binary(Expr::column("event"), BinOp::Eq, Expr::literal(DValue::String("user1".to_string())));
+        assert_eq!(db.delete_where(TEST_TABLE_NAME, &filter).unwrap(), 1000);
+        // deleting again doesn't find the rows that are already deleted
+        assert_eq!(db.delete_where(TEST_TABLE_NAME, &filter).unwrap(), 0);
+        let filter = Expr::binary(Expr::column("timestamp"), BinOp::Lt, Expr::literal(DValue::Uint64(3)));
+        assert_eq!(db.delete_where(TEST_TABLE_NAME, &filter).unwrap(), 2);
+
+        let expected: Vec<Vec<DValue>> = rows.iter().skip(3).filter(|row| row[0] != DValue::String("user1".to_string())).cloned().collect();
+        assert_eq!(db.read_all(TEST_TABLE_NAME).unwrap(), expected);
+        let mask_path = tmp_dir.path().join("events.deleted");
+        assert!(mask_path.exists());
+
+        // merging drops the deleted rows from disk
+        db.optimize_table(TEST_TABLE_NAME).unwrap();
+        assert!(!mask_path.exists());
+        assert_eq!(DB::open(tmp_dir.path()).unwrap().read_all(TEST_TABLE_NAME).unwrap(), expected);
+    }
+
+    #[test]
+    #[named]
+    fn test_delete_where_partitioned() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let db = DB::init(tmp_dir.path(), vec![get_partitioned_table()]).unwrap();
+        let rows = get_partitioned_rows();
+        db.write_data(TEST_TABLE_NAME, &rows).unwrap();
+
+        let filter = Expr::binary(Expr::column("id"), BinOp::Eq, Expr::literal(DValue::Uint64(0)));
+        assert_eq!(db.delete_where(TEST_TABLE_NAME, &filter).unwrap(), 3);
+        // comparing a column to a value of another type is an error, rather than matching nothing
+        let mismatched = Expr::binary(Expr::column("id"), BinOp::Eq, Expr::literal(DValue::String("1".to_string())));
+        assert!(db.delete_where(TEST_TABLE_NAME, &mismatched).is_err());
+        assert!(db.scan(TEST_TABLE_NAME, Some(&mismatched)).is_err());
+        assert!(db.scan_final(TEST_TABLE_NAME, Some(&mismatched)).is_err());
+        assert_eq!(db.scan(TEST_TABLE_NAME, Some(&filter)).unwrap(), Vec::<Vec<DValue>>::new());
+        assert_eq!(db.read_all(TEST_TABLE_NAME).unwrap().len(), 27);
+    }
+
+    #[test]
+    #[named]
+    fn test_interrupted_rewrite() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let db = DB::init(tmp_dir.path(), get_test_tables()).unwrap();
+        db.write_data(TEST_TABLE_NAME, &(0..3).map(|i| event_row("old", i, i)).collect::<Vec<_>>()).unwrap();
+        db.delete_where(TEST_TABLE_NAME, &Expr::binary(Expr::column("id"), BinOp::Eq, Expr::literal(DValue::Uint64(0)))).unwrap();
+
+        // the files a merge would have written, as if it stopped after committing and moving one column
+        let new_dir = TempDir::new(&format!("{}_new", function_name!())).unwrap();
+        let new_rows: Vec<Vec<DValue>> = (0..2).map(|i| event_row("new", i, i)).collect();
+        DB::init(new_dir.path(), get_test_tables()).unwrap().write_data(TEST_TABLE_NAME, &new_rows).unwrap();
+        let committed = tmp_dir.path().join("events.rewritten");
+        std::fs::create_dir(&committed).unwrap();
+        for col in ["event", "timestamp", "id"] {
+            for ext in ["data", "index"] {
+                let name = format!("events.{}.{}", col, ext);
+                let to = if col == "event" { tmp_dir.path().join(&name) } else { committed.join(&name) };
+                std::fs::copy(new_dir.path().join(&name), to).unwrap();
+            }
+        }
+        // a rewrite that hadn't been committed is thrown away
+        std::fs::create_dir(tmp_dir.path().join("events.rewrite")).unwrap();
+
+        let db = DB::open(tmp_dir.path()).unwrap();
+        assert_eq!(db.read_all(TEST_TABLE_NAME).unwrap(), new_rows);
+        assert!(!committed.exists());
+        assert!(!tmp_dir.path().join("events.rewrite").exists());
+        assert!(!tmp_dir.path().join("events.deleted").exists());
+    }
+
+    #[test]
+    #[named]
+    fn test_update_where() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let mut db = DB::init(tmp_dir.path(), get_test_tables()).unwrap();
+        let rows: Vec<Vec<DValue>> = (0..3000).map(|i| vec![
+            DValue::String("test".to_string()),
+            DValue::Uint64(i),
+            DValue::Uint64(i),
+        ]).collect();
+        db.write_data(TEST_TABLE_NAME, &rows).unwrap();
+        db.delete_where(TEST_TABLE_NAME, &Expr::binary(Expr::column("timestamp"), BinOp::Eq, Expr::literal(DValue::Uint64(2500)))).unwrap();
+        let event_data = std::fs::read(tmp_dir.path().join("events.event.data")).unwrap();
+        let id_data_len = std::fs::metadata(tmp_dir.path().join("events.id.data")).unwrap().len();
+
+        // only the last block group has matching rows
+        let filter = Expr::binary(Expr::column("timestamp"), BinOp::GtEq, Expr::literal(DValue::Uint64(2990)));
+        let id = db.update_where(TEST_TABLE_NAME, &[
+            ("id", Expr::binary(Expr::column("id"), BinOp::Mul, Expr::literal(DValue::Uint64(10)))),
+        ], &filter).unwrap();
+        assert!(!db.mutations[0].is_done);
+        // the mutation is only applied when the mutations are run, which can be after a restart
+        assert_eq!(db.read_all(TEST_TABLE_NAME).unwrap().len(), 2999);
+        let mut db = DB::open(tmp_dir.path()).unwrap();
+        db.run_mutations().unwrap();
+        assert!(DB::open(tmp_dir.path()).unwrap().mutations.iter().all(|mutation| mutation.id == id && mutation.is_done));
+
+        let expected: Vec<Vec<DValue>> = rows.iter().filter(|row| row[1] != DValue::Uint64(2500)).map(|row| match row[1] {
+            DValue::Uint64(t) if t >= 2990 => vec![row[0].clone(), row[1].clone(), DValue::Uint64(t * 10)],
+            _ => row.clone(),
+        }).collect();
+        assert_eq!(db.read_all(TEST_TABLE_NAME).unwrap(), expected);
+        // the other columns weren't rewritten, and only one block of the updated column was
+        assert_eq!(std::fs::read(tmp_dir.path().join("events.event.data")).unwrap(), event_data);
+        let new_id_data_len = std::fs::metadata(tmp_dir.path().join("events.id.data")).unwrap().len();
+        assert!(new_id_data_len > id_data_len && new_id_data_len < id_data_len * 2);
+
+        db.optimize_table(TEST_TABLE_NAME).unwrap();
+        assert_eq!(db.read_all(TEST_TABLE_NAME).unwrap(), expected);
+    }
+
+    #[test]
+    #[named]
+    fn test_update_where_resumes() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let mut db = DB::init(tmp_dir.path(), vec![get_partitioned_table()]).unwrap();
+        let rows = get_partitioned_rows();
+        db.write_data(TEST_TABLE_NAME, &rows).unwrap();
+
+        assert!(db.update_where(TEST_TABLE_NAME, &[("timestamp", Expr::literal(DValue::Uint64(0)))], &Expr::literal(DValue::Uint64(1))).is_err());
+        db.update_where(TEST_TABLE_NAME, &[("event", Expr::literal(DValue::String("fixed".to_string())))], &Expr::literal(DValue::Uint64(1))).unwrap();
+        // pretend the first partition was done before being interrupted
+        db.mutations[0].parts_done.push(std::path::Path::new("events").join("partitions").join("202401").to_string_lossy().to_string());
+        // rows written after the mutation was created aren't updated, in an existing partition or a new one
+        db.write_data(TEST_TABLE_NAME, &[event_row("late", 1706745600, 100), event_row("late", 1714521600, 101)]).unwrap();
+        // and the parts can't be merged or removed until it has run
+        assert!(db.optimize_table(TEST_TABLE_NAME).is_err());
+        assert!(db.drop_partition(TEST_TABLE_NAME, &DValue::Uint64(202402)).is_err());
+        db.run_mutations().unwrap();
+
+        let events: Vec<DValue> = db.read_all(TEST_TABLE_NAME).unwrap().into_iter().map(|row| row[0].clone()).collect();
+        let string = |s: &str| DValue::String(s.to_string());
+        assert_eq!(events[..10], vec![string("test"); 10]);
+        assert_eq!(events[10..20], vec![string("fixed"); 10]);
+        assert_eq!(events[20], string("late"));
+        assert_eq!(events[21..31], vec![string("fixed"); 10]);
+        assert_eq!(events[31], string("late"));
+        db.optimize_table(TEST_TABLE_NAME).unwrap();
+    }
+
+    #[test]
+    #[named]
+    fn test_interrupted_write() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let table = get_skip_index_table().with_order_by(&["id"]);
+        let db = DB::init(tmp_dir.path(), vec![table.clone()]).unwrap();
+        let row = |event: &str, id: u64| vec![DValue::String(event.to_string()), DValue::String("user".to_string()), DValue::Uint64(id)];
+        let old_rows: Vec<Vec<DValue>> = (0..2000).map(|i| row("old", i)).collect();
+        db.write_data(TEST_TABLE_NAME, &old_rows).unwrap();
+
+        // the files a write would have left if it stopped after writing the first column's block, and
+        // part way through the second column's index entry
+        let done_dir = TempDir::new(&format!("{}_done", function_name!())).unwrap();
+        for entry in std::fs::read_dir(tmp_dir.path()).unwrap() {
+            let entry = entry.unwrap();
+            std::fs::copy(entry.path(), done_dir.path().join(entry.file_name())).unwrap();
+        }
+        DB::open(done_dir.path()).unwrap().write_data(TEST_TABLE_NAME, &(0..1000).map(|i| row("lost", 5000 + i)).collect::<Vec<_>>()).unwrap();
+        for entry in std::fs::read_dir(done_dir.path()).unwrap() {
+            let entry = entry.unwrap();
+            if entry.file_name().to_string_lossy().starts_with("events.event.") {
+                std::fs::copy(entry.path(), tmp_dir.path().join(entry.file_name())).unwrap();
+            }
+        }
+        let index_path = tmp_dir.path().join("events.distinct_id.index");
+        let mut index = std::fs::read(&index_path).unwrap();
+        index.extend_from_slice(&[0; 12]);
+        std::fs::write(&index_path, index).unwrap();
+
+        // the next write starts from the blocks every column has
+        let db = DB::open(tmp_dir.path()).unwrap();
+        let new_rows: Vec<Vec<DValue>> = (0..1000).map(|i| row("new", 3000 + i)).collect();
+        db.write_data(TEST_TABLE_NAME, &new_rows).unwrap();
+        assert_eq!(db.read_all(TEST_TABLE_NAME).unwrap(), [old_rows, new_rows].concat());
+        let event_eq = |event: &str| Expr::binary(Expr::column("event"), BinOp::Eq, Expr::literal(DValue::String(event.to_string())));
+        assert_eq!(db.scan(TEST_TABLE_NAME, Some(&event_eq("new"))).unwrap().len(), 1000);
+        assert!(db.scan(TEST_TABLE_NAME, Some(&event_eq("lost"))).unwrap().is_empty());
+        let id_eq = Expr::binary(Expr::column("id"), BinOp::Eq, Expr::literal(DValue::Uint64(3500)));
+        assert_eq!(db.scan(TEST_TABLE_NAME, Some(&id_eq)).unwrap().len(), 1);
+        db.optimize_table(TEST_TABLE_NAME).unwrap();
+        assert_eq!(db.read_all(TEST_TABLE_NAME).unwrap().len(), 3000);
+    }
+
+    #[test]
+    #[named]
+    fn test_update_where_interrupted_after_staging() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let mut db = DB::init(tmp_dir.path(), get_test_tables()).unwrap();
+        let rows: Vec<Vec<DValue>> = (0..2000).map(|i| event_row("test", i, i)).collect();
+        db.write_data(TEST_TABLE_NAME, &rows).unwrap();
+        let filter = Expr::binary(Expr::column("id"), BinOp::Lt, Expr::literal(DValue::Uint64(10)));
+        db.update_where(TEST_TABLE_NAME, &[("id", Expr::binary(Expr::column("id"), BinOp::Add, Expr::literal(DValue::Uint64(5000))))], &filter).unwrap();
+
+        // the files the mutation stages, as if it stopped after staging the part but before moving
+        // the new index into place
+        let done_dir = TempDir::new(&format!("{}_done", function_name!())).unwrap();
+        for entry in std::fs::read_dir(tmp_dir.path()).unwrap() {
+            let entry = entry.unwrap();
+            std::fs::copy(entry.path(), done_dir.path().join(entry.file_name())).unwrap();
+        }
+        DB::open(done_dir.path()).unwrap().run_mutations().unwrap();
+        std::fs::copy(done_dir.path().join("events.id.data"), tmp_dir.path().join("events.id.data")).unwrap();
+        std::fs::copy(done_dir.path().join("events.id.index"), tmp_dir.path().join("events.id.index.staged")).unwrap();
+        std::fs::write(tmp_dir.path().join("events.staged"), []).unwrap();
+
+        // the staged index is moved into place before anything else writes to the part
+        let mut db = DB::open(tmp_dir.path()).unwrap();
+        assert!(!tmp_dir.path().join("events.staged").exists());
+        assert_eq!(db.mutations[0].parts_done, vec!["".to_string()]);
+        let new_rows: Vec<Vec<DValue>> = (2000..3000).map(|i| event_row("new", i, i)).collect();
+        db.write_data(TEST_TABLE_NAME, &new_rows).unwrap();
+        db.run_mutations().unwrap();
+        db.optimize_table(TEST_TABLE_NAME).unwrap();
+
+        let expected: Vec<Vec<DValue>> = rows.iter().chain(&new_rows).map(|row| match row[2] {
+            DValue::Uint64(id) if id < 10 => vec![row[0].clone(), row[1].clone(), DValue::Uint64(id + 5000)],
+            _ => row.clone(),
+        }).collect();
+        assert_eq!(db.read_all(TEST_TABLE_NAME).unwrap(), expected);
+    }
+
+    #[test]
+    #[named]
+    fn test_mutation_errors() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let mut db = DB::init(tmp_dir.path(), get_test_tables()).unwrap();
+        let rows: Vec<Vec<DValue>> = (0..3000).map(|i| event_row("test", i, i)).collect();
+        db.write_data(TEST_TABLE_NAME, &rows).unwrap();
+        db.delete_where(TEST_TABLE_NAME, &Expr::binary(Expr::column("timestamp"), BinOp::Eq, Expr::literal(DValue::Uint64(0)))).unwrap();
+        let all = Expr::literal(DValue::Uint64(1));
+
+        // the assignments are type-checked when the mutation is created
+        assert!(db.update_where(TEST_TABLE_NAME, &[("event", Expr::column("id"))], &all).is_err());
+        assert!(db.update_where(TEST_TABLE_NAME, &[("id", Expr::literal(DValue::String("1".to_string())))], &all).is_err());
+
+        // the deleted row would divide by zero
+        let divide = Expr::binary(Expr::literal(DValue::Uint64(6000)), BinOp::Div, Expr::column("timestamp"));
+        db.update_where(TEST_TABLE_NAME, &[("id", divide)], &all).unwrap();
+        db.run_mutations().unwrap();
+        assert_eq!(db.read_all(TEST_TABLE_NAME).unwrap()[..2], [event_row("test", 1, 6000), event_row("test", 2, 3000)]);
+
+        // a mutation that fails blocks the table until it is dropped
+        let id = db.update_where(TEST_TABLE_NAME, &[("id", Expr::binary(Expr::column("id"), BinOp::Sub, Expr::literal(DValue::Uint64(10))))], &all).unwrap();
+        assert!(db.run_mutations().is_err());
+        let add_column = [AlterOperation::AddColumn(ColumnMetaData::new("source", DType::String))];
+        assert!(db.alter_table(TEST_TABLE_NAME, &add_column).is_err());
+        db.drop_mutation(id).unwrap();
+        assert!(db.drop_mutation(id).is_err());
+        assert!(DB::open(tmp_dir.path()).unwrap().mutations.iter().all(|mutation| mutation.is_done));
+        db.alter_table(TEST_TABLE_NAME, &add_column).unwrap();
+        db.run_mutations().unwrap();
+        assert_eq!(db.read_all(TEST_TABLE_NAME).unwrap().len(), 2999);
+
+        // the columns the engine combines rows by can't be updated
+        let tmp_dir = TempDir::new(&format!("{}_replacing", function_name!())).unwrap();
+        let mut db = DB::init(tmp_dir.path(), vec![get_replacing_table()]).unwrap();
+        assert!(db.update_where(TEST_TABLE_NAME, &[("id", Expr::literal(DValue::Uint64(0)))], &all).is_err());
+        assert!(db.update_where(TEST_TABLE_NAME, &[("timestamp", Expr::literal(DValue::Uint64(0)))], &all).is_err());
+        db.update_where(TEST_TABLE_NAME, &[("event", Expr::literal(DValue::String("fixed".to_string())))], &all).unwrap();
+    }
+
+    fn get_replacing_table() -> TableMetaData {
+        get_test_tables()[0].clone().with_engine(TableEngine::replacing(&["id"], Some("timestamp")))
+    }
+
+    fn event_row(event: &str, timestamp: u64, id: u64) -> Vec<DValue> {
+        vec![DValue::String(event.to_string()), DValue::Uint64(timestamp), DValue::Uint64(id)]
+    }
+
+    #[test]
+    #[named]
+    fn test_replacing_engine() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let db = DB::init(tmp_dir.path(), vec![get_replacing_table()]).unwrap();
+        db.write_data(TEST_TABLE_NAME, &[event_row("a", 20, 2), event_row("b", 10, 1), event_row("c", 10, 3)]).unwrap();
+        // a retried write of id 1, a newer version of id 2 and an older version of id 3
+        db.write_data(TEST_TABLE_NAME, &[event_row("b2", 10, 1), event_row("a2", 30, 2), event_row("c2", 5, 3)]).unwrap();
+
+        // without FINAL every row is returned until the table is merged
+        assert_eq!(db.read_all(TEST_TABLE_NAME).unwrap().len(), 6);
+        let expected = vec![event_row("b2", 10, 1), event_row("a2", 30, 2), event_row("c", 10, 3)];
+        assert_eq!(db.scan_final(TEST_TABLE_NAME, None).unwrap(), expected);
+        let filter = Expr::binary(Expr::column("event"), BinOp::Eq, Expr::literal(DValue::String("a".to_string())));
+        assert!(db.scan_final(TEST_TABLE_NAME, Some(&filter)).unwrap().is_empty());
+        let filter = Expr::binary(Expr::column("id"), BinOp::GtEq, Expr::literal(DValue::Uint64(2)));
+        assert_eq!(db.scan_final(TEST_TABLE_NAME, Some(&filter)).unwrap(), expected[1..]);
+
+        db.optimize_table(TEST_TABLE_NAME).unwrap();
+        assert_eq!(db.read_all(TEST_TABLE_NAME).unwrap(), expected);
+        assert_eq!(db.scan_final(TEST_TABLE_NAME, None).unwrap(), expected);
+    }
+
+    #[test]
+    #[named]
+    fn test_replacing_engine_with_order_by() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let table = get_test_tables()[0].clone().with_engine(TableEngine::replacing(&["id"], None)).with_order_by(&["event"]);
+        let db = DB::init(tmp_dir.path(), vec![table]).unwrap();
+        // sorted by event, "a" would come before "z", but it was written last
+        db.write_data(TEST_TABLE_NAME, &[event_row("z", 10, 1), event_row("a", 10, 1), event_row("m", 10, 2)]).unwrap();
+        db.write_data(TEST_TABLE_NAME, &[event_row("b", 10, 2)]).unwrap();
+
+        let expected = vec![event_row("a", 10, 1), event_row("b", 10, 2)];
+        assert_eq!(db.scan_final(TEST_TABLE_NAME, None).unwrap(), expected);
+        db.optimize_table(TEST_TABLE_NAME).unwrap();
+        assert_eq!(db.read_all(TEST_TABLE_NAME).unwrap(), expected);
+    }
+
+    #[test]
+    #[named]
+    fn test_replacing_engine_errors() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let table = get_test_tables()[0].clone().with_engine(TableEngine::replacing(&["missing"], None));
+        assert!(DB::init(tmp_dir.path(), vec![table]).is_err());
+        let table = get_test_tables()[0].clone().with_engine(TableEngine::replacing(&[], None));
+        assert!(DB::init(tmp_dir.path(), vec![table]).is_err());
+
+        let mut db = DB::init(tmp_dir.path(), vec![get_replacing_table()]).unwrap();
+        assert!(db.alter_table(TEST_TABLE_NAME, &[AlterOperation::DropColumn("id".to_string())]).is_err());
+        assert!(db.alter_table(TEST_TABLE_NAME, &[AlterOperation::RenameColumn { from: "timestamp".to_string(), to: "t".to_string() }]).is_err());
+        db.alter_table(TEST_TABLE_NAME, &[AlterOperation::DropColumn("event".to_string())]).unwrap();
+    }
+
+    fn get_hourly_table() -> TableMetaData {
+        TableMetaData::new("hourly", vec![
+            ColumnMetaData::new("event", DType::String),
+            ColumnMetaData::new("hour", DType::Uint64),
+            ColumnMetaData::new("count", DType::Uint64),
+            ColumnMetaData::new("max_id", DType::Uint64),
+        ]).with_engine(TableEngine::aggregating(&["event", "hour"], &[("count", AggregateFunction::Count), ("max_id", AggregateFunction::Max)]))
+    }
+
+    fn get_hourly_view() -> MaterializedView {
+        MaterializedView::new("hourly_view", TEST_TABLE_NAME, "hourly")
+            .with_key("event", Expr::column("event"))
+            .with_key("hour", Expr::function(Function::ToStartOfHour, vec![Expr::column("timestamp")]))
+            .with_aggregate(ViewAggregate::count("count"))
+            .with_aggregate(ViewAggregate::new("max_id", AggregateFunction::Max, Expr::column("id")))
+    }
+
+    #[test]
+    #[named]
+    fn test_materialized_view() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let mut db = DB::init(tmp_dir.path(), vec![get_test_tables()[0].clone(), get_hourly_table()]).unwrap();
+        // rows written before the view exists aren't aggregated
+        db.write_data(TEST_TABLE_NAME, &[event_row("click", 0, 100)]).unwrap();
+        db.create_view(get_hourly_view()).unwrap();
+
+        let rows: Vec<Vec<DValue>> = (0..3000).map(|i| event_row(if i % 3 == 0 { "view" } else { "click" }, i * 3, i)).collect();
+        db.write_data(TEST_TABLE_NAME, &rows[..1000]).unwrap();
+        let db = DB::open(tmp_dir.path()).unwrap();
+        db.write_data(TEST_TABLE_NAME, &rows[1000..]).unwrap();
+
+        let hourly_row = |event: &str, hour: u64, count: u64, max_id: u64| vec![
+            DValue::String(event.to_string()), DValue::Uint64(hour), DValue::Uint64(count), DValue::Uint64(max_id),
+        ];
+        let expected = vec![
+            hourly_row("click", 0, 800, 1199),
+            hourly_row("click", 3600, 800, 2399),
+            hourly_row("click", 7200, 400, 2999),
+            hourly_row("view", 0, 400, 1197),
+            hourly_row("view", 3600, 400, 2397),
+            hourly_row("view", 7200, 200, 2997),
+        ];
+        // each write aggregated its own rows, so there is one row per key per write until a merge
+        assert_eq!(db.read_all("hourly").unwrap().len(), 8);
+        assert_eq!(db.scan_final("hourly", None).unwrap(), expected);
+        db.optimize_table("hourly").unwrap();
+        assert_eq!(db.read_all("hourly").unwrap(), expected);
+    }
+
+    #[test]
+    #[named]
+    fn test_materialized_view_errors() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let mut db = DB::init(tmp_dir.path(), vec![get_test_tables()[0].clone(), get_hourly_table()]).unwrap();
+
+        let bad_state = get_hourly_table().with_engine(TableEngine::aggregating(&["event"], &[("event", AggregateFunction::Count)]));
+        assert!(db.create_table(TableMetaData { name: "bad".to_string(), ..bad_state }).is_err());
+        assert!(db.create_view(MaterializedView::new("v", TEST_TABLE_NAME, "hourly")).is_err());
+        assert!(db.create_view(get_hourly_view().with_aggregate(ViewAggregate::count("missing"))).is_err());
+        assert!(db.create_view(MaterializedView { target: TEST_TABLE_NAME.to_string(), ..get_hourly_view() }).is_err());
+
+        // every view on the table is checked, not just the first
+        let counts = TableMetaData::new("counts", vec![
+            ColumnMetaData::new("event", DType::String),
+            ColumnMetaData::new("count", DType::Uint64),
+        ]).with_engine(TableEngine::aggregating(&["event"], &[("count", AggregateFunction::Count)]));
+        db.create_table(counts).unwrap();
+        db.create_view(MaterializedView::new("counts_view", TEST_TABLE_NAME, "counts")
+            .with_key("event", Expr::column("event"))
+            .with_aggregate(ViewAggregate::count("count"))).unwrap();
+        db.create_view(get_hourly_view()).unwrap();
+        assert!(db.create_view(get_hourly_view()).is_err());
+        assert!(db.drop_table("hourly").is_err());
+        assert!(db.alter_table(TEST_TABLE_NAME, &[AlterOperation::DropColumn("id".to_string())]).is_err());
+        assert!(db.alter_table(TEST_TABLE_NAME, &[
+            AlterOperation::RenameColumn { from: "timestamp".to_string(), to: "ts".to_string() },
+        ]).is_err());
+        db.alter_table(TEST_TABLE_NAME, &[AlterOperation::AddColumn(ColumnMetaData::new("source", DType::String))]).unwrap();
+
+        db.drop_view("hourly_view").unwrap();
+        assert!(db.drop_view("hourly_view").is_err());
+        db.drop_table("hourly").unwrap();
+        db.alter_table(TEST_TABLE_NAME, &[AlterOperation::DropColumn("id".to_string())]).unwrap();
+        db.drop_view("counts_view").unwrap();
+        assert!(DB::open(tmp_dir.path()).unwrap().views.is_empty());
+    }
+
+    fn get_skip_index_table() -> TableMetaData {
+        TableMetaData::new(TEST_TABLE_NAME, vec![
+            ColumnMetaData::new("event", DType::String)
+                .with_skip_index(SkipIndex::ngram_bloom(3, 512, 2))
+                .with_skip_index(SkipIndex::MinMax),
+            ColumnMetaData::new("distinct_id", DType::String).with_skip_index(SkipIndex::bloom(1024, 3)),
+            ColumnMetaData::new("id", DType::Uint64).with_skip_index(SkipIndex::set(10)),
+        ])
+    }
+
+    // The number of blocks the filter can't rule out
+    fn blocks_to_read(db: &DB, filter: &Expr) -> usize {
+        let table = db.tables.iter().find(|table| table.name == TEST_TABLE_NAME).unwrap();
+        let reader = PartReader::open(&db.path, table).unwrap();
+        (0..reader.n_blocks()).filter(|&block| filter.may_match(&reader.block_ranges(block))).count()
+    }
+
+    #[test]
+    #[named]
+    fn test_skip_indexes() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let db = DB::init(tmp_dir.path(), vec![get_skip_index_table()]).unwrap();
+        let rows: Vec<Vec<DValue>> = (0..4096u64).map(|i| vec![
+            DValue::String(format!("{} event in block {}", ["page_view", "click"][i as usize % 2], i / 1024)),
+            DValue::String(format!("user{}", (i * 7919) % 4096)),
+            DValue::Uint64(i / 1024 * 100 + i % 3),
+        ]).collect();
+        db.write_data(TEST_TABLE_NAME, &rows).unwrap();
+        assert!(tmp_dir.path().join("events.distinct_id.bloom").exists());
+
+        let eq = |col: &str, value: DValue| Expr::binary(Expr::column(col), BinOp::Eq, Expr::literal(value));
+        let contains = |needle: &str| Expr::function(Function::Contains, vec![Expr::column("event"), Expr::literal(DValue::String(needle.to_string()))]);
+        let filter = eq("distinct_id", DValue::String("user123".to_string()));
+        assert_eq!(blocks_to_read(&db, &filter), 1);
+        assert_eq!(db.scan(TEST_TABLE_NAME, Some(&filter)).unwrap().len(), 1);
+        assert_eq!(blocks_to_read(&db, &eq("id", DValue::Uint64(201))), 1);
+        assert_eq!(blocks_to_read(&db, &eq("id", DValue::Uint64(150))), 0);
+        assert_eq!(blocks_to_read(&db, &contains("block 3")), 1);
+        assert_eq!(db.scan(TEST_TABLE_NAME, Some(&contains("click event in block 3"))).unwrap().len(), 512);
+        assert_eq!(blocks_to_read(&db, &contains("no such event")), 0);
+        // the block index only has the first 8 bytes of strings, which are the same in every block
+        let filter = Expr::binary(Expr::column("event"), BinOp::Gt, Expr::literal(DValue::String("page_view event in block 2".to_string())));
+        assert_eq!(blocks_to_read(&db, &filter), 1);
+
+        // the updated blocks can't be skipped using their old entries
+        let mut db = DB::open(tmp_dir.path()).unwrap();
+        db.update_where(TEST_TABLE_NAME, &[("distinct_id", Expr::literal(DValue::String("updated".to_string())))], &eq("id", DValue::Uint64(0))).unwrap();
+        db.run_mutations().unwrap();
+        let filter = eq("distinct_id", DValue::String("updated".to_string()));
+        assert_eq!(blocks_to_read(&db, &filter), 1);
+        assert_eq!(db.scan(TEST_TABLE_NAME, Some(&filter)).unwrap().len(), 342);
+
+        db.alter_table(TEST_TABLE_NAME, &[AlterOperation::RenameColumn { from: "distinct_id".to_string(), to: "user".to_string() }]).unwrap();
+        assert!(tmp_dir.path().join("events.user.bloom").exists());
+        assert_eq!(blocks_to_read(&db, &eq("user", DValue::String("user123".to_string()))), 1);
+    }
+
+    #[test]
+    #[named]
+    fn test_skip_index_errors() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let table = |col: ColumnMetaData| TableMetaData::new(TEST_TABLE_NAME, vec![col]);
+        assert!(DB::init(tmp_dir.path(), vec![table(ColumnMetaData::new("id", DType::Uint64).with_skip_index(SkipIndex::ngram_bloom(3, 64, 2)))]).is_err());
+        assert!(DB::init(tmp_dir.path(), vec![table(ColumnMetaData::new("id", DType::Uint64).with_skip_index(SkipIndex::bloom(0, 2)))]).is_err());
+        let twice = ColumnMetaData::new("id", DType::Uint64).with_skip_index(SkipIndex::set(3)).with_skip_index(SkipIndex::set(5));
+        assert!(DB::init(tmp_dir.path(), vec![table(twice)]).is_err());
+
+        let mut db = DB::init(tmp_dir.path(), get_test_tables()).unwrap();
+        let bad = ColumnMetaData::new("source", DType::Uint64).with_skip_index(SkipIndex::ngram_bloom(3, 64, 2));
+        assert!(db.alter_table(TEST_TABLE_NAME, &[AlterOperation::AddColumn(bad)]).is_err());
+    }
+
+    fn get_ordered_table() -> TableMetaData {
+        get_test_tables()[0].clone().with_order_by(&["event", "timestamp", "id"]).with_primary_key(&["event", "timestamp"])
+    }
+
+    #[test]
+    #[named]
+    fn test_order_by() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let db = DB::init(tmp_dir.path(), vec![get_ordered_table()]).unwrap();
+        // two writes, each covering every event
+        let events = ["click", "page_view", "signup"];
+        for write in 0..2u64 {
+            let rows: Vec<Vec<DValue>> = (0..3072u64).rev().map(|i| event_row(events[i as usize % 3], write * 10000 + i, i)).collect();
+            db.write_data(TEST_TABLE_NAME, &rows).unwrap();
+        }
+        assert!(tmp_dir.path().join("events.primary").exists());
+        let rows = db.read_all(TEST_TABLE_NAME).unwrap();
+        assert_eq!(rows[0], event_row("click", 0, 0));
+        assert_eq!(rows[1], event_row("click", 3, 3));
+        assert_eq!(rows[1024], event_row("page_view", 1, 1));
+
+        let eq = |col: &str, value: DValue| Expr::binary(Expr::column(col), BinOp::Eq, Expr::literal(value));
+        let signup = eq("event", DValue::String("signup".to_string()));
+        assert_eq!(blocks_to_read(&db, &signup), 2);
+        // each block only has one event, so the timestamp range is known too
+        let filter = signup.clone().and(Expr::binary(Expr::column("timestamp"), BinOp::Lt, Expr::literal(DValue::Uint64(100))));
+        assert_eq!(blocks_to_read(&db, &filter), 1);
+        assert_eq!(db.scan(TEST_TABLE_NAME, Some(&filter)).unwrap().len(), 33);
+        // id isn't in the primary key
+        assert_eq!(blocks_to_read(&db, &eq("id", DValue::Uint64(5))), 6);
+
+        // merging sorts the whole part, rather than each write
+        db.optimize_table(TEST_TABLE_NAME).unwrap();
+        let table = db.tables[0].clone();
+        assert!(PartReader::open(&db.path, &table).unwrap().is_sorted());
+        let rows = db.read_all(TEST_TABLE_NAME).unwrap();
+        assert_eq!(rows[1023], event_row("click", 3069, 3069));
+        assert_eq!(rows[1024], event_row("click", 10000, 0));
+        assert_eq!(blocks_to_read(&db, &filter), 1);
+    }
+
+    #[test]
+    #[named]
+    fn test_order_by_errors() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let table = get_test_tables()[0].clone().with_order_by(&["event"]).with_primary_key(&["id"]);
+        assert!(DB::init(tmp_dir.path(), vec![table]).is_err());
+        let table = get_test_tables()[0].clone().with_order_by(&["missing"]);
+        assert!(DB::init(tmp_dir.path(), vec![table]).is_err());
+
+        let mut db = DB::init(tmp_dir.path(), vec![get_ordered_table()]).unwrap();
+        assert!(db.alter_table(TEST_TABLE_NAME, &[AlterOperation::DropColumn("id".to_string())]).is_err());
+        assert!(db.update_where(TEST_TABLE_NAME, &[("timestamp", Expr::literal(DValue::Uint64(0)))], &Expr::literal(DValue::Uint64(1))).is_err());
+    }
+
+    #[test]
+    #[named]
+    fn test_block_cache() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let mut db = DB::init(tmp_dir.path(), get_test_tables()).unwrap();
+        let rows: Vec<Vec<DValue>> = (0..3000).map(|i| event_row("test", i, i)).collect();
+        db.write_data(TEST_TABLE_NAME, &rows).unwrap();
+
+        // 3 columns of 3 blocks, all read from disk the first time and from the cache after that
+        let filter = Expr::binary(Expr::column("timestamp"), BinOp::GtEq, Expr::literal(DValue::Uint64(2990)));
+        assert_eq!(db.scan(TEST_TABLE_NAME, Some(&filter)).unwrap().len(), 10);
+        let (hits, misses) = db.cache.stats();
+        assert_eq!((hits, misses), (0, 9));
+        assert_eq!(db.scan(TEST_TABLE_NAME, Some(&filter)).unwrap().len(), 10);
+        assert_eq!(db.cache.stats(), (9, 9));
+
+        // updated blocks aren't read from the cache
+        db.update_where(TEST_TABLE_NAME, &[("id", Expr::literal(DValue::Uint64(0)))], &filter).unwrap();
+        db.run_mutations().unwrap();
+        assert_eq!(db.cache.size(), 0);
+        let ids: Vec<DValue> = db.scan(TEST_TABLE_NAME, Some(&filter)).unwrap().into_iter().map(|row| row[2].clone()).collect();
+        assert_eq!(ids, vec![DValue::Uint64(0); 10]);
+        db.optimize_table(TEST_TABLE_NAME).unwrap();
+        assert_eq!(db.scan(TEST_TABLE_NAME, Some(&filter)).unwrap().len(), 10);
+
+        // a cache of size 0 holds nothing
+        let db = DB::open(tmp_dir.path()).unwrap().with_cache_size(0);
+        db.read_all(TEST_TABLE_NAME).unwrap();
+        db.read_all(TEST_TABLE_NAME).unwrap();
+        assert_eq!(db.cache.stats().0, 0);
+        assert_eq!(db.cache.size(), 0);
+    }
+
+    #[test]
+    #[named]
+    fn test_part_cache() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let mut db = DB::init(tmp_dir.path(), vec![get_partitioned_table()]).unwrap();
+        let rows = get_partitioned_rows();
+        db.write_data(TEST_TABLE_NAME, &rows).unwrap();
+
+        // the parts are loaded by the first scan and kept for the next one, until their files change
+        assert!(db.parts.is_empty());
+        assert_eq!(db.scan(TEST_TABLE_NAME, None).unwrap(), rows);
+        assert_eq!(db.parts.len(), 3);
+        assert_eq!(db.scan(TEST_TABLE_NAME, None).unwrap(), rows);
+        std::fs::remove_file(tmp_dir.path().join("events").join("partitions").join("202401").join("events.id.index")).unwrap();
+        assert!(db.scan(TEST_TABLE_NAME, None).is_err());
+        db.drop_partition(TEST_TABLE_NAME, &DValue::Uint64(202401)).unwrap();
+        assert!(db.parts.is_empty());
+
+        // every change through the handle is seen by the next scan
+        db.scan(TEST_TABLE_NAME, None).unwrap();
+        db.write_data(TEST_TABLE_NAME, &rows[10..11]).unwrap();
+        assert!(db.parts.is_empty());
+        assert_eq!(db.scan(TEST_TABLE_NAME, None).unwrap().len(), 21);
+        let filter = Expr::binary(Expr::column("id"), BinOp::Eq, Expr::literal(DValue::Uint64(0)));
+        assert_eq!(db.delete_where(TEST_TABLE_NAME, &filter).unwrap(), 3);
+        assert_eq!(db.scan(TEST_TABLE_NAME, None).unwrap().len(), 18);
+        db.optimize_table(TEST_TABLE_NAME).unwrap();
+        assert_eq!(db.scan(TEST_TABLE_NAME, None).unwrap().len(), 18);
+
+        // and so is a change through another handle, such as one in another process
+        let other = DB::open(tmp_dir.path()).unwrap();
+        other.write_data(TEST_TABLE_NAME, &[rows[10].clone(), event_row("late", 1714521600, 1)]).unwrap();
+        assert_eq!(db.scan(TEST_TABLE_NAME, None).unwrap().len(), 20);
+        let late = Expr::binary(Expr::column("event"), BinOp::Eq, Expr::literal(DValue::String("late".to_string())));
+        other.delete_where(TEST_TABLE_NAME, &late).unwrap();
+        assert_eq!(db.scan(TEST_TABLE_NAME, None).unwrap().len(), 19);
+        db.alter_table(TEST_TABLE_NAME, &[AlterOperation::DropColumn("id".to_string())]).unwrap();
+        assert!(db.scan(TEST_TABLE_NAME, None).unwrap().iter().all(|row| row.len() == 2));
+    }
+
+    #[test]
+    #[named]
+    fn test_parallel_scan() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let db = DB::init(tmp_dir.path(), vec![get_partitioned_table()]).unwrap();
+        let events = ["click", "page_view", "signup"];
+        for write in 0..3u64 {
+            let rows: Vec<Vec<DValue>> = (0..5000u64).map(|i| event_row(events[i as usize % 3], 1704067200 + write * 2678400 + i, i)).collect();
+            db.write_data(TEST_TABLE_NAME, &rows).unwrap();
+        }
+
+        let filter = Expr::binary(Expr::column("id"), BinOp::Lt, Expr::literal(DValue::Uint64(4000)));
+        let expected = db.scan(TEST_TABLE_NAME, Some(&filter)).unwrap();
+        assert_eq!(expected.len(), 12000);
+        for threads in [0, 2, 7] {
+            assert_eq!(db.scan_parallel(TEST_TABLE_NAME, Some(&filter), threads).unwrap(), expected);
+        }
+
+        let aggregation = Aggregation::new(vec![Expr::column("event")])
+            .with_count()
+            .with_aggregate(AggregateFunction::Sum, Expr::column("id"))
+            .with_aggregate(AggregateFunction::Max, Expr::column("timestamp"));
+        let result = db.aggregate(TEST_TABLE_NAME, &aggregation, Some(&filter), 0).unwrap();
+        assert_eq!(result, db.aggregate(TEST_TABLE_NAME, &aggregation, Some(&filter), 1).unwrap());
+        assert_eq!(result[0], vec![
+            DValue::String("click".to_string()),
+            DValue::Uint64(4002),
+            DValue::Uint64((0..4000).step_by(3).sum::<u64>() * 3),
+            DValue::Uint64(1704067200 + 2 * 2678400 + 3999),
+        ]);
+        assert_eq!(result.len(), 3);
+
+        let total = Aggregation::new(vec![]).with_count();
+        let none = Expr::binary(Expr::column("id"), BinOp::Gt, Expr::literal(DValue::Uint64(10000)));
+        assert_eq!(db.aggregate(TEST_TABLE_NAME, &total, Some(&none), 4).unwrap(), vec![vec![DValue::Uint64(0)]]);
+        let bad = Aggregation::new(vec![]).with_aggregate(AggregateFunction::Sum, Expr::column("event"));
+        assert!(db.aggregate(TEST_TABLE_NAME, &bad, None, 4).is_err());
+    }
+
+    #[test]
+    #[named]
+    fn test_vectorized_filters() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let db = DB::init(tmp_dir.path(), get_test_tables()).unwrap();
+        let events = ["click", "page_view", "Signup"];
+        let rows: Vec<Vec<DValue>> = (0..2500u64).map(|i| event_row(events[i as usize % 3], 1704067200 + i * 60, i)).collect();
+        db.write_data(TEST_TABLE_NAME, &rows).unwrap();
+        let table = db.tables[0].clone();
+
+        let col = Expr::column;
+        let uint = |u: u64| Expr::literal(DValue::Uint64(u));
+        let string = |s: &str| Expr::literal(DValue::String(s.to_string()));
+        let filters = [
+            Expr::binary(col("id"), BinOp::Lt, uint(10)),
+            Expr::binary(uint(2490), BinOp::LtEq, col("id")),
+            Expr::binary(col("event"), BinOp::NotEq, string("click")).and(Expr::binary(col("id"), BinOp::Gt, uint(2400))),
+            Expr::binary(col("event"), BinOp::Eq, string("Signup")).or(Expr::binary(Expr::binary(col("id"), BinOp::Mod, uint(500)), BinOp::Eq, uint(0))),
+            Expr::Not(Box::new(Expr::binary(col("id"), BinOp::GtEq, uint(5)))),
+            Expr::function(Function::Contains, vec![col("event"), string("view")]).and(Expr::binary(col("id"), BinOp::Lt, uint(30))),
+            Expr::binary(Expr::function(Function::Lower, vec![col("event")]), BinOp::Eq, string("signup")).and(Expr::binary(col("id"), BinOp::Lt, uint(30))),
+            Expr::binary(Expr::function(Function::ToStartOfHour, vec![col("timestamp")]), BinOp::Eq, uint(1704067200 + 3600)),
+            Expr::binary(Expr::function(Function::Concat, vec![col("event"), col("id")]), BinOp::Eq, string("click42")),
+            Expr::binary(col("event"), BinOp::Lt, Expr::function(Function::ToString, vec![col("id")])),
+            uint(1),
+        ];
+        for filter in &filters {
+            let expected: Vec<Vec<DValue>> = rows.iter().filter(|row| filter.matches(&TableRow { columns: &table.columns, values: row }).unwrap()).cloned().collect();
+            assert_eq!(db.scan(TEST_TABLE_NAME, Some(filter)).unwrap(), expected, "{:?}", filter);
+        }
+
+        assert!(db.scan(TEST_TABLE_NAME, Some(&col("event"))).is_err());
+        assert!(db.scan(TEST_TABLE_NAME, Some(&Expr::binary(col("event"), BinOp::Eq, uint(1)))).is_err());
+        // only the rows that are still there are evaluated, so a deleted row can't cause an error
+        let overflow = Expr::binary(Expr::binary(uint(u64::MAX - 2500), BinOp::Add, col("id")), BinOp::Gt, uint(0));
+        assert!(db.scan(TEST_TABLE_NAME, Some(&overflow)).is_ok());
+        db.write_data(TEST_TABLE_NAME, &[event_row("click", 0, 2501)]).unwrap();
+        assert!(db.scan(TEST_TABLE_NAME, Some(&overflow)).is_err());
+        db.delete_where(TEST_TABLE_NAME, &Expr::binary(col("id"), BinOp::Eq, uint(2501))).unwrap();
+        assert_eq!(db.scan(TEST_TABLE_NAME, Some(&overflow)).unwrap().len(), 2500);
+    }
+
+    #[test]
+    #[named]
+    fn test_sql_select() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let db = DB::init(tmp_dir.path(), get_test_tables()).unwrap();
+        let events = ["click", "click", "page_view", "signup"];
+        let rows: Vec<Vec<DValue>> = (0..3000u64).map(|i| event_row(events[i as usize % 4], 1704067200 + i * 10, i)).collect();
+        db.write_data(TEST_TABLE_NAME, &rows).unwrap();
+        let string = |s: &str| DValue::String(s.to_string());
+
+        let result = db.query("SELECT event, count() FROM events WHERE timestamp >= 1704067200 + 10000 GROUP BY event ORDER BY 2 DESC LIMIT 2").unwrap();
+        assert_eq!(result.columns, vec![("event".to_string(), DType::String), ("count()".to_string(), DType::Uint64)]);
+        assert_eq!(result.rows, vec![vec![string("click"), DValue::Uint64(1000)], vec![string("page_view"), DValue::Uint64(500)]]);
+
+        let result = db.query("SELECT id, upper(event) AS name FROM events WHERE id % 1000 = 999 ORDER BY id DESC").unwrap();
+        assert_eq!(result.columns[1], ("name".to_string(), DType::String));
+        assert_eq!(result.rows, vec![
+            vec![DValue::Uint64(2999), string("SIGNUP")],
+            vec![DValue::Uint64(1999), string("SIGNUP")],
+            vec![DValue::Uint64(999), string("SIGNUP")],
+        ]);
+
+        let result = db.query("select toStartOfHour(timestamp) hour, sum(id), min(event) from events group by hour having count(*) > 300 order by hour limit 1 offset 2 settings max_threads = 3").unwrap();
+        let hour = (1704067200u64 + 7200..1704067200 + 10800).filter(|t| (t - 1704067200) % 10 == 0).map(|t| (t - 1704067200) / 10);
+        assert_eq!(result.rows, vec![vec![DValue::Uint64(1704067200 + 7200), DValue::Uint64(hour.sum()), string("click")]]);
+
+        let result = db.query("SELECT * FROM events WHERE contains(event, 'view') AND id < 10").unwrap();
+        assert_eq!(result.rows, vec![rows[2].clone(), rows[6].clone()]);
+        // with no GROUP BY there is one row, unless there is nothing for max to be
+        let result = db.query("SELECT count(), sum(id) FROM events WHERE id > 5000").unwrap();
+        assert_eq!(result.rows, vec![vec![DValue::Uint64(0), DValue::Uint64(0)]]);
+        assert!(db.query("SELECT count(), max(id) FROM events WHERE id > 5000").unwrap().rows.is_empty());
+
+        assert!(db.query("SELECT id FROM missing").is_err());
+        assert!(db.query("SELECT nope FROM events").is_err());
+        assert!(db.query("SELECT event, id FROM events GROUP BY event").is_err());
+        assert!(db.query("SELECT id FROM events WHERE").is_err());
+    }
+
+    #[test]
+    #[named]
+    fn test_sql_ddl() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let mut db = DB::init(tmp_dir.path(), vec![]).unwrap();
+        let string = |s: &str| DValue::String(s.to_string());
+
+        let results = db.execute_script("
+            -- set up and seed the tables
+            CREATE TABLE events (event String, timestamp UInt64, id UInt64 DEFAULT timestamp % 100)
+                PARTITION BY toYYYYMM(timestamp) ORDER BY (event, timestamp);
+            CREATE TABLE IF NOT EXISTS events (event String);
+            CREATE TABLE old (id UInt64);
+            INSERT INTO events VALUES ('click', 1704067200, 1), ('signup', 1706745600 + 5, 2);
+            INSERT INTO events (timestamp, event) VALUES (1704067210, concat('c', 'lick'));
+            DROP TABLE old;
+            DROP TABLE IF EXISTS old;
+            SELECT event, count() FROM events GROUP BY event ORDER BY event
+        ").unwrap();
+        assert_eq!(results.len(), 8);
+        assert_eq!(results[7].rows, vec![vec![string("click"), DValue::Uint64(2)], vec![string("signup"), DValue::Uint64(1)]]);
+
+        let table = &db.tables[0];
+        assert_eq!((table.name.as_str(), table.order_by.len(), table.partition_by.is_some()), ("events", 2, true));
+        assert_eq!(db.read_all(TEST_TABLE_NAME).unwrap().len(), 3);
+        // the default is computed from the other columns
+        let result = db.query("SELECT id FROM events WHERE timestamp = 1704067210").unwrap();
+        assert_eq!(result.rows, vec![vec![DValue::Uint64(10)]]);
+
+        assert_eq!(db.query("SHOW TABLES").unwrap().rows, vec![vec![string("events")]]);
+        let result = db.query("DESCRIBE events").unwrap();
+        assert_eq!(result.rows[2], vec![string("id"), string("UInt64")]);
+
+        // writes have to go through execute
+        assert!(db.query("DROP TABLE events").is_err());
+        assert!(db.execute("INSERT INTO events (event) VALUES (1)").is_err());
+        assert!(db.execute("INSERT INTO events (event) VALUES (event)").is_err());
+        assert!(db.execute("INSERT INTO events VALUES ('click', 1)").is_err());
+        assert!(db.execute("INSERT INTO missing VALUES (1)").is_err());
+        assert!(db.execute("CREATE TABLE events (event String)").is_err());
+        assert!(db.execute("CREATE TABLE t (id UInt64) ORDER BY nope").is_err());
+        // names end up in file names
+        assert!(db.execute("CREATE TABLE \"../x\" (id UInt64)").is_err());
+        assert!(db.execute("CREATE TABLE \"\" (id UInt64)").is_err());
+        assert!(db.execute("CREATE TABLE t (\"a.b\" UInt64)").is_err());
+        assert!(db.alter_table(TEST_TABLE_NAME, &[
+            AlterOperation::RenameColumn { from: "id".to_string(), to: "a/b".to_string() },
+        ]).is_err());
+        assert!(!tmp_dir.path().parent().unwrap().join("x").exists());
+        assert_eq!(db.read_all(TEST_TABLE_NAME).unwrap().len(), 3);
+        db.execute("DROP TABLE events").unwrap();
+        assert!(db.tables.is_empty());
+    }
+
+    #[test]
+    #[named]
+    fn test_explain() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let mut db = DB::init(tmp_dir.path(), vec![]).unwrap();
+        db.execute("CREATE TABLE events (event String, timestamp UInt64, id UInt64) ORDER BY id").unwrap();
+        let rows: Vec<Vec<DValue>> = (0..10240u64).map(|i| event_row("click", 1704067200 + i, i)).collect();
+        db.write_data(TEST_TABLE_NAME, &rows).unwrap();
+        db.execute("CREATE TABLE names (name String)").unwrap();
+        db.write_data("names", &[vec![DValue::String("a".to_string())]]).unwrap();
+        let lines = |sql: &str| -> Vec<String> {
+            db.query(sql).unwrap().rows.into_iter().map(|row| match &row[0] {
+                DValue::String(line) => line.clone(),
+                value => panic!("Expected a line of text, got {:?}", value),
+            }).collect()
+        };
+
+        // the primary index rules out all but the first block
+        let explained = lines("EXPLAIN SELECT id, upper(event) AS name FROM events WHERE id < 100 ORDER BY id DESC LIMIT 5");
+        assert_eq!(explained[..6], [
+            "Plan:",
+            "  Limit 5 offset 0",
+            "  Sort by id DESC",
+            "  Project id, upper(event) AS name",
+            "  Filter id < 100",
+            "  Scan events, max_threads 0",
+        ]);
+        assert!(explained.contains(&"  Blocks: 1 of 10".to_string()));
+        assert!(explained.contains(&"  Rows: at most 1024 of 10240".to_string()));
+        assert!(explained.iter().any(|line| line.starts_with("  Column id: 1 of 10 blocks")));
+
+        let analyzed = lines("EXPLAIN ANALYZE SELECT count() FROM events WHERE id < 100");
+        assert!(analyzed.contains(&"  Rows returned: 1".to_string()));
+        assert!(analyzed.contains(&"  Blocks read: 1, skipped by the indexes: 9, column blocks from the cache: 0".to_string()));
+        assert!(analyzed.contains(&"  Rows scanned: 1024, filtered out: 924, matched: 100".to_string()));
+        assert!(analyzed.iter().any(|line| line.starts_with("  Stage scan and aggregate: ")));
+        // the second run finds the blocks in the cache
+        let analyzed = lines("EXPLAIN ANALYZE SELECT count() FROM events WHERE id < 100");
+        assert!(analyzed.contains(&"  Blocks read: 1, skipped by the indexes: 9, column blocks from the cache: 3".to_string()));
+        assert!(analyzed.contains(&"  Bytes read: 0, decompressed: 0".to_string()));
+
+        // the index files only give the rows of a String block by its size once decompressed
+        let explained = lines("EXPLAIN SELECT name FROM names");
+        assert!(explained.contains(&"  Rows: unknown, some blocks only have String columns".to_string()));
+        assert!(explained.contains(&"  Blocks: 1 of 1".to_string()));
+
+        assert!(db.query("EXPLAIN SELECT nope FROM events").is_err());
+        assert!(db.query("EXPLAIN DROP TABLE events").is_err());
+    }
+
+    #[test]
+    #[named]
+    fn test_csv_import() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let db = DB::init(tmp_dir.path(), get_test_tables()).unwrap();
+
+        // big enough for several batches, which are all written as whole blocks except the last
+        let mut csv = "ts;name;id\n".to_string();
+        for i in 0..40000 {
+            csv.push_str(&format!("{};'ev;{}';{}\n", 1704067200 + i, i % 3, i));
+        }
+        let options = CsvOptions::new().with_delimiter(';').with_quote('\'').with_column_mapping("ts", "timestamp").with_column_mapping("name", "event");
+        let summary = db.import_csv(TEST_TABLE_NAME, csv.as_bytes(), &options).unwrap();
+        assert_eq!((summary.rows_written, summary.errors.len()), (40000, 0));
+        let rows = db.read_all(TEST_TABLE_NAME).unwrap();
+        assert_eq!(rows[39999], event_row("ev;0", 1704067200 + 39999, 39999));
+        let blocks = &db.inspect_column(TEST_TABLE_NAME, "id").unwrap()[0].1;
+        assert_eq!(blocks.len(), 40);
+        assert!(blocks[..39].iter().all(|block| block.n_rows == 1024));
+
+        // bad rows are skipped and reported, up to max_errors
+        let csv = "id,timestamp\n1,2\nx,3\n4\n5,6\n";
+        let summary = db.import_csv(TEST_TABLE_NAME, csv.as_bytes(), &CsvOptions::new().with_max_errors(2)).unwrap();
+        assert_eq!(summary.rows_written, 2);
+        let errors: Vec<String> = summary.errors.iter().map(|error| error.to_string()).collect();
+        assert_eq!(errors, vec!["Line 3, column id: Expected a UInt64, got 'x'", "Line 4: Expected 2 fields, got 1"]);
+        let error = db.import_csv(TEST_TABLE_NAME, csv.as_bytes(), &CsvOptions::new().with_max_errors(1)).unwrap_err();
+        assert!(error.to_string().starts_with("Too many errors (2)"));
+
+        let summary = db.import_csv(TEST_TABLE_NAME, "a,7,8\n".as_bytes(), &CsvOptions::new().without_header()).unwrap();
+        assert_eq!(summary.rows_written, 1);
+        assert!(db.import_csv(TEST_TABLE_NAME, "nope\n1\n".as_bytes(), &CsvOptions::new()).is_err());
+        assert!(db.import_csv(TEST_TABLE_NAME, "id,id\n1,1\n".as_bytes(), &CsvOptions::new()).is_err());
+        // the import that failed hadn't written its first batch yet
+        assert_eq!(db.read_all(TEST_TABLE_NAME).unwrap().len(), 40000 + 2 + 1);
+    }
+
+    #[test]
+    #[named]
+    fn test_jsonl_import() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let mut tables = get_test_tables();
+        tables[0].columns.push(ColumnMetaData::new("browser", DType::String));
+        tables[0].columns.push(ColumnMetaData::new("extra", DType::String));
+        let db = DB::init(tmp_dir.path(), tables).unwrap();
+
+        let jsonl = concat!(
+            "{\"event\": \"click\", \"ts\": 1704067200, \"id\": \"1\", \"properties\": {\"$browser\": \"Firefox\", \"os\": \"Linux\"}}\n",
+            "\n",
+            "{\"event\": \"view\", \"ts\": 1704067210, \"id\": 2, \"properties\": {\"$browser\": null}}\n",
+            "{\"event\": \"view\", \"id\": -3}\n",
+            "not json\n",
+            "{\"event\": [\"a\", 1], \"id\": 4, \"tags\": [1, 2]}\n",
+        );
+        let options = JsonlOptions::new()
+            .with_path_mapping("ts", "timestamp")
+            .with_path_mapping("properties.$browser", "browser")
+            .with_catch_all_column("extra")
+            .with_max_errors(2);
+        let summary = db.import_jsonl(TEST_TABLE_NAME, jsonl.as_bytes(), &options).unwrap();
+        assert_eq!(summary.rows_written, 3);
+        let errors: Vec<String> = summary.errors.iter().map(|error| error.to_string()).collect();
+        assert_eq!(errors, vec![
+            "Line 4, column id: Expected a UInt64, got -3",
+            "Line 5: Invalid JSON: expected ident at line 1 column 2",
+        ]);
+        let string = |s: &str| DValue::String(s.to_string());
+        assert_eq!(db.read_all(TEST_TABLE_NAME).unwrap(), vec![
+            vec![string("click"), DValue::Uint64(1704067200), DValue::Uint64(1), string("Firefox"), string("{\"properties\":{\"os\":\"Linux\"}}")],
+            // the null is left out, and so is the object it leaves empty
+            vec![string("view"), DValue::Uint64(1704067210), DValue::Uint64(2), string(""), string("")],
+            vec![string("[\"a\",1]"), DValue::Uint64(0), DValue::Uint64(4), string(""), string("{\"tags\":[1,2]}")],
+        ]);
+
+        // without a catch-all column unknown keys are dropped
+        let summary = db.import_jsonl(TEST_TABLE_NAME, "{\"id\": 5, \"other\": 1}\n".as_bytes(), &JsonlOptions::new()).unwrap();
+        assert_eq!((summary.rows_written, summary.errors.len()), (1, 0));
+        assert_eq!(db.read_all(TEST_TABLE_NAME).unwrap()[3][4], string(""));
+        assert!(db.import_jsonl(TEST_TABLE_NAME, jsonl.as_bytes(), &options.clone().with_max_errors(1)).is_err());
+        assert!(db.import_jsonl(TEST_TABLE_NAME, "".as_bytes(), &JsonlOptions::new().with_catch_all_column("id")).is_err());
+        assert!(db.import_jsonl(TEST_TABLE_NAME, "".as_bytes(), &JsonlOptions::new().with_path_mapping("a.b", "nope")).is_err());
+        assert!(db.import_jsonl(TEST_TABLE_NAME, "".as_bytes(), &JsonlOptions::new().with_path_mapping("a", "id").with_path_mapping("b", "id")).is_err());
+    }
+
+    #[test]
+    #[named]
+    fn test_export() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let db = DB::init(tmp_dir.path(), get_test_tables()).unwrap();
+        let rows: Vec<Vec<DValue>> = (0..3000).map(|i| event_row(if i % 2 == 0 { "a\tb" } else { "c,\"d\"" }, 1704067200 + i, i)).collect();
+        db.write_data(TEST_TABLE_NAME, &rows).unwrap();
+
+        let export = |sql: &str, format| {
+            let mut out = vec![];
+            let n_rows = db.export(sql, format, &mut out).unwrap();
+            (n_rows, String::from_utf8(out).unwrap())
+        };
+        // the offset and limit cross block boundaries while streaming
+        let (n_rows, tsv) = export("SELECT id, event FROM events WHERE id % 3 = 0 LIMIT 500 OFFSET 300", ExportFormat::Tsv);
+        assert_eq!(n_rows, 500);
+        let lines: Vec<&str> = tsv.lines().collect();
+        assert_eq!(lines[..3], ["id\tevent", "900\ta\\tb", "903\tc,\"d\""]);
+        assert_eq!(lines[500], "2397\tc,\"d\"");
+
+        // the FORMAT clause takes precedence
+        let (_, csv) = export("SELECT id, event FROM events WHERE id < 2 FORMAT CSV", ExportFormat::Tsv);
+        assert_eq!(csv, "id,event\n0,a\tb\n1,\"c,\"\"d\"\"\"\n");
+        let (_, json) = export("SELECT event, count() AS n FROM events GROUP BY event ORDER BY event", ExportFormat::Json);
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&json).unwrap(), serde_json::json!([
+            {"event": "a\tb", "n": 1500},
+            {"event": "c,\"d\"", "n": 1500},
+        ]));
+        let (n_rows, _) = export("SELECT * FROM events LIMIT 0", ExportFormat::Csv);
+        assert_eq!(n_rows, 0);
+        let (_, tables) = export("SHOW TABLES", ExportFormat::JsonLines);
+        assert_eq!(tables, "{\"name\":\"events\"}\n");
+
+        let mut out = vec![];
+        let filter = Expr::BinaryOp(Box::new(Expr::Column("id".to_string())), BinOp::GtEq, Box::new(Expr::Literal(DValue::Uint64(2998))));
+        assert_eq!(db.export_table(TEST_TABLE_NAME, Some(&filter), ExportFormat::JsonLines, &mut out).unwrap(), 2);
+        assert_eq!(String::from_utf8(out).unwrap(), concat!(
+            "{\"event\":\"a\\tb\",\"timestamp\":1704070198,\"id\":2998}\n",
+            "{\"event\":\"c,\\\"d\\\"\",\"timestamp\":1704070199,\"id\":2999}\n",
+        ));
+        let mut out = vec![];
+        assert_eq!(db.export_table(TEST_TABLE_NAME, None, ExportFormat::Csv, &mut out).unwrap(), 3000);
+        assert!(db.export("SELECT * FROM events FORMAT xml", ExportFormat::Csv, vec![]).is_err());
+        assert!(db.export("DROP TABLE events", ExportFormat::Csv, vec![]).is_err());
+    }
+
+    #[test]
+    #[named]
+    #[cfg(feature = "arrow")]
+    fn test_arrow() {
+        use std::sync::Arc;
+        use arrow_array::cast::AsArray;
+        use arrow_array::types::UInt64Type;
+        use arrow_array::{ArrayRef, RecordBatch, StringArray, UInt32Array, UInt64Array};
+        use rtcdb::arrow::{result_to_record_batch, table_from_arrow_schema, to_arrow_schema};
+
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let db = DB::init(tmp_dir.path(), get_test_tables()).unwrap();
+        let schema = to_arrow_schema(&get_test_tables()[0]);
+        assert_eq!(table_from_arrow_schema(TEST_TABLE_NAME, &schema).unwrap(), get_test_tables()[0]);
+
+        // the timestamp is left out and gets its default, the id is converted from a UInt32
+        let batch = RecordBatch::try_from_iter(vec![
+            ("id", Arc::new(UInt32Array::from_iter_values(0..3000)) as ArrayRef),
+            ("event", Arc::new(StringArray::from_iter_values((0..3000).map(|i| format!("event {}", i % 3)))) as ArrayRef),
+        ])
+        .unwrap();
+        db.write_arrow(TEST_TABLE_NAME, &batch).unwrap();
+        assert_eq!(db.read_all(TEST_TABLE_NAME).unwrap()[2999], event_row("event 2", 0, 2999));
+
+        let filter = Expr::BinaryOp(Box::new(Expr::Column("id".to_string())), BinOp::Lt, Box::new(Expr::Literal(DValue::Uint64(1500))));
+        let batches = db.scan_arrow(TEST_TABLE_NAME, Some(&filter)).unwrap().collect::<anyhow::Result<Vec<RecordBatch>>>().unwrap();
+        // a whole block, then the matching rows of the second, and the third block is skipped
+        assert_eq!(batches.iter().map(|batch| batch.num_rows()).collect::<Vec<usize>>(), vec![1024, 476]);
+        assert_eq!(*batches[0].schema(), schema);
+        assert_eq!(batches[1].column(0).as_string::<i32>().value(475), "event 2");
+        assert_eq!(batches[1].column(2).as_primitive::<UInt64Type>().value(475), 1499);
+
+        let result = db.query("SELECT event, count() AS n FROM events GROUP BY event ORDER BY event").unwrap();
+        let batch = result_to_record_batch(&result).unwrap();
+        assert_eq!(batch.column(1).as_primitive::<UInt64Type>(), &UInt64Array::from(vec![1000, 1000, 1000]));
+
+        let wrong_type = RecordBatch::try_from_iter(vec![("event", Arc::new(UInt64Array::from(vec![1])) as ArrayRef)]).unwrap();
+        assert!(db.write_arrow(TEST_TABLE_NAME, &wrong_type).is_err());
+        assert!(db.scan_arrow("nope", None).is_err());
+
+        // batches are split into partitions and sorted without going through rows, and end up the
+        // same as the rows written by write_data
+        let mut db = db;
+        let partitioned = |name: &str| TableMetaData { name: name.to_string(), ..get_partitioned_table().with_order_by(&["id"]) };
+        db.create_table(partitioned("by_rows")).unwrap();
+        db.create_table(partitioned("by_batch")).unwrap();
+        let rows: Vec<Vec<DValue>> = get_partitioned_rows().into_iter().rev().collect();
+        db.write_data("by_rows", &rows).unwrap();
+        let uint64s = |i: usize| -> ArrayRef {
+            Arc::new(rows.iter().map(|row| match row[i] {
+                DValue::Uint64(u) => u,
+                _ => panic!("Expected a UInt64"),
+            }).collect::<UInt64Array>())
+        };
+        let batch = RecordBatch::try_from_iter(vec![
+            ("id", uint64s(2)),
+            ("timestamp", uint64s(1)),
+            ("event", Arc::new(StringArray::from_iter_values(rows.iter().map(|_| "test"))) as ArrayRef),
+        ])
+        .unwrap();
+        db.write_arrow("by_batch", &batch).unwrap();
+        assert_eq!(db.scan("by_batch", None).unwrap(), db.scan("by_rows", None).unwrap());
+        let ranges = |table: &str| -> Vec<Vec<String>> {
+            db.inspect_column(table, "id").unwrap().into_iter().map(|(_, blocks)| blocks.iter().map(|block| format!("{:?}", block.range)).collect()).collect()
+        };
+        assert_eq!(ranges("by_batch"), ranges("by_rows"));
+        assert_eq!(ranges("by_batch").len(), 3);
+    }
+
+    #[test]
+    #[named]
+    #[cfg(feature = "parquet")]
+    fn test_parquet() {
+        use std::fs::File;
+        use std::sync::Arc;
+        use arrow_array::{ArrayRef, Date32Array, RecordBatch, StringArray, TimestampMillisecondArray};
+        use parquet::arrow::arrow_reader::{ArrowReaderOptions, ParquetRecordBatchReaderBuilder};
+        use parquet::arrow::ArrowWriter;
+        use parquet::file::page_index::index::Index;
+        use rtcdb::parquet::table_from_parquet;
+
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let mut db = DB::init(tmp_dir.path(), vec![get_test_tables()[0].clone().with_order_by(&["id"])]).unwrap();
+        let rows: Vec<Vec<DValue>> = (0..3000).map(|i| event_row(&format!("event {}", i % 3), 1704067200 + i, i)).collect();
+        db.write_data(TEST_TABLE_NAME, &rows).unwrap();
+
+        let path = tmp_dir.path().join("events.parquet");
+        assert_eq!(db.export_table_parquet(TEST_TABLE_NAME, None, File::create(&path).unwrap()).unwrap(), 3000);
+        // a page per block, with the same min and max as the block
+        let options = ArrowReaderOptions::new().with_page_index(true);
+        let builder = ParquetRecordBatchReaderBuilder::try_new_with_options(File::open(&path).unwrap(), options).unwrap();
+        let Index::INT64(index) = &builder.metadata().column_index().unwrap()[0][2] else {
+            panic!("Expected an INT64 column index");
+        };
+        let pages: Vec<(Option<i64>, Option<i64>)> = index.indexes.iter().map(|page| (page.min, page.max)).collect();
+        let blocks: Vec<(Option<i64>, Option<i64>)> = db.inspect_column(TEST_TABLE_NAME, "id").unwrap()[0]
+            .1
+            .iter()
+            .map(|block| match &block.range {
+                Some((DValue::Uint64(min), DValue::Uint64(max))) => (Some(*min as i64), Some(*max as i64)),
+                _ => panic!("Expected a UInt64 range"),
+            })
+            .collect();
+        assert_eq!(pages, blocks);
+        assert_eq!(pages, vec![(Some(0), Some(1023)), (Some(1024), Some(2047)), (Some(2048), Some(2999))]);
+
+        // import into a table made from the file's schema
+        let table = table_from_parquet("copy", File::open(&path).unwrap()).unwrap();
+        assert_eq!(table.columns, get_test_tables()[0].columns);
+        db.create_table(table).unwrap();
+        assert_eq!(db.import_parquet("copy", File::open(&path).unwrap()).unwrap(), 3000);
+        assert_eq!(db.read_all("copy").unwrap(), rows);
+
+        let path = tmp_dir.path().join("result.parquet");
+        let sql = "SELECT event, count() AS n FROM events GROUP BY event ORDER BY event";
+        assert_eq!(db.export_parquet(sql, File::create(&path).unwrap()).unwrap(), 3);
+        let batches: Vec<RecordBatch> = ParquetRecordBatchReaderBuilder::try_new(File::open(&path).unwrap()).unwrap().build().unwrap().map(|batch| batch.unwrap()).collect();
+        assert_eq!(batches[0].num_rows(), 3);
+        assert_eq!(batches[0].schema().field(1).name(), "n");
+        assert_eq!(db.export_parquet("SELECT id FROM events WHERE id >= 1000", std::io::sink()).unwrap(), 2000);
+
+        // timestamps and dates become seconds since the epoch, and columns left out get their default
+        let path = tmp_dir.path().join("typed.parquet");
+        let batch = RecordBatch::try_from_iter(vec![
+            ("event", Arc::new(StringArray::from(vec!["signup"])) as ArrayRef),
+            ("timestamp", Arc::new(TimestampMillisecondArray::from(vec![1704067200123])) as ArrayRef),
+            ("day", Arc::new(Date32Array::from(vec![19723])) as ArrayRef),
+        ])
+        .unwrap();
+        let mut writer = ArrowWriter::try_new(File::create(&path).unwrap(), batch.schema(), None).unwrap();
+        writer.write(&batch).unwrap();
+        writer.close().unwrap();
+        let mut table = table_from_parquet("typed", File::open(&path).unwrap()).unwrap();
+        assert_eq!(table.columns[2], ColumnMetaData::new("day", DType::Uint64));
+        table.columns.push(ColumnMetaData::new("id", DType::Uint64));
+        db.create_table(table).unwrap();
+        assert_eq!(db.import_parquet("typed", File::open(&path).unwrap()).unwrap(), 1);
+        assert_eq!(db.read_all("typed").unwrap(), vec![vec![
+            DValue::String("signup".to_string()),
+            DValue::Uint64(1704067200),
+            DValue::Uint64(1704067200),
+            DValue::Uint64(0),
+        ]]);
+        assert!(db.import_parquet(TEST_TABLE_NAME, File::open(&path).unwrap()).is_err());
+
+        // and from the command line
+        let dir = tmp_dir.path().to_str().unwrap();
+        let rtcdb = |args: &[&str]| std::process::Command::new(env!("CARGO_BIN_EXE_rtcdb")).args(args).output().unwrap();
+        let output = rtcdb(&["query", dir, "SELECT * FROM events WHERE id < 10", "--format", "parquet"]);
+        assert!(output.status.success());
+        std::fs::write(&path, output.stdout).unwrap();
+        assert!(rtcdb(&["insert", dir, "copy", "--format", "parquet", "--file", path.to_str().unwrap()]).status.success());
+        assert!(!rtcdb(&["insert", dir, "copy", "--format", "parquet"]).status.success());
+        assert_eq!(DB::open(tmp_dir.path()).unwrap().read_all("copy").unwrap().len(), 3010);
+    }
+
+    #[test]
+    #[named]
+    fn test_cli() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        let dir = tmp_dir.path().join("db");
+        let dir = dir.to_str().unwrap();
+        let rtcdb = |args: &[&str], stdin: &str| {
+            let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_rtcdb"))
+                .args(args)
+                .stdin(std::process::Stdio::piped())
+                .stdout(std::process::Stdio::piped())
+                .stderr(std::process::Stdio::piped())
+                .spawn()
+                .unwrap();
+            std::io::Write::write_all(&mut child.stdin.take().unwrap(), stdin.as_bytes()).unwrap();
+            let output = child.wait_with_output().unwrap();
+            (output.status.success(), String::from_utf8(output.stdout).unwrap())
+        };
+
+        let schema_path = tmp_dir.path().join("schema.json");
+        let schema = serde_json::to_string(&get_test_tables()[0].clone().with_order_by(&["id"])).unwrap();
+        std::fs::write(&schema_path, schema).unwrap();
+        assert!(rtcdb(&["init", dir, "--schema", schema_path.to_str().unwrap()], "").0);
+
+        let csv = "id,event,timestamp\n1,click,1704067200\n2,\"page, view\",1704067210\n";
+        assert!(rtcdb(&["insert", dir, "events", "--format", "csv"], csv).0);
+        let jsonl = "{\"event\": \"signup\", \"id\": 3}\n";
+        assert!(rtcdb(&["insert", dir, "events", "--format", "jsonl"], jsonl).0);
+        assert!(!rtcdb(&["insert", dir, "events", "--format", "csv"], "id\nnope\n").0);
+        assert!(!rtcdb(&["insert", dir, "events", "--format", "xml"], "").0);
+
+        let (ok, output) = rtcdb(&["query", dir, "SELECT id, event FROM events WHERE id > 1 ORDER BY id"], "");
+        assert!(ok);
+        assert_eq!(output, "id\tevent\n2\tpage, view\n3\tsignup\n");
+        let query = r#"{"table": "events", "aggregation": {"group_by": [], "aggregates": [["Count", null], ["Max", {"Column": "id"}]]}}"#;
+        assert_eq!(rtcdb(&["query", dir, query], "").1, "count()\tmax(id)\n3\t3\n");
+        let query = r#"{"table": "events", "filter": {"BinaryOp": [{"Column": "id"}, "Lt", {"Literal": {"Uint64": 2}}]}}"#;
+        assert_eq!(rtcdb(&["query", dir, query], "").1, "event\ttimestamp\tid\nclick\t1704067200\t1\n");
+        let (ok, output) = rtcdb(&["query", dir, "SELECT id, event FROM events WHERE id = 2", "--format", "csv"], "");
+        assert!(ok);
+        assert_eq!(output, "id,event\n2,\"page, view\"\n");
+        assert_eq!(rtcdb(&["query", dir, "SELECT id FROM events WHERE id = 3 FORMAT JSONLines"], "").1, "{\"id\":3}\n");
+
+        assert_eq!(rtcdb(&["tables", dir], "").1, "events\tevent String, timestamp Uint64, id Uint64\n");
+        let (ok, output) = rtcdb(&["inspect", dir, "events", "id"], "");
+        assert!(ok);
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[0], "part\tblock\toffset\tcompressed_size\tdecompressed_size\trows\tmin\tmax");
+        // one block per insert, each sorted by id
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].starts_with(".\t0\t") && lines[1].ends_with("\t16\t2\t1\t2"));
+        assert!(lines[2].ends_with("\t8\t1\t3\t3"));
+
+        assert!(!rtcdb(&["inspect", dir, "events", "nope"], "").0);
+        assert!(!rtcdb(&["query", dir, "SELECT nope FROM events"], "").0);
+        assert!(!rtcdb(&["frobnicate"], "").0);
+    }
+
+    #[test]
+    #[named]
+    fn test_shell() {
+        let tmp_dir = TempDir::new(function_name!()).unwrap();
+        DB::init(tmp_dir.path(), vec![]).unwrap();
+        let input = "CREATE TABLE events (event String, id UInt64) ORDER BY id;
+INSERT INTO events VALUES
+    ('click', 1), ('page_view', 22);
+\\d
+\\d events
+SELECT * FROM events
+    ORDER BY id DESC;
+SELECT nope FROM events;
+\\x
+SELECT count() FROM events";
+        let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_rtcdb"))
+            .args(["shell", tmp_dir.path().to_str().unwrap()])
+            // keep the history out of the real home directory
+            .env("HOME", tmp_dir.path())
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        std::io::Write::write_all(&mut child.stdin.take().unwrap(), input.as_bytes()).unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success());
+        let output = String::from_utf8(output.stdout).unwrap();
+
+        assert!(output.contains(" table  | columns\n--------+---------\n events |       2\n"));
+        assert!(output.contains(" name  | type\n-------+--------\n event | String\n id    | UInt64\nOrder by: id\n"));
+        assert!(output.contains(" event     | id\n-----------+----\n page_view | 22\n click     |  1\n2 rows in "));
+        assert!(output.contains("read 2 rows in 1 block, "));
+        assert!(output.contains("Error: Unknown column nope in table events\n"));
+        assert!(output.contains("Unknown command \\x"));
+        // the last statement runs at the end of the input even without a semicolon
+        assert!(output.contains(" count()\n---------\n       2\n1 row in "));
+        assert!(tmp_dir.path().join(".rtcdb_history").exists());
+    }
 }